@@ -0,0 +1,53 @@
+/*
+ * C interface to garner, built as a shared library with
+ *
+ *     cargo rustc --release --lib --crate-type cdylib
+ *
+ * Strings are NUL-terminated UTF-8.  A call that fails returns null or -1
+ * and leaves its message for garner_last_error().  Strings and bodies
+ * returned by garner belong to the caller, who frees them with
+ * garner_string_free() and garner_bytes_free().
+ */
+
+#ifndef GARNER_H
+#define GARNER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct GarnerService GarnerService;
+
+/* The message of the last failed call on this thread, or null. */
+const char *garner_last_error(void);
+
+void garner_string_free(char *value);
+void garner_bytes_free(uint8_t *data, size_t len);
+
+/* The ur:signing-public-key of a private key. */
+char *garner_public_key(const char *private_key);
+
+/* The .onion address of a public key UR. */
+char *garner_onion_address(const char *public_key);
+
+/* Fetch url over Tor into *body and *body_len.  Returns 0 or -1. */
+int garner_fetch(const char *url, uint8_t **body, size_t *body_len);
+
+/* Serve docroot as an onion service; key and nickname may be null. */
+GarnerService *garner_serve(const char *docroot, const char *key,
+                            const char *nickname);
+
+/* The service's .onion address. */
+char *garner_service_address(const GarnerService *service);
+
+/* Stop the service and free it. */
+void garner_service_stop(GarnerService *service);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
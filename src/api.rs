@@ -0,0 +1,413 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context as _, Result, anyhow};
+use arti_client::TorClient;
+use base64::Engine as _;
+use clap::Args;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use serde_json::{Value, json};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_util::compat::TokioAsyncReadCompatExt as _;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::{auth::BearerToken, get, server, tor, ui};
+
+/// Largest request head accepted from an API client.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Largest JSON-RPC request body accepted.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// Options for `garner api`.
+#[derive(Args)]
+pub struct ApiArgs {
+    /// Address to accept JSON-RPC requests on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7784")]
+    pub listen: String,
+    /// Bearer token clients must send [default: a random token, printed
+    /// at startup]
+    #[arg(long, env = "GARNER_API_TOKEN")]
+    pub token: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+/// State shared by every API connection: one Tor client, bootstrapped on
+/// first use, and the onion services launched on it.
+struct Api {
+    token: BearerToken,
+    tor_args: tor::TorArgs,
+    tor: OnceCell<Tor>,
+    services: Mutex<HashMap<String, server::Service>>,
+}
+
+/// The shared Tor client.  Fields drop in order, so the client releases
+/// its locks before its state directory is deleted.
+struct Tor {
+    client: TorClient<PreferredRuntime>,
+    fetcher: get::Fetcher,
+    _state_dir: tor::StateDir,
+}
+
+/// Marks an error as the caller's fault, reported as invalid params.
+#[derive(Debug)]
+struct InvalidParams;
+
+impl std::fmt::Display for InvalidParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid params")
+    }
+}
+
+pub async fn run(args: ApiArgs) -> Result<()> {
+    let token = match args.token {
+        Some(token) => token,
+        None => {
+            let token = hex::encode(bc_rand::random_data(32));
+            ui::log(&format!("API token: {token}"));
+            token
+        }
+    };
+    let listener = tokio::net::TcpListener::bind(&args.listen)
+        .await
+        .with_context(|| format!("listening on {}", args.listen))?;
+    ui::log(&format!("API listening on {}", listener.local_addr()?));
+    let api = Arc::new(Api::new(&token, args.tor));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let api = Arc::clone(&api);
+        tokio::spawn(async move {
+            if let Err(e) = api.handle_connection(stream.compat()).await {
+                ui::log(&format!("API error: {e:#}"));
+            }
+        });
+    }
+}
+
+impl Api {
+    fn new(token: &str, tor_args: tor::TorArgs) -> Self {
+        Self {
+            token: BearerToken::new(token),
+            tor_args,
+            tor: OnceCell::new(),
+            services: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Answer one HTTP request: a JSON-RPC call POSTed with the token.
+    async fn handle_connection<S>(&self, mut stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request =
+            match server::read_http_request(&mut stream, MAX_HEAD_SIZE).await {
+                Ok(request) => request,
+                Err(e) => return server::refuse_head(&mut stream, e).await,
+            };
+        if let Some(status) = request.framing_error() {
+            return server::write_http_response(
+                &mut stream,
+                status,
+                "text/plain",
+                &[],
+                b"request body needs a Content-Length\n",
+            )
+            .await;
+        }
+        if !self.token.authorizes(request.header("authorization")) {
+            let headers = [("WWW-Authenticate", "Bearer".to_string())];
+            return server::write_http_response(
+                &mut stream,
+                401,
+                "text/plain",
+                &headers,
+                b"unauthorized\n",
+            )
+            .await;
+        }
+        if request.method != "POST" {
+            return server::write_http_response(
+                &mut stream,
+                405,
+                "text/plain",
+                &[("Allow", "POST".to_string())],
+                b"method not allowed\n",
+            )
+            .await;
+        }
+        let fits =
+            server::read_http_body(&mut stream, &mut request, MAX_REQUEST_SIZE)
+                .await?;
+        if !fits {
+            return server::write_http_response(
+                &mut stream,
+                413,
+                "text/plain",
+                &[],
+                b"request too large\n",
+            )
+            .await;
+        }
+        let response = self.dispatch(&request.body).await;
+        server::write_http_response(
+            &mut stream,
+            200,
+            "application/json",
+            &[],
+            response.to_string().as_bytes(),
+        )
+        .await
+    }
+
+    /// Run a JSON-RPC request and build its response object.
+    async fn dispatch(&self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, PARSE_ERROR, &e),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(id, INVALID_REQUEST, "missing method");
+        };
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+        let result = match method {
+            "launch-service" => self.launch_service(&params).await,
+            "stop-service" => self.stop_service(&params).await,
+            "fetch-url" => self.fetch_url(&params).await,
+            "resolve-key" => resolve_key(&params),
+            "status" => Ok(self.status().await),
+            _ => {
+                return error_response(
+                    id,
+                    METHOD_NOT_FOUND,
+                    format!("unknown method {method:?}"),
+                );
+            }
+        };
+        match result {
+            Ok(result) => {
+                json!({ "jsonrpc": "2.0", "result": result, "id": id })
+            }
+            Err(e) if e.downcast_ref::<InvalidParams>().is_some() => {
+                error_response(id, INVALID_PARAMS, format!("{e:#}"))
+            }
+            Err(e) => error_response(id, SERVER_ERROR, format!("{e:#}")),
+        }
+    }
+
+    /// The shared Tor client, bootstrapping it on first use.
+    async fn tor(&self) -> Result<&Tor> {
+        self.tor
+            .get_or_try_init(|| async {
+                // Ephemeral state dir avoids lock contention with
+                // concurrent invocations.
+                let (state_dir, cache_dir) = tor::dirs(Some("api"))?;
+                let config =
+                    tor::config(&state_dir, &cache_dir, &self.tor_args)?
+                        .build()?;
+                let client = TorClient::create_bootstrapped(config).await?;
+                Ok(Tor {
+                    fetcher: get::Fetcher::new(client.clone()),
+                    client,
+                    _state_dir: state_dir,
+                })
+            })
+            .await
+    }
+
+    async fn launch_service(&self, params: &Value) -> Result<Value> {
+        let docroot = str_param(params, "docroot")?;
+        let key = optional_str_param(params, "key")?;
+        let nickname =
+            optional_str_param(params, "nickname")?.unwrap_or("garner");
+        if self.services.lock().await.contains_key(nickname) {
+            return Err(anyhow!("service {nickname:?} is already running")
+                .context(InvalidParams));
+        }
+        let tor = self.tor().await?;
+        let service =
+            server::launch(&tor.client, docroot, key, nickname).await?;
+        let result = json!({
+            "nickname": nickname,
+            "address": service.onion_host,
+            "public_key": service.public_key,
+        });
+        self.services
+            .lock()
+            .await
+            .insert(nickname.to_string(), service);
+        Ok(result)
+    }
+
+    async fn stop_service(&self, params: &Value) -> Result<Value> {
+        let nickname = str_param(params, "nickname")?;
+        self.services.lock().await.remove(nickname).ok_or_else(|| {
+            anyhow!("no service {nickname:?} is running").context(InvalidParams)
+        })?;
+        Ok(json!({ "nickname": nickname }))
+    }
+
+    async fn fetch_url(&self, params: &Value) -> Result<Value> {
+        let url = str_param(params, "url")?;
+        let headers = match params.get("headers") {
+            None => Vec::new(),
+            Some(Value::Array(headers)) => headers
+                .iter()
+                .map(|header| {
+                    header
+                        .as_str()
+                        .ok_or_else(|| anyhow!("headers must be strings"))
+                        .and_then(get::parse_header)
+                        .context(InvalidParams)
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => {
+                return Err(anyhow!("headers must be an array of strings")
+                    .context(InvalidParams));
+            }
+        };
+        let tor = self.tor().await?;
+        let response = tor.fetcher.fetch(url, &headers).await?;
+        Ok(json!({
+            "headers": response.headers,
+            "body": base64::engine::general_purpose::STANDARD
+                .encode(&response.body),
+        }))
+    }
+
+    async fn status(&self) -> Value {
+        let services: Vec<Value> = self
+            .services
+            .lock()
+            .await
+            .iter()
+            .map(|(nickname, service)| {
+                json!({
+                    "nickname": nickname,
+                    "address": service.onion_host,
+                    "public_key": service.public_key,
+                })
+            })
+            .collect();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "tor": if self.tor.initialized() { "bootstrapped" } else { "idle" },
+            "services": services,
+        })
+    }
+}
+
+fn resolve_key(params: &Value) -> Result<Value> {
+    let key = str_param(params, "key")?;
+    let address = crate::key::parse_public_key_to_onion_host(key)
+        .context(InvalidParams)?;
+    Ok(json!({ "address": address }))
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str> {
+    optional_str_param(params, name)?
+        .ok_or_else(|| anyhow!("missing {name:?}").context(InvalidParams))
+}
+
+fn optional_str_param<'a>(
+    params: &'a Value,
+    name: &str,
+) -> Result<Option<&'a str>> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => {
+            Err(anyhow!("{name:?} must be a string").context(InvalidParams))
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: impl ToString) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message.to_string() },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    async fn call(api: &Api, request: Value) -> Value {
+        api.dispatch(request.to_string().as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        bc_components::register_tags();
+        let api = Api::new("secret", tor::TorArgs::default());
+        let status = call(
+            &api,
+            json!({ "jsonrpc": "2.0", "method": "status", "id": 1 }),
+        )
+        .await;
+        assert_eq!(status["id"], 1);
+        assert_eq!(status["result"]["tor"], "idle");
+        assert_eq!(status["result"]["services"], json!([]));
+
+        let (_, public_key) = crate::key::generate_keypair().unwrap();
+        let resolved = call(
+            &api,
+            json!({ "method": "resolve-key", "params": { "key": public_key }, "id": "a" }),
+        )
+        .await;
+        assert_eq!(
+            resolved["result"]["address"],
+            crate::key::parse_public_key_to_onion_host(&public_key).unwrap()
+        );
+
+        let errors = [
+            (json!({ "method": "launch", "id": 2 }), METHOD_NOT_FOUND),
+            (json!({ "method": "resolve-key", "id": 2 }), INVALID_PARAMS),
+            (
+                json!({ "method": "stop-service", "params": { "nickname": "x" } }),
+                INVALID_PARAMS,
+            ),
+            (json!({ "id": 2 }), INVALID_REQUEST),
+        ];
+        for (request, code) in errors {
+            let response = call(&api, request.clone()).await;
+            assert_eq!(response["error"]["code"], code, "{request}");
+        }
+        let response = api.dispatch(b"{").await;
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_requires_token() {
+        let api = Api::new("secret", tor::TorArgs::default());
+        let body = r#"{"jsonrpc":"2.0","method":"status","id":1}"#;
+        for (token, status) in [("wrong", "401"), ("secret", "200")] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let mut client = client.compat();
+            let request = format!(
+                "POST / HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\
+                 Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            api.handle_connection(server.compat()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status} ")),
+                "{response}"
+            );
+        }
+    }
+}
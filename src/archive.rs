@@ -0,0 +1,452 @@
+#[cfg(any(feature = "zip", feature = "sqlite"))]
+use std::sync::Mutex;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+#[cfg(feature = "sqlite")]
+use mime_guess::MimeGuess;
+#[cfg(feature = "sqlite")]
+use rusqlite::{Connection, OpenFlags, OptionalExtension as _};
+
+/// A docroot packed into a single `.zip` or uncompressed `.tar` file, or
+/// stored in a SQLite content database made by `garner publish-db`.
+///
+/// Opening a zip or tar file only indexes its entries; each entry is read
+/// from the file when it is requested, so the site is never unpacked.  A
+/// database is queried on every request, so a new `publish-db` goes live
+/// at once.  Zip files need the `zip` feature and databases the `sqlite`
+/// feature.
+pub struct Archive {
+    path: PathBuf,
+    format: Format,
+}
+
+enum Format {
+    /// Offset and size of each file's data.
+    Tar(HashMap<String, (u64, u64)>),
+    /// Index of each file in the central directory.
+    #[cfg(feature = "zip")]
+    Zip {
+        reader: Mutex<zip::ZipArchive<File>>,
+        entries: HashMap<String, usize>,
+    },
+    /// A read-only connection to a content database.
+    #[cfg(feature = "sqlite")]
+    Sqlite(Mutex<Connection>),
+}
+
+/// Schema of a content database: one row per file.
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    mime TEXT NOT NULL,
+    mtime INTEGER NOT NULL
+)";
+
+impl Archive {
+    /// Whether `path` names an archive format that can be served.
+    pub fn is_archive(path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                ["zip", "tar", "db", "sqlite"]
+                    .iter()
+                    .any(|known| ext.eq_ignore_ascii_case(known))
+            })
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let format = match extension.as_str() {
+            "db" | "sqlite" => open_database(path),
+            "zip" => index_zip(path),
+            _ => index_tar(path),
+        }
+        .with_context(|| format!("reading archive {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+        })
+    }
+
+    /// Whether this is a content database, whose files can change while
+    /// it is served.
+    pub fn is_database(&self) -> bool {
+        match self.format {
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Names of the files in the archive.
+    pub fn names(&self) -> Result<Vec<String>> {
+        match &self.format {
+            Format::Tar(entries) => Ok(entries.keys().cloned().collect()),
+            #[cfg(feature = "zip")]
+            Format::Zip { entries, .. } => {
+                Ok(entries.keys().cloned().collect())
+            }
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite(connection) => {
+                let connection = connection.lock().expect("database lock");
+                let mut statement =
+                    connection.prepare("SELECT path FROM files")?;
+                let names = statement
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(names)
+            }
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match &self.format {
+            Format::Tar(entries) => entries.contains_key(name),
+            #[cfg(feature = "zip")]
+            Format::Zip { entries, .. } => entries.contains_key(name),
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite(connection) => connection
+                .lock()
+                .expect("database lock")
+                .query_row(
+                    "SELECT 1 FROM files WHERE path = ?1",
+                    [name],
+                    |_| Ok(()),
+                )
+                .is_ok(),
+        }
+    }
+
+    /// The MIME type recorded for the entry called `name`, when the
+    /// format records one.
+    pub fn mime_type(&self, name: &str) -> Option<String> {
+        #[cfg(feature = "sqlite")]
+        if let Format::Sqlite(connection) = &self.format {
+            return connection
+                .lock()
+                .expect("database lock")
+                .query_row(
+                    "SELECT mime FROM files WHERE path = ?1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .ok();
+        }
+        #[cfg(not(feature = "sqlite"))]
+        let _ = name;
+        None
+    }
+
+    /// Map a request path to an entry name, like a docroot directory:
+    /// for `/`, try `index.html` first then fall back to `index.txt`.
+    pub fn resolve(&self, request_path: &str) -> Option<String> {
+        if request_path == "/" {
+            return ["index.html", "index.txt"]
+                .into_iter()
+                .find(|name| self.contains(name))
+                .map(str::to_string);
+        }
+        let name = request_path.strip_prefix('/')?;
+        self.contains(name).then(|| name.to_string())
+    }
+
+    /// Read the entry called `name`.
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let context = || format!("reading {name} from {}", self.path.display());
+        match &self.format {
+            Format::Tar(entries) => {
+                let &(offset, size) = entries
+                    .get(name)
+                    .ok_or_else(|| anyhow!("no entry {name}"))?;
+                let mut file = File::open(&self.path).with_context(context)?;
+                file.seek(SeekFrom::Start(offset)).with_context(context)?;
+                let mut data = vec![0u8; size as usize];
+                file.read_exact(&mut data).with_context(context)?;
+                Ok(data)
+            }
+            #[cfg(feature = "zip")]
+            Format::Zip { reader, entries } => {
+                let &index = entries
+                    .get(name)
+                    .ok_or_else(|| anyhow!("no entry {name}"))?;
+                let mut reader = reader.lock().expect("archive lock");
+                let mut entry = reader.by_index(index).with_context(context)?;
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data).with_context(context)?;
+                Ok(data)
+            }
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite(connection) => connection
+                .lock()
+                .expect("database lock")
+                .query_row(
+                    "SELECT data FROM files WHERE path = ?1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("no entry {name}")),
+        }
+    }
+}
+
+/// Index a tar file's entries by the offset and size of their data.
+fn index_tar(path: &Path) -> Result<Format> {
+    let mut entries = HashMap::new();
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?;
+        if let Some(name) = entry_name(&path.to_string_lossy()) {
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+    }
+    Ok(Format::Tar(entries))
+}
+
+#[cfg(feature = "zip")]
+fn index_zip(path: &Path) -> Result<Format> {
+    let mut reader = zip::ZipArchive::new(File::open(path)?)?;
+    let mut entries = HashMap::new();
+    for index in 0..reader.len() {
+        let entry = reader.by_index(index)?;
+        if let Some(name) = entry_name(&entry.name()?)
+            && entry.is_file()
+        {
+            entries.insert(name, index);
+        }
+    }
+    Ok(Format::Zip {
+        reader: Mutex::new(reader),
+        entries,
+    })
+}
+
+#[cfg(not(feature = "zip"))]
+fn index_zip(_path: &Path) -> Result<Format> {
+    Err(anyhow!("serving a zip file needs the zip feature"))
+}
+
+#[cfg(feature = "sqlite")]
+fn open_database(path: &Path) -> Result<Format> {
+    let connection =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    connection
+        .query_row("SELECT count(*) FROM files", [], |_| Ok(()))
+        .context("not a garner content database")?;
+    Ok(Format::Sqlite(Mutex::new(connection)))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_database(_path: &Path) -> Result<Format> {
+    Err(anyhow!(
+        "serving a content database needs the sqlite feature"
+    ))
+}
+
+/// Import every file under `docroot` into the content database at
+/// `database`, creating it if needed, and return the number of files.
+/// The import replaces the database's previous content in a single
+/// transaction, so a server reading it sees either the old site or the
+/// new one, never a mix.  Symlinked directories are not followed.
+#[cfg(feature = "sqlite")]
+pub fn publish_db(docroot: &Path, database: &Path) -> Result<usize> {
+    let mut connection = Connection::open(database)
+        .with_context(|| format!("opening {}", database.display()))?;
+    // Write-ahead logging lets servers keep reading during the import.
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.execute(SCHEMA, [])?;
+    let transaction = connection.transaction()?;
+    transaction.execute("DELETE FROM files", [])?;
+    let mut count = 0;
+    {
+        let mut insert = transaction.prepare(
+            "INSERT INTO files (path, data, mime, mtime) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let mut dirs = vec![docroot.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("reading {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                if !path.is_file() {
+                    continue;
+                }
+                let relative = path.strip_prefix(docroot)?;
+                let Some(name) = relative.to_str().and_then(entry_name) else {
+                    continue;
+                };
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let mime = MimeGuess::from_path(&path).first_or_octet_stream();
+                let mtime = std::fs::metadata(&path)?
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs() as i64);
+                insert.execute(rusqlite::params![
+                    name,
+                    data,
+                    mime.as_ref(),
+                    mtime
+                ])?;
+                count += 1;
+            }
+        }
+    }
+    transaction.commit()?;
+    Ok(count)
+}
+
+/// Normalize an entry's name to the form requests use, or `None` for
+/// names that could not be requested (such as those with `..`).
+fn entry_name(name: &str) -> Option<String> {
+    let name = name.trim_start_matches("./").trim_start_matches('/');
+    let valid = !name.is_empty()
+        && name.split('/').all(|part| !part.is_empty() && part != "..");
+    valid.then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_tar(path: &Path) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, data) in
+            [("./index.html", "<p>home</p>"), ("docs/a.txt", "a")]
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, data.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    fn write_zip(path: &Path) {
+        use std::io::Write as _;
+
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("docs/", options).unwrap();
+        writer.start_file("docs/a.txt", options).unwrap();
+        writer.write_all(b"a").unwrap();
+        writer.start_file("index.txt", options).unwrap();
+        writer.write_all(b"home").unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_tar_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.tar");
+        write_tar(&path);
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.resolve("/").as_deref(), Some("index.html"));
+        assert_eq!(archive.read("index.html").unwrap(), b"<p>home</p>");
+        assert_eq!(
+            archive.resolve("/docs/a.txt").as_deref(),
+            Some("docs/a.txt")
+        );
+        assert_eq!(archive.read("docs/a.txt").unwrap(), b"a");
+        assert!(archive.resolve("/missing").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "zip")]
+    fn test_zip_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.zip");
+        write_zip(&path);
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.resolve("/").as_deref(), Some("index.txt"));
+        assert_eq!(archive.read("index.txt").unwrap(), b"home");
+        assert_eq!(archive.read("docs/a.txt").unwrap(), b"a");
+        assert!(archive.resolve("/docs").is_none());
+        assert_eq!(archive.names().unwrap().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_content_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let docroot = dir.path().join("public");
+        std::fs::create_dir_all(docroot.join("docs")).unwrap();
+        std::fs::write(docroot.join("index.html"), "<p>home</p>").unwrap();
+        std::fs::write(docroot.join("docs/a.txt"), "a").unwrap();
+        let path = dir.path().join("site.db");
+        assert_eq!(publish_db(&docroot, &path).unwrap(), 2);
+
+        let archive = Archive::open(&path).unwrap();
+        assert!(archive.is_database());
+        assert_eq!(archive.resolve("/").as_deref(), Some("index.html"));
+        assert_eq!(archive.read("docs/a.txt").unwrap(), b"a");
+        assert_eq!(
+            archive.mime_type("index.html").as_deref(),
+            Some("text/html")
+        );
+        assert!(archive.resolve("/missing").is_none());
+
+        // Publishing again replaces the whole site, and an open database
+        // serves the new one.
+        std::fs::remove_file(docroot.join("docs/a.txt")).unwrap();
+        std::fs::write(docroot.join("index.html"), "<p>new</p>").unwrap();
+        assert_eq!(publish_db(&docroot, &path).unwrap(), 1);
+        assert_eq!(archive.read("index.html").unwrap(), b"<p>new</p>");
+        assert!(archive.resolve("/docs/a.txt").is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "zip"))]
+    fn test_zip_needs_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.zip");
+        std::fs::write(&path, "PK").unwrap();
+        assert!(Archive::is_archive(&path));
+        let error = format!("{:#}", Archive::open(&path).err().unwrap());
+        assert!(error.contains("needs the zip feature"), "{error}");
+    }
+
+    #[test]
+    #[cfg(not(feature = "sqlite"))]
+    fn test_content_database_needs_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.db");
+        std::fs::write(&path, "").unwrap();
+        let error = format!("{:#}", Archive::open(&path).err().unwrap());
+        assert!(error.contains("needs the sqlite feature"), "{error}");
+    }
+
+    #[test]
+    fn test_entry_name() {
+        assert_eq!(entry_name("./a/b.txt").as_deref(), Some("a/b.txt"));
+        assert_eq!(entry_name("/a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(entry_name("../a.txt"), None);
+        assert_eq!(entry_name("a//b"), None);
+    }
+}
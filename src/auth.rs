@@ -0,0 +1,40 @@
+use sha2::{Digest, Sha256};
+
+/// A secret that requests present as `Authorization: Bearer <TOKEN>`.
+pub struct BearerToken {
+    /// Only the digest is kept.  Digests are compared, so the time taken
+    /// does not reveal how much of a guess was right.
+    digest: Vec<u8>,
+}
+
+impl BearerToken {
+    pub fn new(token: &str) -> Self {
+        Self {
+            digest: Sha256::digest(token).to_vec(),
+        }
+    }
+
+    /// Whether an `Authorization` header value carries this token.
+    pub fn authorizes(&self, authorization: Option<&str>) -> bool {
+        authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                Sha256::digest(token.trim()).as_slice() == self.digest
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bearer_token() {
+        let token = BearerToken::new("secret");
+        assert!(token.authorizes(Some("Bearer secret")));
+        assert!(token.authorizes(Some("Bearer secret ")));
+        assert!(!token.authorizes(Some("Bearer other")));
+        assert!(!token.authorizes(Some("secret")));
+        assert!(!token.authorizes(None));
+    }
+}
@@ -0,0 +1,390 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result, anyhow};
+use clap::Args;
+use futures_util::{StreamExt as _, stream};
+use serde_json::{Value, json};
+
+use crate::{get, tor};
+
+/// Options for `garner bench`.
+#[derive(Args)]
+pub struct BenchArgs {
+    /// URL to request (e.g. http://xxxx.onion/)
+    pub url: String,
+    /// Number of requests to time, after a first one that fetches the
+    /// service's descriptor
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub requests: u32,
+    /// Make at most N requests at a time
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub concurrency: u32,
+    /// Print the results as a JSON object
+    #[arg(long)]
+    pub json: bool,
+    /// Connect through a running Tor daemon's SOCKS port (e.g.
+    /// 127.0.0.1:9050) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_SOCKS")]
+    pub tor_socks: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+/// The timings of one successful request.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// Opening the stream, including the rendezvous with the service.
+    connect: Duration,
+    first_byte: Duration,
+    total: Duration,
+    bytes: usize,
+}
+
+/// The spread of one timing across the samples.
+#[derive(Debug, PartialEq, Eq)]
+struct Percentiles {
+    min: Duration,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+/// Resources this process used, where the platform reports them.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    /// User and system CPU time.
+    cpu: Duration,
+    /// Peak resident memory, in bytes.
+    max_rss: u64,
+}
+
+/// What `garner bench` prints.
+struct Report {
+    url: String,
+    /// The runtime garner ran on, as `--current-thread` and `--workers`
+    /// set it.
+    runtime: String,
+    /// CPU time spent on the timed requests, and peak memory.
+    usage: Option<Usage>,
+    /// The first request's stream, which also fetched the descriptor.
+    first_connect: Duration,
+    connect: Percentiles,
+    first_byte: Percentiles,
+    total: Percentiles,
+    succeeded: usize,
+    failed: usize,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+pub async fn run(args: BenchArgs, runtime: String) -> Result<()> {
+    // Every request gets a circuit of its own, so each one measures a
+    // rendezvous with the service rather than reusing the last.
+    let fetcher = get::Fetcher::connect(args.tor_socks.as_deref(), &args.tor)
+        .await?
+        .with_streams(args.concurrency, get::CircuitPolicy::PerRequest);
+    let first = sample(&fetcher, &args.url)
+        .await
+        .with_context(|| format!("first request to {}", args.url))?;
+
+    let started = Instant::now();
+    let before = usage();
+    let results = stream::iter(0..args.requests)
+        .map(|_| sample(&fetcher, &args.url))
+        .buffer_unordered(args.concurrency as usize)
+        .collect::<Vec<_>>()
+        .await;
+    let elapsed = started.elapsed();
+    let usage = before.zip(usage()).map(|(before, after)| Usage {
+        cpu: after.cpu.saturating_sub(before.cpu),
+        max_rss: after.max_rss,
+    });
+    let mut samples = Vec::new();
+    let mut failed = 0;
+    for result in results {
+        match result {
+            Ok(sample) => samples.push(sample),
+            Err(e) => {
+                eprintln!("{}: {e:#}", args.url);
+                failed += 1;
+            }
+        }
+    }
+    if samples.is_empty() {
+        return Err(anyhow!("all {} requests failed", args.requests));
+    }
+
+    let report = Report {
+        runtime,
+        usage,
+        ..Report::new(&args.url, first, &samples, failed, elapsed)
+    };
+    if args.json {
+        println!("{}", report.json());
+    } else {
+        print!("{}", report.table());
+    }
+    Ok(())
+}
+
+/// Request `url` once, timing it.
+async fn sample(fetcher: &get::Fetcher, url: &str) -> Result<Sample> {
+    let start = Instant::now();
+    let response = fetcher.fetch(url, &[]).await?;
+    Ok(Sample {
+        connect: response.timing.connect.unwrap_or_default(),
+        first_byte: response.timing.first_byte,
+        total: start.elapsed(),
+        bytes: response.body.len(),
+    })
+}
+
+/// The resources this process has used so far.
+#[cfg(target_os = "linux")]
+fn usage() -> Option<Usage> {
+    // SAFETY: `usage` is a zero-initialized rusage for getrusage to fill.
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        usage
+    };
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64)
+            + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some(Usage {
+        cpu: time(usage.ru_utime) + time(usage.ru_stime),
+        // Linux reports kilobytes.
+        max_rss: usage.ru_maxrss as u64 * 1024,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn usage() -> Option<Usage> { None }
+
+impl Percentiles {
+    fn new(mut durations: Vec<Duration>) -> Self {
+        durations.sort();
+        Self {
+            min: durations[0],
+            p50: percentile(&durations, 50),
+            p90: percentile(&durations, 90),
+            p99: percentile(&durations, 99),
+            max: durations[durations.len() - 1],
+        }
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "min": self.min.as_secs_f64(),
+            "p50": self.p50.as_secs_f64(),
+            "p90": self.p90.as_secs_f64(),
+            "p99": self.p99.as_secs_f64(),
+            "max": self.max.as_secs_f64(),
+        })
+    }
+}
+
+/// The `p`th percentile of `sorted`, by the nearest-rank method.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+impl Report {
+    fn new(
+        url: &str,
+        first: Sample,
+        samples: &[Sample],
+        failed: usize,
+        elapsed: Duration,
+    ) -> Self {
+        let timing = |pick: fn(&Sample) -> Duration| {
+            Percentiles::new(samples.iter().map(pick).collect())
+        };
+        Self {
+            url: url.to_string(),
+            runtime: String::new(),
+            usage: None,
+            first_connect: first.connect,
+            connect: timing(|sample| sample.connect),
+            first_byte: timing(|sample| sample.first_byte),
+            total: timing(|sample| sample.total),
+            succeeded: samples.len(),
+            failed,
+            bytes: samples.iter().map(|sample| sample.bytes).sum(),
+            elapsed,
+        }
+    }
+
+    /// About how long the first request spent fetching the service's
+    /// descriptor: its stream took that much longer to open than is
+    /// typical once the descriptor is cached.
+    fn descriptor(&self) -> Duration {
+        self.first_connect.saturating_sub(self.connect.p50)
+    }
+
+    fn per_second(&self, count: usize) -> f64 {
+        count as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "url": self.url,
+            "requests": self.succeeded,
+            "failed": self.failed,
+            "first_connect": self.first_connect.as_secs_f64(),
+            "descriptor": self.descriptor().as_secs_f64(),
+            "connect": self.connect.json(),
+            "first_byte": self.first_byte.json(),
+            "total": self.total.json(),
+            "bytes": self.bytes,
+            "seconds": self.elapsed.as_secs_f64(),
+            "requests_per_second": self.per_second(self.succeeded),
+            "bytes_per_second": self.per_second(self.bytes),
+            "runtime": self.runtime,
+            "cpu_seconds": self.usage.map(|usage| usage.cpu.as_secs_f64()),
+            "max_rss": self.usage.map(|usage| usage.max_rss),
+        })
+    }
+
+    fn table(&self) -> String {
+        let mut table = format!(
+            "{:<12}{:>9}{:>9}{:>9}{:>9}{:>9}\n",
+            "seconds", "min", "p50", "p90", "p99", "max"
+        );
+        for (name, timing) in [
+            ("connect", &self.connect),
+            ("first byte", &self.first_byte),
+            ("total", &self.total),
+        ] {
+            table.push_str(&format!(
+                "{name:<12}{:>9.3}{:>9.3}{:>9.3}{:>9.3}{:>9.3}\n",
+                timing.min.as_secs_f64(),
+                timing.p50.as_secs_f64(),
+                timing.p90.as_secs_f64(),
+                timing.p99.as_secs_f64(),
+                timing.max.as_secs_f64(),
+            ));
+        }
+        table.push_str(&format!(
+            "\nFirst connection: {:.3}s, about {:.3}s of it fetching the \
+             descriptor\n",
+            self.first_connect.as_secs_f64(),
+            self.descriptor().as_secs_f64()
+        ));
+        table.push_str(&format!(
+            "Requests: {} succeeded, {} failed in {:.3}s ({:.2}/s)\n",
+            self.succeeded,
+            self.failed,
+            self.elapsed.as_secs_f64(),
+            self.per_second(self.succeeded)
+        ));
+        table.push_str(&format!(
+            "Throughput: {:.1} KiB/s\n",
+            self.per_second(self.bytes) / 1024.0
+        ));
+        table.push_str(&format!("Runtime: {}\n", self.runtime));
+        if let Some(usage) = self.usage {
+            table.push_str(&format!(
+                "CPU: {:.3}s, peak memory {:.1} MiB\n",
+                usage.cpu.as_secs_f64(),
+                usage.max_rss as f64 / (1024.0 * 1024.0)
+            ));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration { Duration::from_millis(millis) }
+
+    #[test]
+    fn test_percentiles() {
+        let durations: Vec<Duration> = (1..=200).rev().map(ms).collect();
+        assert_eq!(
+            Percentiles::new(durations),
+            Percentiles {
+                min: ms(1),
+                p50: ms(100),
+                p90: ms(180),
+                p99: ms(198),
+                max: ms(200),
+            }
+        );
+        assert_eq!(percentile(&[ms(7)], 50), ms(7));
+        assert_eq!(percentile(&[ms(1), ms(2), ms(3)], 50), ms(2));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_usage() {
+        let usage = usage().unwrap();
+        assert!(usage.max_rss > 0);
+        assert!(super::usage().unwrap().cpu >= usage.cpu);
+    }
+
+    #[test]
+    fn test_report() {
+        let sample = |connect| Sample {
+            connect: ms(connect),
+            first_byte: ms(200),
+            total: ms(connect + 300),
+            bytes: 1024,
+        };
+        let report = Report {
+            runtime: "current-thread".to_string(),
+            usage: Some(Usage {
+                cpu: ms(250),
+                max_rss: 48 << 20,
+            }),
+            ..Report::new(
+                "http://a.onion/",
+                sample(3000),
+                &[sample(1000), sample(1200), sample(800)],
+                1,
+                ms(2000),
+            )
+        };
+        let json = report.json();
+        assert_eq!(json["requests"], 3);
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["descriptor"], 2.0);
+        assert_eq!(json["connect"]["p50"], 1.0);
+        assert_eq!(json["total"]["max"], 1.5);
+        assert_eq!(json["bytes_per_second"], 1536.0);
+        assert_eq!(json["runtime"], "current-thread");
+        assert_eq!(json["cpu_seconds"], 0.25);
+
+        let table = report.table();
+        assert!(
+            table.contains(
+                "connect         0.800    1.000    1.200    1.200    1.200\n"
+            ),
+            "{table}"
+        );
+        assert!(table.contains("about 2.000s of it fetching"), "{table}");
+        assert!(table.contains("Throughput: 1.5 KiB/s"), "{table}");
+        assert!(table.contains("Runtime: current-thread\n"), "{table}");
+        assert!(
+            table.contains("CPU: 0.250s, peak memory 48.0 MiB"),
+            "{table}"
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::ui;
+
+/// An on-disk cache of response bodies fetched by `garner get`.
+///
+/// Entries are keyed by URL plus the values of the request headers the
+/// response named in `Vary`, so content-negotiated variants of one URL
+/// (for example with different `Accept` headers) are kept apart.  For
+/// each URL the cache records the `Vary` header names of its latest
+/// response in `<url-hash>.vary`, and each variant's body, store time,
+/// and lifetime in `<url-hash>-<variant-hash>.entry`.
+pub struct Cache {
+    dir: PathBuf,
+    /// Lifetime given by the user, in place of the server's.
+    ttl: Option<u64>,
+}
+
+impl Cache {
+    /// The default cache directory under garner's data dir.
+    pub fn default_dir() -> PathBuf { ui::data_dir().join("http-cache") }
+
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!("creating cache directory {}", dir.display())
+        })?;
+        Ok(Self { dir, ttl: None })
+    }
+
+    /// Keep entries fresh for `ttl` seconds from when they were stored,
+    /// whatever lifetime the server gave them, and store responses that
+    /// it gave none (but not those it marked `no-store`).
+    pub fn with_ttl(mut self, ttl: Option<u64>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Return the cached body for `url` requested with `request_headers`,
+    /// if a fresh entry exists.
+    pub fn get(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<Option<Vec<u8>>> {
+        let url_key = hash(url.as_bytes());
+        let Some(vary) = read_optional(&self.vary_path(&url_key))? else {
+            return Ok(None);
+        };
+        let vary = String::from_utf8_lossy(&vary);
+        let variant = variant_key(vary.lines(), request_headers);
+        let entry_path = self.entry_path(&url_key, &variant);
+        let Some(entry) = read_optional(&entry_path)? else {
+            return Ok(None);
+        };
+        let Some(newline) = entry.iter().position(|&b| b == b'\n') else {
+            return Ok(None);
+        };
+        let meta = String::from_utf8_lossy(&entry[..newline]);
+        let mut fields = meta.split(' ').map(str::parse::<u64>);
+        let (Some(Ok(stored)), Some(Ok(max_age))) =
+            (fields.next(), fields.next())
+        else {
+            return Ok(None);
+        };
+        if now().saturating_sub(stored) >= self.ttl.unwrap_or(max_age) {
+            return Ok(None);
+        }
+        Ok(Some(entry[newline + 1..].to_vec()))
+    }
+
+    /// Store `body` if the response allows it: its `Cache-Control` must
+    /// give a `max-age` (unless there is a TTL) and not forbid storing,
+    /// and it must not vary on `*`.
+    pub fn put(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+        response_headers: &[(String, String)],
+        body: &[u8],
+    ) -> Result<()> {
+        let max_age = match self.ttl {
+            Some(ttl) if !no_store(response_headers) => ttl,
+            Some(_) => return Ok(()),
+            None => match max_age(response_headers) {
+                Some(max_age) => max_age,
+                None => return Ok(()),
+            },
+        };
+        let vary: Vec<String> = header_values(response_headers, "vary")
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if vary.iter().any(|name| name == "*") {
+            return Ok(());
+        }
+        let url_key = hash(url.as_bytes());
+        let variant =
+            variant_key(vary.iter().map(String::as_str), request_headers);
+        let mut entry = format!("{} {max_age}\n", now()).into_bytes();
+        entry.extend_from_slice(body);
+        write_atomic(&self.entry_path(&url_key, &variant), &entry)?;
+        write_atomic(&self.vary_path(&url_key), vary.join("\n").as_bytes())
+    }
+
+    fn vary_path(&self, url_key: &str) -> PathBuf {
+        self.dir.join(format!("{url_key}.vary"))
+    }
+
+    fn entry_path(&self, url_key: &str, variant: &str) -> PathBuf {
+        self.dir.join(format!("{url_key}-{variant}.entry"))
+    }
+}
+
+/// The validators of responses fetched by `garner get --if-changed`, so
+/// that later runs can ask for each URL only if it changed since.
+///
+/// Each URL's `ETag` and `Last-Modified` values are kept as `Name: value`
+/// lines in `<url-hash>.validators`.
+pub struct Validators {
+    dir: PathBuf,
+}
+
+impl Validators {
+    /// The default validator directory under garner's data dir.
+    pub fn default_dir() -> PathBuf { ui::data_dir().join("validators") }
+
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!("creating validator directory {}", dir.display())
+        })?;
+        Ok(Self { dir })
+    }
+
+    /// The conditional request headers for `url`: `If-None-Match` and
+    /// `If-Modified-Since` with the validators of its last response.
+    pub fn conditions(&self, url: &str) -> Result<Vec<(String, String)>> {
+        let Some(data) = read_optional(&self.path(url))? else {
+            return Ok(Vec::new());
+        };
+        let data = String::from_utf8_lossy(&data);
+        Ok(data
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .filter_map(|(name, value)| {
+                let condition = match name {
+                    "ETag" => "If-None-Match",
+                    "Last-Modified" => "If-Modified-Since",
+                    _ => return None,
+                };
+                Some((condition.to_string(), value.to_string()))
+            })
+            .collect())
+    }
+
+    /// Record the validators of a response to `url`, or forget the URL's
+    /// validators if the response has none.
+    pub fn put(
+        &self,
+        url: &str,
+        response_headers: &[(String, String)],
+    ) -> Result<()> {
+        let mut data = String::new();
+        for name in ["ETag", "Last-Modified"] {
+            if let Some(value) = header_values(response_headers, name).next() {
+                data.push_str(&format!("{name}: {}\n", value.trim()));
+            }
+        }
+        let path = self.path(url);
+        if !data.is_empty() {
+            return write_atomic(&path, data.as_bytes());
+        }
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.validators", hash(url.as_bytes())))
+    }
+}
+
+/// The freshness lifetime from `Cache-Control`, or `None` if the
+/// response must not be reused without revalidation.
+fn max_age(response_headers: &[(String, String)]) -> Option<u64> {
+    let mut max_age = None;
+    for directive in header_values(response_headers, "cache-control")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+    {
+        let (name, value) =
+            directive.split_once('=').unwrap_or((directive, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" => return None,
+            "max-age" => max_age = value.trim_matches('"').parse().ok(),
+            _ => {}
+        }
+    }
+    max_age.filter(|&age| age > 0)
+}
+
+/// Whether `Cache-Control` forbids storing the response at all.
+fn no_store(response_headers: &[(String, String)]) -> bool {
+    header_values(response_headers, "cache-control")
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+/// Hash the request's values of the headers named in `vary` (lowercase),
+/// so requests that the server treats alike share an entry.
+fn variant_key<'a>(
+    vary: impl Iterator<Item = &'a str>,
+    request_headers: &[(String, String)],
+) -> String {
+    let mut names: Vec<&str> = vary.collect();
+    names.sort_unstable();
+    names.dedup();
+    let mut input = String::new();
+    for name in names {
+        let values: Vec<&str> = header_values(request_headers, name)
+            .map(str::trim)
+            .collect();
+        input.push_str(&format!("{name}:{}\n", values.join(",")));
+    }
+    hash(input.as_bytes())
+}
+
+fn header_values<'a>(
+    headers: &'a [(String, String)],
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> {
+    headers
+        .iter()
+        .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn hash(data: &[u8]) -> String { hex::encode(Sha256::digest(data)) }
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Write via a temporary file and rename, so concurrent `get`
+/// invocations never see a partial entry.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut file, data)?;
+    file.persist(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_cache_keys_by_vary_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path().to_path_buf()).unwrap();
+        let url = "abc.onion/data";
+        let response =
+            headers(&[("Cache-Control", "max-age=60"), ("Vary", "Accept")]);
+        let json = headers(&[("Accept", "application/json")]);
+        let text = headers(&[("accept", "text/plain")]);
+        cache.put(url, &json, &response, b"{}").unwrap();
+        cache.put(url, &text, &response, b"text").unwrap();
+
+        assert_eq!(cache.get(url, &json).unwrap().unwrap(), b"{}");
+        assert_eq!(cache.get(url, &text).unwrap().unwrap(), b"text");
+        assert!(cache.get(url, &[]).unwrap().is_none());
+        // Headers not named in Vary do not split entries.
+        let json_gzip = headers(&[
+            ("Accept", "application/json"),
+            ("Accept-Encoding", "gzip"),
+        ]);
+        assert_eq!(cache.get(url, &json_gzip).unwrap().unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_cache_respects_cache_control() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path().to_path_buf()).unwrap();
+        for (url, cache_control) in [
+            ("a.onion/", ""),
+            ("b.onion/", "no-store, max-age=60"),
+            ("c.onion/", "max-age=0"),
+        ] {
+            let response = headers(&[("Cache-Control", cache_control)]);
+            cache.put(url, &[], &response, b"x").unwrap();
+            assert!(cache.get(url, &[]).unwrap().is_none(), "{url}");
+        }
+        let response =
+            headers(&[("Cache-Control", "max-age=60"), ("Vary", "*")]);
+        cache.put("d.onion/", &[], &response, b"x").unwrap();
+        assert!(cache.get("d.onion/", &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path().to_path_buf())
+            .unwrap()
+            .with_ttl(Some(60));
+        cache.put("a.onion/", &[], &[], b"a").unwrap();
+        let response = headers(&[("Cache-Control", "no-cache")]);
+        cache.put("b.onion/", &[], &response, b"b").unwrap();
+        let response = headers(&[("Cache-Control", "no-store")]);
+        cache.put("c.onion/", &[], &response, b"c").unwrap();
+        assert_eq!(cache.get("a.onion/", &[]).unwrap().unwrap(), b"a");
+        assert_eq!(cache.get("b.onion/", &[]).unwrap().unwrap(), b"b");
+        assert!(cache.get("c.onion/", &[]).unwrap().is_none());
+
+        // The TTL also decides whether entries stored earlier are fresh.
+        let response = headers(&[("Cache-Control", "max-age=600")]);
+        let cache = Cache::open(dir.path().to_path_buf()).unwrap();
+        cache.put("d.onion/", &[], &response, b"d").unwrap();
+        assert!(cache.get("a.onion/", &[]).unwrap().is_some());
+        let cache = cache.with_ttl(Some(0));
+        assert!(cache.get("d.onion/", &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validators() {
+        let dir = tempfile::tempdir().unwrap();
+        let validators = Validators::open(dir.path().to_path_buf()).unwrap();
+        let url = "abc.onion/status";
+        assert!(validators.conditions(url).unwrap().is_empty());
+        let response = headers(&[
+            ("etag", "\"v1\""),
+            ("Last-Modified", "Wed, 14 Oct 2026 08:00:00 GMT"),
+            ("Content-Type", "text/plain"),
+        ]);
+        validators.put(url, &response).unwrap();
+        assert_eq!(
+            validators.conditions(url).unwrap(),
+            headers(&[
+                ("If-None-Match", "\"v1\""),
+                ("If-Modified-Since", "Wed, 14 Oct 2026 08:00:00 GMT"),
+            ])
+        );
+        assert!(validators.conditions("abc.onion/").unwrap().is_empty());
+        validators.put(url, &[]).unwrap();
+        assert!(validators.conditions(url).unwrap().is_empty());
+        validators.put(url, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(
+            max_age(&headers(&[("cache-control", "public, max-age=30")])),
+            Some(30)
+        );
+        assert_eq!(max_age(&headers(&[("Cache-Control", "no-cache")])), None);
+        assert_eq!(max_age(&headers(&[])), None);
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Result, anyhow};
+
+/// A `Cache-Control` value for the files whose paths match a glob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pattern: String,
+    value: String,
+}
+
+/// Parse a `--cache-control` value: `<GLOB>=<VALUE>`, such as
+/// `/assets/**=public, max-age=31536000, immutable`.
+pub fn parse_rule(spec: &str) -> Result<Rule> {
+    let (pattern, value) = spec.split_once('=').ok_or_else(|| {
+        anyhow!("cache control {spec:?} must be <GLOB>=<VALUE>")
+    })?;
+    if !pattern.starts_with('/') {
+        return Err(anyhow!(
+            "cache control glob {pattern:?} must start with /"
+        ));
+    }
+    let value = value.trim();
+    if value.is_empty() || value.contains(['\r', '\n']) {
+        return Err(anyhow!("invalid Cache-Control value {value:?}"));
+    }
+    Ok(Rule {
+        pattern: pattern.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// `Cache-Control` values by request path, so fingerprinted assets can be
+/// cached for good while pages are revalidated.  The first rule whose glob
+/// matches wins.
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self { Self { rules } }
+
+    /// The `Cache-Control` value for `path`, if a rule matches it.
+    pub fn value(&self, path: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| glob_matches(rule.pattern.as_bytes(), path.as_bytes()))
+            .map(|rule| rule.value.as_str())
+    }
+}
+
+/// Whether `path` matches `pattern`, in which `?` matches one character
+/// and `*` any run of characters within a path segment, `**` matches
+/// across segments, and `**/` matches zero or more whole directories.
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob_matches(rest, path)
+                || path.iter().enumerate().any(|(i, &c)| {
+                    c == b'/' && glob_matches(rest, &path[i + 1..])
+                })
+        }
+        [b'*', b'*', rest @ ..] => {
+            (0..=path.len()).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/');
+            (0..=segment.unwrap_or(path.len()))
+                .any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => path
+            .split_first()
+            .is_some_and(|(&c, path)| c != b'/' && glob_matches(rest, path)),
+        [c, rest @ ..] => path.split_first().is_some_and(|(first, path)| {
+            first == c && glob_matches(rest, path)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_policy() {
+        let policy = Policy::new(vec![
+            parse_rule("/assets/**=public, max-age=31536000, immutable")
+                .unwrap(),
+            parse_rule("/**/*.html=no-cache").unwrap(),
+            parse_rule("/v?/*=max-age=60").unwrap(),
+        ]);
+        assert_eq!(
+            policy.value("/assets/css/site.css"),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(
+            policy.value("/assets/index.html"),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(policy.value("/index.html"), Some("no-cache"));
+        assert_eq!(policy.value("/docs/a/b.html"), Some("no-cache"));
+        assert_eq!(policy.value("/v1/notes.txt"), Some("max-age=60"));
+        assert_eq!(policy.value("/v1/a/notes.txt"), None);
+        assert_eq!(policy.value("/index.htm"), None);
+
+        assert!(parse_rule("/a").is_err());
+        assert!(parse_rule("a/**=no-cache").is_err());
+        assert!(parse_rule("/a=").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+/// Optional subsystems and whether this build includes them, in the
+/// order they appear in `Cargo.toml`.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("bridges", cfg!(feature = "bridges")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("zip", cfg!(feature = "zip")),
+    ("native-keychain", cfg!(feature = "native-keychain")),
+];
+
+/// A description of the build variant and effective configuration,
+/// printed on startup and by `garner version --features` so that support
+/// requests show exactly what is running.
+pub struct Report {
+    sections: Vec<(&'static str, String)>,
+    config_file: Option<PathBuf>,
+}
+
+impl Default for Report {
+    fn default() -> Self { Self::new() }
+}
+
+impl Report {
+    /// Start a report with the version and compiled-in features.
+    pub fn new() -> Self {
+        let mut report = Self {
+            sections: Vec::new(),
+            config_file: None,
+        };
+        report.section("version", env!("CARGO_PKG_VERSION"));
+        report.section("features", features_summary());
+        report
+    }
+
+    /// Add an active configuration section.
+    pub fn section(&mut self, name: &'static str, value: impl Into<String>) {
+        self.sections.push((name, value.into()));
+    }
+
+    /// Record the configuration file in use.
+    pub fn set_config_file(&mut self, path: &Path) {
+        self.section("config", path.display().to_string());
+        self.config_file = Some(path.to_path_buf());
+    }
+
+    /// The value of the section called `name`, if there is one.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find(|(section, _)| *section == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The configuration file in use, if any.
+    pub fn config_file(&self) -> Option<&Path> { self.config_file.as_deref() }
+
+    /// The report as `name: value` lines.
+    pub fn lines(&self) -> Vec<String> {
+        self.sections
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect()
+    }
+}
+
+/// Compiled-in features as `+name` and left-out ones as `-name`.
+fn features_summary() -> String {
+    FEATURES
+        .iter()
+        .map(|(name, enabled)| {
+            format!("{}{name}", if *enabled { '+' } else { '-' })
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features_summary() {
+        let summary = features_summary();
+        assert_eq!(summary.split(' ').count(), FEATURES.len());
+        #[cfg(feature = "bridges")]
+        assert!(summary.contains("+bridges"));
+        #[cfg(not(feature = "bridges"))]
+        assert!(summary.contains("-bridges"));
+    }
+
+    #[test]
+    fn test_report_lines() {
+        let mut report = Report::new();
+        report.section("tor", "embedded Arti");
+        let lines = report.lines();
+        assert_eq!(lines[0], format!("version: {}", env!("CARGO_PKG_VERSION")));
+        assert!(lines[1].starts_with("features: "));
+        assert_eq!(lines[2], "tor: embedded Arti");
+        assert_eq!(report.value("tor"), Some("embedded Arti"));
+        assert_eq!(report.value("missing"), None);
+    }
+}
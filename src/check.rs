@@ -0,0 +1,184 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use arti_client::{ErrorKind, HasKind as _};
+use clap::Args;
+use serde_json::json;
+
+use crate::{get, key, system_tor, tor};
+
+/// Exit status when the service's descriptor cannot be found: it is not
+/// published, or does not exist.
+pub const EXIT_DESCRIPTOR_MISSING: i32 = 1;
+
+/// Exit status when the service could not be connected to, or did not
+/// answer in time.
+pub const EXIT_CONNECT_FAILED: i32 = 2;
+
+/// Options for `garner check`.
+#[derive(Args)]
+pub struct CheckArgs {
+    /// The .onion address to check (e.g. xxxx.onion)
+    #[arg(required_unless_present_any = ["key", "key_file"])]
+    pub address: Option<String>,
+    /// Ed25519 public key in UR format to derive the .onion address, or
+    /// "-" to read it from stdin
+    #[arg(long, env = "GARNER_KEY")]
+    pub key: Option<String>,
+    /// Read the public key from FILE, or from stdin for "-", instead of
+    /// --key or GARNER_KEY
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+    /// Path to request
+    #[arg(long, default_value = "/")]
+    pub path: String,
+    /// Send a GET request rather than HEAD
+    #[arg(long)]
+    pub get: bool,
+    /// Give up after this many seconds in all, including bootstrapping
+    /// Tor
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 120,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub timeout: u64,
+    /// Print the result as a JSON object
+    #[arg(long)]
+    pub json: bool,
+    /// Connect through a running Tor daemon's SOCKS port (e.g.
+    /// 127.0.0.1:9050) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_SOCKS")]
+    pub tor_socks: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+/// What a check found.
+#[derive(Debug)]
+enum Outcome {
+    /// The service answered with this HTTP status.
+    Reachable(u16),
+    DescriptorMissing(anyhow::Error),
+    ConnectFailed(anyhow::Error),
+}
+
+pub async fn run(args: CheckArgs) -> Result<()> {
+    let key = key::read_key_arg(args.key.clone(), args.key_file.as_deref())?;
+    let host = get::onion_host(key.as_deref(), args.address.as_deref())?
+        .ok_or_else(|| anyhow!("check needs an address or --key"))?;
+    let path = match args.path.starts_with('/') {
+        true => args.path.clone(),
+        false => format!("/{}", args.path),
+    };
+    let url = format!("{host}{path}");
+    let method = if args.get { "GET" } else { "HEAD" };
+
+    let started = Instant::now();
+    let limit = Duration::from_secs(args.timeout);
+    let result = tokio::time::timeout(limit, async {
+        let fetcher =
+            get::Fetcher::connect(args.tor_socks.as_deref(), &args.tor).await?;
+        fetcher.request(method, &url, &[]).await
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(anyhow!("no answer within {} seconds", args.timeout))
+    });
+    let outcome = Outcome::new(result.map(|response| response.status));
+    let seconds = started.elapsed().as_secs_f64();
+
+    if args.json {
+        println!("{}", outcome.json(&host, seconds));
+    }
+    match outcome {
+        Outcome::Reachable(status) => {
+            if !args.json {
+                println!("{host} is reachable (HTTP {status}, {seconds:.1}s)");
+            }
+            Ok(())
+        }
+        Outcome::DescriptorMissing(e) => {
+            let e = e.context(format!("{host}: descriptor not found"));
+            Err(get::Failed::new(EXIT_DESCRIPTOR_MISSING, e).into())
+        }
+        Outcome::ConnectFailed(e) => {
+            let e = e.context(format!("{host}: not reachable"));
+            Err(get::Failed::new(EXIT_CONNECT_FAILED, e).into())
+        }
+    }
+}
+
+impl Outcome {
+    /// Classify the status of a request, or why it failed.  Any response,
+    /// even an HTTP error, shows that the service is up.
+    fn new(result: Result<u16>) -> Self {
+        match result {
+            Ok(status) => Outcome::Reachable(status),
+            Err(e) => match get::response_status(&e) {
+                Some(status) => Outcome::Reachable(status),
+                None if descriptor_missing(&e) => Outcome::DescriptorMissing(e),
+                None => Outcome::ConnectFailed(e),
+            },
+        }
+    }
+
+    fn json(&self, host: &str, seconds: f64) -> serde_json::Value {
+        let (result, status, error) = match self {
+            Outcome::Reachable(status) => ("reachable", Some(*status), None),
+            Outcome::DescriptorMissing(e) => {
+                ("descriptor-missing", None, Some(format!("{e:#}")))
+            }
+            Outcome::ConnectFailed(e) => {
+                ("connect-failed", None, Some(format!("{e:#}")))
+            }
+        };
+        json!({
+            "address": host,
+            "result": result,
+            "status": status,
+            "seconds": seconds,
+            "error": error,
+        })
+    }
+}
+
+/// Whether `error` means that Tor could not find the service's
+/// descriptor.
+fn descriptor_missing(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<arti_client::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::OnionServiceNotFound)
+            || cause
+                .downcast_ref::<system_tor::SocksFailure>()
+                .is_some_and(|failure| {
+                    failure.code == system_tor::SOCKS_DESCRIPTOR_NOT_FOUND
+                })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome() {
+        let reachable = Outcome::new(Ok(200));
+        assert!(matches!(reachable, Outcome::Reachable(200)));
+        let json = reachable.json("a.onion", 1.5);
+        assert_eq!(json["result"], "reachable");
+        assert_eq!(json["status"], 200);
+        assert!(json["error"].is_null());
+
+        let refused = Outcome::new(Err(anyhow!(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused
+        ))));
+        assert!(matches!(refused, Outcome::ConnectFailed(_)));
+        assert_eq!(refused.json("a.onion", 1.5)["result"], "connect-failed");
+    }
+}
@@ -0,0 +1,223 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+/// Expected SHA-256 digests of files, by path.
+///
+/// Read from `sha256sum` output (`<HEX>  <PATH>` lines) or from a JSON
+/// manifest as served at `/.manifest.json` or `/.manifest`.
+#[derive(Debug, Default)]
+pub struct Checksums {
+    /// Hex digests by path, without a leading `/`.
+    digests: HashMap<String, String>,
+}
+
+impl Checksums {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&data)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| anyhow!("checksum file is not UTF-8"))?;
+        let mut digests = HashMap::new();
+        if text.trim_start().starts_with('{') {
+            let manifest: Value = serde_json::from_str(text)?;
+            let files = manifest
+                .get("files")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("manifest has no files array"))?;
+            for file in files {
+                let (Some(path), Some(digest)) = (
+                    file.get("path").and_then(Value::as_str),
+                    file.get("sha256").and_then(Value::as_str),
+                ) else {
+                    return Err(anyhow!(
+                        "manifest entry without path or sha256"
+                    ));
+                };
+                digests.insert(path.to_string(), digest.to_ascii_lowercase());
+            }
+        } else {
+            for (number, line) in text.lines().enumerate() {
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // `sha256sum` separates the digest from the name with two
+                // spaces, or a space and `*` in binary mode.
+                let (digest, path) = line
+                    .split_once("  ")
+                    .or_else(|| line.split_once(" *"))
+                    .filter(|(digest, _)| {
+                        digest.len() == 64
+                            && digest.bytes().all(|b| b.is_ascii_hexdigit())
+                    })
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "line {}: expected <SHA256>  <PATH>",
+                            number + 1
+                        )
+                    })?;
+                let path = path.strip_prefix("./").unwrap_or(path);
+                digests.insert(path.to_string(), digest.to_ascii_lowercase());
+            }
+        }
+        Ok(Self { digests })
+    }
+
+    /// The expected digest for a URL path: the entry for the same path, or
+    /// else the only entry with the same file name.
+    pub fn expected(&self, url_path: &str) -> Option<&str> {
+        let path = url_path.split(['?', '#']).next().unwrap_or("");
+        let path = path.trim_start_matches('/');
+        if let Some(digest) = self.digests.get(path) {
+            return Some(digest);
+        }
+        let name = path.rsplit('/').next()?;
+        let mut matches = self
+            .digests
+            .iter()
+            .filter(|(listed, _)| listed.rsplit('/').next() == Some(name));
+        match (matches.next(), matches.next()) {
+            (Some((_, digest)), None) => Some(digest),
+            _ => None,
+        }
+    }
+}
+
+/// How a downloaded file compared with its listed digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Verified,
+    /// The digest the file actually had.
+    Mismatch(String),
+    /// The checksum file does not list it.
+    Unlisted,
+    /// It could not be downloaded.
+    Failed,
+}
+
+/// Checks downloaded bodies against a checksum file on a pool of
+/// blocking threads, so hashing one file overlaps with downloading the
+/// next instead of waiting for the whole transfer.
+pub struct Verifier {
+    checksums: Checksums,
+    /// Bounds the files hashed at once to the number of CPUs.
+    workers: Semaphore,
+}
+
+impl Verifier {
+    pub fn new(checksums: Checksums) -> Self {
+        let workers =
+            std::thread::available_parallelism().map_or(1, usize::from);
+        Self {
+            checksums,
+            workers: Semaphore::new(workers),
+        }
+    }
+
+    /// Verify `body`, fetched from `url_path`, handing it back for output.
+    pub async fn verify(
+        &self,
+        url_path: &str,
+        body: Vec<u8>,
+    ) -> Result<(Vec<u8>, Outcome)> {
+        let Some(expected) = self.checksums.expected(url_path) else {
+            return Ok((body, Outcome::Unlisted));
+        };
+        let expected = expected.to_string();
+        let _permit = self.workers.acquire().await?;
+        let (body, actual) = tokio::task::spawn_blocking(move || {
+            let digest = hex::encode(Sha256::digest(&body));
+            (body, digest)
+        })
+        .await?;
+        let outcome = if actual == expected {
+            Outcome::Verified
+        } else {
+            Outcome::Mismatch(actual)
+        };
+        Ok((body, outcome))
+    }
+}
+
+/// A table of verification results, one row per URL, for stderr.
+pub fn report(rows: &[(String, Outcome)]) -> String {
+    let width = rows.iter().map(|(url, _)| url.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for (url, outcome) in rows {
+        let (status, detail) = match outcome {
+            Outcome::Verified => ("OK", String::new()),
+            Outcome::Mismatch(actual) => ("MISMATCH", format!("got {actual}")),
+            Outcome::Unlisted => ("UNLISTED", String::new()),
+            Outcome::Failed => ("FAILED", "not downloaded".to_string()),
+        };
+        if detail.is_empty() {
+            table.push_str(&format!("{status:<8}  {url}\n"));
+        } else {
+            table.push_str(&format!("{status:<8}  {url:<width$}  {detail}\n"));
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str =
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_parse_checksums() {
+        let sums = Checksums::parse(
+            format!("{HELLO}  ./docs/a.txt\n{} *b.bin\n", "0".repeat(64))
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(sums.expected("/docs/a.txt"), Some(HELLO));
+        assert_eq!(sums.expected("/mirror/a.txt?x=1"), Some(HELLO));
+        assert_eq!(sums.expected("/b.bin"), Some("0".repeat(64).as_str()));
+        assert_eq!(sums.expected("/c.txt"), None);
+        assert!(Checksums::parse(b"abc  a.txt\n").is_err());
+
+        let manifest = format!(
+            r#"{{"files": [{{"path": "a.txt", "size": 5, "sha256": "{HELLO}"}}]}}"#
+        );
+        let sums = Checksums::parse(manifest.as_bytes()).unwrap();
+        assert_eq!(sums.expected("/a.txt"), Some(HELLO));
+    }
+
+    #[tokio::test]
+    async fn test_verifier() {
+        let sums =
+            Checksums::parse(format!("{HELLO}  a.txt\n").as_bytes()).unwrap();
+        let verifier = Verifier::new(sums);
+        let (body, outcome) =
+            verifier.verify("/a.txt", b"hello".to_vec()).await.unwrap();
+        assert_eq!(
+            (body.as_slice(), outcome),
+            (&b"hello"[..], Outcome::Verified)
+        );
+        let (_, outcome) =
+            verifier.verify("/a.txt", b"bye".to_vec()).await.unwrap();
+        assert!(matches!(outcome, Outcome::Mismatch(_)));
+        let (_, outcome) =
+            verifier.verify("/b.txt", b"bye".to_vec()).await.unwrap();
+        assert_eq!(outcome, Outcome::Unlisted);
+
+        assert_eq!(
+            report(&[
+                ("/a.txt".into(), Outcome::Verified),
+                ("/long.txt".into(), Outcome::Failed),
+            ]),
+            "OK        /a.txt\nFAILED    /long.txt  not downloaded\n"
+        );
+    }
+}
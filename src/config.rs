@@ -0,0 +1,428 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::{ArgAction, Command};
+use toml::{Table, Value};
+
+/// Expand `args` with the options from the configuration file named by
+/// `--config` or `GARNER_CONFIG`, if any.
+///
+/// Top-level keys in the file set global options and `[server]` or
+/// `[get]` sections set the options of that subcommand, named by their
+/// long flag.  The options are inserted ahead of the ones given on the
+/// command line, which therefore take precedence.  An option whose
+/// environment variable is set is skipped, so the environment also
+/// overrides the file.
+pub fn expand_args(
+    args: Vec<OsString>,
+    cmd: &Command,
+) -> Result<Vec<OsString>> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let table = load(&path)?;
+    let context = || format!("in {}", path.display());
+
+    let mut globals = Vec::new();
+    let mut sections = Vec::new();
+    for (key, value) in &table {
+        if let Value::Table(section) = value
+            && !is_indirection(section)
+        {
+            sections.push((key, section));
+        } else {
+            push_option(&mut globals, cmd, key, value).with_context(context)?;
+        }
+    }
+
+    let subcommand = subcommand_index(&args, cmd);
+    let mut section_args = Vec::new();
+    for (name, section) in sections {
+        let subcmd = cmd
+            .find_subcommand(name)
+            .ok_or_else(|| anyhow!("unknown section [{name}]"))
+            .with_context(context)?;
+        let invoked =
+            subcommand.is_some_and(|i| args[i].to_str() == Some(name.as_str()));
+        for (key, value) in section {
+            // Only the invoked subcommand's secrets are resolved, but
+            // every section is checked for unknown options.
+            let mut out = Vec::new();
+            if invoked {
+                push_option(&mut out, subcmd, key, value)
+                    .with_context(|| format!("[{name}]"))
+                    .with_context(context)?;
+                section_args.extend(out);
+            } else {
+                find_arg(subcmd, key)
+                    .with_context(|| format!("[{name}]"))
+                    .with_context(context)?;
+            }
+        }
+    }
+
+    let split = subcommand.map_or(args.len(), |i| i + 1);
+    let mut expanded = Vec::with_capacity(args.len() + globals.len());
+    expanded.push(args[0].clone());
+    expanded.extend(globals);
+    expanded.extend_from_slice(&args[1..split]);
+    expanded.extend(section_args);
+    expanded.extend_from_slice(&args[split..]);
+    Ok(expanded)
+}
+
+/// The configuration file given with `--config PATH`, `--config=PATH`,
+/// or `GARNER_CONFIG`.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        } else if let Some(path) =
+            arg.to_str().and_then(|a| a.strip_prefix("--config="))
+        {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("GARNER_CONFIG").map(PathBuf::from)
+}
+
+/// Position of the subcommand name in `args`: the first argument that is
+/// neither an option nor the value of a global option.
+fn subcommand_index(args: &[OsString], cmd: &Command) -> Option<usize> {
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_str()?;
+        match arg.strip_prefix("--") {
+            Some(long) if !long.contains('=') => {
+                let takes_value = cmd
+                    .get_arguments()
+                    .find(|a| a.get_long() == Some(long))
+                    .is_some_and(|a| a.get_action().takes_values());
+                i += if takes_value { 2 } else { 1 };
+            }
+            _ if arg.starts_with('-') => i += 1,
+            _ => return Some(i),
+        }
+    }
+    None
+}
+
+/// Load a configuration file, merging in the files it includes.
+///
+/// `include = ["a.toml", "b.toml"]` merges each listed file (relative to
+/// the including file) in order, then the including file's own settings
+/// over them.  Relative paths in `{ file = … }` values are resolved
+/// against the file they appear in.
+pub fn load(path: &Path) -> Result<Table> { load_nested(path, &mut Vec::new()) }
+
+fn load_nested(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Table> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("reading {}", path.display()))?;
+    if stack.contains(&canonical) {
+        return Err(anyhow!("{} includes itself", path.display()));
+    }
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    let mut table: Table = toml::from_str(&text)
+        .with_context(|| format!("parsing {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    absolutize_files(&mut table, dir);
+
+    let includes = match table.remove("include") {
+        None => Vec::new(),
+        Some(Value::Array(includes)) => includes,
+        Some(_) => {
+            return Err(anyhow!(
+                "include in {} must be an array of paths",
+                path.display()
+            ));
+        }
+    };
+    stack.push(canonical);
+    let mut merged = Table::new();
+    for include in includes {
+        let Value::String(include) = include else {
+            return Err(anyhow!(
+                "include in {} must be an array of paths",
+                path.display()
+            ));
+        };
+        merge(&mut merged, load_nested(&dir.join(include), stack)?);
+    }
+    stack.pop();
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+/// Merge `overlay` into `base`: tables merge key by key, and any other
+/// value in `overlay` replaces the one in `base`.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay))
+                if !is_indirection(&overlay) =>
+            {
+                merge(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Whether `table` is a `{ file = … }` or `{ env = … }` secret reference
+/// rather than a section.
+pub fn is_indirection(table: &Table) -> bool {
+    table.len() == 1
+        && (table.get("file").is_some_and(Value::is_str)
+            || table.get("env").is_some_and(Value::is_str))
+}
+
+/// Rewrite relative `{ file = … }` paths to be relative to `dir`.
+fn absolutize_files(table: &mut Table, dir: &Path) {
+    for (_, value) in table.iter_mut() {
+        let Value::Table(inner) = value else {
+            continue;
+        };
+        if is_indirection(inner) {
+            if let Some(Value::String(file)) = inner.get_mut("file") {
+                *file = dir.join(&*file).to_string_lossy().into_owned();
+            }
+        } else {
+            absolutize_files(inner, dir);
+        }
+    }
+}
+
+/// Resolve a configuration value to the text of one option value,
+/// reading `{ file = … }` and `{ env = … }` references.
+fn resolve(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Table(table) if is_indirection(table) => {
+            if let Some(Value::String(file)) = table.get("file") {
+                let text = std::fs::read_to_string(file)
+                    .with_context(|| format!("reading secret {file}"))?;
+                let text = text.strip_suffix('\n').unwrap_or(&text);
+                Ok(text.strip_suffix('\r').unwrap_or(text).to_string())
+            } else if let Some(Value::String(var)) = table.get("env") {
+                std::env::var(var)
+                    .with_context(|| format!("reading secret from ${var}"))
+            } else {
+                unreachable!("is_indirection checked the keys")
+            }
+        }
+        _ => Err(anyhow!("unsupported value {value}")),
+    }
+}
+
+/// Find the option named `key` (its long flag, with `-` or `_`).
+fn find_arg<'a>(cmd: &'a Command, key: &str) -> Result<&'a clap::Arg> {
+    let long = key.replace('_', "-");
+    cmd.get_arguments()
+        .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+        .ok_or_else(|| anyhow!("unknown option {key:?}"))
+}
+
+/// Append the command-line form of `key = value` to `out`.
+fn push_option(
+    out: &mut Vec<OsString>,
+    cmd: &Command,
+    key: &str,
+    value: &Value,
+) -> Result<()> {
+    let arg = find_arg(cmd, key)?;
+    if arg
+        .get_env()
+        .is_some_and(|var| std::env::var_os(var).is_some())
+    {
+        return Ok(());
+    }
+    let flag = format!("--{}", arg.get_long().expect("found by long name"));
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(enabled)) => {
+            if *enabled {
+                out.push(flag.into());
+            }
+        }
+        (ArgAction::SetTrue, _) => {
+            return Err(anyhow!("{key} must be true or false"));
+        }
+        (ArgAction::Append, Value::Array(values)) => {
+            for value in values {
+                out.push(flag.clone().into());
+                out.push(
+                    resolve(value).with_context(|| key.to_string())?.into(),
+                );
+            }
+        }
+        (_, Value::Array(_)) => {
+            return Err(anyhow!("{key} does not take a list"));
+        }
+        _ => {
+            out.push(flag.into());
+            out.push(resolve(value).with_context(|| key.to_string())?.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction};
+
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("garner")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("workers").long("workers"))
+            .subcommand(
+                Command::new("server")
+                    .arg(Arg::new("docroot").long("docroot"))
+                    .arg(
+                        Arg::new("low_memory")
+                            .long("low-memory")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("bridges")
+                            .long("bridge")
+                            .action(ArgAction::Append),
+                    )
+                    .arg(Arg::new("key").long("key")),
+            )
+            .subcommand(Command::new("get").arg(Arg::new("key").long("key")))
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn expand(dir: &Path, config: &str, cli: &[&str]) -> Result<Vec<String>> {
+        let path = dir.join("garner.toml");
+        std::fs::write(&path, config).unwrap();
+        let path = path.to_str().unwrap();
+        let mut full = vec!["garner", "--config", path];
+        full.extend(cli);
+        let expanded = expand_args(args(&full), &command())?;
+        Ok(expanded
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .filter(|a| a != "--config" && a != path)
+            .collect())
+    }
+
+    #[test]
+    fn test_expand_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let expanded = expand(
+            dir.path(),
+            "workers = 2\n\
+             [server]\n\
+             docroot = \"site\"\n\
+             low_memory = true\n\
+             bridge = [\"a\", \"b\"]\n\
+             [get]\n\
+             key = { file = \"missing\" }\n",
+            &["server", "--docroot", "other"],
+        )
+        .unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "garner",
+                "--workers",
+                "2",
+                "server",
+                "--bridge",
+                "a",
+                "--bridge",
+                "b",
+                "--docroot",
+                "site",
+                "--low-memory",
+                "--docroot",
+                "other",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_args_unknown_option() {
+        let dir = tempfile::tempdir().unwrap();
+        let err =
+            expand(dir.path(), "[get]\nbogus = 80\n", &["server"]).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown option \"bogus\""));
+    }
+
+    #[test]
+    fn test_include_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("site.toml"),
+            "[server]\ndocroot = \"included\"\nlow-memory = true\n",
+        )
+        .unwrap();
+        let expanded = expand(
+            dir.path(),
+            "include = [\"site.toml\"]\n[server]\ndocroot = \"main\"\n",
+            &["server"],
+        )
+        .unwrap();
+        assert_eq!(
+            expanded,
+            ["garner", "server", "--docroot", "main", "--low-memory"]
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]\n")
+            .unwrap();
+        std::fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]\n")
+            .unwrap();
+        let err = load(&dir.path().join("a.toml")).unwrap_err();
+        assert!(err.to_string().contains("includes itself"), "{err}");
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("secrets")).unwrap();
+        std::fs::write(dir.path().join("secrets/key"), "ur:secret\n").unwrap();
+        std::fs::write(
+            dir.path().join("secrets/secrets.toml"),
+            "[server]\nkey = { file = \"key\" }\n",
+        )
+        .unwrap();
+        let expanded = expand(
+            dir.path(),
+            "include = [\"secrets/secrets.toml\"]\n",
+            &["server"],
+        )
+        .unwrap();
+        assert_eq!(expanded, ["garner", "server", "--key", "ur:secret"]);
+    }
+
+    #[test]
+    fn test_secret_env() {
+        let value = Value::Table(toml::toml! { env = "PATH" });
+        assert_eq!(resolve(&value).unwrap(), std::env::var("PATH").unwrap());
+        let value =
+            Value::Table(toml::toml! { env = "GARNER_TEST_UNSET_VARIABLE" });
+        assert!(resolve(&value).is_err());
+    }
+}
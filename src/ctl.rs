@@ -0,0 +1,256 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::{Args, Subcommand, ValueEnum};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Options for `garner ctl`.
+#[derive(Args)]
+pub struct CtlArgs {
+    /// Control socket of the running server (its --control-socket)
+    #[arg(long, value_name = "PATH", env = "GARNER_CONTROL_SOCKET")]
+    pub socket: PathBuf,
+    #[command(subcommand)]
+    pub command: CtlCommands,
+}
+
+#[derive(Subcommand)]
+pub enum CtlCommands {
+    /// Move the access log aside to <PATH>.1 and start a new one
+    RotateLogs,
+    /// Save a diagnostics bundle (report, redacted config, recent logs,
+    /// status history) for bug reports
+    Diagnostics {
+        /// Where to write the bundle [default:
+        /// garner-diagnostics-<TIMESTAMP>.tar.gz]
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Answer every request with 503 Service Unavailable (on), or serve
+    /// normally again (off), keeping the onion service published
+    Maintenance {
+        #[arg(value_enum)]
+        mode: Switch,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+/// Operations a running server performs on behalf of `garner ctl`.
+pub trait Control: Send + Sync + 'static {
+    fn rotate_logs(&self) -> Result<()>;
+    fn diagnostics(&self) -> Result<Vec<u8>>;
+    fn set_maintenance(&self, on: bool) -> Result<()>;
+}
+
+// Protocol: the client sends one command line; the server answers
+// `ok <LENGTH>` followed by LENGTH bytes of payload, or `error <MESSAGE>`.
+
+pub async fn run(args: CtlArgs) -> Result<()> {
+    match args.command {
+        CtlCommands::RotateLogs => {
+            request(&args.socket, "rotate-logs").await?;
+            eprintln!("Logs rotated");
+        }
+        CtlCommands::Diagnostics { output } => {
+            let bundle = request(&args.socket, "diagnostics").await?;
+            let output = output.unwrap_or_else(|| {
+                PathBuf::from(format!(
+                    "garner-diagnostics-{}.tar.gz",
+                    chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+                ))
+            });
+            std::fs::write(&output, bundle)
+                .with_context(|| format!("writing {}", output.display()))?;
+            eprintln!("Diagnostics written to {}", output.display());
+        }
+        CtlCommands::Maintenance { mode } => {
+            let on = mode == Switch::On;
+            let command = if on {
+                "maintenance on"
+            } else {
+                "maintenance off"
+            };
+            request(&args.socket, command).await?;
+            eprintln!(
+                "Maintenance mode {}",
+                if on { "enabled" } else { "disabled" }
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub async fn request(socket: &Path, command: &str) -> Result<Vec<u8>> {
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("connecting to {}", socket.display()))?;
+    let mut stream = BufReader::new(stream);
+    stream
+        .get_mut()
+        .write_all(format!("{command}\n").as_bytes())
+        .await?;
+    let mut status = String::new();
+    stream.read_line(&mut status).await?;
+    let status = status.trim_end();
+    if let Some(message) = status.strip_prefix("error ") {
+        return Err(anyhow!("server: {message}"));
+    }
+    let len: usize = status
+        .strip_prefix("ok ")
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| anyhow!("unexpected control reply {status:?}"))?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+#[cfg(not(unix))]
+pub async fn request(_socket: &Path, _command: &str) -> Result<Vec<u8>> {
+    Err(anyhow!("control sockets are only supported on Unix"))
+}
+
+/// Listen for `garner ctl` commands on a Unix socket at `path`, replacing
+/// a stale socket left by an earlier run.  The socket is only accessible
+/// to the server's user.
+#[cfg(unix)]
+pub fn listen(path: &Path, control: Arc<dyn Control>) -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = std::fs::symlink_metadata(path)
+        && meta.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path).with_context(|| {
+        format!("binding control socket {}", path.display())
+    })?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let control = Arc::clone(&control);
+            tokio::spawn(async move {
+                let _ = serve(stream, control.as_ref()).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path, _control: Arc<dyn Control>) -> Result<()> {
+    Err(anyhow!("control sockets are only supported on Unix"))
+}
+
+/// Answer one command on a control connection.
+async fn serve(
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    control: &dyn Control,
+) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut command = String::new();
+    stream.read_line(&mut command).await?;
+    let result = match command.trim_end() {
+        "rotate-logs" => control.rotate_logs().map(|()| Vec::new()),
+        "diagnostics" => control.diagnostics(),
+        "maintenance on" => control.set_maintenance(true).map(|()| Vec::new()),
+        "maintenance off" => {
+            control.set_maintenance(false).map(|()| Vec::new())
+        }
+        other => Err(anyhow!("unknown command {other:?}")),
+    };
+    let stream = stream.get_mut();
+    reply(stream, result).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Answer a command with its payload, or the error it failed with.
+pub async fn reply(
+    stream: &mut (impl tokio::io::AsyncWrite + Unpin),
+    result: Result<Vec<u8>>,
+) -> Result<()> {
+    match result {
+        Ok(payload) => {
+            stream
+                .write_all(format!("ok {}\n", payload.len()).as_bytes())
+                .await?;
+            stream.write_all(&payload).await?;
+        }
+        Err(e) => {
+            let message = format!("{e:#}").replace('\n', " ");
+            stream
+                .write_all(format!("error {message}\n").as_bytes())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fake;
+
+    impl Control for Fake {
+        fn rotate_logs(&self) -> Result<()> { Err(anyhow!("no access log")) }
+
+        fn diagnostics(&self) -> Result<Vec<u8>> { Ok(b"bundle".to_vec()) }
+
+        fn set_maintenance(&self, on: bool) -> Result<()> {
+            if on {
+                Ok(())
+            } else {
+                Err(anyhow!("not in maintenance"))
+            }
+        }
+    }
+
+    async fn exchange(command: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(command.as_bytes()).await.unwrap();
+        serve(server, &Fake).await.unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        assert_eq!(exchange("diagnostics\n").await, "ok 6\nbundle");
+        assert_eq!(exchange("rotate-logs\n").await, "error no access log\n");
+        assert_eq!(exchange("maintenance on\n").await, "ok 0\n");
+        assert_eq!(
+            exchange("maintenance off\n").await,
+            "error not in maintenance\n"
+        );
+        assert!(exchange("bogus\n").await.starts_with("error unknown"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_request_over_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("ctl.sock");
+        listen(&socket, Arc::new(Fake)).unwrap();
+        let args = CtlArgs {
+            socket,
+            command: CtlCommands::RotateLogs,
+        };
+        assert_eq!(
+            request(&args.socket, "diagnostics").await.unwrap(),
+            b"bundle"
+        );
+        let err = request(&args.socket, "rotate-logs").await.unwrap_err();
+        assert_eq!(err.to_string(), "server: no access log");
+    }
+}
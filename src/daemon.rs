@@ -0,0 +1,290 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use clap::{Args, Subcommand};
+
+use crate::{ctl, tor, ui};
+
+/// Longest reply line accepted from the daemon.
+#[cfg(unix)]
+const MAX_REPLY_LINE: usize = 4096;
+
+/// Options for `garner daemon`.
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Socket the daemon listens on [default: daemon.sock in garner's
+    /// data directory]
+    #[arg(long, value_name = "PATH", env = "GARNER_DAEMON_SOCKET")]
+    pub socket: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<DaemonCommands>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+#[derive(Subcommand)]
+pub enum DaemonCommands {
+    /// Ask the running daemon to exit
+    Stop,
+    /// Show the running daemon's process ID, uptime, and streams opened,
+    /// failing if none is running
+    Status,
+}
+
+// Protocol: the client sends one command line, answered as `garner ctl`
+// commands are.  After `connect <HOST> <PORT> [<ISOLATION>]` is answered
+// `ok 0`, the connection carries the stream to that host.
+
+/// The daemon's Tor client, and what it has done.  Fields drop in order,
+/// so the client releases its locks before its state directory is
+/// deleted.
+#[cfg(unix)]
+struct Daemon {
+    fetcher: crate::get::Fetcher,
+    started: Instant,
+    streams: AtomicU64,
+    stop: tokio::sync::Notify,
+    _state_dir: tor::StateDir,
+}
+
+/// The daemon socket that commands look for: `$GARNER_DAEMON_SOCKET`,
+/// or else `daemon.sock` in garner's data directory.
+pub fn socket_path() -> PathBuf {
+    std::env::var_os("GARNER_DAEMON_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| ui::data_dir().join("daemon.sock"))
+}
+
+pub async fn run(args: DaemonArgs) -> Result<()> {
+    let socket = args.socket.clone().unwrap_or_else(socket_path);
+    match args.command {
+        Some(DaemonCommands::Stop) => {
+            ctl::request(&socket, "stop").await?;
+            eprintln!("Daemon stopped");
+            Ok(())
+        }
+        Some(DaemonCommands::Status) => {
+            let status = ctl::request(&socket, "status")
+                .await
+                .context("no daemon is running")?;
+            print!("{}", String::from_utf8_lossy(&status));
+            Ok(())
+        }
+        None => serve(&socket, &args.tor).await,
+    }
+}
+
+/// Bootstrap a Tor client and open streams on it for clients of
+/// `socket` until told to stop.
+#[cfg(unix)]
+async fn serve(socket: &Path, tor_args: &tor::TorArgs) -> Result<()> {
+    use std::{os::unix::fs::PermissionsExt, sync::Arc};
+
+    if is_running(socket).await {
+        return Err(anyhow!(
+            "a daemon is already running on {}",
+            socket.display()
+        ));
+    }
+    // Nothing answers on the socket, so it was left by a daemon that
+    // did not stop cleanly.
+    if socket.exists() {
+        std::fs::remove_file(socket)?;
+    }
+    if let Some(parent) = socket.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Clients that connect while Tor bootstraps wait for it, rather than
+    // bootstrapping their own.
+    let listener = tokio::net::UnixListener::bind(socket)
+        .with_context(|| format!("binding {}", socket.display()))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+
+    ui::log("Bootstrapping Tor...");
+    let (state_dir, cache_dir) = tor::dirs(Some("daemon"))?;
+    let config = tor::config(&state_dir, &cache_dir, tor_args)?.build()?;
+    let client = arti_client::TorClient::create_bootstrapped(config).await?;
+    let daemon = Arc::new(Daemon {
+        fetcher: crate::get::Fetcher::new(client),
+        started: Instant::now(),
+        streams: AtomicU64::new(0),
+        stop: tokio::sync::Notify::new(),
+        _state_dir: state_dir,
+    });
+    ui::log(&format!("Daemon listening on {}", socket.display()));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let daemon = Arc::clone(&daemon);
+                tokio::spawn(async move {
+                    if let Err(e) = daemon.handle(stream).await {
+                        ui::log(&format!("daemon error: {e:#}"));
+                    }
+                });
+            }
+            () = daemon.stop.notified() => break,
+        }
+    }
+    std::fs::remove_file(socket)?;
+    ui::log("Daemon stopped");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve(_socket: &Path, _tor_args: &tor::TorArgs) -> Result<()> {
+    Err(anyhow!("the daemon is only supported on Unix"))
+}
+
+#[cfg(unix)]
+impl Daemon {
+    /// Answer one command from a client.
+    async fn handle(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+        use tokio_util::compat::FuturesAsyncReadCompatExt as _;
+
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        // Clients connect without a command to see if a daemon is running.
+        if stream.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            ["connect", host, port, isolation @ ..] if isolation.len() < 2 => {
+                let isolation = isolation.first().copied();
+                let opened = match port.parse() {
+                    Ok(port) => self.fetcher.open(host, port, isolation).await,
+                    Err(_) => Err(anyhow!("invalid port {port:?}")),
+                };
+                match opened {
+                    Ok(service) => {
+                        self.streams.fetch_add(1, Ordering::Relaxed);
+                        ctl::reply(stream.get_mut(), Ok(Vec::new())).await?;
+                        let mut service = service.compat();
+                        // Anything the client sent after its command.
+                        service.write_all(stream.buffer()).await?;
+                        let mut stream = stream.into_inner();
+                        let _ = tokio::io::copy_bidirectional(
+                            &mut stream,
+                            &mut service,
+                        )
+                        .await;
+                        return Ok(());
+                    }
+                    Err(e) => Err(e.context(format!("connecting to {host}"))),
+                }
+            }
+            ["status"] => Ok(self.status().into_bytes()),
+            ["stop"] => {
+                // Answer before the daemon exits.
+                let stream = stream.get_mut();
+                ctl::reply(stream, Ok(Vec::new())).await?;
+                stream.shutdown().await?;
+                self.stop.notify_one();
+                return Ok(());
+            }
+            _ => Err(anyhow!("unknown command {:?}", line.trim_end())),
+        };
+        let stream = stream.get_mut();
+        ctl::reply(stream, result).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "pid: {}\nuptime: {}s\nstreams: {}\n",
+            std::process::id(),
+            self.started.elapsed().as_secs(),
+            self.streams.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Whether a daemon answers on `socket`.
+#[cfg(unix)]
+pub async fn is_running(socket: &Path) -> bool {
+    tokio::net::UnixStream::connect(socket).await.is_ok()
+}
+
+/// Ask the daemon on `socket` for a stream to `port` on `host`, on a
+/// circuit shared only with streams that have the same `isolation` key.
+#[cfg(unix)]
+pub async fn connect(
+    socket: &Path,
+    host: &str,
+    port: u16,
+    isolation: Option<&str>,
+) -> Result<tokio::net::UnixStream> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let mut stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("connecting to {}", socket.display()))?;
+    let command = match isolation {
+        Some(key) => format!("connect {host} {port} {key}\n"),
+        None => format!("connect {host} {port}\n"),
+    };
+    stream.write_all(command.as_bytes()).await?;
+    // Read the reply a byte at a time, so that none of what the service
+    // sends after it is consumed with it.
+    let mut reply = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            _ if reply.len() == MAX_REPLY_LINE => {
+                return Err(anyhow!("daemon reply too long"));
+            }
+            byte => reply.push(byte),
+        }
+    }
+    let reply = String::from_utf8_lossy(&reply);
+    if let Some(message) = reply.strip_prefix("error ") {
+        return Err(anyhow!("daemon: {message}"));
+    }
+    if reply != "ok 0" {
+        return Err(anyhow!("unexpected daemon reply {reply:?}"));
+    }
+    Ok(stream)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("daemon.sock");
+        assert!(!is_running(&socket).await);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        assert!(is_running(&socket).await);
+        let _probe = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            for reply in ["ok 0\nhello", "error no route\n"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut command = [0u8; 64];
+                let n = stream.read(&mut command).await.unwrap();
+                assert_eq!(&command[..n], b"connect a.onion 80 key\n");
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        let mut stream =
+            connect(&socket, "a.onion", 80, Some("key")).await.unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "hello");
+        let err = connect(&socket, "a.onion", 80, Some("key"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "daemon: no route");
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    io::{Read as _, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+/// Path of the manifest listing every file with the digest of its
+/// current version.
+pub const MANIFEST_PATH: &str = "/.manifest";
+
+/// Request path prefix for a delta between two versions of a file:
+/// `/.delta/<FROM>/<TO>`, each the SHA-256 digest of a version's content.
+pub const PATH_PREFIX: &str = "/.delta/";
+
+/// Media type of a delta: a Zstandard frame compressed against the old
+/// version.
+pub const MEDIA_TYPE: &str = "application/zstd";
+
+/// Compression level used for deltas.
+const LEVEL: i32 = 19;
+
+/// A file listed in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    /// Path relative to the docroot, with `/` separators.
+    pub path: String,
+    /// SHA-256 digest of the content, in hex.
+    pub digest: String,
+    pub size: u64,
+}
+
+/// The versions of a docroot's files seen while it is served.
+///
+/// Every version listed in a manifest is copied into `store`, named by its
+/// digest, so the server can later compute a delta from it to whatever
+/// version replaced it.  Versions can be removed from the store at any
+/// time; clients holding them then download the file whole.
+pub struct Versions {
+    docroot: PathBuf,
+    store: PathBuf,
+    /// The current version of each file, by relative path, with the size
+    /// and modification time it had when hashed.
+    files: Mutex<HashMap<String, (File, Option<SystemTime>)>>,
+}
+
+impl Versions {
+    /// Start tracking the files under `docroot`, recording their current
+    /// versions in `store`.
+    pub fn open(docroot: &Path, store: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&store).with_context(|| {
+            format!("creating version store {}", store.display())
+        })?;
+        let versions = Self {
+            docroot: docroot.to_path_buf(),
+            store,
+            files: Mutex::new(HashMap::new()),
+        };
+        versions.manifest()?;
+        Ok(versions)
+    }
+
+    pub fn store(&self) -> &Path { &self.store }
+
+    /// The current version of every file under the docroot, sorted by
+    /// path.  Files that changed since they were last hashed are hashed
+    /// again and their new versions recorded.  Symlinked directories are
+    /// not followed.
+    pub fn manifest(&self) -> Result<Vec<File>> {
+        let mut files = self.files.lock().expect("versions lock");
+        let mut current = HashMap::new();
+        let mut dirs = vec![(self.docroot.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("reading {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                let Some(name) = entry.file_name().to_str().map(str::to_owned)
+                else {
+                    continue;
+                };
+                let path = entry.path();
+                let relative = format!("{prefix}{name}");
+                if entry.file_type()?.is_dir() {
+                    dirs.push((path, format!("{relative}/")));
+                    continue;
+                }
+                let meta = std::fs::metadata(&path)?;
+                if !meta.is_file() {
+                    continue;
+                }
+                let stamp = (meta.len(), meta.modified().ok());
+                let known = files
+                    .remove(&relative)
+                    .filter(|(file, mtime)| (file.size, *mtime) == stamp);
+                let version = match known {
+                    Some(version) => version,
+                    None => (self.record(&path, &relative)?, stamp.1),
+                };
+                current.insert(relative, version);
+            }
+        }
+        *files = current;
+        let mut manifest: Vec<File> =
+            files.values().map(|(file, _)| file.clone()).collect();
+        manifest.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(manifest)
+    }
+
+    /// Hash the file at `path` and keep a copy of this version.
+    fn record(&self, path: &Path, relative: &str) -> Result<File> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let digest = hex::encode(Sha256::digest(&data));
+        let stored = self.store.join(&digest);
+        if !stored.exists() {
+            // Write via a temporary file and rename, so a delta is never
+            // computed from a partial copy.
+            let mut file = tempfile::NamedTempFile::new_in(&self.store)?;
+            file.write_all(&data)?;
+            file.persist(&stored)
+                .with_context(|| format!("writing {}", stored.display()))?;
+        }
+        Ok(File {
+            path: relative.to_string(),
+            digest,
+            size: data.len() as u64,
+        })
+    }
+
+    /// The delta for a `/.delta/<FROM>/<TO>` request path, if both
+    /// versions are in the store.
+    pub fn delta(&self, request_path: &str) -> Result<Option<Vec<u8>>> {
+        let Some((from, to)) = request_path
+            .strip_prefix(PATH_PREFIX)
+            .and_then(|rest| rest.split_once('/'))
+            .filter(|(from, to)| is_digest(from) && is_digest(to))
+        else {
+            return Ok(None);
+        };
+        let (Some(old), Some(new)) = (self.read(from)?, self.read(to)?) else {
+            return Ok(None);
+        };
+        diff(&old, &new).map(Some)
+    }
+
+    fn read(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.store.join(digest);
+        match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(e).with_context(|| format!("reading {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Whether `s` is a SHA-256 digest in lowercase hex.
+fn is_digest(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The manifest as served: `{"files": [{"path", "sha256", "size"}, ...]}`.
+pub fn manifest_json(files: &[File]) -> Vec<u8> {
+    let files: Vec<Value> = files
+        .iter()
+        .map(|file| {
+            json!({
+                "path": file.path,
+                "sha256": file.digest,
+                "size": file.size,
+            })
+        })
+        .collect();
+    json!({ "files": files }).to_string().into_bytes()
+}
+
+/// Parse a manifest fetched from a server.
+pub fn parse_manifest(body: &[u8]) -> Result<Vec<File>> {
+    let manifest: Value =
+        serde_json::from_slice(body).context("parsing manifest")?;
+    let files = manifest
+        .get("files")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("manifest has no files array"))?;
+    files
+        .iter()
+        .map(|file| {
+            let field = |name| {
+                file.get(name)
+                    .ok_or_else(|| anyhow!("manifest entry without {name}"))
+            };
+            let digest = field("sha256")?
+                .as_str()
+                .filter(|digest| is_digest(digest))
+                .ok_or_else(|| anyhow!("manifest entry with a bad sha256"))?;
+            Ok(File {
+                path: field("path")?
+                    .as_str()
+                    .ok_or_else(|| anyhow!("manifest entry with a bad path"))?
+                    .to_string(),
+                digest: digest.to_string(),
+                size: field("size")?
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("manifest entry with a bad size"))?,
+            })
+        })
+        .collect()
+}
+
+/// Window size, as a power of two, that lets a delta refer back to any
+/// part of the old version.
+fn window_log(old_len: usize, new_len: usize) -> u32 {
+    let span = (old_len + new_len).max(1) as u64;
+    (64 - (span - 1).leading_zeros()).clamp(10, 31)
+}
+
+/// A delta that turns `old` into `new`: `new` compressed with `old` as a
+/// reference prefix.
+pub fn diff(old: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder =
+        zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), LEVEL, old)?;
+    encoder.window_log(window_log(old.len(), new.len()))?;
+    encoder.long_distance_matching(true)?;
+    encoder.write_all(new)?;
+    Ok(encoder.finish()?)
+}
+
+/// Apply a delta to `old`, giving the version of `size` bytes it was
+/// computed for.
+pub fn patch(old: &[u8], delta: &[u8], size: u64) -> Result<Vec<u8>> {
+    let new_len = usize::try_from(size).context("file too large")?;
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(delta, old)?;
+    decoder.window_log_max(window_log(old.len(), new_len))?;
+    let mut data = Vec::new();
+    decoder
+        .take(size + 1)
+        .read_to_end(&mut data)
+        .context("applying delta")?;
+    if data.len() != new_len {
+        return Err(anyhow!(
+            "delta gave {} bytes, expected {size}",
+            data.len()
+        ));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_and_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let docroot = dir.path().join("public");
+        std::fs::create_dir_all(docroot.join("sub")).unwrap();
+        let old: Vec<u8> = (0..200_000u32).flat_map(u32::to_le_bytes).collect();
+        std::fs::write(docroot.join("sub/big.bin"), &old).unwrap();
+        std::fs::write(docroot.join("a.txt"), "hello").unwrap();
+        let versions =
+            Versions::open(&docroot, dir.path().join("store")).unwrap();
+
+        let manifest = versions.manifest().unwrap();
+        let paths: Vec<_> = manifest.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "sub/big.bin"]);
+        assert_eq!(
+            parse_manifest(&manifest_json(&manifest)).unwrap(),
+            manifest
+        );
+        let from = manifest[1].digest.clone();
+
+        let mut new = old.clone();
+        new[1000..1004].copy_from_slice(b"edit");
+        new.extend_from_slice(b"appended");
+        std::fs::write(docroot.join("sub/big.bin"), &new).unwrap();
+        let manifest = versions.manifest().unwrap();
+        let to = &manifest[1];
+        assert_ne!(to.digest, from);
+
+        let delta = versions
+            .delta(&format!("{PATH_PREFIX}{from}/{}", to.digest))
+            .unwrap()
+            .unwrap();
+        assert!(delta.len() < 1000, "delta is {} bytes", delta.len());
+        assert_eq!(patch(&old, &delta, to.size).unwrap(), new);
+        assert!(patch(&old, &delta, to.size - 1).is_err());
+
+        let unknown = "0".repeat(64);
+        assert!(
+            versions
+                .delta(&format!("{PATH_PREFIX}{unknown}/{}", to.digest))
+                .unwrap()
+                .is_none()
+        );
+        assert!(versions.delta("/.delta/../a.txt").unwrap().is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{collections::VecDeque, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use toml::{Table, Value};
+
+use crate::config;
+
+/// Number of recent log lines kept for diagnostics bundles.
+const RECENT_LOG_LINES: usize = 500;
+
+/// Text that replaces secret values in a bundled configuration file.
+const REDACTED: &str = "<redacted>";
+
+/// An in-memory record of recent log lines and service status changes,
+/// kept so a diagnostics bundle can include them.
+#[derive(Default)]
+pub struct Journal {
+    recent: Mutex<VecDeque<String>>,
+    status: Mutex<Vec<String>>,
+}
+
+impl Journal {
+    /// Remember a log line, dropping the oldest once the buffer is full.
+    pub fn log(&self, line: &str) {
+        let mut recent = self.recent.lock().expect("journal lock");
+        if recent.len() == RECENT_LOG_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.to_string());
+    }
+
+    /// Record a timestamped status change.
+    pub fn status(&self, event: &str) {
+        self.status.lock().expect("journal lock").push(format!(
+            "[{}] {event}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        ));
+    }
+
+    fn recent_text(&self) -> String {
+        lines_text(self.recent.lock().expect("journal lock").iter())
+    }
+
+    fn status_text(&self) -> String {
+        lines_text(self.status.lock().expect("journal lock").iter())
+    }
+}
+
+fn lines_text<'a>(lines: impl Iterator<Item = &'a String>) -> String {
+    lines.map(|line| format!("{line}\n")).collect()
+}
+
+/// Build a `.tar.gz` bundle for bug reports containing the capability
+/// report (version, features, and active settings), the configuration
+/// file with secrets redacted, recent log lines, and the status history.
+pub fn bundle(
+    report: &[String],
+    config_file: Option<&Path>,
+    journal: &Journal,
+) -> Result<Vec<u8>> {
+    let encoder = flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    );
+    let mut archive = tar::Builder::new(encoder);
+    let mut add = |name: &str, text: &str| -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp().max(0) as u64);
+        header.set_cksum();
+        archive
+            .append_data(
+                &mut header,
+                format!("garner-diagnostics/{name}"),
+                text.as_bytes(),
+            )
+            .with_context(|| format!("adding {name} to bundle"))
+    };
+    add("report.txt", &lines_text(report.iter()))?;
+    if let Some(path) = config_file {
+        let text = match config::load(path) {
+            Ok(table) => toml::to_string(&redact(table))?,
+            Err(e) => format!("# could not load {}: {e:#}\n", path.display()),
+        };
+        add("config.toml", &text)?;
+    }
+    add("recent.log", &journal.recent_text())?;
+    add("status.log", &journal.status_text())?;
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// Replace the values of keys that hold secrets.  `{ file = … }` and
+/// `{ env = … }` references are kept, since they name where a secret
+/// lives rather than containing it.
+fn redact(mut table: Table) -> Table {
+    for (key, value) in table.iter_mut() {
+        match value {
+            Value::Table(inner) if !config::is_indirection(inner) => {
+                *inner = redact(std::mem::take(inner));
+            }
+            Value::Table(_) => {}
+            _ if is_secret(key) => *value = Value::String(REDACTED.into()),
+            _ => {}
+        }
+    }
+    table
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["key", "password", "secret", "token"]
+        .iter()
+        .any(|word| key.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let table: Table = toml::from_str(
+            "workers = 2\n\
+             [server]\n\
+             key = \"ur:secret\"\n\
+             tor-control-password = \"hunter2\"\n\
+             docroot = \"public\"\n\
+             [get]\n\
+             key = { env = \"GARNER_KEY\" }\n",
+        )
+        .unwrap();
+        let text = toml::to_string(&redact(table)).unwrap();
+        assert!(!text.contains("ur:secret"));
+        assert!(!text.contains("hunter2"));
+        assert!(text.contains("docroot = \"public\""));
+        assert!(text.contains("GARNER_KEY"));
+    }
+
+    #[test]
+    fn test_journal_keeps_recent_lines() {
+        let journal = Journal::default();
+        for i in 0..RECENT_LOG_LINES + 10 {
+            journal.log(&format!("line {i}"));
+        }
+        let text = journal.recent_text();
+        assert_eq!(text.lines().count(), RECENT_LOG_LINES);
+        assert!(text.starts_with("line 10\n"));
+    }
+
+    #[test]
+    fn test_bundle() {
+        let journal = Journal::default();
+        journal.log("GET /");
+        journal.status("started");
+        let bytes =
+            bundle(&["version: 0.1.0".to_string()], None, &journal).unwrap();
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+        let mut files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut text = String::new();
+            entry.read_to_string(&mut text).unwrap();
+            files.push((entry.path().unwrap().display().to_string(), text));
+        }
+        assert_eq!(files[0].0, "garner-diagnostics/report.txt");
+        assert_eq!(files[0].1, "version: 0.1.0\n");
+        assert_eq!(files[1].1, "GET /\n");
+        assert!(files[2].1.ends_with("] started\n"));
+    }
+}
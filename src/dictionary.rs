@@ -0,0 +1,170 @@
+use std::{io::Read as _, path::Path};
+
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// Content coding for a Zstandard frame compressed with the site's
+/// dictionary.  Clients list it in `Accept-Encoding`.
+pub const CODING: &str = "garner-zstd";
+
+/// Request header naming the dictionary a client holds, by the SHA-256
+/// digest of its bytes in hex.  The server answers with the same header
+/// when it serves the dictionary.
+pub const DIGEST_HEADER: &str = "X-Garner-Dictionary";
+
+/// Path the server publishes its dictionary at.
+pub const PATH: &str = "/.zstd-dictionary";
+
+/// Default dictionary size, as for the `zstd --train` command.
+pub const DEFAULT_SIZE: usize = 112_640;
+
+/// Compression level used for responses.
+const LEVEL: i32 = 3;
+
+/// A Zstandard dictionary trained on a site's files.  Many small,
+/// similar documents share most of their markup, so compressing each with
+/// a dictionary of that common content saves far more than compressing
+/// it alone.
+pub struct Dictionary {
+    data: Vec<u8>,
+    digest: String,
+    encoder: EncoderDictionary<'static>,
+}
+
+impl Dictionary {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| {
+            format!("reading dictionary {}", path.display())
+        })?;
+        Ok(Self {
+            digest: digest(&data),
+            encoder: EncoderDictionary::copy(&data, LEVEL),
+            data,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] { &self.data }
+
+    pub fn digest(&self) -> &str { &self.digest }
+
+    /// Whether a request's `Accept-Encoding` and [`DIGEST_HEADER`] show
+    /// the client can decode responses compressed with this dictionary.
+    pub fn accepted_by(
+        &self,
+        accept_encoding: Option<&str>,
+        dictionary: Option<&str>,
+    ) -> bool {
+        dictionary.is_some_and(|d| d.trim().eq_ignore_ascii_case(&self.digest))
+            && accept_encoding.is_some_and(|value| {
+                value.split(',').any(|coding| {
+                    let coding = coding.split(';').next().unwrap_or("").trim();
+                    coding.eq_ignore_ascii_case(CODING)
+                })
+            })
+    }
+
+    /// An encoder compressing with this dictionary into a buffer, for
+    /// bodies compressed as they are sent.
+    pub fn encoder(&self) -> Result<zstd::stream::write::Encoder<'_, Vec<u8>>> {
+        Ok(zstd::stream::write::Encoder::with_prepared_dictionary(
+            Vec::new(),
+            &self.encoder,
+        )?)
+    }
+
+    pub fn compress(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut compressor =
+            zstd::bulk::Compressor::with_prepared_dictionary(&self.encoder)?;
+        Ok(compressor.compress(body)?)
+    }
+}
+
+/// SHA-256 digest of a dictionary, in hex, as sent in [`DIGEST_HEADER`].
+pub fn digest(dictionary: &[u8]) -> String {
+    hex::encode(Sha256::digest(dictionary))
+}
+
+/// Decode a response body compressed with `dictionary`.
+pub fn decompress(dictionary: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+    let dictionary = DecoderDictionary::copy(dictionary);
+    let mut decoder =
+        zstd::stream::Decoder::with_prepared_dictionary(body, &dictionary)?;
+    let mut data = Vec::new();
+    decoder
+        .read_to_end(&mut data)
+        .context("decompressing response")?;
+    Ok(data)
+}
+
+/// Train a dictionary of at most `max_size` bytes on every file under
+/// `docroot`.  Symlinked directories are not followed.
+pub fn build(docroot: &Path, max_size: usize) -> Result<Vec<u8>> {
+    let mut samples = Vec::new();
+    let mut dirs = vec![docroot.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("reading {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.is_file() {
+                samples.push(
+                    std::fs::read(&path).with_context(|| {
+                        format!("reading {}", path.display())
+                    })?,
+                );
+            }
+        }
+    }
+    zstd::dict::from_samples(&samples, max_size).map_err(|e| {
+        anyhow!(
+            "training a dictionary on {} files: {e} (a dictionary needs \
+             many sample files)",
+            samples.len()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let docroot = dir.path().join("public");
+        std::fs::create_dir(&docroot).unwrap();
+        for i in 0..200 {
+            std::fs::write(
+                docroot.join(format!("{i}.html")),
+                format!(
+                    "<!DOCTYPE html><html><head><title>Note {i}</title>\
+                     <link rel=\"stylesheet\" href=\"/style.css\"></head>\
+                     <body><nav><a href=\"/\">Home</a></nav><p>{}</p>\
+                     </body></html>",
+                    i * 7919
+                ),
+            )
+            .unwrap();
+        }
+        let data = build(&docroot, 4096).unwrap();
+        let path = dir.path().join("site.dict");
+        std::fs::write(&path, &data).unwrap();
+        let dictionary = Dictionary::open(&path).unwrap();
+
+        let digest = dictionary.digest().to_string();
+        assert!(
+            dictionary.accepted_by(Some("gzip, garner-zstd"), Some(&digest))
+        );
+        assert!(!dictionary.accepted_by(Some("gzip"), Some(&digest)));
+        assert!(!dictionary.accepted_by(Some("garner-zstd"), Some("00")));
+
+        let page = std::fs::read(docroot.join("5.html")).unwrap();
+        let compressed = dictionary.compress(&page).unwrap();
+        assert!(compressed.len() < page.len() / 2);
+        assert_eq!(decompress(dictionary.data(), &compressed).unwrap(), page);
+    }
+}
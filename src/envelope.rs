@@ -0,0 +1,128 @@
+use anyhow::{Context as _, Result, anyhow};
+use bc_components::{Digest, SigningPrivateKey, SigningPublicKey};
+use bc_envelope::{Envelope, known_values};
+use dcbor::{CBOR, CBOREncodable as _, Date};
+
+/// Media type of a binary CBOR Gordian Envelope.
+pub const MEDIA_TYPE: &str = "application/envelope";
+
+/// Request path prefix that asks for the file at the rest of the path as
+/// an envelope.
+pub const PATH_PREFIX: &str = "/.envelope/";
+
+/// Wrap `body` in a Gordian Envelope asserting its SHA-256 digest, its
+/// MIME type, and the time it was served, sign the whole envelope with
+/// `key`, and return its binary CBOR.
+pub fn file_envelope(
+    body: &[u8],
+    mime: &str,
+    key: &SigningPrivateKey,
+) -> Vec<u8> {
+    Envelope::new(CBOR::to_byte_string(body))
+        .add_assertion("digest", CBOR::from(Digest::from_image(body)))
+        .add_assertion("mimeType", mime)
+        .add_assertion(known_values::DATE, Date::now())
+        .sign(key)
+        .to_cbor_data()
+}
+
+/// Verify an envelope made by [`file_envelope`] against `key`, check that
+/// its digest assertion matches its subject, and return the subject's
+/// bytes.
+pub fn open_file_envelope(
+    data: &[u8],
+    key: &SigningPublicKey,
+) -> Result<Vec<u8>> {
+    let envelope = Envelope::try_from_cbor_data(data.to_vec())
+        .map_err(|e| anyhow!("{e}"))
+        .context("response is not a Gordian Envelope")?;
+    let inner = envelope
+        .verify(key)
+        .map_err(|_| anyhow!("envelope signature does not match"))?;
+    let body = inner
+        .subject()
+        .try_byte_string()
+        .map_err(|e| anyhow!("{e}"))
+        .context("envelope subject is not a byte string")?;
+    let digest = inner
+        .object_for_predicate("digest")
+        .and_then(|digest| Ok(Digest::try_from(digest.try_leaf()?)?))
+        .map_err(|e| anyhow!("{e}"))
+        .context("envelope has no digest assertion")?;
+    if digest != Digest::from_image(&body) {
+        return Err(anyhow!("envelope digest does not match its subject"));
+    }
+    Ok(body)
+}
+
+/// Whether an `Accept` header value lists the envelope media type.
+pub fn accepts_envelope(accept: &str) -> bool {
+    accept.split(',').any(|range| {
+        let media_type = range.split(';').next().unwrap_or("");
+        media_type.trim().eq_ignore_ascii_case(MEDIA_TYPE)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_envelope_is_signed() {
+        bc_envelope::register_tags();
+        let (priv_ur, _) = crate::key::generate_keypair().unwrap();
+        let key = crate::key::parse_signing_key(&priv_ur).unwrap();
+        let data = file_envelope(b"hello", "text/plain", &key);
+
+        let envelope = Envelope::try_from_cbor_data(data).unwrap();
+        let inner = envelope.verify(&key.public_key().unwrap()).unwrap();
+        assert_eq!(inner.subject().try_byte_string().unwrap(), b"hello");
+        let mime: String =
+            inner.extract_object_for_predicate("mimeType").unwrap();
+        assert_eq!(mime, "text/plain");
+        let digest = inner.object_for_predicate("digest").unwrap();
+        assert_eq!(
+            Digest::try_from(digest.try_leaf().unwrap()).unwrap(),
+            Digest::from_image(b"hello")
+        );
+        inner
+            .extract_object_for_predicate::<Date>(known_values::DATE)
+            .unwrap();
+
+        let (other_ur, _) = crate::key::generate_keypair().unwrap();
+        let other = crate::key::parse_signing_key(&other_ur).unwrap();
+        assert!(envelope.verify(&other.public_key().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_open_file_envelope() {
+        bc_envelope::register_tags();
+        let (priv_ur, _) = crate::key::generate_keypair().unwrap();
+        let key = crate::key::parse_signing_key(&priv_ur).unwrap();
+        let public_key = key.public_key().unwrap();
+        let data = file_envelope(b"hello", "text/plain", &key);
+        assert_eq!(open_file_envelope(&data, &public_key).unwrap(), b"hello");
+
+        let (other_ur, _) = crate::key::generate_keypair().unwrap();
+        let other = crate::key::parse_signing_key(&other_ur).unwrap();
+        assert!(
+            open_file_envelope(&data, &other.public_key().unwrap()).is_err()
+        );
+        assert!(open_file_envelope(b"hello", &public_key).is_err());
+
+        // A signed envelope whose digest is not that of its subject.
+        let forged = Envelope::new(CBOR::to_byte_string(b"hello"))
+            .add_assertion("digest", CBOR::from(Digest::from_image(b"other")))
+            .sign(&key)
+            .to_cbor_data();
+        assert!(open_file_envelope(&forged, &public_key).is_err());
+    }
+
+    #[test]
+    fn test_accepts_envelope() {
+        assert!(accepts_envelope("application/envelope"));
+        assert!(accepts_envelope("text/html, Application/Envelope;q=0.9"));
+        assert!(!accepts_envelope("*/*"));
+        assert!(!accepts_envelope("application/envelope-x"));
+    }
+}
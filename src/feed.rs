@@ -0,0 +1,149 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use crate::auth::BearerToken;
+
+/// Path of the feed: GET to read it, POST to append a record.
+pub const PATH: &str = "/.feed";
+
+/// Largest record accepted by POST, in bytes.
+pub const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+/// Most bytes returned by one GET; readers ask again from where it
+/// stopped.
+const MAX_READ_SIZE: u64 = 1024 * 1024;
+
+/// An append-only file published as an event feed.
+///
+/// Anyone can read the feed from a byte offset, so a reader tails it by
+/// asking for the bytes after the end it last saw.  Records are appended
+/// by POST with the feed's bearer token, or by any other process writing
+/// to the end of the file; each record ends with a newline.
+pub struct Feed {
+    path: PathBuf,
+    token: Option<BearerToken>,
+    /// Open for appending, and held while appending so records are
+    /// never interleaved.
+    file: Mutex<File>,
+}
+
+impl Feed {
+    /// Open the feed at `path`, creating it if needed.  Without a `token`,
+    /// the feed cannot be appended to over HTTP.
+    pub fn open(path: &Path, token: Option<&str>) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening feed {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            token: token.map(BearerToken::new),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Whether an `Authorization` header allows appending.
+    pub fn authorized(&self, authorization: Option<&str>) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(|token| token.authorizes(authorization))
+    }
+
+    /// Append `record`, adding a newline if it lacks one, and return the
+    /// new length of the feed.
+    pub fn append(&self, record: &[u8]) -> Result<u64> {
+        let mut file = self.file.lock().expect("feed lock");
+        let mut data = record.to_vec();
+        if data.last() != Some(&b'\n') {
+            data.push(b'\n');
+        }
+        file.write_all(&data)
+            .with_context(|| format!("appending to {}", self.path.display()))?;
+        Ok(file.metadata()?.len())
+    }
+
+    /// The bytes from `offset` on, at most [`MAX_READ_SIZE`] of them,
+    /// and the feed's current length.  There are none if `offset` is at
+    /// or past the end.
+    pub fn read_from(&self, offset: u64) -> Result<(Vec<u8>, u64)> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        let len = file.metadata()?.len();
+        let mut data = Vec::new();
+        if offset < len {
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(MAX_READ_SIZE.min(len - offset))
+                .read_to_end(&mut data)?;
+        }
+        Ok((data, len))
+    }
+}
+
+/// The offset a GET asks for: from an `offset=<N>` query parameter or a
+/// `Range: bytes=<N>-` header, and whether it came from the header.
+/// `None` if either is malformed.
+pub fn requested_offset(
+    query: Option<&str>,
+    range: Option<&str>,
+) -> Option<(u64, bool)> {
+    if let Some(range) = range {
+        let start = range.trim().strip_prefix("bytes=")?.strip_suffix('-')?;
+        return Some((start.parse().ok()?, true));
+    }
+    let offset = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| param.strip_prefix("offset="));
+    match offset {
+        Some(offset) => Some((offset.parse().ok()?, false)),
+        None => Some((0, false)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let feed =
+            Feed::open(&dir.path().join("events.log"), Some("secret")).unwrap();
+        assert!(feed.authorized(Some("Bearer secret")));
+        assert_eq!(feed.append(b"one").unwrap(), 4);
+        assert_eq!(feed.append(b"two\n").unwrap(), 8);
+        assert_eq!(feed.read_from(0).unwrap(), (b"one\ntwo\n".to_vec(), 8));
+        assert_eq!(feed.read_from(4).unwrap(), (b"two\n".to_vec(), 8));
+        assert_eq!(feed.read_from(9).unwrap(), (Vec::new(), 8));
+
+        let read_only =
+            Feed::open(&dir.path().join("events.log"), None).unwrap();
+        assert!(!read_only.authorized(Some("Bearer secret")));
+    }
+
+    #[test]
+    fn test_requested_offset() {
+        assert_eq!(requested_offset(None, None), Some((0, false)));
+        assert_eq!(
+            requested_offset(Some("offset=12"), None),
+            Some((12, false))
+        );
+        assert_eq!(
+            requested_offset(Some("a=b&offset=3"), None),
+            Some((3, false))
+        );
+        assert_eq!(
+            requested_offset(Some("offset=1"), Some("bytes=7-")),
+            Some((7, true))
+        );
+        assert_eq!(requested_offset(None, Some("bytes=0-9")), None);
+        assert_eq!(requested_offset(Some("offset=x"), None), None);
+    }
+}
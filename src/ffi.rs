@@ -0,0 +1,376 @@
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    sync::{Once, OnceLock},
+};
+
+use anyhow::{Result, anyhow};
+use arti_client::TorClient;
+use tokio::sync::OnceCell;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::{get, key, server, tor};
+
+thread_local! {
+    /// The message of the last call on this thread that failed.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The runtime every call that reaches Tor runs on, started on first use.
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+/// The Tor client shared by every fetch and service, bootstrapped on
+/// first use.
+static TOR: OnceCell<Tor> = OnceCell::const_new();
+
+/// The shared Tor client.  Fields drop in order, so the client releases
+/// its locks before its state directory is deleted.
+struct Tor {
+    client: TorClient<PreferredRuntime>,
+    fetcher: get::Fetcher,
+    _state_dir: tor::StateDir,
+}
+
+/// An onion service started by [`garner_serve`], stopped by
+/// [`garner_service_stop`].
+pub struct GarnerService {
+    service: server::Service,
+}
+
+/// Register the UR tags keys are parsed with, as `main` does.
+fn init() {
+    static TAGS: Once = Once::new();
+    TAGS.call_once(bc_envelope::register_tags);
+}
+
+fn runtime() -> Result<&'static tokio::runtime::Runtime> {
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+async fn shared_tor() -> Result<&'static Tor> {
+    TOR.get_or_try_init(|| async {
+        let (state_dir, cache_dir) = tor::dirs(Some("ffi"))?;
+        let config =
+            tor::config(&state_dir, &cache_dir, &tor::TorArgs::default())?
+                .build()?;
+        let client = TorClient::create_bootstrapped(config).await?;
+        Ok(Tor {
+            fetcher: get::Fetcher::new(client.clone()),
+            client,
+            _state_dir: state_dir,
+        })
+    })
+    .await
+}
+
+/// Run `call`, keeping its error for [`garner_last_error`].
+fn catch<T>(call: impl FnOnce() -> Result<T>) -> Option<T> {
+    init();
+    match call() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            let message = format!("{e:#}").replace('\0', " ");
+            let message = CString::new(message).expect("no NUL bytes");
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            None
+        }
+    }
+}
+
+/// Read a NUL-terminated UTF-8 argument.
+///
+/// # Safety
+///
+/// `arg` must be null or point to a NUL-terminated string.
+unsafe fn arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str> {
+    if arg.is_null() {
+        return Err(anyhow!("{name} is null"));
+    }
+    // SAFETY: the caller passes a NUL-terminated string.
+    let arg = unsafe { CStr::from_ptr(arg) };
+    arg.to_str().map_err(|_| anyhow!("{name} is not UTF-8"))
+}
+
+/// Read an argument that may be null.
+///
+/// # Safety
+///
+/// As for [`arg`].
+unsafe fn optional_arg<'a>(
+    value: *const c_char,
+    name: &str,
+) -> Result<Option<&'a str>> {
+    match value.is_null() {
+        true => Ok(None),
+        // SAFETY: as for this function.
+        false => unsafe { arg(value, name) }.map(Some),
+    }
+}
+
+/// Hand `value` to the caller, who frees it with [`garner_string_free`].
+fn string_out(value: String) -> Result<*mut c_char> {
+    Ok(CString::new(value)?.into_raw())
+}
+
+/// The message of the last call on this thread that failed, or null if
+/// none has.  It stays valid until the next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn garner_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |e| e.as_ptr())
+    })
+}
+
+/// Free a string returned by garner.
+///
+/// # Safety
+///
+/// `value` must be null or a string returned by garner and not yet freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        // SAFETY: the string came from `CString::into_raw`.
+        drop(unsafe { CString::from_raw(value) });
+    }
+}
+
+/// Free a body returned by [`garner_fetch`].
+///
+/// # Safety
+///
+/// `data` and `len` must be a body returned by [`garner_fetch`] and not
+/// yet freed, or `data` must be null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_bytes_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        // SAFETY: the body came from a boxed slice of `len` bytes.
+        drop(unsafe {
+            Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len))
+        });
+    }
+}
+
+/// The `ur:signing-public-key` of a private key, in any form `--key`
+/// takes, or null on failure.
+///
+/// # Safety
+///
+/// `private_key` must point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_public_key(
+    private_key: *const c_char,
+) -> *mut c_char {
+    catch(|| {
+        // SAFETY: as for this function.
+        let private_key = unsafe { arg(private_key, "private_key") }?;
+        string_out(key::public_key_ur_from_private_key(private_key)?)
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// The `.onion` address of a public key UR, or null on failure.
+///
+/// # Safety
+///
+/// `public_key` must point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_onion_address(
+    public_key: *const c_char,
+) -> *mut c_char {
+    catch(|| {
+        // SAFETY: as for this function.
+        let public_key = unsafe { arg(public_key, "public_key") }?;
+        string_out(key::parse_public_key_to_onion_host(public_key)?)
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// Fetch `url` over Tor and store its body in `*body` and `*body_len`,
+/// to be freed with [`garner_bytes_free`].  The first call bootstraps
+/// Tor.  Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `url` must point to a NUL-terminated string, and `body` and
+/// `body_len` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_fetch(
+    url: *const c_char,
+    body: *mut *mut u8,
+    body_len: *mut usize,
+) -> c_int {
+    let fetched = catch(|| {
+        // SAFETY: as for this function.
+        let url = unsafe { arg(url, "url") }?;
+        if body.is_null() || body_len.is_null() {
+            return Err(anyhow!("body and body_len must not be null"));
+        }
+        let response = runtime()?.block_on(async {
+            shared_tor().await?.fetcher.fetch(url, &[]).await
+        })?;
+        Ok(response.body.into_boxed_slice())
+    });
+    let Some(data) = fetched else {
+        return -1;
+    };
+    // SAFETY: as for this function.
+    unsafe {
+        *body_len = data.len();
+        *body = Box::into_raw(data).cast();
+    }
+    0
+}
+
+/// Serve `docroot` as an onion service with default settings, under the
+/// private key `key`, or a new address if it is null.  `nickname` names
+/// the service, and defaults to `garner` if null.  Returns once the
+/// service is reachable, or null on failure.  The first call bootstraps
+/// Tor.
+///
+/// # Safety
+///
+/// `docroot` must point to a NUL-terminated string, and `key` and
+/// `nickname` must be null or do so.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_serve(
+    docroot: *const c_char,
+    key: *const c_char,
+    nickname: *const c_char,
+) -> *mut GarnerService {
+    catch(|| {
+        // SAFETY: as for this function.
+        let (docroot, key, nickname) = unsafe {
+            (
+                arg(docroot, "docroot")?,
+                optional_arg(key, "key")?,
+                optional_arg(nickname, "nickname")?.unwrap_or("garner"),
+            )
+        };
+        let service = runtime()?.block_on(async {
+            let tor = shared_tor().await?;
+            server::launch(&tor.client, docroot, key, nickname).await
+        })?;
+        Ok(Box::into_raw(Box::new(GarnerService { service })))
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// The `.onion` address of a service, to be freed with
+/// [`garner_string_free`].
+///
+/// # Safety
+///
+/// `service` must be a live service returned by [`garner_serve`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_service_address(
+    service: *const GarnerService,
+) -> *mut c_char {
+    // SAFETY: as for this function.
+    let service = unsafe { &*service };
+    catch(|| string_out(service.service.onion_host.clone()))
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Stop a service and free it.
+///
+/// # Safety
+///
+/// `service` must be null or a service returned by [`garner_serve`] and
+/// not yet stopped.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn garner_service_stop(service: *mut GarnerService) {
+    if service.is_null() {
+        return;
+    }
+    // The service's tasks belong to the runtime, which must be current
+    // while they are stopped.
+    let _guard = RUNTIME.get().map(|runtime| runtime.enter());
+    // SAFETY: the service came from `Box::into_raw`.
+    drop(unsafe { Box::from_raw(service) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Take a string garner returned, freeing it.
+    fn take(value: *mut c_char) -> String {
+        assert!(!value.is_null(), "{}", last_error());
+        let string = unsafe { CStr::from_ptr(value) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        unsafe { garner_string_free(value) };
+        string
+    }
+
+    fn last_error() -> String {
+        let error = garner_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_key_conversion() {
+        init();
+        let (private, public) = key::generate_keypair().unwrap();
+        let private = CString::new(private).unwrap();
+        assert_eq!(
+            take(unsafe { garner_public_key(private.as_ptr()) }),
+            public
+        );
+
+        let public = CString::new(public).unwrap();
+        let address = take(unsafe { garner_onion_address(public.as_ptr()) });
+        assert_eq!(
+            address,
+            key::parse_public_key_to_onion_host(public.to_str().unwrap())
+                .unwrap()
+        );
+        assert!(address.ends_with(".onion"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let bad = CString::new("ur:nonsense").unwrap();
+        assert!(unsafe { garner_public_key(bad.as_ptr()) }.is_null());
+        assert!(!last_error().is_empty());
+        assert!(unsafe { garner_onion_address(std::ptr::null()) }.is_null());
+        assert_eq!(last_error(), "public_key is null");
+
+        let mut body = std::ptr::null_mut();
+        let url = CString::new("http://x.onion/").unwrap();
+        let status = unsafe {
+            garner_fetch(url.as_ptr(), &mut body, std::ptr::null_mut())
+        };
+        assert_eq!(status, -1);
+        assert_eq!(last_error(), "body and body_len must not be null");
+        assert!(
+            unsafe {
+                garner_serve(
+                    std::ptr::null(),
+                    std::ptr::null(),
+                    std::ptr::null(),
+                )
+            }
+            .is_null()
+        );
+        assert_eq!(last_error(), "docroot is null");
+        unsafe {
+            garner_string_free(std::ptr::null_mut());
+            garner_bytes_free(body, 0);
+            garner_service_stop(std::ptr::null_mut());
+        }
+    }
+}
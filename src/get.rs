@@ -1,193 +1,1467 @@
-use std::time::Duration;
+mod batch;
+mod connector;
+mod cookies;
+mod http;
+mod output;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use anyhow::{Context, Result, anyhow};
 use arti_client::TorClient;
-use futures_util::io::{AsyncReadExt, AsyncWriteExt};
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::{Args, ValueEnum};
+use tor_rtcompat::PreferredRuntime;
 
-use crate::ui;
+use self::{
+    batch::{Batch, OnChange, follow, is_transient, parse_interval, watch},
+    connector::{
+        CONNECT_TIMEOUT, Connection, Connector, Pool, READ_TIMEOUT, Timeouts,
+        connect_tor, tor_socks_conflict,
+    },
+    http::{
+        BodyChecks, Outgoing, Response, fetch_url, request_method,
+        trace_to_stderr,
+    },
+    output::{Output, Progress, plan_outputs, url_list},
+};
+pub use self::{connector::parse_rate, output::mirror_path};
+use crate::{dictionary, envelope, tls, tor, ui};
 
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+/// Options for `garner get`.
+#[derive(Args)]
+pub struct GetArgs {
+    /// URL(s) or path(s) to fetch (paths when --key or --address is given)
+    #[arg(required_unless_present = "input_file")]
+    pub urls: Vec<String>,
+    /// Also fetch the URLs or paths listed in FILE, or on stdin for "-",
+    /// one per line; blank lines and lines starting with # are skipped
+    #[arg(long, value_name = "FILE")]
+    pub input_file: Option<std::path::PathBuf>,
+    /// Ed25519 public key in UR format to derive the .onion address, or
+    /// "-" to read it from stdin
+    #[arg(long, env = "GARNER_KEY")]
+    pub key: Option<String>,
+    /// Read the public key from FILE, or from stdin for "-", instead of
+    /// --key or GARNER_KEY
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<std::path::PathBuf>,
+    /// The .onion address to connect to (e.g. xxxx.onion); repeat it, or
+    /// give a comma-separated list, for mirrors to fall back to in order
+    #[arg(long, env = "GARNER_ADDRESS", value_delimiter = ',')]
+    pub address: Vec<String>,
+    /// Connect through a running Tor daemon's SOCKS port (e.g.
+    /// 127.0.0.1:9050) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_SOCKS")]
+    pub tor_socks: Option<String>,
+    /// Bootstrap a Tor client of its own even when a garner daemon is
+    /// running
+    #[arg(long)]
+    pub no_daemon: bool,
+    /// Also fetch hosts that are not onion services, and https:// URLs,
+    /// through Tor exit relays
+    #[arg(long)]
+    pub allow_clearnet: bool,
+    /// Onion service port for URLs that do not give one [default: 80]
+    #[arg(
+        long,
+        value_name = "PORT",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub port: Option<u16>,
+    /// X25519 key (ur:agreement-private-key, or a ur:crypto-prvkeys
+    /// bundle) that authorizes this client to an onion service in
+    /// restricted discovery mode
+    #[arg(
+        long,
+        value_name = "UR",
+        env = "GARNER_CLIENT_AUTH_KEY",
+        conflicts_with = "tor_socks"
+    )]
+    pub client_auth_key: Option<String>,
+    /// Fail unless every response carries a valid X-Garner-Signature
+    /// (signatures that are present are always verified)
+    #[arg(long, visible_alias = "verify")]
+    pub require_signature: bool,
+    /// Ask for each file as a signed Gordian Envelope, verify it against
+    /// the service's key and its digest, and write the file it holds
+    #[arg(long, conflicts_with_all = ["follow", "head", "resume"])]
+    pub envelope: bool,
+    /// Extra request header as "Name: value" (repeatable)
+    #[arg(long = "header", short = 'H', value_name = "HEADER")]
+    pub headers: Vec<String>,
+    /// Send the cookies kept in FILE, a Netscape cookie file as curl
+    /// writes, and save those that responses set back to it
+    #[arg(long, value_name = "FILE")]
+    pub cookie_jar: Option<std::path::PathBuf>,
+    /// Send a cookie as "NAME=VALUE", or several separated by ";", with
+    /// every request (repeatable)
+    #[arg(long = "cookie", value_name = "COOKIE")]
+    pub cookies: Vec<String>,
+    /// Reuse fresh responses from the local HTTP cache, and store
+    /// cacheable ones
+    #[arg(long)]
+    pub cache: bool,
+    /// Use the local HTTP cache, keeping each response fresh for SECONDS
+    /// whatever its Cache-Control says (unless it forbids storing it)
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub cache_ttl: Option<u64>,
+    /// Neither use nor fill the local HTTP cache, overriding --cache and
+    /// --cache-ttl given earlier, such as in a configuration file
+    #[arg(long, overrides_with_all = ["cache", "cache_ttl"])]
+    pub no_cache: bool,
+    /// Ask for each URL only if it changed since the last run that
+    /// fetched it, by its ETag or Last-Modified, and write nothing for
+    /// one that did not; exit with status 7 when none changed
+    #[arg(
+        long,
+        conflicts_with_all = ["follow", "watch", "resume", "json"]
+    )]
+    pub if_changed: bool,
+    /// Ask for responses compressed with this Zstandard dictionary, as
+    /// published by the server at /.zstd-dictionary
+    #[arg(long, value_name = "FILE")]
+    pub zstd_dictionary: Option<std::path::PathBuf>,
+    /// Ask for gzip or Brotli compressed responses, and decompress them
+    #[arg(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub compressed: bool,
+    /// Keep polling the URL for data appended to it, writing each new
+    /// part to stdout as it arrives, like `tail -f`
+    #[arg(long, conflicts_with_all = ["cache", "cache_ttl"])]
+    pub follow: bool,
+    /// Seconds between polls with --follow
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        requires = "follow",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub interval: u64,
+    /// Fetch the URL again every INTERVAL (such as 30s, 5m, or 1h), and
+    /// write its body again each time it changes
+    #[arg(
+        long,
+        value_name = "INTERVAL",
+        conflicts_with_all = [
+            "follow", "cache", "cache_ttl", "head", "include", "json",
+            "dump_header", "remote_name", "output_dir", "resume",
+            "checksum_file", "max_time",
+        ]
+    )]
+    pub watch: Option<String>,
+    /// With --watch, run CMD with the shell on each change instead of
+    /// writing the body, with the new body on its stdin and the URL and
+    /// the body's SHA-256 in GARNER_URL and GARNER_SHA256
+    #[arg(
+        long,
+        value_name = "CMD",
+        requires = "watch",
+        conflicts_with = "output"
+    )]
+    pub exec: Option<String>,
+    /// Send HEAD and print each response's status line and headers
+    /// instead of its body
+    #[arg(
+        long,
+        short = 'I',
+        conflicts_with_all = ["method", "data", "data_file", "follow", "checksum_file"]
+    )]
+    pub head: bool,
+    /// Write each response's status line and headers before its body
+    #[arg(
+        long,
+        short = 'i',
+        conflicts_with_all = ["cache", "cache_ttl", "head"]
+    )]
+    pub include: bool,
+    /// Print one JSON object per URL, with its final URL, status, headers,
+    /// timings, size, SHA-256, and body, instead of the bodies themselves
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "follow", "cache", "cache_ttl", "include", "output",
+            "remote_name", "output_dir", "resume", "checksum_file",
+            "fail_with_body", "format",
+        ]
+    )]
+    pub json: bool,
+    /// How to write the bodies of several URLs
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = Format::Concat
+    )]
+    pub format: Format,
+    /// Trace each request and response head, and the steps of each
+    /// connection to an onion service, on stderr
+    #[arg(long, short = 'v')]
+    pub verbose: bool,
+    /// Write each response's status line and headers to FILE, in the
+    /// order of the URLs
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["cache", "cache_ttl", "follow"]
+    )]
+    pub dump_header: Option<std::path::PathBuf>,
+    /// Request method [default: GET, or POST with --data or --data-file]
+    #[arg(long, short = 'X', value_name = "METHOD")]
+    pub method: Option<String>,
+    /// Send DATA as the request body
+    #[arg(
+        long,
+        short = 'd',
+        value_name = "DATA",
+        conflicts_with_all = ["data_file", "follow"]
+    )]
+    pub data: Option<String>,
+    /// Send the contents of FILE, or of stdin for "-", as the request body
+    #[arg(long, value_name = "FILE", conflicts_with = "follow")]
+    pub data_file: Option<std::path::PathBuf>,
+    /// Write the body of the next URL to FILE instead of stdout, or to
+    /// stdout with "-" even if it is binary (repeatable, one per URL)
+    #[arg(long, short = 'o', value_name = "FILE", conflicts_with = "follow")]
+    pub output: Vec<std::path::PathBuf>,
+    /// Write each body without an --output to a file in the current
+    /// directory named after the last segment of its URL path
+    #[arg(long, short = 'O', conflicts_with = "follow")]
+    pub remote_name: bool,
+    /// Write each body to DIR/<PATH>, creating directories as needed, so
+    /// the fetched paths reproduce the site's layout
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["follow", "output", "remote_name"]
+    )]
+    pub output_dir: Option<std::path::PathBuf>,
+    /// Resume output files already partly downloaded, asking the server
+    /// only for the rest, and keep what arrives of a body that breaks off
+    #[arg(
+        long = "continue",
+        conflicts_with_all = [
+            "follow", "cache", "cache_ttl", "head", "include", "method",
+            "data", "data_file", "zstd_dictionary",
+        ]
+    )]
+    pub resume: bool,
+    /// Verify each downloaded file against this sha256sum output or JSON
+    /// manifest (as served at /.manifest.json), hashing while other
+    /// downloads continue, and print a verification table
+    #[arg(long, value_name = "FILE", conflicts_with = "follow")]
+    pub checksum_file: Option<std::path::PathBuf>,
+    /// Print a "DIGEST  URL" line with the SHA-256 of each body fetched,
+    /// to stderr or, with --sha256=FILE, to FILE
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["follow", "watch", "head", "include", "json"]
+    )]
+    pub sha256: Option<Option<std::path::PathBuf>>,
+    /// Print a "DIGEST  URL" line with the BLAKE3 hash of each body
+    /// fetched, to stderr or, with --blake3=FILE, to FILE
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["follow", "watch", "head", "include", "json"]
+    )]
+    pub blake3: Option<Option<std::path::PathBuf>>,
+    /// Write the body of an HTTP error response as if it had succeeded,
+    /// and still fail
+    #[arg(long, conflicts_with = "follow")]
+    pub fail_with_body: bool,
+    /// Retry every URL even when its host was unreachable, or the path
+    /// missing, earlier in this run
+    #[arg(long)]
+    pub no_negative_cache: bool,
+    /// Retry a URL up to N times when the service cannot be reached or the
+    /// connection fails, waiting longer before each attempt (HTTP errors
+    /// are not retried)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+    /// Follow at most N redirects from one URL to another on an onion
+    /// service (0 to follow none)
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub max_redirects: u32,
+    /// Seconds to wait for a stream to the onion service to open
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = CONNECT_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub connect_timeout: u64,
+    /// Seconds a response may go without sending any data
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = READ_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub read_timeout: u64,
+    /// Seconds to spend on each URL in all, retries and redirects included
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "follow",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_time: Option<u64>,
+    /// Maximum number of streams open to one host at a time
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_per_host: u32,
+    /// Maximum number of URLs fetched at once across all hosts [default:
+    /// no limit beyond --max-per-host]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub jobs: Option<u32>,
+    /// Which requests may share a Tor circuit, trading speed for
+    /// unlinkability
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = CircuitPolicy::Shared
+    )]
+    pub circuit_policy: CircuitPolicy,
+    /// Give each URL its own circuit, as --circuit-policy per-url, and
+    /// never share one with another garner run on the same Tor daemon
+    #[arg(long, conflicts_with = "circuit_policy")]
+    pub isolate: bool,
+    /// Read response bodies at most RATE bytes per second in all, with an
+    /// optional K, M, or G suffix (e.g. 200k)
+    #[arg(long, value_name = "RATE")]
+    pub limit_rate: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
 
-pub async fn run(
-    urls: &[String],
-    key: Option<&str>,
-    address: Option<&str>,
-) -> Result<()> {
-    let interactive = ui::is_interactive();
-
-    // Set up spinner (interactive only)
-    let bar = if interactive {
-        let bar = ProgressBar::new_spinner();
-        bar.set_prefix(" 0s");
-        bar.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.yellow} {prefix} Connecting to the Tor network...")
-                .expect("valid template"),
-        );
-        bar.enable_steady_tick(Duration::from_millis(100));
-        Some(bar)
-    } else {
-        None
-    };
+/// How `get` writes the bodies of several URLs.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One after another on stdout, separated by newlines
+    Concat,
+    /// A JSON array of the records --json prints
+    Json,
+    /// A MIME multipart/mixed stream on stdout, with a part per URL
+    Multipart,
+    /// Each to a file named after its URL, as with -O
+    Files,
+}
+
+/// Which streams may share a Tor circuit.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CircuitPolicy {
+    /// Any requests in this run
+    Shared,
+    /// Only requests to the same host, so hosts cannot be linked by
+    /// circuit
+    PerHost,
+    /// Only requests for the same URL, such as its retries
+    PerUrl,
+    /// None: each request gets its own circuit (slowest)
+    PerRequest,
+}
+
+impl CircuitPolicy {
+    /// The isolation group for a new stream to `host` for `url`.  Streams
+    /// with the same key may share a circuit; `None` may share with any.
+    fn isolation_key(self, host: &str, url: &str) -> Option<String> {
+        use sha2::Digest as _;
+
+        static NEXT_STREAM: AtomicU64 = AtomicU64::new(0);
+        match self {
+            CircuitPolicy::Shared => None,
+            CircuitPolicy::PerHost => Some(host.to_string()),
+            // Hashed, as URLs may be too long for SOCKS credentials.
+            CircuitPolicy::PerUrl => Some(format!(
+                "url-{}",
+                hex::encode(&sha2::Sha256::digest(url)[..8])
+            )),
+            CircuitPolicy::PerRequest => Some(format!(
+                "stream-{}",
+                NEXT_STREAM.fetch_add(1, Ordering::Relaxed)
+            )),
+        }
+    }
+}
+
+pub async fn run(args: GetArgs) -> Result<()> {
+    // Progress bars would garble the trace.
+    if args.verbose {
+        trace_to_stderr();
+    }
+    let interactive = ui::is_interactive() && !args.verbose;
+
+    // Set up spinner and download bars (interactive only)
+    let progress = interactive.then(Progress::new);
+    let bar = progress.as_ref().map(|progress| progress.spinner.clone());
 
     let updater = bar.as_ref().map(ui::spawn_elapsed_updater);
+    // Clean up the spinner *before* writing to stdout so finish_and_clear
+    // doesn't erase the output line.
+    let stop_spinner = || {
+        if let Some(ref h) = updater {
+            h.abort();
+        }
+        if let Some(ref bar) = bar {
+            bar.finish_and_clear();
+        }
+    };
 
-    // Resolve the .onion host when --key or --address is provided.
-    let onion_host: Option<String> = if let Some(key_ur) = key {
-        Some(crate::key::parse_public_key_to_onion_host(key_ur)?)
-    } else if let Some(addr) = address {
-        let host = addr.strip_prefix("http://").unwrap_or(addr);
-        let host = host.strip_suffix('/').unwrap_or(host);
-        Some(host.to_string())
-    } else {
-        None
+    let plan = Plan::new(&args)?;
+    let client_auth = plan.client_auth(&args)?;
+    if args.follow {
+        let [url] = plan.urls.as_slice() else {
+            return Err(anyhow!("--follow takes a single URL"));
+        };
+        let pool = stream_pool(&args)?;
+        let connector = connect(&args, &client_auth).await?;
+        stop_spinner();
+        return follow(
+            &connector,
+            &pool,
+            url,
+            &plan.headers,
+            &args,
+            plan.zstd_dictionary.as_deref(),
+        )
+        .await;
+    }
+    if let Some(interval) = &args.watch {
+        let interval = parse_interval(interval)?;
+        let [url] = plan.urls.as_slice() else {
+            return Err(anyhow!("--watch takes a single URL"));
+        };
+        let on_change = match &args.exec {
+            Some(command) => OnChange::Exec(command),
+            None => OnChange::Write(&plan.outputs[0]),
+        };
+        let pool = stream_pool(&args)?;
+        let connector = connect(&args, &client_auth).await?;
+        stop_spinner();
+        return watch(
+            &connector,
+            &pool,
+            url,
+            &plan.headers,
+            plan.checks(&args),
+            on_change,
+            interval,
+        )
+        .await;
+    }
+
+    let batch = Batch::new(&args, &plan, &client_auth, progress.as_ref())?;
+    let fetches = plan
+        .urls
+        .iter()
+        .zip(&plan.outputs)
+        .map(|(url, output)| batch.fetch_limited(url, output));
+    let results = futures_util::future::try_join_all(fetches).await?;
+    stop_spinner();
+    batch.finish(results)
+}
+
+/// What `get` sends and where each response goes, worked out from its
+/// options before anything is fetched.
+struct Plan {
+    /// The service's host followed by the mirrors to fall back to, when
+    /// `--key` or `--address` names them.
+    mirrors: Vec<String>,
+    /// The URLs to fetch, each with its output.
+    urls: Vec<String>,
+    outputs: Vec<Output>,
+    /// Whether every response goes into one document on stdout.
+    collated: bool,
+    /// Whether each response is written as a JSON record.
+    json: bool,
+    /// The boundary between parts, for `--format multipart`.
+    boundary: Option<String>,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    zstd_dictionary: Option<Vec<u8>>,
+    /// Whether gzip and Brotli bodies are asked for, and decompressed.
+    decompress: bool,
+}
+
+impl Plan {
+    fn new(args: &GetArgs) -> Result<Self> {
+        let mirrors = service_hosts(args)?;
+        let urls = target_urls(args, mirrors.first())?;
+
+        if args.tor_socks.is_some() && args.tor.uses_arti_options() {
+            return Err(tor_socks_conflict());
+        }
+        let outputs = match &args.output_dir {
+            Some(dir) => urls
+                .iter()
+                .map(|url| Ok(Output::File(mirror_path(dir, url)?)))
+                .collect::<Result<Vec<_>>>()?,
+            None => plan_outputs(
+                &args.output,
+                args.remote_name || args.format == Format::Files,
+                &urls,
+            )?,
+        };
+        // Formats that put every response in one document on stdout.
+        let collated = matches!(args.format, Format::Json | Format::Multipart);
+        if collated
+            && (!args.output.is_empty()
+                || args.remote_name
+                || args.output_dir.is_some()
+                || args.include
+                || args.resume
+                || args.fail_with_body
+                || args.sha256.is_some()
+                || args.blake3.is_some()
+                || args.follow
+                || args.watch.is_some())
+        {
+            return Err(anyhow!(
+                "--format {} writes one document to stdout; it cannot be \
+                 used with --output, -O, --output-dir, --include, \
+                 --continue, --fail-with-body, --sha256, --blake3, \
+                 --follow, or --watch",
+                args.format.to_possible_value().expect("value").get_name()
+            ));
+        }
+        let json = args.json || args.format == Format::Json;
+        let boundary = (args.format == Format::Multipart).then(|| {
+            format!("garner-{}", hex::encode(bc_rand::random_data(12)))
+        });
+        if args.resume && outputs.iter().any(|o| !matches!(o, Output::File(_)))
+        {
+            return Err(anyhow!(
+                "--continue resumes files; use --output FILE, -O, or \
+                 --output-dir"
+            ));
+        }
+        let mut headers = args
+            .headers
+            .iter()
+            .map(|h| parse_header(h))
+            .collect::<Result<Vec<_>>>()?;
+        let zstd_dictionary = match &args.zstd_dictionary {
+            Some(path) => {
+                let data = std::fs::read(path).with_context(|| {
+                    format!("reading dictionary {}", path.display())
+                })?;
+                headers.push((
+                    dictionary::DIGEST_HEADER.to_string(),
+                    dictionary::digest(&data),
+                ));
+                Some(data)
+            }
+            None => None,
+        };
+        // Ask for compressed bodies, unless -H says what to accept, or the
+        // bytes are wanted as the server stores them to pick up partway.
+        let decompress = args.compressed && !args.resume && !args.follow;
+        let mut codings = Vec::new();
+        if zstd_dictionary.is_some() {
+            codings.push(dictionary::CODING);
+        }
+        if decompress {
+            codings.extend(["gzip", "br"]);
+        }
+        if !codings.is_empty()
+            && !headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Accept-Encoding"))
+        {
+            headers.push(("Accept-Encoding".to_string(), codings.join(", ")));
+        }
+        if args.envelope
+            && !headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Accept"))
+        {
+            headers
+                .push(("Accept".to_string(), envelope::MEDIA_TYPE.to_string()));
+        }
+        let body = request_body(args)?;
+        let method = match args.head {
+            true => "HEAD".to_string(),
+            false => request_method(args.method.as_deref(), body.is_some())?,
+        };
+        if method != "GET" && args.follow {
+            return Err(anyhow!("--follow only polls with GET"));
+        }
+        if method != "GET" && args.watch.is_some() {
+            return Err(anyhow!("--watch only polls with GET"));
+        }
+        Ok(Self {
+            mirrors,
+            urls,
+            outputs,
+            collated,
+            json,
+            boundary,
+            method,
+            headers,
+            body,
+            zstd_dictionary,
+            decompress,
+        })
+    }
+
+    fn request(&self) -> Outgoing<'_> {
+        Outgoing {
+            method: &self.method,
+            headers: &self.headers,
+            body: self.body.as_deref(),
+        }
+    }
+
+    fn checks(&self, args: &GetArgs) -> BodyChecks<'_> {
+        BodyChecks {
+            require_signature: args.require_signature,
+            zstd_dictionary: self.zstd_dictionary.as_deref(),
+            decompress: self.decompress,
+            envelope: args.envelope,
+        }
+    }
+
+    /// The `--client-auth-key` for each onion service fetched from.
+    fn client_auth(&self, args: &GetArgs) -> Result<Vec<(&str, [u8; 32])>> {
+        let Some(ur) = &args.client_auth_key else {
+            return Ok(Vec::new());
+        };
+        let secret = crate::key::parse_client_auth_key(ur)?;
+        let mut hosts = Vec::new();
+        for host in self
+            .urls
+            .iter()
+            .map(|url| split_url(url).0)
+            .chain(self.mirrors.iter().map(String::as_str))
+        {
+            let (name, _) = host_port(host, crate::server::HTTP_PORT)?;
+            if name.ends_with(".onion") {
+                hosts.push(name);
+            }
+        }
+        hosts.sort_unstable();
+        hosts.dedup();
+        Ok(hosts.into_iter().map(|host| (host, secret)).collect())
+    }
+}
+
+/// The `.onion` host that `--key` or `--address` names, followed by any
+/// mirrors to fall back to, with `--port` applied.
+fn service_hosts(args: &GetArgs) -> Result<Vec<String>> {
+    let stdin = |file: &Option<std::path::PathBuf>| {
+        file.as_ref().is_some_and(|path| path.as_os_str() == "-")
     };
+    if crate::key::reads_stdin(args.key.as_deref(), args.key_file.as_deref())
+        && (stdin(&args.input_file) || stdin(&args.data_file))
+    {
+        return Err(anyhow!(
+            "the key cannot be read from stdin along with --input-file or \
+             --data-file"
+        ));
+    }
+    let public_key =
+        crate::key::read_key_arg(args.key.clone(), args.key_file.as_deref())?;
+    let mut mirrors = Vec::new();
+    if public_key.is_some() {
+        mirrors.extend(onion_host(public_key.as_deref(), None)?);
+    } else {
+        for address in &args.address {
+            mirrors.extend(onion_host(None, Some(address))?);
+        }
+    }
+    if let Some(port) = args.port {
+        for mirror in &mut mirrors {
+            *mirror = split_url(&with_port(mirror, port)).0.to_string();
+        }
+    }
+    Ok(mirrors)
+}
+
+/// The URLs given as arguments and in `--input-file`, as full URLs: paths
+/// are taken to be on `onion_host`, when there is one.
+fn target_urls(
+    args: &GetArgs,
+    onion_host: Option<&String>,
+) -> Result<Vec<String>> {
+    let mut urls = args.urls.clone();
+    if let Some(path) = &args.input_file {
+        let stdin = path.as_os_str() == "-";
+        if stdin
+            && args
+                .data_file
+                .as_ref()
+                .is_some_and(|data| data.as_os_str() == "-")
+        {
+            return Err(anyhow!(
+                "--input-file and --data-file cannot both read stdin"
+            ));
+        }
+        let text = if stdin {
+            std::io::read_to_string(std::io::stdin())
+                .context("reading URLs from stdin")?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?
+        };
+        urls.extend(url_list(&text));
+    }
+    if urls.is_empty() {
+        return Err(anyhow!("no URLs to fetch"));
+    }
 
     // Build full URLs from paths (when host is known) or use as-is.
-    let resolved: Vec<String> = urls
-        .iter()
+    Ok(urls
+        .into_iter()
         .map(|u| {
-            if let Some(ref host) = onion_host {
+            if let Some(host) = onion_host {
                 let path = if u.starts_with('/') {
-                    u.clone()
+                    u
                 } else {
                     format!("/{u}")
                 };
                 format!("{host}{path}")
             } else {
-                u.clone()
+                u
             }
         })
-        .collect();
+        .map(|url| match args.port {
+            Some(port) => with_port(&url, port),
+            None => url,
+        })
+        .collect())
+}
 
-    // Bootstrap Tor once, then fetch each URL.
-    // Ephemeral state dir avoids lock contention with concurrent
-    // invocations.  Declared before `tor` so it drops (and is deleted)
-    // after the TorClient releases its locks.
-    let (state_dir, cache_dir) = crate::tor_dirs()?;
-    let mut builder = crate::tor_config(state_dir.path(), &cache_dir);
-    builder.stream_timeouts().connect_timeout(CONNECT_TIMEOUT);
-    let config = builder.build()?;
-    let tor = TorClient::create_bootstrapped(config).await?;
+/// The request body given with `--data` or `--data-file`.
+fn request_body(args: &GetArgs) -> Result<Option<Vec<u8>>> {
+    Ok(match (&args.data, &args.data_file) {
+        (Some(data), _) => Some(data.clone().into_bytes()),
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            use std::io::Read as _;
+            let mut data = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .context("reading request body from stdin")?;
+            Some(data)
+        }
+        (None, Some(path)) => Some(
+            std::fs::read(path)
+                .with_context(|| format!("reading {}", path.display()))?,
+        ),
+        (None, None) => None,
+    })
+}
 
-    let mut bodies: Vec<Vec<u8>> = Vec::with_capacity(resolved.len());
-    for url in &resolved {
-        bodies.push(fetch_url(&tor, url, bar.as_ref()).await?);
+fn timeouts(args: &GetArgs) -> Timeouts {
+    Timeouts {
+        connect: Duration::from_secs(args.connect_timeout),
+        read: Duration::from_secs(args.read_timeout),
     }
+}
 
-    // Clean up spinner *before* writing to stdout so finish_and_clear
-    // doesn't erase the output line.
-    if let Some(ref h) = updater {
-        h.abort();
+/// A pool of streams with the limits, timeouts, and cookies `args` ask
+/// for.
+fn stream_pool(args: &GetArgs) -> Result<Pool> {
+    let circuit_policy = match args.isolate {
+        true => CircuitPolicy::PerUrl,
+        false => args.circuit_policy,
+    };
+    let rate_limit = args.limit_rate.as_deref().map(parse_rate).transpose()?;
+    let mut jar = match &args.cookie_jar {
+        Some(path) => Some(cookies::Jar::load(path)?),
+        None if !args.cookies.is_empty() => Some(cookies::Jar::default()),
+        None => None,
+    };
+    if let Some(jar) = &mut jar {
+        for cookie in &args.cookies {
+            jar.give(cookie)?;
+        }
     }
-    if let Some(ref bar) = bar {
-        bar.finish_and_clear();
+    Ok(Pool::new(args.max_per_host, circuit_policy)
+        .with_run_isolation(args.isolate)
+        .with_timeouts(timeouts(args))
+        .with_clearnet(args.allow_clearnet)
+        .with_rate_limit(rate_limit)
+        .with_cookies(jar))
+}
+
+/// Connect to Tor the way `args` say: through the daemon or a Tor
+/// daemon's SOCKS port, or by bootstrapping a client of our own.
+async fn connect(
+    args: &GetArgs,
+    client_auth: &[(&str, [u8; 32])],
+) -> Result<Connector> {
+    let daemon = (!args.no_daemon).then(crate::daemon::socket_path);
+    connect_tor(
+        args.tor_socks.as_deref(),
+        daemon.as_deref(),
+        &args.tor,
+        timeouts(args).connect,
+        client_auth,
+    )
+    .await
+}
+
+/// A URL not fetched because an earlier one failed in a way it would too,
+/// and the exit status of that failure.
+#[derive(Debug)]
+struct Skipped {
+    reason: String,
+    status: i32,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "skipped: {}", self.reason)
     }
+}
 
-    use std::io::Write;
-    let stdout = std::io::stdout();
-    let mut out = stdout.lock();
-    for (i, body) in bodies.iter().enumerate() {
-        if i > 0 {
-            out.write_all(b"\n")?;
-        }
-        out.write_all(body)?;
+impl std::error::Error for Skipped {}
+
+/// Exit status when an onion service could not be reached, or the
+/// connection to it failed.
+pub const EXIT_UNREACHABLE: i32 = 3;
+/// Exit status when a server answered with a 4xx error.
+pub const EXIT_CLIENT_ERROR: i32 = 4;
+/// Exit status when a server answered with a 5xx error.
+pub const EXIT_SERVER_ERROR: i32 = 5;
+/// Exit status when a response's signature did not match the service's
+/// key, or a signature was required and missing.
+pub const EXIT_BAD_SIGNATURE: i32 = 6;
+/// Exit status with `--if-changed` when no URL changed.
+pub const EXIT_UNCHANGED: i32 = 7;
+
+/// With `--if-changed`, no URL changed since the last run: not a failure
+/// to report, but an exit status for scripts.
+#[derive(Debug)]
+pub struct Unchanged;
+
+impl std::fmt::Display for Unchanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not modified since the last fetch")
+    }
+}
+
+impl std::error::Error for Unchanged {}
+
+/// URLs that failed, and the exit status `garner get` reports for them.
+#[derive(Debug)]
+pub struct Failed {
+    pub status: i32,
+    error: anyhow::Error,
+}
+
+impl std::fmt::Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for Failed {}
+
+impl Failed {
+    pub fn new(status: i32, error: anyhow::Error) -> Self {
+        Self { status, error }
+    }
+}
+
+/// The exit status for `failures`: the one for their kind if they all
+/// failed alike, and 1 otherwise.
+fn failed_status(failures: &[anyhow::Error]) -> i32 {
+    let mut statuses = failures.iter().map(exit_status);
+    let first = statuses.next().unwrap_or(1);
+    if statuses.all(|status| status == first) {
+        first
+    } else {
+        1
+    }
+}
+
+/// The exit status for a failure to fetch a URL.
+fn exit_status(error: &anyhow::Error) -> i32 {
+    if let Some(skipped) = error.downcast_ref::<Skipped>() {
+        return skipped.status;
+    }
+    if error.downcast_ref::<BadSignature>().is_some() {
+        return EXIT_BAD_SIGNATURE;
+    }
+    match error.downcast_ref::<HttpStatus>() {
+        Some(HttpStatus(400..500, ..)) => EXIT_CLIENT_ERROR,
+        Some(HttpStatus(500..600, ..)) => EXIT_SERVER_ERROR,
+        Some(_) => 1,
+        None if is_transient(error) => EXIT_UNREACHABLE,
+        None => 1,
+    }
+}
+
+/// The status of the response that a fetch failed on, if the server sent
+/// one.
+pub fn response_status(error: &anyhow::Error) -> Option<u16> {
+    if let Some(HttpStatus(status, ..)) = error.downcast_ref::<HttpStatus>() {
+        return Some(*status);
+    }
+    error
+        .downcast_ref::<Redirect>()
+        .map(|redirect| redirect.status)
+}
+
+/// Error context marking a failure to reach an onion service at all.
+#[derive(Debug)]
+struct Unreachable;
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("connecting to onion service")
     }
+}
 
-    Ok(())
+/// Error context for a body that broke off partway, with the bytes that
+/// did arrive and the position in the file of the first of them.
+#[derive(Debug)]
+struct Truncated {
+    start: u64,
+    received: Vec<u8>,
 }
 
-/// Connect to an onion service and fetch a single URL, reusing an
-/// already-bootstrapped Tor client.
-async fn fetch_url<R: tor_rtcompat::Runtime>(
-    tor: &TorClient<R>,
-    url: &str,
-    bar: Option<&ProgressBar>,
-) -> Result<Vec<u8>> {
-    // Parse the URL to extract host and path
-    let url = url.strip_prefix("http://").unwrap_or(url);
-    let (host, path) = match url.find('/') {
+impl std::fmt::Display for Truncated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "reading response (broke off after {} bytes)",
+            self.received.len()
+        )
+    }
+}
+
+/// Error context marking a response whose signature is missing or does
+/// not match the service's key.
+#[derive(Debug)]
+struct BadSignature;
+
+impl std::fmt::Display for BadSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("verifying response signature")
+    }
+}
+
+/// A response with a status other than 2xx, its status line, and its body.
+#[derive(Debug)]
+struct HttpStatus(u16, String, Vec<u8>);
+
+impl std::fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server returned HTTP {}: {}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// A redirect response, and where it points.
+#[derive(Debug)]
+pub struct Redirect {
+    pub status: u16,
+    pub location: String,
+}
+
+impl std::fmt::Display for Redirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server redirected to {} (HTTP {})",
+            self.location, self.status
+        )
+    }
+}
+
+impl std::error::Error for Redirect {}
+
+/// Split a URL (with or without `http://` or `https://`) into its host
+/// and path.
+fn split_url(url: &str) -> (&str, &str) {
+    let url = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))
+        .unwrap_or(url);
+    match url.find('/') {
         Some(i) => (&url[..i], &url[i..]),
         None => (url, "/"),
+    }
+}
+
+/// Whether `url` is fetched over TLS.
+fn is_https(url: &str) -> bool { url.starts_with("https://") }
+
+/// Split a URL's host into its name and port, which is `default_port`
+/// unless the host gives one.
+pub fn host_port(host: &str, default_port: u16) -> Result<(&str, u16)> {
+    match host.rsplit_once(':') {
+        None => Ok((host, default_port)),
+        Some((name, port)) => match port.parse() {
+            Ok(port) if port > 0 => Ok((name, port)),
+            _ => Err(anyhow!("invalid port in {host}")),
+        },
+    }
+}
+
+/// `url` with `port` on its host, unless the host already gives one.
+fn with_port(url: &str, port: u16) -> String {
+    let (host, path) = split_url(url);
+    let (scheme, default_port) = match is_https(url) {
+        true => ("https://", tls::PORT),
+        false if url.starts_with("http://") => {
+            ("http://", crate::server::HTTP_PORT)
+        }
+        false => ("", crate::server::HTTP_PORT),
     };
+    if host.contains(':') || port == default_port {
+        return url.to_string();
+    }
+    format!("{scheme}{host}:{port}{path}")
+}
 
-    if !host.ends_with(".onion") {
-        return Err(anyhow!("expected a .onion address, got: {host}"));
+/// Parse a `Name: value` request header.
+pub fn parse_header(header: &str) -> Result<(String, String)> {
+    let (name, value) = header
+        .split_once(':')
+        .filter(|(name, _)| {
+            !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .ok_or_else(|| {
+            anyhow!("invalid header {header:?}: expected NAME: VALUE")
+        })?;
+    if value.contains(['\r', '\n']) {
+        return Err(anyhow!(
+            "invalid header {header:?}: contains a line break"
+        ));
     }
+    Ok((name.to_string(), value.trim().to_string()))
+}
 
-    // Switch to connect phase
-    if let Some(bar) = bar {
-        bar.set_style(
-            ProgressStyle::default_spinner()
-                .template(&format!(
-                    "{{spinner:.cyan}} {{prefix}} Connecting to {}...",
-                    host
-                ))
-                .expect("valid template"),
-        );
+/// The .onion host named by `--key` or `--address`, if either is given.
+pub fn onion_host(
+    key: Option<&str>,
+    address: Option<&str>,
+) -> Result<Option<String>> {
+    if let Some(key_ur) = key {
+        Ok(Some(crate::key::parse_public_key_to_onion_host(key_ur)?))
+    } else if let Some(addr) = address {
+        let host = addr.strip_prefix("http://").unwrap_or(addr);
+        let host = host.strip_suffix('/').unwrap_or(host);
+        let (name, _) = host_port(host, crate::server::HTTP_PORT)?;
+        crate::key::check_onion_address(name)?;
+        Ok(Some(host.to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Fetches URLs for `garner api` and `garner sync`, keeping streams alive
+/// between calls.
+pub struct Fetcher {
+    connector: Connector,
+    pool: Pool,
+}
+
+impl Fetcher {
+    pub fn new(tor: TorClient<PreferredRuntime>) -> Self {
+        Self {
+            connector: Connector::Arti {
+                tor: Box::new(tor),
+                isolation: Mutex::new(HashMap::new()),
+                _state_dir: None,
+            },
+            pool: Pool::new(4, CircuitPolicy::Shared),
+        }
+    }
+
+    /// Connect through a running Tor daemon's SOCKS port, a running
+    /// `garner daemon`, or else a freshly bootstrapped embedded client
+    /// with its own state.
+    pub async fn connect(
+        tor_socks: Option<&str>,
+        tor_args: &tor::TorArgs,
+    ) -> Result<Self> {
+        if tor_socks.is_some() && tor_args.uses_arti_options() {
+            return Err(tor_socks_conflict());
+        }
+        let daemon = crate::daemon::socket_path();
+        Ok(Self {
+            connector: connect_tor(
+                tor_socks,
+                Some(&daemon),
+                tor_args,
+                CONNECT_TIMEOUT,
+                &[],
+            )
+            .await?,
+            pool: Pool::new(4, CircuitPolicy::Shared),
+        })
+    }
+
+    /// Read bodies at most `rate` bytes per second in all.
+    pub fn with_rate_limit(self, rate: Option<u64>) -> Self {
+        Self {
+            pool: self.pool.with_rate_limit(rate),
+            ..self
+        }
+    }
+
+    /// Open up to `max_per_host` streams to each host at a time, sharing
+    /// circuits as `circuit_policy` allows.
+    pub fn with_streams(
+        self,
+        max_per_host: u32,
+        circuit_policy: CircuitPolicy,
+    ) -> Self {
+        Self {
+            pool: self.pool.with_streams(max_per_host, circuit_policy),
+            ..self
+        }
+    }
+
+    /// Open a stream to `port` on `host`, for `garner proxy` to relay, on
+    /// a circuit shared only with streams that have the same `isolation`
+    /// key.
+    pub async fn open(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: Option<&str>,
+    ) -> Result<Box<dyn Connection>> {
+        self.connector.connect(host, port, isolation).await
+    }
+
+    /// Fetch `url`, verifying the response signature if there is one.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<Response> {
+        self.request("GET", url, request_headers).await
     }
 
-    let mut stream = tor
-        .connect((host, 80))
+    /// Send a `method` request without a body for `url`, as `fetch` does.
+    pub async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        request_headers: &[(String, String)],
+    ) -> Result<Response> {
+        let (host, _) = split_url(url);
+        let _permit = self.pool.acquire(host).await;
+        fetch_url(
+            &self.connector,
+            &self.pool,
+            url,
+            Outgoing {
+                method,
+                headers: request_headers,
+                body: None,
+            },
+            BodyChecks::default(),
+            None,
+            None,
+        )
         .await
-        .context("connecting to onion service")?;
-
-    // Send a minimal HTTP/1.1 GET request
-    let request = format!(
-        "GET {path} HTTP/1.1\r\n\
-         Host: {host}\r\n\
-         Connection: close\r\n\
-         \r\n"
-    );
-    stream
-        .write_all(request.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::AtomicUsize};
+
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::get::http::encode_request;
+
+    /// A request that reached a [`FakeTor`], with the host its stream was
+    /// opened to and the SOCKS password that isolated the stream.
+    #[derive(Debug, Clone)]
+    pub struct Sent {
+        pub host: String,
+        pub isolation: Option<String>,
+        pub head: String,
+    }
+
+    /// A stand-in for a Tor daemon's SOCKS port, for `--tor-socks`.  Each
+    /// request on a stream is answered with what `respond` makes of the
+    /// stream's host and the request head, closing the stream after a
+    /// response with `Connection: close`, and streams to the hosts in
+    /// `unreachable` fail as Tor fails them when it cannot find a
+    /// service's descriptor.
+    pub struct FakeTor {
+        /// The address of the SOCKS port.
+        pub proxy: String,
+        /// Every request answered, in order.
+        pub sent: Arc<Mutex<Vec<Sent>>>,
+        /// The most requests answered at once.
+        pub peak: Arc<AtomicUsize>,
+    }
+
+    type Respond = dyn Fn(&str, &str) -> Vec<u8> + Send + Sync;
+
+    impl FakeTor {
+        pub async fn start(
+            unreachable: &[&str],
+            respond: impl Fn(&str, &str) -> Vec<u8> + Send + Sync + 'static,
+        ) -> Self {
+            let listener =
+                tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tor = Self {
+                proxy: listener.local_addr().unwrap().to_string(),
+                sent: Arc::default(),
+                peak: Arc::default(),
+            };
+            let unreachable: Vec<String> =
+                unreachable.iter().map(|host| host.to_string()).collect();
+            let shared = Arc::new(Shared {
+                unreachable,
+                respond: Box::new(respond),
+                sent: Arc::clone(&tor.sent),
+                peak: Arc::clone(&tor.peak),
+                active: AtomicUsize::new(0),
+            });
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let shared = Arc::clone(&shared);
+                    tokio::spawn(async move {
+                        let _ = shared.serve(stream).await;
+                    });
+                }
+            });
+            tor
+        }
+
+        /// The hosts the requests went to, in order.
+        pub fn hosts(&self) -> Vec<String> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter().map(|sent| sent.host.clone()).collect()
+        }
+    }
+
+    /// What the streams of a [`FakeTor`] share.
+    struct Shared {
+        unreachable: Vec<String>,
+        respond: Box<Respond>,
+        sent: Arc<Mutex<Vec<Sent>>>,
+        peak: Arc<AtomicUsize>,
+        active: AtomicUsize,
+    }
+
+    impl Shared {
+        /// Open a stream as a SOCKS5 proxy does, then answer requests on
+        /// it until the client closes it.
+        async fn serve(&self, mut stream: TcpStream) -> std::io::Result<()> {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await?;
+            stream.write_all(&[5, greeting[2]]).await?;
+            let mut isolation = None;
+            if greeting[2] == 2 {
+                let mut user = vec![0u8; 2];
+                stream.read_exact(&mut user).await?;
+                user.resize(usize::from(user[1]), 0);
+                stream.read_exact(&mut user).await?;
+                let mut password =
+                    vec![0u8; usize::from(stream.read_u8().await?)];
+                stream.read_exact(&mut password).await?;
+                isolation = Some(String::from_utf8(password).unwrap());
+                stream.write_all(&[1, 0]).await?;
+            }
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).await?;
+            let mut host = vec![0u8; usize::from(request[4]) + 2];
+            stream.read_exact(&mut host).await?;
+            host.truncate(host.len() - 2);
+            let host = String::from_utf8(host).unwrap();
+            if self.unreachable.contains(&host) {
+                let not_found = crate::system_tor::SOCKS_DESCRIPTOR_NOT_FOUND;
+                return stream.write_all(&[5, not_found, 0, 1]).await;
+            }
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+            loop {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => head.push(byte),
+                        Err(_) => return Ok(()),
+                    }
+                }
+                let head = String::from_utf8(head).unwrap();
+                let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(active, Ordering::SeqCst);
+                // Long enough for requests made together to overlap.
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let response = (self.respond)(&host, &head);
+                self.sent.lock().unwrap().push(Sent {
+                    host: host.clone(),
+                    isolation: isolation.clone(),
+                    head,
+                });
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                stream.write_all(&response).await?;
+                let close = b"\r\nConnection: close\r\n";
+                if response.windows(close.len()).any(|line| line == close) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// A `200` response with `body`.
+    pub fn ok(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes()
+    }
+
+    /// The path a request head asks for.
+    pub fn requested(head: &str) -> &str {
+        head.split(' ').nth(1).unwrap_or_default()
+    }
+
+    /// The address of a new onion service.
+    pub fn onion() -> String {
+        bc_components::register_tags();
+        let (_, public) = crate::key::generate_keypair().unwrap();
+        crate::key::parse_public_key_to_onion_host(&public).unwrap()
+    }
+
+    /// Run `garner get` with `args`, through `tor`.
+    pub async fn get(tor: &FakeTor, args: &[&str]) -> Result<()> {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            args: GetArgs,
+        }
+        let argv = ["get", "--tor-socks", &tor.proxy].into_iter();
+        let cli: Cli =
+            clap::Parser::try_parse_from(argv.chain(args.iter().copied()))?;
+        run(cli.args).await
+    }
+
+    #[tokio::test]
+    async fn test_output_dir() {
+        let tor = FakeTor::start(&[], |_, head| ok(requested(head))).await;
+        let host = onion();
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().to_str().unwrap();
+        get(
+            &tor,
+            &[
+                "--output-dir",
+                out,
+                &format!("{host}/docs/a.txt"),
+                &format!("{host}/docs/"),
+                &format!("{host}/b.txt?v=1"),
+                &host,
+            ],
+        )
         .await
-        .context("writing request")?;
-    stream.flush().await.context("flushing request")?;
-
-    // Workaround for arti bug https://gitlab.torproject.org/tpo/core/arti/-/issues/1931
-    //
-    // The Tor spec requires stream originators to close with END reason
-    // MISC (not DONE), and arti has no public API to send END DONE.
-    // However, arti's reader treats END MISC as an error rather than
-    // EOF, so read_to_end() fails even though all response bytes were
-    // already delivered into the buffer.  We tolerate only that
-    // specific error when data has already been received.
-    let mut response = Vec::new();
-    if let Err(e) = stream.read_to_end(&mut response).await {
-        let is_end_misc = e.to_string().contains("END cell with reason MISC");
-        if !is_end_misc || response.is_empty() {
-            return Err(anyhow!(e).context("reading response"));
-        }
-    }
-
-    let response_str = String::from_utf8_lossy(&response);
-
-    // Parse status line
-    let status_line = response_str
-        .lines()
-        .next()
-        .ok_or_else(|| anyhow!("empty response"))?;
-
-    let status_code: u16 = status_line
-        .split_whitespace()
-        .nth(1)
-        .ok_or_else(|| anyhow!("malformed status line: {status_line}"))?
-        .parse()
-        .context("parsing status code")?;
-
-    if status_code != 200 {
-        return Err(anyhow!(
-            "server returned HTTP {status_code}: {status_line}"
-        ));
+        .unwrap();
+        let read = |path: &str| {
+            std::fs::read_to_string(dir.path().join(path)).unwrap()
+        };
+        assert_eq!(read("docs/a.txt"), "/docs/a.txt");
+        assert_eq!(read("docs/index.html"), "/docs/");
+        assert_eq!(read("b.txt"), "/b.txt?v=1");
+        assert_eq!(read("index.html"), "/");
+
+        // A path that climbs out of the directory fetches nothing.
+        let error = get(&tor, &["--output-dir", out, &format!("{host}/../x")])
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("outside the output directory"));
+        assert_eq!(tor.hosts(), vec![host.clone(); 4]);
+        let sent = tor.sent.lock().unwrap();
+        assert!(sent.iter().all(|sent| sent.head.starts_with("GET /")));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("Accept:  text/plain ").unwrap(),
+            ("Accept".to_string(), "text/plain".to_string())
+        );
+        assert!(parse_header("Accept").is_err());
+        assert!(parse_header(": x").is_err());
+        assert!(parse_header("Bad Name: x").is_err());
+        assert!(parse_header("X: a\r\nHost: evil").is_err());
+
+        let accept = [parse_header("Accept: application/json").unwrap()];
+        assert_eq!(
+            encode_request("abc.onion", "/data", Outgoing::get(&accept)),
+            b"GET /data HTTP/1.1\r\nHost: abc.onion\r\n\
+              Accept: application/json\r\n\r\n"
+        );
+        let host = [parse_header("host: www.abc.onion").unwrap()];
+        assert_eq!(
+            encode_request("abc.onion", "/", Outgoing::get(&host)),
+            b"GET / HTTP/1.1\r\nhost: www.abc.onion\r\n\r\n"
+        );
     }
 
-    // Find the end of headers (\r\n\r\n) and return body
-    let header_end = response_str
-        .find("\r\n\r\n")
-        .ok_or_else(|| anyhow!("no header/body separator found"))?;
+    #[test]
+    fn test_host_port() {
+        assert_eq!(host_port("abc.onion", 80).unwrap(), ("abc.onion", 80));
+        assert_eq!(
+            host_port("abc.onion:8080", 80).unwrap(),
+            ("abc.onion", 8080)
+        );
+        assert!(host_port("abc.onion:0", 80).is_err());
+        assert!(host_port("abc.onion:http", 80).is_err());
 
-    let body_start = header_end + 4;
-    Ok(response[body_start..].to_vec())
+        assert_eq!(with_port("abc.onion/a", 8080), "abc.onion:8080/a");
+        assert_eq!(
+            with_port("http://abc.onion", 8080),
+            "http://abc.onion:8080/"
+        );
+        assert_eq!(with_port("abc.onion:81/a", 8080), "abc.onion:81/a");
+        assert_eq!(with_port("abc.onion/a", 80), "abc.onion/a");
+        assert_eq!(
+            with_port("https://example.com/", 8443),
+            "https://example.com:8443/"
+        );
+        assert_eq!(
+            with_port("https://example.com/", 443),
+            "https://example.com/"
+        );
+        assert_eq!(split_url("https://example.com/a"), ("example.com", "/a"));
+    }
 }
@@ -0,0 +1,1387 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::sync::Semaphore;
+
+use super::{
+    EXIT_CLIENT_ERROR, EXIT_UNREACHABLE, Failed, GetArgs, HttpStatus, Plan,
+    Redirect, Skipped, Truncated, Unchanged, Unreachable, connect,
+    connector::{Connector, Pool},
+    failed_status, host_port,
+    http::{BodyChecks, Outgoing, Response, fetch_url},
+    is_https,
+    output::{
+        Digests, Hashing, Output, Progress, Sink, is_binary, json_error,
+        json_record, multipart_part, write_bodies, write_digests,
+    },
+    split_url, stream_pool,
+};
+use crate::{
+    cache::{Cache, Validators},
+    checksums::{self, Checksums, Outcome, Verifier},
+};
+
+/// Longest `--follow` asks a server to hold a poll until the file
+/// changes.
+const FOLLOW_WAIT: Duration = Duration::from_secs(60);
+
+/// Delay before the first retry with `--retries`; each later one waits
+/// twice as long as the one before, up to [`RETRY_MAX_DELAY`].
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A URL's body, or `None` when there is nothing left to write, or why
+/// fetching it failed.  A failed URL does not stop the others.
+type Fetched = Result<Option<Vec<u8>>>;
+
+/// What the URLs of one `get` share while they are fetched concurrently,
+/// up to `--max-per-host` at a time from each host and up to `--jobs` at
+/// a time in all.
+pub struct Batch<'a> {
+    args: &'a GetArgs,
+    plan: &'a Plan,
+    client_auth: &'a [(&'a str, [u8; 32])],
+    progress: Option<&'a Progress>,
+    /// Tor, bootstrapped (unless a system Tor daemon is used) only once a
+    /// URL needs the network.
+    connector: tokio::sync::OnceCell<Connector>,
+    pool: Pool,
+    jobs: Option<Semaphore>,
+    failures: Mutex<Failures>,
+    cache: Option<Cache>,
+    validators: Option<Validators>,
+    verifier: Option<Verifier>,
+    /// How each URL compared with the checksum file.
+    outcomes: Mutex<Vec<(String, Outcome)>>,
+    /// How many URLs had not changed since they were last fetched.
+    unchanged: AtomicU64,
+    /// Response heads for `--dump-header`.
+    heads: Mutex<Vec<(String, Vec<u8>)>>,
+    hashing: Hashing,
+    /// Digests of the bodies written as they arrived.
+    streamed: Mutex<Vec<(String, Digests)>>,
+    /// Whether a body goes to its output as it arrives, rather than being
+    /// held until something has seen all of it.
+    streamable: bool,
+    terminal: bool,
+}
+
+/// How the requests for one URL ended.
+enum Attempts {
+    /// A response, from `target` after any fallbacks and redirects.
+    Response {
+        response: Response,
+        target: String,
+        /// Whether it answers a HEAD request.
+        head: bool,
+    },
+    /// The URL's result, with no response left to handle.
+    Done(Fetched),
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(
+        args: &'a GetArgs,
+        plan: &'a Plan,
+        client_auth: &'a [(&'a str, [u8; 32])],
+        progress: Option<&'a Progress>,
+    ) -> Result<Self> {
+        let pool = stream_pool(args)?;
+        let cache = if args.cache || args.cache_ttl.is_some() {
+            Some(Cache::open(Cache::default_dir())?.with_ttl(args.cache_ttl))
+        } else {
+            None
+        };
+        let validators = if args.if_changed {
+            Some(Validators::open(Validators::default_dir())?)
+        } else {
+            None
+        };
+        let verifier = args
+            .checksum_file
+            .as_deref()
+            .map(|path| Checksums::load(path).map(Verifier::new))
+            .transpose()?;
+        // Only plain GETs are answered from the cache or stored in it.
+        let plain_get = plan.request().is_plain_get();
+        let cache = cache.filter(|_| plain_get);
+        let validators = validators.filter(|_| plain_get);
+        // A body goes to its output as it arrives, unless something must
+        // see all of it first or it shares stdout with other bodies.
+        let streamable = cache.is_none()
+            && verifier.is_none()
+            && plan.zstd_dictionary.is_none()
+            && !(args.head
+                || args.include
+                || args.resume
+                || args.require_signature
+                || args.envelope
+                || plan.json
+                || plan.collated);
+        Ok(Self {
+            args,
+            plan,
+            client_auth,
+            progress,
+            connector: tokio::sync::OnceCell::new(),
+            pool,
+            jobs: args.jobs.map(|n| Semaphore::new(n as usize)),
+            failures: Mutex::new(Failures::default()),
+            cache,
+            validators,
+            verifier,
+            outcomes: Mutex::new(Vec::new()),
+            unchanged: AtomicU64::new(0),
+            heads: Mutex::new(Vec::new()),
+            hashing: Hashing {
+                sha256: args.sha256.is_some(),
+                blake3: args.blake3.is_some(),
+            },
+            streamed: Mutex::new(Vec::new()),
+            streamable,
+            terminal: std::io::IsTerminal::is_terminal(&std::io::stdout()),
+        })
+    }
+
+    /// Fetch `url` for `output` within `--jobs` and `--max-time`, and check
+    /// its body against the checksum file.
+    pub async fn fetch_limited(
+        &self,
+        url: &str,
+        output: &Output,
+    ) -> Result<Fetched> {
+        let _job = match &self.jobs {
+            Some(jobs) => Some(jobs.acquire().await?),
+            None => None,
+        };
+        let result = match self.args.max_time {
+            Some(secs) => {
+                let limit = Duration::from_secs(secs);
+                tokio::time::timeout(limit, self.fetch(url, output))
+                    .await
+                    .unwrap_or_else(|_| {
+                        let timed_out = std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("gave up after {limit:?} (--max-time)"),
+                        );
+                        Ok(Err(anyhow::Error::new(timed_out)
+                            .context(url.to_string())))
+                    })?
+            }
+            None => self.fetch(url, output).await?,
+        };
+        let Some(verifier) = &self.verifier else {
+            return Ok(result);
+        };
+        let (_, path) = split_url(url);
+        let (result, outcome) = match result {
+            Ok(body) => {
+                let body = body.expect("checked bodies are held");
+                let (body, outcome) = verifier.verify(path, body).await?;
+                (Ok(Some(body)), outcome)
+            }
+            Err(e) => (Err(e), Outcome::Failed),
+        };
+        self.outcomes
+            .lock()
+            .expect("lock")
+            .push((url.to_string(), outcome));
+        Ok(result)
+    }
+
+    /// Fetch `url` for `output`: from the cache when it can, and otherwise
+    /// from the service or, when it cannot be reached, its mirrors.
+    async fn fetch(&self, url: &str, output: &Output) -> Result<Fetched> {
+        let (args, plan) = (self.args, self.plan);
+        if let Some(cache) = &self.cache
+            && let Some(body) = cache.get(url, &plan.headers)?
+        {
+            return Ok(Ok(Some(body)));
+        }
+        let started = std::time::Instant::now();
+        let (host, path) = split_url(url);
+        let _permit = self.pool.acquire(host).await;
+        // The URL on each mirror in turn, leaving out those already found
+        // unreachable.
+        let mut candidates = match plan.mirrors.first() {
+            Some(first) if first == host => plan
+                .mirrors
+                .iter()
+                .map(|mirror| format!("{mirror}{path}"))
+                .collect(),
+            _ => vec![url.to_string()],
+        };
+        if !args.no_negative_cache {
+            let failures = self.failures.lock().expect("lock");
+            let mut known = None;
+            candidates.retain(|candidate| match failures.known(candidate) {
+                Some(reason) => {
+                    known.get_or_insert(reason);
+                    false
+                }
+                None => true,
+            });
+            if let (Some(reason), true) = (known, candidates.is_empty()) {
+                return Ok(Err(
+                    anyhow::Error::new(reason).context(url.to_string())
+                ));
+            }
+        }
+        let bootstrapping = std::time::Instant::now();
+        let connector = self
+            .connector
+            .get_or_try_init(|| connect(args, self.client_auth))
+            .await?;
+        let bootstrap = bootstrapping.elapsed();
+        let resume = match output {
+            Output::File(path) if args.resume => Some(path),
+            _ => None,
+        };
+        let single = plan.urls.len() == 1;
+        let mut sink = match output {
+            _ if !self.streamable => None,
+            Output::File(_) => Some(Sink::new(output)),
+            Output::ForcedStdout if single => Some(Sink::new(output)),
+            Output::Stdout if single && !self.terminal => {
+                Some(Sink::new(output))
+            }
+            _ => None,
+        }
+        .map(|sink| sink.with_hashing(self.hashing));
+        let (response, target, head) = match self
+            .attempt(url, candidates, connector, resume, &mut sink)
+            .await?
+        {
+            Attempts::Response {
+                response,
+                target,
+                head,
+            } => (response, target, head),
+            Attempts::Done(result) => return Ok(result),
+        };
+        if let Some(cache) = &self.cache {
+            cache.put(url, &plan.headers, &response.headers, &response.body)?;
+        }
+        if let Some(validators) = &self.validators
+            && response.status == 200
+        {
+            validators.put(url, &response.headers)?;
+        }
+        if args.dump_header.is_some() {
+            self.heads
+                .lock()
+                .expect("lock")
+                .push((url.to_string(), response.head()));
+        }
+        if let Some(sink) = &mut sink {
+            match sink.finish() {
+                Ok(true) => {
+                    let digests = sink.digests();
+                    self.streamed
+                        .lock()
+                        .expect("lock")
+                        .push((url.to_string(), digests));
+                    return Ok(Ok(None));
+                }
+                Ok(false) => {}
+                Err(e) => return Ok(Err(e.context(url.to_string()))),
+            }
+        }
+        if plan.json {
+            let timings = (bootstrap, started.elapsed());
+            return Ok(Ok(Some(json_record(url, &target, &response, timings))));
+        }
+        if let Some(boundary) = &plan.boundary {
+            return Ok(Ok(Some(multipart_part(boundary, url, &response))));
+        }
+        if head {
+            return Ok(Ok(Some(response.head())));
+        }
+        if args.include {
+            return Ok(Ok(Some(response.included())));
+        }
+        Ok(Ok(Some(response.body)))
+    }
+
+    /// Request `url` from the first of `candidates`: retry transient
+    /// failures up to `--retries` times, then fall back to the next
+    /// candidate, and follow redirects.  With `resume`, ask only for what
+    /// is not already in that file, and complete the body from it.
+    async fn attempt(
+        &self,
+        url: &str,
+        mut candidates: Vec<String>,
+        connector: &Connector,
+        resume: Option<&std::path::PathBuf>,
+        sink: &mut Option<Sink<'_>>,
+    ) -> Result<Attempts> {
+        let args = self.args;
+        let failures = &self.failures;
+        // Conditions given with -H take the place of stored ones.
+        let conditions: Vec<_> = match &self.validators {
+            Some(validators) => validators
+                .conditions(url)?
+                .into_iter()
+                .filter(|(name, _)| {
+                    !self
+                        .plan
+                        .headers
+                        .iter()
+                        .any(|(given, _)| given.eq_ignore_ascii_case(name))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut fallbacks = candidates.split_off(1);
+        let mut target = candidates.remove(0);
+        let (mut attempt, mut redirects) = (0, 0);
+        let first_request = self.plan.request();
+        let cross_host = first_request.cross_host_headers();
+        let mut request = first_request;
+        let (mut response, offset) = loop {
+            // Ask only for what is not already on disk, which a broken
+            // attempt may have added to.
+            let offset = match resume {
+                Some(path) => partial_len(path)?,
+                None => 0,
+            };
+            let mut headers = request.headers.to_vec();
+            headers.extend(conditions.iter().cloned());
+            if offset > 0 {
+                headers.push(("Range".to_string(), format!("bytes={offset}-")));
+            }
+            let result = fetch_url(
+                connector,
+                &self.pool,
+                &target,
+                Outgoing {
+                    headers: &headers,
+                    ..request
+                },
+                self.plan.checks(args),
+                self.progress,
+                sink.as_mut(),
+            )
+            .await;
+            if let (Some(path), Err(e)) = (resume, &result)
+                && let Some(truncated) = e.downcast_ref::<Truncated>()
+            {
+                keep_partial(path, offset, truncated)?;
+            }
+            match result {
+                Ok(response) => break (response, offset),
+                Err(e)
+                    if offset > 0
+                        && matches!(
+                            e.downcast_ref(),
+                            Some(HttpStatus(416, ..))
+                        ) =>
+                {
+                    let path = resume.expect("resuming");
+                    eprintln!("{url}: {} is already complete", path.display());
+                    let body = std::fs::read(path).with_context(|| {
+                        format!("reading {}", path.display())
+                    })?;
+                    return Ok(Attempts::Done(Ok(Some(body))));
+                }
+                Err(e)
+                    if self.validators.is_some()
+                        && matches!(
+                            e.downcast_ref(),
+                            Some(HttpStatus(304, ..))
+                        ) =>
+                {
+                    self.unchanged.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Attempts::Done(Ok(None)));
+                }
+                // A retry must start its body over, which stdout cannot.
+                Err(e)
+                    if attempt < args.retries
+                        && is_transient(&e)
+                        && sink.as_mut().is_none_or(Sink::restart) =>
+                {
+                    attempt += 1;
+                    let random = bc_rand::random_data(8);
+                    let random = u64::from_le_bytes(
+                        random.try_into().expect("8 random bytes"),
+                    );
+                    let delay = retry_delay(attempt, random);
+                    if self.progress.is_none() {
+                        eprintln!(
+                            "{url}: {e:#}; retrying in {:.1}s",
+                            delay.as_secs_f64()
+                        );
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e)
+                    if is_transient(&e)
+                        && !fallbacks.is_empty()
+                        && sink.as_mut().is_none_or(Sink::restart) =>
+                {
+                    failures.lock().expect("lock").record(&target, &e);
+                    let next = fallbacks.remove(0);
+                    self.note(format!("{target}: {e:#}; trying {next}"))?;
+                    target = next;
+                    request = first_request;
+                    (attempt, redirects) = (0, 0);
+                }
+                Err(e)
+                    if redirects < args.max_redirects && e.is::<Redirect>() =>
+                {
+                    let redirect =
+                        e.downcast_ref::<Redirect>().expect("redirect");
+                    match redirect_target(
+                        &target,
+                        &redirect.location,
+                        args.allow_clearnet,
+                    ) {
+                        Ok(next) => {
+                            request = request.redirected_to(
+                                redirect.status,
+                                &target,
+                                &next,
+                                &cross_host,
+                            );
+                            target = next;
+                        }
+                        Err(e) => {
+                            let e = e.context(url.to_string());
+                            return Ok(Attempts::Done(Err(e)));
+                        }
+                    }
+                    (attempt, redirects) = (0, redirects + 1);
+                }
+                Err(e) => {
+                    let mut failures = failures.lock().expect("lock");
+                    failures.record(url, &e);
+                    if target != url {
+                        failures.record(&target, &e);
+                    }
+                    return Ok(Attempts::Done(Err(e.context(url.to_string()))));
+                }
+            }
+        };
+        if redirects > 0 {
+            self.note(format!("{url}: redirected to {target}"))?;
+        }
+        if let Some(path) = resume.filter(|_| offset > 0)
+            && let Err(e) = resume_body(path, offset, &mut response)
+        {
+            return Ok(Attempts::Done(Err(e.context(url.to_string()))));
+        }
+        Ok(Attempts::Response {
+            response,
+            target,
+            head: request.method == "HEAD",
+        })
+    }
+
+    /// Print a note about a URL, above the download bars if there are any.
+    fn note(&self, note: String) -> Result<()> {
+        match self.progress {
+            Some(progress) => progress.multi.println(note)?,
+            None => eprintln!("{note}"),
+        }
+        Ok(())
+    }
+
+    /// Write what every URL fetched, and the reports asked for, once they
+    /// are all done; then fail if any URL did.
+    pub fn finish(self, results: Vec<Fetched>) -> Result<()> {
+        let (args, plan) = (self.args, self.plan);
+        let mut bodies = Vec::with_capacity(results.len());
+        let mut errors = Vec::new();
+        let mut digests = Vec::new();
+        for (url, result) in plan.urls.iter().zip(results) {
+            match result {
+                Ok(Some(body)) => {
+                    if self.hashing.any() {
+                        digests.push((url, self.hashing.digest(&body)));
+                    }
+                    bodies.push(Some(body));
+                }
+                // A body already written as it arrived has nothing left to
+                // write, and was hashed as it was written.
+                Ok(None) => {
+                    let streamed = self.streamed.lock().expect("lock");
+                    if let Some((_, found)) =
+                        streamed.iter().find(|(done, _)| done == url)
+                    {
+                        digests.push((url, found.clone()));
+                    }
+                    bodies.push(None);
+                }
+                Err(e) => {
+                    let body = match e.downcast_ref::<HttpStatus>() {
+                        _ if plan.json => Some(json_error(url, &e)),
+                        Some(HttpStatus(_, _, body)) if args.fail_with_body => {
+                            Some(body.clone())
+                        }
+                        _ => None,
+                    };
+                    bodies.push(body);
+                    errors.push(e);
+                }
+            }
+        }
+
+        write_bodies(args, plan, &bodies, self.terminal, &mut errors)?;
+        if let Some(path) = &args.dump_header {
+            let heads = self.heads.lock().expect("lock");
+            let dump: Vec<u8> = plan
+                .urls
+                .iter()
+                .filter_map(|url| heads.iter().find(|(done, _)| done == url))
+                .map(|(_, head)| head.as_slice())
+                .collect::<Vec<_>>()
+                .join(&b'\n');
+            std::fs::write(path, dump)
+                .with_context(|| format!("writing {}", path.display()))?;
+        }
+        write_digests(args, &digests)?;
+        if self.verifier.is_some() {
+            self.report_checksums()?;
+        }
+
+        if errors.is_empty() {
+            if self.unchanged.load(Ordering::Relaxed) == plan.urls.len() as u64
+            {
+                return Err(Unchanged.into());
+            }
+            return Ok(());
+        }
+        let status = failed_status(&errors);
+        let error = if plan.urls.len() == 1 {
+            errors.pop().expect("one error")
+        } else {
+            for e in &errors {
+                eprintln!("error: {e:#}");
+            }
+            anyhow!("{} of {} URLs failed", errors.len(), plan.urls.len())
+        };
+        Err(Failed { status, error }.into())
+    }
+
+    /// Print how each URL compared with the checksum file, and fail if
+    /// any did not match.
+    fn report_checksums(&self) -> Result<()> {
+        let outcomes = self.outcomes.lock().expect("lock");
+        let rows: Vec<_> = self
+            .plan
+            .urls
+            .iter()
+            .filter_map(|url| outcomes.iter().find(|(done, _)| done == url))
+            .cloned()
+            .collect();
+        eprint!("{}", checksums::report(&rows));
+        let mismatched = rows
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Mismatch(_)))
+            .count();
+        if mismatched > 0 {
+            return Err(anyhow!(
+                "{mismatched} of {} files did not match the checksum file",
+                rows.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Poll `url` every `--interval` seconds for data past what has been
+/// seen, asking for it with `Range: bytes=<SEEN>-`, and write it to
+/// stdout.  A server that ignores the range sends the whole body, of
+/// which only the new part is written.  With the `ETag` of the last
+/// response, each poll also asks the server to hold it until the file
+/// changes, for up to [`FOLLOW_WAIT`].  Runs until interrupted; failed
+/// polls are reported and retried.
+pub async fn follow(
+    connector: &Connector,
+    pool: &Pool,
+    url: &str,
+    request_headers: &[(String, String)],
+    args: &GetArgs,
+    zstd_dictionary: Option<&[u8]>,
+) -> Result<()> {
+    use std::io::Write;
+
+    // A held poll must end before the read timeout gives up on it.
+    let follow_wait = FOLLOW_WAIT.min(pool.timeouts.read / 2);
+    let mut seen: usize = 0;
+    let mut etag: Option<String> = None;
+    loop {
+        let started = std::time::Instant::now();
+        let mut headers = request_headers.to_vec();
+        if seen > 0 {
+            headers.push(("Range".to_string(), format!("bytes={seen}-")));
+        }
+        if let Some(etag) = &etag {
+            headers.extend([
+                ("If-None-Match".to_string(), etag.clone()),
+                (
+                    "Prefer".to_string(),
+                    format!("wait={}", follow_wait.as_secs()),
+                ),
+            ]);
+        }
+        let result = fetch_url(
+            connector,
+            pool,
+            url,
+            Outgoing::get(&headers),
+            BodyChecks {
+                require_signature: args.require_signature,
+                zstd_dictionary,
+                decompress: false,
+                envelope: false,
+            },
+            None,
+            None,
+        )
+        .await;
+        if let Ok(response) = &result {
+            etag = response.header("etag").map(str::to_string);
+        }
+        let new = match result {
+            Ok(response) if response.status == 206 => {
+                let start = response
+                    .header("content-range")
+                    .and_then(range_start)
+                    .ok_or_else(|| anyhow!("{url}: bad Content-Range"))?;
+                if start != seen as u64 {
+                    return Err(anyhow!(
+                        "{url}: asked for bytes from {seen}, got them from \
+                         {start}"
+                    ));
+                }
+                response.body
+            }
+            Ok(mut response) => {
+                if response.body.len() < seen {
+                    eprintln!("{url}: shrank; following it from the start");
+                    seen = 0;
+                }
+                response.body.split_off(seen)
+            }
+            Err(e)
+                if e.downcast_ref::<HttpStatus>()
+                    .is_some_and(|s| matches!(s.0, 304 | 416)) =>
+            {
+                Vec::new()
+            }
+            Err(e) => {
+                eprintln!("error: {:#}", e.context(url.to_string()));
+                Vec::new()
+            }
+        };
+        if new.is_empty() {
+            // A server that held the poll has already waited.
+            let interval = Duration::from_secs(args.interval);
+            tokio::time::sleep(interval.saturating_sub(started.elapsed()))
+                .await;
+        } else {
+            let mut out = std::io::stdout().lock();
+            out.write_all(&new)?;
+            out.flush()?;
+            seen += new.len();
+        }
+    }
+}
+
+/// Parse a `--watch` interval: seconds, or a number followed by `s`, `m`,
+/// `h`, or `d`.
+pub fn parse_interval(spec: &str) -> Result<Duration> {
+    let (number, unit) = match spec.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => spec.split_at(i),
+        None => (spec, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("invalid interval {spec:?}; use e.g. 5m")),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Duration::from_secs(n.saturating_mul(seconds))),
+        _ => Err(anyhow!("invalid interval {spec:?}; use e.g. 5m")),
+    }
+}
+
+/// What `--watch` does with each new version of a body.
+pub enum OnChange<'a> {
+    Write(&'a Output),
+    /// Run a shell command (`--exec`), except for the first version.
+    Exec(&'a str),
+}
+
+/// Fetch `url` every `interval`, and act on its body whenever its SHA-256
+/// differs from the last one seen.  With the `ETag` of the last response,
+/// each fetch asks for the body only if it changed.  Runs until
+/// interrupted; failed fetches, and commands that fail, are reported and
+/// the watch goes on.
+pub async fn watch(
+    connector: &Connector,
+    pool: &Pool,
+    url: &str,
+    request_headers: &[(String, String)],
+    checks: BodyChecks<'_>,
+    on_change: OnChange<'_>,
+    interval: Duration,
+) -> Result<()> {
+    use std::io::Write;
+
+    use sha2::Digest as _;
+
+    let terminal = std::io::IsTerminal::is_terminal(&std::io::stdout());
+    let mut last: Option<String> = None;
+    let mut etag: Option<String> = None;
+    loop {
+        let started = std::time::Instant::now();
+        let mut headers = request_headers.to_vec();
+        if let Some(etag) = &etag {
+            headers.push(("If-None-Match".to_string(), etag.clone()));
+        }
+        let result = fetch_url(
+            connector,
+            pool,
+            url,
+            Outgoing::get(&headers),
+            checks,
+            None,
+            None,
+        )
+        .await;
+        match result {
+            Ok(response) => {
+                etag = response.header("etag").map(str::to_string);
+                let digest = hex::encode(sha2::Sha256::digest(&response.body));
+                let first = last.is_none();
+                if last.as_ref() != Some(&digest) {
+                    if !first {
+                        eprintln!("{url}: changed (sha256 {})", &digest[..16]);
+                    }
+                    match on_change {
+                        OnChange::Write(Output::File(path)) => {
+                            std::fs::write(path, &response.body).with_context(
+                                || format!("writing {}", path.display()),
+                            )?
+                        }
+                        OnChange::Write(Output::Stdout)
+                            if terminal && is_binary(&response.body) =>
+                        {
+                            return Err(anyhow!(
+                                "{url}: not writing binary output to the \
+                                 terminal; use --output FILE, or --output - \
+                                 to write it anyway"
+                            ));
+                        }
+                        OnChange::Write(
+                            Output::Stdout | Output::ForcedStdout,
+                        ) => {
+                            let mut out = std::io::stdout().lock();
+                            out.write_all(&response.body)?;
+                            out.flush()?;
+                        }
+                        OnChange::Exec(_) if first => {}
+                        OnChange::Exec(command) => {
+                            let (command, url, body, sha256) = (
+                                command.to_string(),
+                                url.to_string(),
+                                response.body,
+                                digest.clone(),
+                            );
+                            let result =
+                                tokio::task::spawn_blocking(move || {
+                                    run_exec(&command, &url, &body, &sha256)
+                                })
+                                .await?;
+                            if let Err(e) = result {
+                                eprintln!("error: {e:#}");
+                            }
+                        }
+                    }
+                    last = Some(digest);
+                }
+            }
+            Err(e)
+                if e.downcast_ref::<HttpStatus>()
+                    .is_some_and(|s| s.0 == 304) => {}
+            Err(e) => {
+                eprintln!("error: {:#}", e.context(url.to_string()));
+            }
+        }
+        tokio::time::sleep(interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// Run `--exec` command `command` with the shell, passing it a changed
+/// body on stdin.
+fn run_exec(command: &str, url: &str, body: &[u8], sha256: &str) -> Result<()> {
+    use std::{io::Write as _, process::Stdio};
+
+    #[cfg(unix)]
+    let mut shell = std::process::Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = std::process::Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+    let mut child = shell
+        .arg(command)
+        .env("GARNER_URL", url)
+        .env("GARNER_SHA256", sha256)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {command:?}"))?;
+    // The command need not read its input.
+    let _ = child.stdin.take().expect("piped stdin").write_all(body);
+    let status = child
+        .wait()
+        .with_context(|| format!("running {command:?}"))?;
+    if !status.success() {
+        return Err(anyhow!("{command:?} failed: {status}"));
+    }
+    Ok(())
+}
+
+/// How much of a `--continue` download is already at `path`.
+fn partial_len(path: &std::path::Path) -> Result<u64> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => {
+            Err(anyhow!(e).context(format!("reading {}", path.display())))
+        }
+    }
+}
+
+/// Save the part of a body that arrived before the connection broke, for
+/// `--continue` to pick up from: appended to the `offset` bytes at `path`
+/// when the server sent the rest from there, or in place of them when it
+/// sent the whole body and got further.
+fn keep_partial(
+    path: &std::path::Path,
+    offset: u64,
+    truncated: &Truncated,
+) -> Result<()> {
+    use std::io::Write as _;
+
+    let received = truncated.received.len() as u64;
+    let mut options = std::fs::OpenOptions::new();
+    if truncated.start == offset && received > 0 {
+        options.create(true).append(true);
+    } else if truncated.start == 0 && received > offset {
+        options.create(true).write(true).truncate(true);
+    } else {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating {}", parent.display()))?;
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&truncated.received))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Turn the answer to a `--continue` request from `offset` into the whole
+/// body: a 206 for the rest is appended to the first `offset` bytes at
+/// `path`, while a 200 is the whole body already.
+fn resume_body(
+    path: &std::path::Path,
+    offset: u64,
+    response: &mut Response,
+) -> Result<()> {
+    if response.status != 206 {
+        eprintln!(
+            "{}: the server sent the whole file; replacing it",
+            path.display()
+        );
+        return Ok(());
+    }
+    let start = response
+        .header("content-range")
+        .and_then(range_start)
+        .ok_or_else(|| anyhow!("bad Content-Range"))?;
+    if start != offset {
+        return Err(anyhow!(
+            "asked for bytes from {offset}, got them from {start}"
+        ));
+    }
+    let mut body = std::fs::read(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    body.truncate(offset as usize);
+    body.append(&mut response.body);
+    response.body = body;
+    Ok(())
+}
+
+/// The first byte position in a `Content-Range: bytes <FIRST>-<LAST>/<LEN>`
+/// header.
+pub fn range_start(content_range: &str) -> Option<u64> {
+    let range = content_range.trim().strip_prefix("bytes ")?;
+    range.split_once('-')?.0.parse().ok()
+}
+
+/// Hosts that could not be reached, and URLs that do not exist, as seen
+/// earlier in this run.  Later URLs in a batch that would fail the same
+/// way are skipped, rather than each waiting out another rendezvous
+/// timeout.
+#[derive(Default)]
+struct Failures {
+    hosts: HashMap<String, String>,
+    urls: HashMap<String, String>,
+}
+
+impl Failures {
+    /// Why `url` is expected to fail, if it is.
+    fn known(&self, url: &str) -> Option<Skipped> {
+        if let Some(reason) = self.urls.get(url) {
+            return Some(Skipped {
+                reason: reason.clone(),
+                status: EXIT_CLIENT_ERROR,
+            });
+        }
+        let (host, _) = split_url(url);
+        self.hosts.get(host).map(|reason| Skipped {
+            reason: format!("{host} was unreachable earlier: {reason}"),
+            status: EXIT_UNREACHABLE,
+        })
+    }
+
+    /// Remember `error` from fetching `url` if it is permanent for this
+    /// run: an unreachable host, or a path the server says is missing.
+    fn record(&mut self, url: &str, error: &anyhow::Error) {
+        if error.downcast_ref::<Unreachable>().is_some() {
+            let (host, _) = split_url(url);
+            self.hosts.insert(host.to_string(), format!("{error:#}"));
+        } else if let Some(HttpStatus(404 | 410, line, _)) =
+            error.downcast_ref()
+        {
+            self.urls
+                .insert(url.to_string(), format!("returned {line} earlier"));
+        }
+    }
+}
+
+/// Whether a failed fetch may succeed if tried again: the service could
+/// not be reached (its descriptor not yet available, or its introduction
+/// points changing) or the connection failed partway.  A response the
+/// server did send, such as an HTTP error or a bad signature, is final.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    if error.downcast_ref::<HttpStatus>().is_some() {
+        return false;
+    }
+    error.downcast_ref::<Unreachable>().is_some()
+        || error.chain().any(|cause| cause.is::<std::io::Error>())
+}
+
+/// How long to wait before retry number `attempt` (from 1): exponential
+/// backoff with "equal jitter", between half and all of the doubled delay,
+/// so that many clients retrying at once spread out.  `random` picks the
+/// point in that range.
+fn retry_delay(attempt: u32, random: u64) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(RETRY_MAX_DELAY);
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + random % (half + 1))
+}
+
+/// The URL a redirect from `url` to `location` leads to.  Only plain
+/// HTTP redirects to onion services are followed, unless `clearnet`
+/// allows any host and HTTPS as well.
+fn redirect_target(
+    url: &str,
+    location: &str,
+    clearnet: bool,
+) -> Result<String> {
+    let (host, path) = split_url(url);
+    let https = is_https(url);
+    let origin = match https {
+        true => format!("https://{host}"),
+        false => host.to_string(),
+    };
+    let target = if let Some(rest) = location.strip_prefix("http://") {
+        rest.to_string()
+    } else if let Some(rest) = location.strip_prefix("//") {
+        match https {
+            true => format!("https://{rest}"),
+            false => rest.to_string(),
+        }
+    } else if location.starts_with("https://") && clearnet {
+        location.to_string()
+    } else if location.contains("://") {
+        return Err(anyhow!("not following redirect to {location}"));
+    } else if location.starts_with('/') {
+        format!("{origin}{location}")
+    } else {
+        let path = path.split(['?', '#']).next().unwrap_or("");
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{origin}{dir}{location}")
+    };
+    let (target_host, target_path) = split_url(&target);
+    let onion = host_port(target_host, crate::server::HTTP_PORT)
+        .is_ok_and(|(name, _)| name.ends_with(".onion"));
+    if !onion && !clearnet {
+        return Err(anyhow!(
+            "not following redirect off the onion service to {location}"
+        ));
+    }
+    Ok(match is_https(&target) {
+        true => format!("https://{target_host}{target_path}"),
+        false => format!("{target_host}{target_path}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get::{
+        BadSignature, EXIT_BAD_SIGNATURE, EXIT_SERVER_ERROR, Failed,
+        exit_status,
+        http::{Timing, read_response},
+        tests::{FakeTor, get, ok, onion, requested},
+    };
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let other = onion();
+        let to = other.clone();
+        let tor = FakeTor::start(&[], move |_, head| {
+            let path = requested(head);
+            let location = match path.strip_prefix("/loop/") {
+                Some(n) => format!("/loop/{}", n.parse::<u32>().unwrap() + 1),
+                None if path == "/off" => "http://example.com/".to_string(),
+                None if path == "/hop" => format!("http://{to}/end"),
+                None => return ok(path),
+            };
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {location}\r\n\
+                 Content-Length: 0\r\n\r\n"
+            )
+            .into_bytes()
+        })
+        .await;
+        let host = onion();
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let out = out.to_str().unwrap();
+
+        get(&tor, &["-o", out, &format!("{host}/hop")])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(out).unwrap(), "/end");
+        assert_eq!(tor.hosts(), [host.clone(), other]);
+
+        // A redirect off the onion service is not followed.
+        tor.sent.lock().unwrap().clear();
+        let error = get(&tor, &[&format!("{host}/off")]).await.unwrap_err();
+        assert!(
+            format!("{error:#}").contains("off the onion service"),
+            "{error:#}"
+        );
+        assert_eq!(tor.hosts().len(), 1);
+
+        tor.sent.lock().unwrap().clear();
+        let url = format!("{host}/loop/0");
+        get(&tor, &["--max-redirects", "2", &url])
+            .await
+            .unwrap_err();
+        assert_eq!(tor.hosts().len(), 3);
+        tor.sent.lock().unwrap().clear();
+        get(&tor, &["--max-redirects", "0", &url])
+            .await
+            .unwrap_err();
+        assert_eq!(tor.hosts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_address_fallback() {
+        let (down, up) = (onion(), onion());
+        let tor = FakeTor::start(&[&down], |_, head| ok(requested(head))).await;
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let out = out.to_str().unwrap();
+        let mirrors = format!("{down},{up}");
+        get(&tor, &["--address", &mirrors, "-o", out, "/x"])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(out).unwrap(), "/x");
+        assert_eq!(tor.hosts(), [up]);
+
+        let error = get(&tor, &["--address", &down, "--address", &down, "/x"])
+            .await
+            .unwrap_err();
+        let status = error.downcast_ref::<Failed>().unwrap().status;
+        assert_eq!(status, EXIT_UNREACHABLE);
+        assert_eq!(tor.hosts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let host = onion();
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = vec!["--jobs", "2", "--max-per-host", "8"];
+        let urls: Vec<(String, String)> = (0..6)
+            .map(|i| {
+                let out = dir.path().join(format!("{i}.txt"));
+                (format!("{host}/{i}"), out.to_str().unwrap().to_string())
+            })
+            .collect();
+        for (url, out) in &urls {
+            args.extend(["-o", out, url]);
+        }
+        let tor = FakeTor::start(&[], |_, head| ok(requested(head))).await;
+        get(&tor, &args).await.unwrap();
+        assert_eq!(tor.peak.load(Ordering::SeqCst), 2);
+        for (i, (_, out)) in urls.iter().enumerate() {
+            assert_eq!(std::fs::read_to_string(out).unwrap(), format!("/{i}"));
+        }
+
+        // Without --jobs, --max-per-host alone bounds one host's URLs.
+        let tor = FakeTor::start(&[], |_, head| ok(requested(head))).await;
+        get(&tor, &args[2..]).await.unwrap();
+        assert_eq!(tor.peak.load(Ordering::SeqCst), urls.len());
+        let tor = FakeTor::start(&[], |_, head| ok(requested(head))).await;
+        args[3] = "3";
+        get(&tor, &args[2..]).await.unwrap();
+        assert_eq!(tor.peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let mut stream = futures_util::io::Cursor::new(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\n\
+              Content-Length: 5\r\n\r\nfg"
+                .to_vec(),
+        );
+        let Err(error) = read_response(&mut stream, false, None, None).await
+        else {
+            panic!("a short body should fail");
+        };
+        assert!(is_transient(&error));
+        let truncated = error.downcast_ref::<Truncated>().unwrap();
+        assert_eq!((truncated.start, &truncated.received[..]), (5, &b"fg"[..]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abcde").unwrap();
+        keep_partial(&path, 5, truncated).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefg");
+
+        // A whole body that got less far than the file is not kept.
+        let restarted = Truncated {
+            start: 0,
+            received: b"ABC".to_vec(),
+        };
+        keep_partial(&path, 7, &restarted).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefg");
+        let restarted = Truncated {
+            start: 0,
+            received: b"ABCDEFGH".to_vec(),
+        };
+        keep_partial(&path, 7, &restarted).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ABCDEFGH");
+
+        let rest = |range: &str| Response {
+            status: 206,
+            status_line: "HTTP/1.1 206 Partial Content".to_string(),
+            headers: vec![("Content-Range".to_string(), range.to_string())],
+            body: b"ij".to_vec(),
+            timing: Timing::default(),
+        };
+        let mut response = rest("bytes 8-9/10");
+        resume_body(&path, 8, &mut response).unwrap();
+        assert_eq!(response.body, b"ABCDEFGHij");
+        assert!(resume_body(&path, 8, &mut rest("bytes 0-9/10")).is_err());
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes 12-40/41"), Some(12));
+        assert_eq!(range_start("bytes */41"), None);
+        assert_eq!(range_start("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn test_retries() {
+        assert_eq!(retry_delay(1, 0), Duration::from_secs(1));
+        assert_eq!(retry_delay(1, 1000), Duration::from_secs(2));
+        assert_eq!(retry_delay(3, 0), Duration::from_secs(4));
+        assert!(retry_delay(30, u64::MAX) <= RETRY_MAX_DELAY);
+
+        let unreachable = anyhow!("timed out").context(Unreachable);
+        assert!(is_transient(&unreachable));
+        let reset =
+            anyhow!(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+                .context("reading response");
+        assert!(is_transient(&reset));
+        let not_found: anyhow::Error =
+            HttpStatus(404, "HTTP/1.1 404 Not Found".into(), Vec::new()).into();
+        assert!(!is_transient(&not_found));
+        assert!(!is_transient(&anyhow!(
+            "response from x.onion is not signed"
+        )));
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let url = "abc.onion/docs/a.html?x=1";
+        for (location, target) in [
+            ("/docs/", "abc.onion/docs/"),
+            ("b.html", "abc.onion/docs/b.html"),
+            ("http://def.onion/new", "def.onion/new"),
+            ("//def.onion", "def.onion/"),
+        ] {
+            assert_eq!(redirect_target(url, location, false).unwrap(), target);
+        }
+        assert_eq!(
+            redirect_target("http://abc.onion", "/docs/", false).unwrap(),
+            "abc.onion/docs/"
+        );
+        assert!(redirect_target(url, "http://example.com/", false).is_err());
+        assert!(redirect_target(url, "https://abc.onion/", false).is_err());
+        assert_eq!(
+            redirect_target("abc.onion:8080/a", "/b", false).unwrap(),
+            "abc.onion:8080/b"
+        );
+        assert!(redirect_target(url, "http://example.com:80/", false).is_err());
+
+        // Anywhere goes with --allow-clearnet, keeping to HTTPS.
+        assert_eq!(
+            redirect_target(url, "http://example.com/", true).unwrap(),
+            "example.com/"
+        );
+        assert_eq!(
+            redirect_target(url, "https://example.com/a", true).unwrap(),
+            "https://example.com/a"
+        );
+        let secure = "https://example.com/docs/a.html";
+        for (location, target) in [
+            ("/b", "https://example.com/b"),
+            ("b.html", "https://example.com/docs/b.html"),
+            ("//example.org/", "https://example.org/"),
+        ] {
+            assert_eq!(
+                redirect_target(secure, location, true).unwrap(),
+                target
+            );
+        }
+        assert!(!is_transient(
+            &Redirect {
+                status: 301,
+                location: "/".into()
+            }
+            .into()
+        ));
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_interval("45s").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_interval("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_interval("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_interval("1d").unwrap(), Duration::from_secs(86400));
+        for bad in ["", "0", "0m", "m", "5x", "1.5m", "-1", "5 m"] {
+            assert!(parse_interval(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_exec() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("out");
+        let command = format!(
+            "cat > {} && echo \"$GARNER_URL $GARNER_SHA256\" >> {0}",
+            file.display()
+        );
+        run_exec(&command, "abc.onion/status", b"up\n", "1234").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "up\nabc.onion/status 1234\n"
+        );
+        assert!(run_exec("exit 3", "abc.onion/", b"", "").is_err());
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut failures = Failures::default();
+        let down = anyhow!("timed out").context(Unreachable);
+        failures.record("http://a.onion/x", &down);
+        assert!(
+            failures
+                .known("a.onion/y")
+                .unwrap()
+                .reason
+                .contains("timed out")
+        );
+
+        let missing = anyhow::Error::from(HttpStatus(
+            404,
+            "HTTP/1.1 404 Not Found".to_string(),
+            b"no such page".to_vec(),
+        ));
+        failures.record("b.onion/gone", &missing);
+        assert!(failures.known("b.onion/gone").is_some());
+        assert!(failures.known("b.onion/other").is_none());
+
+        let transient = anyhow::Error::from(HttpStatus(
+            503,
+            "HTTP/1.1 503 Service Unavailable".to_string(),
+            Vec::new(),
+        ));
+        failures.record("c.onion/busy", &transient);
+        assert!(failures.known("c.onion/busy").is_none());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let mut failures = Failures::default();
+        let down = anyhow!("timed out").context(Unreachable);
+        let missing = anyhow::Error::from(HttpStatus(
+            404,
+            "HTTP/1.1 404 Not Found".to_string(),
+            Vec::new(),
+        ));
+        let busy = anyhow::Error::from(HttpStatus(
+            503,
+            "HTTP/1.1 503 Service Unavailable".to_string(),
+            Vec::new(),
+        ));
+        assert_eq!(exit_status(&down), EXIT_UNREACHABLE);
+        assert_eq!(exit_status(&missing), EXIT_CLIENT_ERROR);
+        assert_eq!(exit_status(&busy), EXIT_SERVER_ERROR);
+        assert_eq!(exit_status(&anyhow!("bad envelope")), 1);
+        let unsigned = anyhow!("not signed").context(BadSignature);
+        assert_eq!(exit_status(&unsigned), EXIT_BAD_SIGNATURE);
+
+        // Skipped URLs fail the way the ones they follow did.
+        failures.record("a.onion/x", &down);
+        failures.record("b.onion/gone", &missing);
+        let skipped =
+            |url| anyhow::Error::new(failures.known(url).unwrap()).context(url);
+        assert_eq!(exit_status(&skipped("a.onion/y")), EXIT_UNREACHABLE);
+        assert_eq!(exit_status(&skipped("b.onion/gone")), EXIT_CLIENT_ERROR);
+
+        assert_eq!(
+            failed_status(&[skipped("a.onion/y"), down]),
+            EXIT_UNREACHABLE
+        );
+        assert_eq!(failed_status(&[missing, busy]), 1);
+    }
+}
@@ -0,0 +1,686 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use arti_client::{IsolationToken, StreamPrefs, TorClient};
+use futures_util::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::compat::TokioAsyncReadCompatExt as _;
+use tor_hscrypto::pk::{HsClientDescEncSecretKey, HsId};
+use tor_keymgr::KeystoreSelector;
+use tor_llcrypto::pk::curve25519;
+use tor_rtcompat::PreferredRuntime;
+
+use super::{CircuitPolicy, cookies};
+use crate::{system_tor, tor};
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Longest a response may go without sending any data.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A bidirectional byte stream to a remote service.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// How `get` opens streams to onion services.
+pub enum Connector {
+    /// The embedded Arti client.  Fields drop in order, so the client
+    /// releases its locks before its state directory is deleted.
+    Arti {
+        tor: Box<TorClient<PreferredRuntime>>,
+        /// Arti isolation tokens by isolation key.
+        isolation: Mutex<HashMap<String, IsolationToken>>,
+        /// The client's state directory, unless the client belongs to
+        /// someone else.
+        _state_dir: Option<tor::StateDir>,
+    },
+    /// A running Tor daemon's SOCKS port.
+    Socks(String),
+    /// A running `garner daemon`'s socket.
+    #[cfg(unix)]
+    Daemon(std::path::PathBuf),
+}
+
+impl Connector {
+    /// Open a stream, on a circuit shared only with streams that have the
+    /// same `isolation` key (or with any, when there is none).
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: Option<&str>,
+    ) -> Result<Box<dyn Connection>> {
+        match self {
+            Connector::Arti {
+                tor,
+                isolation: tokens,
+                ..
+            } => {
+                let mut prefs = StreamPrefs::new();
+                if let Some(key) = isolation {
+                    let token = *tokens
+                        .lock()
+                        .expect("isolation lock")
+                        .entry(key.to_string())
+                        .or_insert_with(IsolationToken::new);
+                    prefs.set_isolation(token);
+                }
+                Ok(Box::new(
+                    tor.connect_with_prefs((host, port), &prefs).await?,
+                ))
+            }
+            Connector::Socks(proxy) => {
+                let stream =
+                    system_tor::socks_connect(proxy, host, port, isolation)
+                        .await?;
+                Ok(Box::new(stream.compat()))
+            }
+            #[cfg(unix)]
+            Connector::Daemon(socket) => {
+                let stream =
+                    crate::daemon::connect(socket, host, port, isolation)
+                        .await?;
+                Ok(Box::new(stream.compat()))
+            }
+        }
+    }
+}
+
+/// Start TLS on a stream to `name`, checking its certificate.
+pub async fn start_tls(
+    name: &str,
+    stream: Box<dyn Connection>,
+) -> Result<Box<dyn Connection>> {
+    let stream = async_native_tls::TlsConnector::new()
+        .connect(name, stream)
+        .await
+        .with_context(|| format!("TLS handshake with {name}"))?;
+    Ok(Box::new(stream))
+}
+
+pub fn tor_socks_conflict() -> anyhow::Error {
+    anyhow!(
+        "--tor-socks cannot be combined with options for the embedded Arti \
+         client"
+    )
+}
+
+/// Set up the connection to the Tor network: a running Tor daemon's
+/// SOCKS port, a `garner daemon` listening on `daemon`, or else a freshly
+/// bootstrapped embedded client.  The garner daemon is only used when
+/// its client needs no options of this run's.
+pub async fn connect_tor(
+    tor_socks: Option<&str>,
+    daemon: Option<&std::path::Path>,
+    tor_args: &tor::TorArgs,
+    connect_timeout: Duration,
+    client_auth: &[(&str, [u8; 32])],
+) -> Result<Connector> {
+    #[cfg(unix)]
+    if let Some(socket) = daemon
+        && tor_socks.is_none()
+        && client_auth.is_empty()
+        && !tor_args.uses_arti_options()
+        && crate::daemon::is_running(socket).await
+    {
+        tracing::debug!("* Using the garner daemon at {}", socket.display());
+        return Ok(Connector::Daemon(socket.to_path_buf()));
+    }
+    #[cfg(not(unix))]
+    let _ = daemon;
+    let connector = match tor_socks {
+        Some(proxy) => Connector::Socks(proxy.to_string()),
+        None => {
+            // Ephemeral state dir avoids lock contention with concurrent
+            // invocations.
+            let (state_dir, cache_dir) = tor::dirs(None)?;
+            let mut builder = tor::config(&state_dir, &cache_dir, tor_args)?;
+            builder.stream_timeouts().connect_timeout(connect_timeout);
+            let config = builder.build()?;
+            let tor = TorClient::create_bootstrapped(config).await?;
+            // Restricted discovery keys go in the client's keystore, where
+            // it looks for them when it fetches each service's descriptor.
+            for (host, secret) in client_auth {
+                let hs_id: HsId = host.parse().map_err(|e| {
+                    anyhow!("invalid onion address {host}: {e}")
+                })?;
+                let secret = curve25519::StaticSecret::from(*secret);
+                tor.insert_service_discovery_key(
+                    KeystoreSelector::Primary,
+                    hs_id,
+                    HsClientDescEncSecretKey::from(secret),
+                )
+                .context("adding client authorization key")?;
+            }
+            Connector::Arti {
+                tor: Box::new(tor),
+                isolation: Mutex::new(HashMap::new()),
+                _state_dir: Some(state_dir),
+            }
+        }
+    };
+    Ok(connector)
+}
+
+/// Per-host stream limits, and idle keep-alive streams kept for reuse.
+pub struct Pool {
+    max_per_host: usize,
+    circuit_policy: CircuitPolicy,
+    pub timeouts: Timeouts,
+    /// Whether hosts other than onion services may be fetched.
+    pub clearnet: bool,
+    /// The `--limit-rate` that all response bodies share.
+    pub rate_limit: Option<RateLimit>,
+    /// Prefixed to every isolation key, so that no stream shares a
+    /// circuit with another run's.
+    run_isolation: Option<String>,
+    /// The `--cookie-jar` and `--cookie` cookies sent with requests.
+    cookies: Option<Mutex<cookies::Jar>>,
+    hosts: Mutex<HashMap<String, Arc<HostStreams>>>,
+}
+
+/// How long to wait for a stream to open, and for data on it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: CONNECT_TIMEOUT,
+            read: READ_TIMEOUT,
+        }
+    }
+}
+
+struct HostStreams {
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Box<dyn Connection>>>,
+}
+
+impl Pool {
+    pub fn new(max_per_host: u32, circuit_policy: CircuitPolicy) -> Self {
+        Self {
+            max_per_host: max_per_host as usize,
+            circuit_policy,
+            timeouts: Timeouts::default(),
+            clearnet: false,
+            rate_limit: None,
+            run_isolation: None,
+            cookies: None,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_streams(
+        self,
+        max_per_host: u32,
+        circuit_policy: CircuitPolicy,
+    ) -> Self {
+        Self {
+            max_per_host: max_per_host as usize,
+            circuit_policy,
+            ..self
+        }
+    }
+
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    pub fn with_clearnet(self, clearnet: bool) -> Self {
+        Self { clearnet, ..self }
+    }
+
+    /// Read bodies at most `rate` bytes per second in all.
+    pub fn with_rate_limit(self, rate: Option<u64>) -> Self {
+        Self {
+            rate_limit: rate.map(RateLimit::new),
+            ..self
+        }
+    }
+
+    /// Send the cookies in `jar`, and keep those that responses set.
+    pub fn with_cookies(self, jar: Option<cookies::Jar>) -> Self {
+        Self {
+            cookies: jar.map(Mutex::new),
+            ..self
+        }
+    }
+
+    /// The request headers for `path` on `host`, with the cookies that
+    /// apply to it unless `headers` already has a `Cookie` header.
+    pub fn with_cookie(
+        &self,
+        host: &str,
+        path: &str,
+        secure: bool,
+        headers: &[(String, String)],
+    ) -> Vec<(String, String)> {
+        let mut headers = headers.to_vec();
+        if let Some(jar) = &self.cookies
+            && !headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+            && let Some(cookie) = jar.lock().expect("lock").header(
+                host,
+                path,
+                secure,
+                chrono::Utc::now().timestamp(),
+            )
+        {
+            headers.push(("Cookie".to_string(), cookie));
+        }
+        headers
+    }
+
+    /// Keep the cookies that a response from `path` on `host` sets,
+    /// saving the jar if they change it.
+    pub fn store_cookies(
+        &self,
+        host: &str,
+        path: &str,
+        headers: &[(String, String)],
+    ) -> Result<()> {
+        let Some(jar) = &self.cookies else {
+            return Ok(());
+        };
+        let now = chrono::Utc::now().timestamp();
+        let mut jar = jar.lock().expect("lock");
+        let mut changed = false;
+        for (_, value) in headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Set-Cookie"))
+        {
+            changed |= jar.store(host, path, value, now);
+        }
+        match changed {
+            true => jar.save(now),
+            false => Ok(()),
+        }
+    }
+
+    /// Keep this run's streams off the circuits of other runs, for
+    /// `--isolate`.
+    pub fn with_run_isolation(self, isolate: bool) -> Self {
+        let run_isolation = isolate
+            .then(|| format!("run-{}", hex::encode(bc_rand::random_data(8))));
+        Self {
+            run_isolation,
+            ..self
+        }
+    }
+
+    /// The isolation group for a new stream to `host` for `url`.
+    pub fn isolation_key(&self, host: &str, url: &str) -> Option<String> {
+        let key = self.circuit_policy.isolation_key(host, url);
+        match &self.run_isolation {
+            Some(run) => {
+                Some(format!("{run}-{}", key.as_deref().unwrap_or("")))
+            }
+            None => key,
+        }
+    }
+
+    fn host(&self, host: &str) -> Arc<HostStreams> {
+        let mut hosts = self.hosts.lock().expect("pool lock");
+        let streams = hosts.entry(host.to_string()).or_insert_with(|| {
+            Arc::new(HostStreams {
+                permits: Arc::new(Semaphore::new(self.max_per_host)),
+                idle: Mutex::new(Vec::new()),
+            })
+        });
+        Arc::clone(streams)
+    }
+
+    /// Wait for a free stream slot for `host`, held until the permit is
+    /// dropped.
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let permits = Arc::clone(&self.host(host).permits);
+        permits
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    pub fn take_idle(&self, host: &str) -> Option<Box<dyn Connection>> {
+        self.host(host).idle.lock().expect("pool lock").pop()
+    }
+
+    /// Keep `stream` for a later request to `host`, unless requests may
+    /// not share a circuit with other requests to it.
+    pub fn put_idle(&self, host: &str, stream: Box<dyn Connection>) {
+        if matches!(
+            self.circuit_policy,
+            CircuitPolicy::Shared | CircuitPolicy::PerHost
+        ) {
+            self.host(host).idle.lock().expect("pool lock").push(stream);
+        }
+    }
+}
+
+/// Parse a `--limit-rate` value: bytes per second, with an optional `K`,
+/// `M`, or `G` suffix for multiples of 1024.
+pub fn parse_rate(spec: &str) -> Result<u64> {
+    let (number, multiple) = match spec.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&spec[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&spec[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&spec[..i], 1 << 30),
+        _ => (spec, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n.saturating_mul(multiple)),
+        _ => Err(anyhow!("invalid rate {spec:?}; use e.g. 200k")),
+    }
+}
+
+/// A token bucket that response bodies draw on for `--limit-rate`, so
+/// that together they are read at most `rate` bytes per second, after a
+/// burst of up to a second's worth.
+pub struct RateLimit {
+    rate: f64,
+    /// Bytes that may be read now (negative once overdrawn), as of when.
+    bucket: Mutex<(f64, std::time::Instant)>,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            bucket: Mutex::new((rate as f64, std::time::Instant::now())),
+        }
+    }
+
+    /// The most to read at once, so no one read overdraws the bucket by
+    /// more than a second.
+    fn chunk(&self) -> usize { (self.rate as usize).max(1) }
+
+    /// Take `n` bytes read from the bucket, returning how long to wait
+    /// before reading more.
+    fn take(&self, n: usize) -> Duration {
+        let mut bucket = self.bucket.lock().expect("rate limit lock");
+        let (tokens, refilled) = &mut *bucket;
+        let now = std::time::Instant::now();
+        *tokens = (*tokens
+            + now.duration_since(*refilled).as_secs_f64() * self.rate)
+            .min(self.rate);
+        *refilled = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// A reader that waits between reads as its [`RateLimit`] says.
+pub struct Throttled<'a, S> {
+    inner: &'a mut S,
+    rate_limit: &'a RateLimit,
+    wait: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<'a, S> Throttled<'a, S> {
+    pub fn new(inner: &'a mut S, rate_limit: &'a RateLimit) -> Self {
+        Self {
+            inner,
+            rate_limit,
+            wait: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<'_, S> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::{future::Future as _, task::Poll};
+
+        let this = &mut *self;
+        if let Some(wait) = &mut this.wait {
+            if wait.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.wait = None;
+        }
+        let limit = buf.len().min(this.rate_limit.chunk());
+        let result = std::pin::Pin::new(&mut *this.inner)
+            .poll_read(cx, &mut buf[..limit]);
+        if let Poll::Ready(Ok(n)) = result {
+            let delay = this.rate_limit.take(n);
+            if !delay.is_zero() {
+                this.wait = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+        result
+    }
+}
+
+/// A reader that fails with `TimedOut` once its stream has sent nothing
+/// for `timeout`, so a stalled response cannot hang a fetch for good.
+pub struct Stalled<'a, S> {
+    inner: &'a mut S,
+    timeout: Duration,
+    deadline: std::pin::Pin<Box<tokio::time::Sleep>>,
+}
+
+impl<'a, S> Stalled<'a, S> {
+    pub fn new(inner: &'a mut S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stalled<'_, S> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::{future::Future as _, task::Poll};
+
+        let this = &mut *self;
+        match std::pin::Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                let next = tokio::time::Instant::now() + this.timeout;
+                this.deadline.as_mut().reset(next);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no data for {:?}", this.timeout),
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::io::AsyncReadExt as _;
+
+    use super::*;
+    use crate::get::{
+        batch::is_transient,
+        http::read_response,
+        tests::{FakeTor, get, ok, onion},
+    };
+
+    #[tokio::test]
+    async fn test_isolate() {
+        let tor = FakeTor::start(&[], |_, _| ok("")).await;
+        let host = onion();
+        let urls = [format!("{host}/a"), format!("{host}/b")];
+        let isolations = async |isolate: bool| {
+            tor.sent.lock().unwrap().clear();
+            let mut args = vec![urls[0].as_str(), &urls[1]];
+            if isolate {
+                args.push("--isolate");
+            }
+            get(&tor, &args).await.unwrap();
+            let sent = tor.sent.lock().unwrap();
+            sent.iter()
+                .map(|sent| sent.isolation.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(isolations(false).await, [None, None]);
+        let first = isolations(true).await;
+        assert!(first.iter().all(Option::is_some));
+        assert_ne!(first[0], first[1]);
+        // Nor do the streams of one run share circuits with another's.
+        let second = isolations(true).await;
+        assert!(second.iter().all(|key| !first.contains(key)));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_times_out() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut client = client.compat();
+        let mut stream = Stalled::new(&mut client, Duration::from_millis(50));
+        tokio::io::AsyncWriteExt::write_all(
+            &mut server,
+            b"HTTP/1.1 200 OK\r\n\r\nstarted",
+        )
+        .await
+        .unwrap();
+        let Err(error) = read_response(&mut stream, false, None, None).await
+        else {
+            panic!("a stalled response should time out");
+        };
+        assert!(is_transient(&error), "{error:#}");
+        assert!(format!("{error:#}").contains("no data for 50ms"));
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn test_pool_limits_streams_per_host() {
+        let pool = Pool::new(2, CircuitPolicy::Shared);
+        let _a = pool.acquire("a.onion").await;
+        let _b = pool.acquire("a.onion").await;
+        let _other = pool.acquire("b.onion").await;
+        let third = tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.acquire("a.onion"),
+        );
+        assert!(third.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuses_idle_streams() {
+        let stream = || -> Box<dyn Connection> {
+            Box::new(tokio::io::duplex(64).0.compat())
+        };
+        let pool = Pool::new(2, CircuitPolicy::Shared);
+        pool.put_idle("a.onion", stream());
+        assert!(pool.take_idle("b.onion").is_none());
+        assert!(pool.take_idle("a.onion").is_some());
+        assert!(pool.take_idle("a.onion").is_none());
+
+        for policy in [CircuitPolicy::PerUrl, CircuitPolicy::PerRequest] {
+            let pool = Pool::new(2, policy);
+            pool.put_idle("a.onion", stream());
+            assert!(pool.take_idle("a.onion").is_none());
+        }
+    }
+
+    #[test]
+    fn test_pool_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        let pool = Pool::new(2, CircuitPolicy::Shared)
+            .with_cookies(Some(cookies::Jar::load(&path).unwrap()));
+        assert!(pool.with_cookie("a.onion", "/", false, &[]).is_empty());
+        let set = [("Set-Cookie".to_string(), "session=s1".to_string())];
+        pool.store_cookies("a.onion", "/login", &set).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("session"));
+        assert_eq!(
+            pool.with_cookie("a.onion", "/account", false, &[]),
+            [("Cookie".to_string(), "session=s1".to_string())]
+        );
+        // A Cookie header given with -H is sent instead.
+        let given = [("cookie".to_string(), "x=1".to_string())];
+        assert_eq!(pool.with_cookie("a.onion", "/", false, &given), given);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("512").unwrap(), 512);
+        assert_eq!(parse_rate("200k").unwrap(), 200 * 1024);
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1g").unwrap(), 1 << 30);
+        for bad in ["", "0", "k", "1.5m", "-1", "10 k", "10kb"] {
+            assert!(parse_rate(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttled() {
+        let rate_limit = RateLimit::new(10_000);
+        // The first second's worth comes at once, and the rest at the rate.
+        let mut data = futures_util::io::Cursor::new(vec![7u8; 13_000]);
+        let mut stream = Throttled::new(&mut data, &rate_limit);
+        let started = std::time::Instant::now();
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body.len(), 13_000);
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(250)
+                && elapsed < Duration::from_secs(2),
+            "{elapsed:?}"
+        );
+    }
+
+    #[test]
+    fn test_isolation_key() {
+        let (a, b) = ("a.onion/x", "a.onion/y");
+        assert_eq!(CircuitPolicy::Shared.isolation_key("a.onion", a), None);
+        assert_eq!(
+            CircuitPolicy::PerHost
+                .isolation_key("a.onion", a)
+                .as_deref(),
+            Some("a.onion")
+        );
+        let policy = CircuitPolicy::PerUrl;
+        assert_eq!(
+            policy.isolation_key("a.onion", a),
+            policy.isolation_key("a.onion", a)
+        );
+        assert_ne!(
+            policy.isolation_key("a.onion", a),
+            policy.isolation_key("a.onion", b)
+        );
+        let policy = CircuitPolicy::PerRequest;
+        assert_ne!(
+            policy.isolation_key("a.onion", a),
+            policy.isolation_key("a.onion", a)
+        );
+
+        // --isolate keeps each run apart from the others.
+        let isolate = || {
+            Pool::new(2, CircuitPolicy::PerUrl)
+                .with_run_isolation(true)
+                .isolation_key("a.onion", a)
+                .unwrap()
+        };
+        let key = isolate();
+        assert!(key.starts_with("run-") && key.len() < 64, "{key}");
+        assert_ne!(key, isolate());
+    }
+}
@@ -0,0 +1,460 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result, anyhow};
+use chrono::{DateTime, NaiveDateTime};
+
+/// The cookies a run sends, and those the services it fetches from set.
+///
+/// Kept in a Netscape cookie file, as curl and wget read and write, so
+/// that a session begun in one run carries over to the next.
+#[derive(Debug, Default)]
+pub struct Jar {
+    /// The file loaded from and saved to, if any.
+    path: Option<PathBuf>,
+    /// Given with `--cookie`, and sent to every host.
+    given: Vec<(String, String)>,
+    cookies: Vec<Cookie>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cookie {
+    /// The host set it, without a leading dot.
+    domain: String,
+    /// Whether it is also sent to subdomains of `domain`.
+    subdomains: bool,
+    path: String,
+    /// Whether it is sent only over HTTPS.
+    secure: bool,
+    /// Seconds since the Unix epoch, or 0 to keep it for the session.
+    expires: i64,
+    name: String,
+    value: String,
+}
+
+impl Jar {
+    /// The jar kept in `path`, empty if there is no such file yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).context(format!("reading {}", path.display()));
+            }
+        };
+        let mut jar = Self::parse(&text)
+            .with_context(|| format!("parsing {}", path.display()))?;
+        jar.path = Some(path.to_path_buf());
+        Ok(jar)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut cookies = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            // curl marks HttpOnly cookies with a prefix on the domain.
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] =
+                fields[..]
+            else {
+                return Err(anyhow!(
+                    "line {}: expected 7 tab-separated fields",
+                    number + 1
+                ));
+            };
+            let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+            cookies.push(Cookie {
+                subdomains: subdomains == "TRUE" && !is_top_level(&domain),
+                domain,
+                path: path.to_string(),
+                secure: secure == "TRUE",
+                expires: expires.parse().map_err(|_| {
+                    anyhow!("line {}: invalid expiry {expires:?}", number + 1)
+                })?,
+                name: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+        Ok(Self {
+            cookies,
+            ..Self::default()
+        })
+    }
+
+    /// Send `NAME=VALUE` pairs, separated by semicolons, to every host.
+    pub fn give(&mut self, cookies: &str) -> Result<()> {
+        for pair in cookies.split(';').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .filter(|(name, _)| !name.trim().is_empty())
+                .ok_or_else(|| {
+                    anyhow!("expected --cookie NAME=VALUE, got: {pair:?}")
+                })?;
+            self.given
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(())
+    }
+
+    /// The `Cookie` header value for a request to `path` on `host`, or
+    /// `None` if no cookie applies.
+    pub fn header(
+        &self,
+        host: &str,
+        path: &str,
+        secure: bool,
+        now: i64,
+    ) -> Option<String> {
+        let host = host.to_ascii_lowercase();
+        let path = path.split(['?', '#']).next().unwrap_or("/");
+        let mut matching: Vec<&Cookie> = self
+            .cookies
+            .iter()
+            .filter(|cookie| {
+                cookie.matches_host(&host)
+                    && path_matches(&cookie.path, path)
+                    && (secure || !cookie.secure)
+                    && !cookie.expired(now)
+            })
+            .collect();
+        // Longer paths first, as RFC 6265 asks.
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let pairs: Vec<String> = self
+            .given
+            .iter()
+            .filter(|(name, _)| !matching.iter().any(|c| c.name == *name))
+            .map(|(name, value)| format!("{name}={value}"))
+            .chain(
+                matching
+                    .iter()
+                    .map(|cookie| format!("{}={}", cookie.name, cookie.value)),
+            )
+            .collect();
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+
+    /// Keep the cookie that a `Set-Cookie` header in a response from
+    /// `path` on `host` sets, or drop it if the header expires it.
+    /// Returns whether the jar changed.
+    pub fn store(
+        &mut self,
+        host: &str,
+        path: &str,
+        set_cookie: &str,
+        now: i64,
+    ) -> bool {
+        let Some(cookie) = Cookie::parse(host, path, set_cookie, now) else {
+            return false;
+        };
+        let same = |c: &Cookie| {
+            c.domain == cookie.domain
+                && c.path == cookie.path
+                && c.name == cookie.name
+        };
+        let before = self.cookies.len();
+        let existing = self.cookies.iter().position(same);
+        if cookie.expired(now) {
+            self.cookies.retain(|c| !same(c));
+            return self.cookies.len() != before;
+        }
+        match existing {
+            Some(i) if self.cookies[i] == cookie => false,
+            Some(i) => {
+                self.cookies[i] = cookie;
+                true
+            }
+            None => {
+                self.cookies.push(cookie);
+                true
+            }
+        }
+    }
+
+    /// Write the jar back to the file it was loaded from, leaving out
+    /// expired cookies.
+    pub fn save(&self, now: i64) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut text = String::from(
+            "# Netscape HTTP Cookie File\n# Written by garner; edit with \
+             care.\n\n",
+        );
+        for cookie in self.cookies.iter().filter(|c| !c.expired(now)) {
+            let flag = |set| if set { "TRUE" } else { "FALSE" };
+            let domain = match cookie.subdomains {
+                true => format!(".{}", cookie.domain),
+                false => cookie.domain.clone(),
+            };
+            text.push_str(&format!(
+                "{domain}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                flag(cookie.subdomains),
+                cookie.path,
+                flag(cookie.secure),
+                cookie.expires,
+                cookie.name,
+                cookie.value
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, text)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+impl Cookie {
+    /// The cookie a `Set-Cookie` header from `path` on `host` sets, or
+    /// `None` if it is malformed or names a domain `host` is not in.
+    fn parse(
+        host: &str,
+        path: &str,
+        set_cookie: &str,
+        now: i64,
+    ) -> Option<Self> {
+        let mut parts = set_cookie.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let host = host.to_ascii_lowercase();
+        let mut cookie = Cookie {
+            domain: host.clone(),
+            subdomains: false,
+            path: default_path(path),
+            secure: false,
+            expires: 0,
+            name: name.to_string(),
+            value: value.trim().trim_matches('"').to_string(),
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain =
+                        value.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_matches(&domain, &host) {
+                        return None;
+                    }
+                    // A cookie for a whole top-level domain, such as
+                    // `onion`, would follow the client to every service
+                    // in it, so it is kept for the host alone.
+                    if !is_top_level(&domain) {
+                        cookie.domain = domain;
+                        cookie.subdomains = true;
+                    }
+                }
+                "path" if value.starts_with('/') => {
+                    cookie.path = value.to_string();
+                }
+                "secure" => cookie.secure = true,
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" if cookie.expires == 0 => {
+                    if let Some(expires) = parse_expires(value) {
+                        // Already past, which is how a cookie is deleted;
+                        // 0 would keep it for the session instead.
+                        cookie.expires = expires.max(1);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Max-Age takes precedence over Expires.
+        if let Some(seconds) = max_age {
+            cookie.expires = match seconds {
+                ..=0 => 1,
+                seconds => now.saturating_add(seconds),
+            };
+        }
+        Some(cookie)
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self.subdomains {
+            true => domain_matches(&self.domain, host),
+            false => self.domain == host,
+        }
+    }
+
+    fn expired(&self, now: i64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+fn domain_matches(domain: &str, host: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Whether `domain` is a bare top-level domain, such as `onion` or `com`,
+/// rather than a name within one.
+fn is_top_level(domain: &str) -> bool {
+    !domain.trim_end_matches('.').contains('.')
+}
+
+/// Whether a cookie for `cookie_path` is sent with a request for `path`.
+fn path_matches(cookie_path: &str, path: &str) -> bool {
+    path == cookie_path
+        || path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/')
+                || path[cookie_path.len()..].starts_with('/'))
+}
+
+/// The path a cookie set without one applies to: the directory of the
+/// request's path.
+fn default_path(path: &str) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+/// Seconds since the Unix epoch for an `Expires` date, in the HTTP
+/// format or the older one with dashes.
+fn parse_expires(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc2822(value)
+        .map(|date| date.timestamp())
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(value, "%a, %d-%b-%Y %H:%M:%S GMT")
+                .map(|date| date.and_utc().timestamp())
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn test_store_and_send() {
+        let mut jar = Jar::default();
+        let host = "abc.onion";
+        assert!(jar.store(host, "/login", "session=s1; Path=/; HttpOnly", NOW));
+        assert!(!jar.store(host, "/login", "session=s1; Path=/", NOW));
+        assert!(jar.store(host, "/app/page", "step=2; Max-Age=60", NOW));
+        assert!(jar.store(host, "/", "theme=dark; Secure", NOW));
+        assert!(!jar.store("other.onion", "/", "x=1; Domain=abc.onion", NOW));
+
+        assert_eq!(
+            jar.header(host, "/app/next?q=1", false, NOW).as_deref(),
+            Some("step=2; session=s1")
+        );
+        assert_eq!(
+            jar.header("ABC.onion", "/", true, NOW).as_deref(),
+            Some("session=s1; theme=dark")
+        );
+        assert_eq!(
+            jar.header(host, "/application", false, NOW).as_deref(),
+            Some("session=s1")
+        );
+        assert_eq!(
+            jar.header(host, "/app/next", false, NOW + 61).as_deref(),
+            Some("session=s1")
+        );
+        assert_eq!(jar.header("xyz.onion", "/", true, NOW), None);
+
+        // Expiring a cookie removes it.
+        assert!(jar.store(
+            host,
+            "/",
+            "session=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+            NOW
+        ));
+        assert_eq!(jar.header(host, "/", false, NOW), None);
+    }
+
+    #[test]
+    fn test_top_level_domain() {
+        let mut jar = Jar::default();
+        assert!(jar.store("abc.onion", "/", "id=x; Domain=onion", NOW));
+        assert!(jar.store("abc.onion", "/", "seen=1; Domain=.onion", NOW));
+        assert_eq!(
+            jar.header("abc.onion", "/", false, NOW).as_deref(),
+            Some("id=x; seen=1")
+        );
+        assert_eq!(jar.header("xyz.onion", "/", false, NOW), None);
+        assert_eq!(jar.header("www.abc.onion", "/", false, NOW), None);
+
+        let mut jar = Jar::default();
+        assert!(jar.store("example.com", "/", "id=x; Domain=com", NOW));
+        assert_eq!(jar.header("other.com", "/", false, NOW), None);
+
+        // Nor does a jar file widen one to the whole domain.
+        let jar = Jar::parse(".onion\tTRUE\t/\tFALSE\t0\tid\tx\n").unwrap();
+        assert_eq!(jar.header("abc.onion", "/", false, NOW), None);
+    }
+
+    #[test]
+    fn test_given() {
+        let mut jar = Jar::default();
+        jar.give("lang=en; session=given").unwrap();
+        jar.store("abc.onion", "/", "session=set", NOW);
+        assert_eq!(
+            jar.header("abc.onion", "/", false, NOW).as_deref(),
+            Some("lang=en; session=set")
+        );
+        assert_eq!(
+            jar.header("xyz.onion", "/", false, NOW).as_deref(),
+            Some("lang=en; session=given")
+        );
+        assert!(jar.give("novalue").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        let mut jar = Jar::load(&path).unwrap();
+        jar.store("abc.onion", "/", "session=s1", NOW);
+        jar.store("abc.onion", "/", "id=7; Domain=abc.onion; Max-Age=60", NOW);
+        jar.store("abc.onion", "/", "old=1; Max-Age=1", NOW);
+        jar.save(NOW + 30).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("abc.onion\tFALSE\t/\tFALSE\t0\tsession\ts1\n"));
+        assert!(
+            text.contains(".abc.onion\tTRUE\t/\tFALSE\t1700000060\tid\t7\n")
+        );
+        assert!(!text.contains("old"));
+        let jar = Jar::load(&path).unwrap();
+        assert_eq!(
+            jar.header("www.abc.onion", "/", false, NOW).as_deref(),
+            Some("id=7")
+        );
+        assert!(Jar::parse("abc.onion\tFALSE\t/\n").is_err());
+        assert_eq!(
+            Jar::parse("#HttpOnly_abc.onion\tFALSE\t/\tFALSE\t0\ta\tb\n")
+                .unwrap()
+                .header("abc.onion", "/", false, NOW)
+                .as_deref(),
+            Some("a=b")
+        );
+    }
+
+    #[test]
+    fn test_parse_expires() {
+        assert_eq!(
+            parse_expires("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(1_445_412_480)
+        );
+        assert_eq!(
+            parse_expires("Wed, 21-Oct-2015 07:28:00 GMT"),
+            Some(1_445_412_480)
+        );
+        assert_eq!(parse_expires("soon"), None);
+    }
+}
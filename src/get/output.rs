@@ -0,0 +1,690 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use super::{Format, GetArgs, HttpStatus, Plan, http::Response, split_url};
+
+/// Write each URL's body to its output, or all of them as one document on
+/// stdout for `--format json` and `multipart`.  A binary body meant for
+/// the terminal is left out, and counted among `errors`.
+pub fn write_bodies(
+    args: &GetArgs,
+    plan: &Plan,
+    bodies: &[Option<Vec<u8>>],
+    terminal: bool,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<()> {
+    use std::io::Write;
+    let mut out = std::io::stdout().lock();
+    if args.format == Format::Json {
+        let records: Vec<&[u8]> =
+            bodies.iter().flatten().map(Vec::as_slice).collect();
+        out.write_all(b"[")?;
+        out.write_all(&records.join(&b',')[..])?;
+        out.write_all(b"]\n")?;
+    }
+    if let Some(boundary) = &plan.boundary {
+        out.write_all(
+            format!(
+                "MIME-Version: 1.0\r\nContent-Type: multipart/mixed; \
+                 boundary=\"{boundary}\"\r\n\r\n"
+            )
+            .as_bytes(),
+        )?;
+        for part in bodies.iter().flatten() {
+            out.write_all(part)?;
+        }
+        out.write_all(format!("--{boundary}--\r\n").as_bytes())?;
+    }
+    if plan.collated {
+        return Ok(());
+    }
+    let mut first = true;
+    for ((url, output), body) in plan.urls.iter().zip(&plan.outputs).zip(bodies)
+    {
+        let Some(body) = body else {
+            continue;
+        };
+        match output {
+            Output::File(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!("creating {}", parent.display())
+                    })?;
+                }
+                std::fs::write(path, body)
+                    .with_context(|| format!("writing {}", path.display()))?
+            }
+            Output::Stdout if terminal && is_binary(body) => {
+                errors.push(anyhow!(
+                    "{url}: not writing binary output to the terminal; use \
+                     --output FILE, -O, or --output - to write it anyway"
+                ));
+            }
+            // JSON records are lines of their own.
+            Output::Stdout | Output::ForcedStdout if plan.json => {
+                out.write_all(body)?;
+                out.write_all(b"\n")?;
+            }
+            Output::Stdout | Output::ForcedStdout => {
+                if !first {
+                    out.write_all(b"\n")?;
+                }
+                first = false;
+                out.write_all(body)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the `--sha256` and `--blake3` digests of the bodies, in the
+/// format of `sha256sum`, to their files or stderr.
+pub fn write_digests(
+    args: &GetArgs,
+    digests: &[(&String, Digests)],
+) -> Result<()> {
+    type Pick = fn(&Digests) -> Option<&String>;
+    let algorithms: [(_, Pick); 2] = [
+        (&args.sha256, |digests| digests.sha256.as_ref()),
+        (&args.blake3, |digests| digests.blake3.as_ref()),
+    ];
+    for (destination, pick) in algorithms {
+        let Some(destination) = destination else {
+            continue;
+        };
+        let lines: String = digests
+            .iter()
+            .filter_map(|(url, digests)| {
+                pick(digests).map(|digest| format!("{digest}  {url}\n"))
+            })
+            .collect();
+        match destination {
+            Some(path) => std::fs::write(path, lines)
+                .with_context(|| format!("writing {}", path.display()))?,
+            None => eprint!("{lines}"),
+        }
+    }
+    Ok(())
+}
+
+/// The `--json` record of `url`, fetched in the end from `final_url`,
+/// given the time spent waiting for Tor to bootstrap and on the URL in
+/// all.  A UTF-8 body is inlined as `body`, and any other as
+/// `body_base64`.
+pub fn json_record(
+    url: &str,
+    final_url: &str,
+    response: &Response,
+    (bootstrap, total): (Duration, Duration),
+) -> Vec<u8> {
+    use base64::Engine as _;
+    use sha2::Digest as _;
+
+    let mut record = serde_json::json!({
+        "url": url,
+        "final_url": final_url,
+        "status": response.status,
+        "status_line": response.status_line,
+        "headers": response.headers,
+        "timing": {
+            "bootstrap": bootstrap.as_secs_f64(),
+            "connect": response.timing.connect.map(|d| d.as_secs_f64()),
+            "first_byte": response.timing.first_byte.as_secs_f64(),
+            "total": total.as_secs_f64(),
+        },
+        "size": response.body.len(),
+        "sha256": hex::encode(sha2::Sha256::digest(&response.body)),
+    });
+    match std::str::from_utf8(&response.body) {
+        Ok(text) => record["body"] = text.into(),
+        Err(_) => {
+            record["body_base64"] = base64::engine::general_purpose::STANDARD
+                .encode(&response.body)
+                .into();
+        }
+    }
+    record.to_string().into_bytes()
+}
+
+/// The part of a `--format multipart` stream for `url`, with the response's
+/// `Content-Type`, ending in the CRLF that the next boundary line belongs
+/// to.
+pub fn multipart_part(
+    boundary: &str,
+    url: &str,
+    response: &Response,
+) -> Vec<u8> {
+    let content_type = response
+        .header("content-type")
+        .unwrap_or("application/octet-stream");
+    let mut part = format!(
+        "--{boundary}\r\nContent-Location: {url}\r\nContent-Type: \
+         {content_type}\r\nContent-Length: {}\r\n\r\n",
+        response.body.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(&response.body);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// The `--json` record of a URL that failed, with the status of an HTTP
+/// error.
+pub fn json_error(url: &str, error: &anyhow::Error) -> Vec<u8> {
+    let mut record = serde_json::json!({
+        "url": url,
+        "error": format!("{error:#}"),
+    });
+    if let Some(HttpStatus(status, ..)) = error.downcast_ref() {
+        record["status"] = (*status).into();
+    }
+    record.to_string().into_bytes()
+}
+
+/// Where `garner get` writes a URL's body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Stdout, unless the body is binary and stdout is a terminal.
+    Stdout,
+    /// Stdout, whatever the body (`--output -`).
+    ForcedStdout,
+    File(std::path::PathBuf),
+}
+
+/// Match each of `urls` with its `--output`, in order, and give the rest
+/// a file named after their path with `-O`, or stdout.
+pub fn plan_outputs(
+    outputs: &[std::path::PathBuf],
+    remote_name: bool,
+    urls: &[String],
+) -> Result<Vec<Output>> {
+    if outputs.len() > urls.len() {
+        return Err(anyhow!(
+            "{} --output files given for {} URLs",
+            outputs.len(),
+            urls.len()
+        ));
+    }
+    urls.iter()
+        .enumerate()
+        .map(|(i, url)| match outputs.get(i) {
+            Some(path) if path.as_os_str() == "-" => Ok(Output::ForcedStdout),
+            Some(path) => Ok(Output::File(path.clone())),
+            None if remote_name => Ok(Output::File(file_name_for(url)?.into())),
+            None => Ok(Output::Stdout),
+        })
+        .collect()
+}
+
+/// The file name `-O` gives the body of `url`: the last segment of its
+/// path, or `index.html` for a directory.
+fn file_name_for(url: &str) -> Result<String> {
+    let (_, path) = split_url(url);
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    match path.rsplit('/').next().unwrap_or("") {
+        "" => Ok("index.html".to_string()),
+        "." | ".." => Err(anyhow!("{url}: no file name in the URL path")),
+        name => Ok(name.to_string()),
+    }
+}
+
+/// Where `--output-dir` puts the body of `url`: its path under `dir`,
+/// with `index.html` for a directory.
+pub fn mirror_path(
+    dir: &std::path::Path,
+    url: &str,
+) -> Result<std::path::PathBuf> {
+    let (_, path) = split_url(url);
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let mut file = dir.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." {
+            return Err(anyhow!(
+                "{url}: refusing to write outside the output directory"
+            ));
+        }
+        file.push(segment);
+    }
+    if path.is_empty() || path.ends_with('/') {
+        file.push("index.html");
+    }
+    Ok(file)
+}
+
+/// The URLs or paths in an `--input-file`: one per line, skipping blank
+/// lines and comment lines starting with `#`.
+pub fn url_list(text: &str) -> impl Iterator<Item = String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+/// Whether a body would garble a terminal: it is not UTF-8 text, or has
+/// NUL bytes.
+pub fn is_binary(body: &[u8]) -> bool {
+    body.contains(&0) || std::str::from_utf8(body).is_err()
+}
+
+pub fn write_out(out: &mut dyn std::io::Write, data: &[u8]) -> Result<()> {
+    out.write_all(data)
+        .map_err(|e| anyhow!("writing output: {e}"))
+}
+
+/// Which digests `--sha256` and `--blake3` ask for.
+#[derive(Clone, Copy, Default)]
+pub struct Hashing {
+    pub sha256: bool,
+    pub blake3: bool,
+}
+
+impl Hashing {
+    pub fn any(self) -> bool { self.sha256 || self.blake3 }
+
+    fn hasher(self) -> Hasher {
+        Hasher {
+            sha256: self.sha256.then(sha2::Sha256::default),
+            blake3: self.blake3.then(blake3::Hasher::new),
+        }
+    }
+
+    pub fn digest(self, body: &[u8]) -> Digests {
+        let mut hasher = self.hasher();
+        hasher.update(body);
+        hasher.finish()
+    }
+}
+
+/// Hashes a body as it is written.
+#[derive(Default)]
+struct Hasher {
+    sha256: Option<sha2::Sha256>,
+    blake3: Option<blake3::Hasher>,
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        use sha2::Digest as _;
+
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+        if let Some(blake3) = &mut self.blake3 {
+            blake3.update(data);
+        }
+    }
+
+    fn finish(&self) -> Digests {
+        use sha2::Digest as _;
+
+        Digests {
+            sha256: self
+                .sha256
+                .as_ref()
+                .map(|sha256| hex::encode(sha256.clone().finalize())),
+            blake3: self
+                .blake3
+                .as_ref()
+                .map(|blake3| blake3.finalize().to_hex().to_string()),
+        }
+    }
+}
+
+/// The hex digests of a body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digests {
+    sha256: Option<String>,
+    blake3: Option<String>,
+}
+
+/// A body's output, opened once the body starts to arrive so that a large
+/// file is written as it comes rather than held in memory.  A file left
+/// partly written by a fetch that failed is removed.
+pub struct Sink<'a> {
+    output: &'a Output,
+    writer: Option<Box<dyn std::io::Write + Send>>,
+    finished: bool,
+    hashing: Hashing,
+    hasher: Hasher,
+}
+
+impl<'a> Sink<'a> {
+    pub fn new(output: &'a Output) -> Self {
+        Self {
+            output,
+            writer: None,
+            finished: false,
+            hashing: Hashing::default(),
+            hasher: Hasher::default(),
+        }
+    }
+
+    /// Also hash what is written, for [`Sink::digests`].
+    pub fn with_hashing(mut self, hashing: Hashing) -> Self {
+        self.hashing = hashing;
+        self.hasher = hashing.hasher();
+        self
+    }
+
+    /// The digests of the body written so far.
+    pub fn digests(&self) -> Digests { self.hasher.finish() }
+
+    /// Get ready to write the body again from the start, if the output
+    /// allows it: a file is removed to be written afresh, but stdout
+    /// cannot take back what it was sent.
+    pub fn restart(&mut self) -> bool {
+        match (self.output, &self.writer) {
+            (_, None) => true,
+            (Output::File(path), Some(_)) => {
+                self.writer = None;
+                self.hasher = self.hashing.hasher();
+                let _ = std::fs::remove_file(path);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Flush the body, returning whether any of it was written here.
+    pub fn finish(&mut self) -> Result<bool> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(false);
+        };
+        writer.flush().context("writing output")?;
+        self.finished = true;
+        Ok(true)
+    }
+}
+
+impl std::io::Write for Sink<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                let opened: Box<dyn std::io::Write + Send> = match self.output {
+                    Output::File(path) => {
+                        if let Some(parent) = path.parent() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                        let file = std::fs::File::create(path)?;
+                        Box::new(std::io::BufWriter::new(file))
+                    }
+                    Output::Stdout | Output::ForcedStdout => {
+                        Box::new(std::io::stdout())
+                    }
+                };
+                self.writer.insert(opened)
+            }
+        };
+        let written = writer.write(data)?;
+        self.hasher.update(&data[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.as_mut().map_or(Ok(()), |writer| writer.flush())
+    }
+}
+
+impl Drop for Sink<'_> {
+    fn drop(&mut self) {
+        if let (Some(_), false, Output::File(path)) =
+            (&self.writer, self.finished, self.output)
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Progress display for `garner get` (interactive only): a spinner for
+/// connecting, with a line below it for each download in progress.
+pub struct Progress {
+    pub multi: MultiProgress,
+    pub spinner: ProgressBar,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        let multi = MultiProgress::new();
+        let spinner = multi.add(ProgressBar::new_spinner());
+        spinner.set_prefix(" 0s");
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.yellow} {prefix} Connecting to the Tor network...")
+                .expect("valid template"),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        Self { multi, spinner }
+    }
+
+    /// Add a line for a download of `path`: a byte counter, until the
+    /// response says how long the body is.
+    pub fn download(&self, path: &str) -> Download {
+        let bar = self.multi.add(ProgressBar::no_length());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "  {prefix} {bytes} {binary_bytes_per_sec}",
+            )
+            .expect("valid template"),
+        );
+        bar.set_prefix(path.to_string());
+        Download(bar)
+    }
+}
+
+/// A download's progress line, removed when the download ends.
+pub struct Download(pub ProgressBar);
+
+impl Drop for Download {
+    fn drop(&mut self) { self.0.finish_and_clear(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get::{
+        http::Timing,
+        tests::{FakeTor, get, onion},
+    };
+
+    #[tokio::test]
+    async fn test_include() {
+        let tor = FakeTor::start(&[], |_, _| {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-A: b\r\n\r\nhello"
+                .to_vec()
+        })
+        .await;
+        let host = onion();
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let read = |name: &str| std::fs::read_to_string(path(name)).unwrap();
+        let out = path("out").to_str().unwrap().to_string();
+        get(&tor, &["-i", "-o", &out, &host]).await.unwrap();
+        assert_eq!(
+            read("out"),
+            "HTTP/1.1 200 OK\nContent-Length: 5\nX-A: b\n\nhello"
+        );
+
+        // --dump-header writes the head alone, and the body stays as it is.
+        let body = path("body").to_str().unwrap().to_string();
+        let head = path("head").to_str().unwrap().to_string();
+        get(&tor, &["--dump-header", &head, "-o", &body, &host])
+            .await
+            .unwrap();
+        assert_eq!(read("body"), "hello");
+        assert_eq!(
+            read("head"),
+            "HTTP/1.1 200 OK\nContent-Length: 5\nX-A: b\n"
+        );
+    }
+
+    #[test]
+    fn test_multipart_part() {
+        let mut response = Response {
+            status: 200,
+            status_line: "HTTP/1.1 200 OK".to_string(),
+            headers: vec![(
+                "Content-Type".to_string(),
+                "text/plain".to_string(),
+            )],
+            body: b"hello".to_vec(),
+            timing: Timing {
+                connect: None,
+                first_byte: Duration::ZERO,
+            },
+        };
+        assert_eq!(
+            multipart_part("b1", "a.onion/x", &response),
+            b"--b1\r\nContent-Location: a.onion/x\r\n\
+              Content-Type: text/plain\r\nContent-Length: 5\r\n\r\n\
+              hello\r\n"
+        );
+        response.headers.clear();
+        response.body = vec![0xff];
+        let part = multipart_part("b1", "a.onion/y", &response);
+        assert!(part.ends_with(
+            b"application/octet-stream\r\n\
+              Content-Length: 1\r\n\r\n\xff\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_json_record() {
+        let mut response = Response {
+            status: 200,
+            status_line: "HTTP/1.1 200 OK".to_string(),
+            headers: vec![(
+                "Content-Type".to_string(),
+                "text/plain".to_string(),
+            )],
+            body: b"hello".to_vec(),
+            timing: Timing {
+                connect: None,
+                first_byte: Duration::from_millis(250),
+            },
+        };
+        let timings = (Duration::from_secs(2), Duration::from_secs(3));
+        let record = |response: &Response| -> serde_json::Value {
+            let json = json_record(
+                "http://a.onion/",
+                "http://a.onion/b",
+                response,
+                timings,
+            );
+            serde_json::from_slice(&json).unwrap()
+        };
+        let json = record(&response);
+        assert_eq!(json["final_url"], "http://a.onion/b");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["headers"][0][1], "text/plain");
+        assert_eq!(json["timing"]["bootstrap"], 2.0);
+        assert!(json["timing"]["connect"].is_null());
+        assert_eq!(json["timing"]["first_byte"], 0.25);
+        assert_eq!(json["size"], 5);
+        assert_eq!(
+            json["sha256"],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(json["body"], "hello");
+
+        response.body = vec![0xff, 0x00];
+        let json = record(&response);
+        assert!(json.get("body").is_none());
+        assert_eq!(json["body_base64"], "/wA=");
+
+        let error = anyhow::Error::new(HttpStatus(
+            404,
+            "HTTP/1.1 404 Not Found".to_string(),
+            Vec::new(),
+        ))
+        .context("http://a.onion/");
+        let json: serde_json::Value =
+            serde_json::from_slice(&json_error("http://a.onion/", &error))
+                .unwrap();
+        assert_eq!(json["status"], 404);
+        assert!(json["error"].as_str().unwrap().contains("404"));
+    }
+
+    #[test]
+    fn test_plan_outputs() {
+        let urls: Vec<String> =
+            ["x.onion/a/b.tar.gz?v=2", "x.onion/docs/", "x.onion"]
+                .map(String::from)
+                .to_vec();
+        assert_eq!(
+            plan_outputs(&["out.tgz".into(), "-".into()], true, &urls).unwrap(),
+            [
+                Output::File("out.tgz".into()),
+                Output::ForcedStdout,
+                Output::File("index.html".into()),
+            ]
+        );
+        assert_eq!(
+            plan_outputs(&[], true, &urls[..1]).unwrap(),
+            [Output::File("b.tar.gz".into())]
+        );
+        assert_eq!(
+            plan_outputs(&[], false, &urls[..1]).unwrap(),
+            [Output::Stdout]
+        );
+        assert!(
+            plan_outputs(&["a".into(), "b".into()], false, &urls[..1]).is_err()
+        );
+        assert!(file_name_for("x.onion/a/..").is_err());
+
+        let dir = std::path::Path::new("mirror");
+        assert_eq!(
+            mirror_path(dir, "x.onion/docs/a.txt?v=1").unwrap(),
+            dir.join("docs/a.txt")
+        );
+        assert_eq!(
+            mirror_path(dir, "x.onion/docs/").unwrap(),
+            dir.join("docs/index.html")
+        );
+        assert_eq!(
+            mirror_path(dir, "x.onion").unwrap(),
+            dir.join("index.html")
+        );
+        assert!(mirror_path(dir, "x.onion/a/../../etc/passwd").is_err());
+
+        assert!(!is_binary("héllo\n".as_bytes()));
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\0"));
+        assert!(is_binary(&[0xff, 0xfe]));
+    }
+
+    #[test]
+    fn test_hashing() {
+        let digests = Hashing {
+            sha256: true,
+            blake3: true,
+        }
+        .digest(b"abc");
+        assert_eq!(
+            digests.sha256.as_deref(),
+            Some(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            )
+        );
+        assert_eq!(
+            digests.blake3.as_deref(),
+            Some(
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+            )
+        );
+        let digests = Hashing::default().digest(b"abc");
+        assert_eq!((digests.sha256, digests.blake3), (None, None));
+    }
+
+    #[test]
+    fn test_url_list() {
+        let text = "# status pages\n/index.html\n\n  /docs/#intro  \r\n\
+                    \t# indented comment\nhttp://abc.onion/a b\n";
+        assert_eq!(
+            url_list(text).collect::<Vec<_>>(),
+            ["/index.html", "/docs/#intro", "http://abc.onion/a b"]
+        );
+        assert_eq!(url_list("\n# none\n").count(), 0);
+    }
+}
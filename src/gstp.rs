@@ -0,0 +1,227 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow};
+use bc_components::{PrivateKeys, PublicKeys};
+use bc_envelope::prelude::*;
+use sha2::{Digest as _, Sha256};
+
+/// Path that accepts sealed GSTP requests by POST.
+pub const PATH: &str = "/.gstp";
+
+/// Largest sealed request accepted, in bytes.
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// A Gordian Sealed Transaction Protocol endpoint.
+///
+/// Requests arrive signed by their sender and encrypted to the service's
+/// key bundle.  The sender names its `ur:crypto-pubkeys` in a `sender`
+/// assertion, which is used to verify the request and to seal the
+/// response back to it.  The endpoint holds no state between requests:
+/// each response returns the sender's own `senderContinuation` as its
+/// `recipientContinuation`, as GSTP requires.
+///
+/// Two functions are provided: `store(key:, value:)` saves an envelope
+/// and `retrieve(key:)` returns it.  Values are kept per sender, so one
+/// client cannot read or overwrite another's.
+pub struct Endpoint {
+    keys: PrivateKeys,
+    public_keys: PublicKeys,
+    store: PathBuf,
+}
+
+impl Endpoint {
+    pub fn new(keys: PrivateKeys, store: PathBuf) -> Result<Self> {
+        let public_keys = keys.public_keys().map_err(|e| anyhow!("{e}"))?;
+        std::fs::create_dir_all(&store).with_context(|| {
+            format!("creating GSTP store {}", store.display())
+        })?;
+        Ok(Self {
+            keys,
+            public_keys,
+            store,
+        })
+    }
+
+    /// Open a sealed request and return the sealed response.  Fails only
+    /// when the request cannot be opened at all; errors from the called
+    /// function are returned to the sender as failure responses.
+    pub fn handle(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let sealed = Envelope::try_from_cbor_data(sealed.to_vec())
+            .context("request is not an envelope")?;
+        let signed = sealed
+            .decrypt_to_recipient(&self.keys)
+            .context("request is not sealed to this service")?;
+        let sender: PublicKeys = signed
+            .try_unwrap()?
+            .object_for_predicate(known_values::SENDER)?
+            .extract_subject()
+            .context("request sender is not a ur:crypto-pubkeys bundle")?;
+        let request_envelope = signed
+            .verify(&sender)
+            .context("request is not signed by its sender")?;
+        let continuation = request_envelope
+            .optional_object_for_predicate(known_values::SENDER_CONTINUATION)?
+            .filter(|c| c.subject().is_encrypted())
+            .ok_or_else(|| anyhow!("request has no encrypted continuation"))?;
+        let request = Request::try_from(request_envelope)?;
+
+        let response = match self.call(&sender, &request) {
+            Ok(Some(result)) => {
+                Response::new_success(request.id()).with_result(result)
+            }
+            Ok(None) => Response::new_success(request.id()),
+            Err(e) => {
+                Response::new_failure(request.id()).with_error(format!("{e:#}"))
+            }
+        };
+        let sealed = response
+            .into_envelope()
+            .add_assertion(known_values::SENDER, self.public_keys.clone())
+            .add_assertion(known_values::RECIPIENT_CONTINUATION, continuation)
+            .sign(&self.keys)
+            .wrap()
+            .encrypt_subject_to_recipient(&sender)?;
+        Ok(sealed.to_cbor_data())
+    }
+
+    fn call(
+        &self,
+        sender: &PublicKeys,
+        request: &Request,
+    ) -> Result<Option<Envelope>> {
+        let body = request.body();
+        let function = body.function();
+        let key: String = body
+            .extract_object_for_parameter("key")
+            .context("missing key parameter")?;
+        let path = self
+            .store
+            .join(hash(&sender.to_cbor_data()))
+            .join(hash(key.as_bytes()));
+        match function.named_name().as_deref() {
+            Some("store") => {
+                let value = body
+                    .object_for_parameter("value")
+                    .context("missing value parameter")?;
+                std::fs::create_dir_all(path.parent().expect("has parent"))?;
+                std::fs::write(&path, value.to_cbor_data())
+                    .with_context(|| format!("writing {}", path.display()))?;
+                Ok(None)
+            }
+            Some("retrieve") => {
+                let data = match std::fs::read(&path) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(anyhow!("no value stored for {key:?}"));
+                    }
+                    result => result.with_context(|| {
+                        format!("reading {}", path.display())
+                    })?,
+                };
+                Ok(Some(Envelope::try_from_cbor_data(data)?))
+            }
+            _ => Err(anyhow!("unknown function {}", function.name())),
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> String { hex::encode(Sha256::digest(data)) }
+
+#[cfg(test)]
+mod tests {
+    use bc_components::{
+        ARID, Ed25519PrivateKey, EncapsulationPrivateKey, SigningPrivateKey,
+        X25519PrivateKey,
+    };
+
+    use super::*;
+
+    fn new_keys() -> PrivateKeys {
+        PrivateKeys::with_keys(
+            SigningPrivateKey::new_ed25519(Ed25519PrivateKey::new()),
+            EncapsulationPrivateKey::X25519(X25519PrivateKey::new()),
+        )
+    }
+
+    /// Seal `request` the way a GSTP client does, send it, and open the
+    /// response.
+    fn transact(
+        endpoint: &Endpoint,
+        client: &PrivateKeys,
+        request: Request,
+    ) -> Response {
+        let client_public = client.public_keys().unwrap();
+        let continuation = Envelope::null()
+            .wrap()
+            .add_assertion(known_values::ID, request.id())
+            .encrypt_to_recipient(&client_public);
+        let sealed = request
+            .into_envelope()
+            .add_assertion(known_values::SENDER, client_public)
+            .add_assertion(known_values::SENDER_CONTINUATION, continuation)
+            .sign(client)
+            .wrap()
+            .encrypt_subject_to_recipient(&endpoint.public_keys)
+            .unwrap();
+
+        let reply = endpoint.handle(&sealed.to_cbor_data()).unwrap();
+        let signed = Envelope::try_from_cbor_data(reply)
+            .unwrap()
+            .decrypt_to_recipient(client)
+            .unwrap();
+        let response = signed.verify(&endpoint.public_keys).unwrap();
+        let returned = response
+            .object_for_predicate(known_values::RECIPIENT_CONTINUATION)
+            .unwrap()
+            .decrypt_to_recipient(client)
+            .unwrap();
+        let id: ARID = returned
+            .extract_object_for_predicate(known_values::ID)
+            .unwrap();
+        let response = Response::try_from(response).unwrap();
+        assert_eq!(response.id(), Some(id));
+        response
+    }
+
+    #[test]
+    fn test_store_and_retrieve() {
+        bc_envelope::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint =
+            Endpoint::new(new_keys(), dir.path().to_path_buf()).unwrap();
+        let alice = new_keys();
+        let bob = new_keys();
+
+        let store = Request::new("store", ARID::new())
+            .with_parameter("key", "greeting")
+            .with_parameter("value", "hello");
+        assert!(transact(&endpoint, &alice, store).is_ok());
+
+        let retrieve = || {
+            Request::new("retrieve", ARID::new())
+                .with_parameter("key", "greeting")
+        };
+        let response = transact(&endpoint, &alice, retrieve());
+        let value: String =
+            response.result().unwrap().extract_subject().unwrap();
+        assert_eq!(value, "hello");
+        // Values are kept per sender.
+        assert!(transact(&endpoint, &bob, retrieve()).is_err());
+
+        let unknown = Request::new("delete", ARID::new())
+            .with_parameter("key", "greeting");
+        assert!(transact(&endpoint, &alice, unknown).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsealed_request() {
+        bc_envelope::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint =
+            Endpoint::new(new_keys(), dir.path().to_path_buf()).unwrap();
+        let plain = Request::new("retrieve", ARID::new())
+            .with_parameter("key", "greeting")
+            .into_envelope();
+        assert!(endpoint.handle(&plain.to_cbor_data()).is_err());
+        assert!(endpoint.handle(b"not cbor").is_err());
+    }
+}
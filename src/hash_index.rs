@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::archive::Archive;
+
+/// Request path prefix for fetching a file by the SHA-256 digest of its
+/// content, in lowercase hex.
+pub const PATH_PREFIX: &str = "/.by-hash/";
+
+/// `Cache-Control` for content-addressed responses, which never change.
+pub const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// An index of the files under a docroot (or the entries in a docroot
+/// archive) by content digest, built at startup.
+pub struct HashIndex {
+    files: HashMap<String, Entry>,
+}
+
+struct Entry {
+    /// File path, or entry name within an archive.
+    path: PathBuf,
+    /// Size and modification time when hashed, so a file that has
+    /// changed since is no longer served under its old digest.  Archive
+    /// entries, which cannot change, have none.
+    stamp: Option<(u64, Option<SystemTime>)>,
+}
+
+impl HashIndex {
+    /// Hash every file under `docroot`.  Symlinked directories are not
+    /// followed.
+    pub fn build(docroot: &Path) -> Result<Self> {
+        let mut index = Self {
+            files: HashMap::new(),
+        };
+        index.add_dir(docroot)?;
+        Ok(index)
+    }
+
+    /// Hash every entry in `archive`.
+    pub fn build_archive(archive: &Archive) -> Result<Self> {
+        let mut files = HashMap::new();
+        for name in archive.names()? {
+            let data = archive.read(&name)?;
+            files.insert(
+                hex::encode(Sha256::digest(&data)),
+                Entry {
+                    path: PathBuf::from(name),
+                    stamp: None,
+                },
+            );
+        }
+        Ok(Self { files })
+    }
+
+    fn add_dir(&mut self, dir: &Path) -> Result<()> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("reading {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                self.add_dir(&path)?;
+            } else if path.is_file() {
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let meta = std::fs::metadata(&path)?;
+                self.files.insert(
+                    hex::encode(Sha256::digest(&data)),
+                    Entry {
+                        path,
+                        stamp: Some((meta.len(), meta.modified().ok())),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of distinct contents indexed.
+    pub fn len(&self) -> usize { self.files.len() }
+
+    /// The file (or archive entry) for a `/.by-hash/<digest>` request
+    /// path, if the digest is known and the file is unchanged since it was
+    /// indexed.
+    pub fn resolve(&self, request_path: &str) -> Option<&Path> {
+        let digest = request_path.strip_prefix(PATH_PREFIX)?;
+        let entry = self.files.get(&digest.to_ascii_lowercase())?;
+        if let Some(stamp) = entry.stamp {
+            let meta = std::fs::metadata(&entry.path).ok()?;
+            if !meta.is_file() || (meta.len(), meta.modified().ok()) != stamp {
+                return None;
+            }
+        }
+        Some(&entry.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_by_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/a.txt"), "hello").unwrap();
+        let index = HashIndex::build(dir.path()).unwrap();
+        assert_eq!(index.len(), 1);
+
+        let digest = hex::encode(Sha256::digest(b"hello"));
+        let path = format!("{PATH_PREFIX}{digest}");
+        assert_eq!(index.resolve(&path), Some(&*dir.path().join("sub/a.txt")));
+        let upper = format!("{PATH_PREFIX}{}", digest.to_ascii_uppercase());
+        assert!(index.resolve(&upper).is_some());
+        assert!(
+            index
+                .resolve(&format!("{PATH_PREFIX}{}", "0".repeat(64)))
+                .is_none()
+        );
+
+        std::fs::write(dir.path().join("sub/a.txt"), "changed").unwrap();
+        assert!(index.resolve(&path).is_none());
+    }
+}
@@ -0,0 +1,271 @@
+use std::path::Path;
+
+use anyhow::{Context as _, Result, anyhow};
+
+use crate::key;
+
+/// C Tor's file for a service's expanded secret key.
+pub const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+
+/// C Tor's file for a service's public key.
+pub const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+
+/// C Tor's file for a service's address, as `<ADDRESS>.onion` and a
+/// newline.
+pub const HOSTNAME_FILE: &str = "hostname";
+
+/// C Tor's directory of the client keys a service in restricted
+/// discovery mode accepts.
+pub const AUTHORIZED_CLIENTS_DIR: &str = "authorized_clients";
+
+/// The 32-byte tag before the 64-byte key in a secret key file.
+const SECRET_KEY_HEADER: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+
+/// The 32-byte tag before the 32-byte key in a public key file.
+const PUBLIC_KEY_HEADER: &[u8; 32] = b"== ed25519v1-public: type0 ==\0\0\0";
+
+/// Read the key of the C Tor onion service in `dir` (its
+/// `HiddenServiceDir`), and return it as a `tor:` key.  If the directory
+/// also has a public key file, it must match.
+pub fn import(dir: &Path) -> Result<String> {
+    let secret: [u8; 64] =
+        read_key(&dir.join(SECRET_KEY_FILE), SECRET_KEY_HEADER)?;
+    let tor_key = key::tor_key_from_expanded(secret)?;
+    let public_path = dir.join(PUBLIC_KEY_FILE);
+    if public_path.exists() {
+        let public: [u8; 32] = read_key(&public_path, PUBLIC_KEY_HEADER)?;
+        let keypair = key::parse_expanded_keypair(&tor_key)?;
+        if keypair.public().to_bytes() != public {
+            return Err(anyhow!(
+                "{} does not match {}",
+                public_path.display(),
+                SECRET_KEY_FILE
+            ));
+        }
+    }
+    Ok(tor_key)
+}
+
+/// Write the key files for the private key `key_ur` into `dir`, as C Tor
+/// keeps them in a `HiddenServiceDir`.  An existing secret key is not
+/// overwritten, since that would lose the address it belongs to.
+pub fn export(dir: &Path, key_ur: &str) -> Result<()> {
+    let keypair = key::parse_expanded_keypair(key_ur)?;
+    let secret_path = dir.join(SECRET_KEY_FILE);
+    if secret_path.exists() {
+        return Err(anyhow!(
+            "{} already exists; move it aside first",
+            secret_path.display()
+        ));
+    }
+    create_private_dir(dir)?;
+    write_private(
+        &secret_path,
+        &[&SECRET_KEY_HEADER[..], &keypair.to_secret_key_bytes()].concat(),
+    )?;
+    let public_path = dir.join(PUBLIC_KEY_FILE);
+    std::fs::write(
+        &public_path,
+        [&PUBLIC_KEY_HEADER[..], &keypair.public().to_bytes()].concat(),
+    )
+    .with_context(|| format!("writing {}", public_path.display()))
+}
+
+/// Lay out a complete `HiddenServiceDir` for the private key `key_ur` in
+/// `dir`: its key files, its `hostname`, and an empty
+/// `authorized_clients` directory.  Returns the service's address.
+pub fn export_service(dir: &Path, key_ur: &str) -> Result<String> {
+    export(dir, key_ur)?;
+    let public_ur = key::public_key_ur_from_private_key(key_ur)?;
+    let host = key::parse_public_key_to_onion_host(&public_ur)?;
+    let hostname = dir.join(HOSTNAME_FILE);
+    std::fs::write(&hostname, format!("{host}\n"))
+        .with_context(|| format!("writing {}", hostname.display()))?;
+    create_private_dir(&dir.join(AUTHORIZED_CLIENTS_DIR))?;
+    Ok(host)
+}
+
+fn read_key<const N: usize>(path: &Path, header: &[u8; 32]) -> Result<[u8; N]> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    data.strip_prefix(&header[..])
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            anyhow!("{} is not a C Tor v3 onion service key", path.display())
+        })
+}
+
+/// Create `dir` readable only by its owner, as Tor insists.  A directory
+/// that is already there is used only if it is empty and already
+/// private: it may be one of the user's own, such as `.`, whose
+/// permissions are not garner's to change.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        return check_private_dir(dir);
+    }
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating {}", parent.display()))?;
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt as _;
+        builder.mode(0o700);
+    }
+    builder
+        .create(dir)
+        .with_context(|| format!("creating {}", dir.display()))
+}
+
+/// Check that the existing directory `dir` is empty and readable only by
+/// its owner.
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let meta = std::fs::metadata(dir)
+        .with_context(|| format!("reading {}", dir.display()))?;
+    if !meta.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        let mode = meta.permissions().mode() & 0o777;
+        if mode != 0o700 {
+            return Err(anyhow!(
+                "{} already exists with mode {mode:o}; use a new directory, \
+                 or an empty one with mode 700",
+                dir.display()
+            ));
+        }
+    }
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?;
+    if entries.next().is_some() {
+        return Err(anyhow!(
+            "{} already exists and is not empty; use a new directory",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Write `data` to a new file at `path` that only its owner can read.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write as _;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_and_import() {
+        bc_components::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        let hs_dir = dir.path().join("hs");
+        let (priv_ur, pub_ur) = key::generate_keypair().unwrap();
+        export(&hs_dir, &priv_ur).unwrap();
+        let secret = std::fs::read(hs_dir.join(SECRET_KEY_FILE)).unwrap();
+        assert_eq!(secret.len(), 96);
+        assert!(secret.starts_with(b"== ed25519v1-secret: type0 =="));
+        assert_eq!(
+            std::fs::read(hs_dir.join(PUBLIC_KEY_FILE)).unwrap().len(),
+            64
+        );
+        assert!(export(&hs_dir, &priv_ur).is_err());
+
+        let tor_key = import(&hs_dir).unwrap();
+        assert_eq!(
+            key::public_key_ur_from_private_key(&tor_key).unwrap(),
+            pub_ur
+        );
+        assert_eq!(
+            key::parse_private_key_expanded(&tor_key).unwrap(),
+            key::parse_private_key_expanded(&priv_ur).unwrap()
+        );
+
+        // A public key from another service is caught.
+        let other = dir.path().join("other");
+        export(&other, &key::generate_keypair().unwrap().0).unwrap();
+        std::fs::copy(
+            other.join(PUBLIC_KEY_FILE),
+            hs_dir.join(PUBLIC_KEY_FILE),
+        )
+        .unwrap();
+        assert!(import(&hs_dir).is_err());
+        std::fs::write(hs_dir.join(SECRET_KEY_FILE), b"not a key").unwrap();
+        assert!(import(&hs_dir).is_err());
+    }
+
+    #[test]
+    fn test_export_service() {
+        bc_components::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        let hs_dir = dir.path().join("hs");
+        let (priv_ur, pub_ur) = key::generate_keypair().unwrap();
+        let host = export_service(&hs_dir, &priv_ur).unwrap();
+        assert_eq!(host, key::parse_public_key_to_onion_host(&pub_ur).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(hs_dir.join(HOSTNAME_FILE)).unwrap(),
+            format!("{host}\n")
+        );
+        assert!(hs_dir.join(AUTHORIZED_CLIENTS_DIR).is_dir());
+        assert!(hs_dir.join(SECRET_KEY_FILE).is_file());
+        assert!(export_service(&hs_dir, &priv_ur).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_existing_dir() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        bc_components::register_tags();
+        let (priv_ur, _) = key::generate_keypair().unwrap();
+        let mode = |path: &Path| {
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        };
+        let set_mode = |path: &Path, mode| {
+            std::fs::set_permissions(
+                path,
+                std::fs::Permissions::from_mode(mode),
+            )
+            .unwrap()
+        };
+
+        // A user's own directory keeps its mode and contents.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+        set_mode(dir.path(), 0o755);
+        assert!(export(dir.path(), &priv_ur).is_err());
+        assert_eq!(mode(dir.path()), 0o755);
+        set_mode(dir.path(), 0o700);
+        assert!(export(dir.path(), &priv_ur).is_err());
+        assert!(!dir.path().join(SECRET_KEY_FILE).exists());
+
+        // An empty one is used only if it is already private.
+        let empty = dir.path().join("empty");
+        std::fs::create_dir(&empty).unwrap();
+        set_mode(&empty, 0o755);
+        assert!(export(&empty, &priv_ur).is_err());
+        assert_eq!(mode(&empty), 0o755);
+        set_mode(&empty, 0o700);
+        export(&empty, &priv_ur).unwrap();
+
+        let created = dir.path().join("a/b");
+        export(&created, &priv_ur).unwrap();
+        assert_eq!(mode(&created), 0o700);
+    }
+}
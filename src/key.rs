@@ -1,13 +1,19 @@
 use anyhow::{Context, Result, anyhow};
 use bc_components::{
-    Ed25519PrivateKey, Ed25519PublicKey, PrivateKeys, PublicKeys,
-    SigningPrivateKey, SigningPublicKey,
+    Decrypter as _, Ed25519PrivateKey, Ed25519PublicKey,
+    EncapsulationPrivateKey, KeyDerivationMethod, PrivateKeys, PublicKeys,
+    SSKRGroupSpec, SSKRSecret, SSKRShare, SSKRSpec, Seed, Signature,
+    Signer as _, SigningPrivateKey, SigningPublicKey, Verifier as _,
+    X25519PrivateKey,
 };
+use bc_envelope::Envelope;
 use bc_ur::{URDecodable, UREncodable};
 use safelog::DisplayRedacted as _;
 use tor_hscrypto::pk::{HsId, HsIdKeypair};
 use tor_llcrypto::pk::ed25519::{ExpandedKeypair, Keypair};
 
+use crate::{keychain, mnemonic};
+
 /// Convert an [`HsId`] (the raw Ed25519 public key bytes of a Tor onion
 /// service) into a `ur:signing-public-key/…` UR string.
 pub fn public_key_ur_from_hsid(hs_id: &HsId) -> Result<String> {
@@ -18,8 +24,18 @@ pub fn public_key_ur_from_hsid(hs_id: &HsId) -> Result<String> {
 }
 
 /// Extract the Ed25519 signing key from either a `ur:crypto-prvkeys`
-/// (combined key bundle) or a `ur:signing-private-key` UR string.
+/// (combined key bundle) or a `ur:signing-private-key` UR string, or
+/// restore it from a `mnemonic:` recovery phrase.
 fn extract_signing_private_key(ur: &str) -> Result<SigningPrivateKey> {
+    if let Some(phrase) = ur.strip_prefix(mnemonic::PREFIX) {
+        return Ok(SigningPrivateKey::new_ed25519(mnemonic_key(phrase)?));
+    }
+    if ur.starts_with(TOR_PREFIX) {
+        return Err(anyhow!(
+            "a key imported from C Tor can run the onion service, but has \
+             no seed to sign with, back up, or split"
+        ));
+    }
     // Try ur:crypto-prvkeys first (the envelope CLI's default output)
     if let Ok(keys) = PrivateKeys::from_ur_string(ur) {
         return Ok(keys.signing_private_key().clone());
@@ -31,8 +47,13 @@ fn extract_signing_private_key(ur: &str) -> Result<SigningPrivateKey> {
 }
 
 /// Extract the Ed25519 signing key from either a `ur:crypto-pubkeys`
-/// (combined key bundle) or a `ur:signing-public-key` UR string.
+/// (combined key bundle) or a `ur:signing-public-key` UR string, or the
+/// public half of the key a `mnemonic:` recovery phrase restores.
 fn extract_signing_public_key(ur: &str) -> Result<SigningPublicKey> {
+    if let Some(phrase) = ur.strip_prefix(mnemonic::PREFIX) {
+        let key = mnemonic_key(phrase)?;
+        return Ok(SigningPublicKey::from_ed25519(key.public_key()));
+    }
     // Try ur:crypto-pubkeys first (the envelope CLI's default output)
     if let Ok(keys) = PublicKeys::from_ur_string(ur) {
         return Ok(keys.signing_public_key().clone());
@@ -43,12 +64,114 @@ fn extract_signing_public_key(ur: &str) -> Result<SigningPublicKey> {
         .context("expected ur:crypto-pubkeys or ur:signing-public-key")
 }
 
+/// The Ed25519 private key that a recovery phrase holds.
+fn mnemonic_key(phrase: &str) -> Result<Ed25519PrivateKey> {
+    let data = mnemonic::from_phrase(phrase)?;
+    Ed25519PrivateKey::from_data_ref(&data)
+        .map_err(|_| anyhow!("expected a 24-word recovery phrase for a key"))
+}
+
+/// The 24-word recovery phrase for a private key UR, from which `--key
+/// mnemonic:"…"` restores the key.
+pub fn mnemonic_for_private_key(ur: &str) -> Result<String> {
+    let signing_key = parse_signing_key(ur)?;
+    let SigningPrivateKey::Ed25519(key) = signing_key else {
+        return Err(anyhow!("expected an Ed25519 private key"));
+    };
+    mnemonic::to_phrase(key.data())
+}
+
+/// The `ur:signing-public-key` of a private key UR, to share with those
+/// who connect to the service.
+pub fn public_key_ur_from_private_key(ur: &str) -> Result<String> {
+    let keypair = parse_expanded_keypair(ur)?;
+    let public_key = Ed25519PublicKey::from_data(keypair.public().to_bytes());
+    Ok(SigningPublicKey::from_ed25519(public_key).ur_string())
+}
+
+/// The `tor:` key for the 64-byte expanded secret key of a C Tor onion
+/// service, which has no seed to hold in a UR.
+pub fn tor_key_from_expanded(secret: [u8; 64]) -> Result<String> {
+    ExpandedKeypair::from_secret_key_bytes(secret)
+        .ok_or_else(|| anyhow!("invalid expanded Ed25519 key"))?;
+    Ok(format!("{TOR_PREFIX}{}", hex::encode(secret)))
+}
+
+/// Split a private key into SSKR shares, in groups of `M-of-N` shares
+/// such as `2-of-3`, any `group_threshold` of which restore the key.
+/// Returns the `ur:sskr` shares of each group.
+pub fn sskr_split(
+    ur: &str,
+    group_threshold: usize,
+    groups: &[String],
+) -> Result<Vec<Vec<String>>> {
+    let SigningPrivateKey::Ed25519(key) = parse_signing_key(ur)? else {
+        return Err(anyhow!("expected an Ed25519 private key"));
+    };
+    let groups = groups
+        .iter()
+        .map(|group| {
+            SSKRGroupSpec::parse(group).map_err(|e| {
+                anyhow!("invalid group {group:?} (expected M-of-N): {e}")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let spec =
+        SSKRSpec::new(group_threshold, groups).map_err(|e| anyhow!("{e}"))?;
+    let secret = SSKRSecret::new(key.data()).map_err(|e| anyhow!("{e}"))?;
+    let shares = bc_components::sskr_generate(&spec, &secret)
+        .map_err(|e| anyhow!("{e}"))?;
+    Ok(shares
+        .iter()
+        .map(|group| group.iter().map(UREncodable::ur_string).collect())
+        .collect())
+}
+
+/// Reassemble the `ur:signing-private-key` that `garner generate sskr`
+/// split into the `ur:sskr` `shares`.
+pub fn sskr_recover(shares: &[String]) -> Result<String> {
+    let shares = shares
+        .iter()
+        .map(|share| {
+            SSKRShare::from_ur_string(share)
+                .map_err(|e| anyhow!("{e}"))
+                .with_context(|| format!("expected a ur:sskr share: {share}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let secret =
+        bc_components::sskr_combine(&shares).map_err(|e| anyhow!("{e}"))?;
+    let key = Ed25519PrivateKey::from_data_ref(secret.data())
+        .map_err(|_| anyhow!("the shares do not hold an Ed25519 key"))?;
+    Ok(SigningPrivateKey::new_ed25519(key).ur_string())
+}
+
 /// Parse a private key UR string into an [`HsIdKeypair`] suitable for
 /// launching a Tor onion service with a deterministic address.
 ///
 /// Accepts either `ur:crypto-prvkeys/...` (from `envelope generate prvkeys`)
 /// or `ur:signing-private-key/...`.
 pub fn parse_private_key(ur: &str) -> Result<HsIdKeypair> {
+    Ok(HsIdKeypair::from(parse_expanded_keypair(ur)?))
+}
+
+/// Parse a private key UR string into the 64-byte expanded Ed25519
+/// secret key, the form C Tor uses for `ADD_ONION` and its
+/// `hs_ed25519_secret_key` files.
+pub fn parse_private_key_expanded(ur: &str) -> Result<[u8; 64]> {
+    Ok(parse_expanded_keypair(ur)?.to_secret_key_bytes())
+}
+
+/// Parse a private key UR string, or a `tor:` key, into an expanded
+/// keypair.
+pub fn parse_expanded_keypair(ur: &str) -> Result<ExpandedKeypair> {
+    if let Some(hex_key) = ur.strip_prefix(TOR_PREFIX) {
+        let bytes: [u8; 64] = hex::decode(hex_key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("expected tor: and 128 hex digits"))?;
+        return ExpandedKeypair::from_secret_key_bytes(bytes)
+            .ok_or_else(|| anyhow!("invalid expanded Ed25519 key"));
+    }
     let signing_key = extract_signing_private_key(ur)?;
 
     let ed_key = match signing_key {
@@ -59,8 +182,148 @@ pub fn parse_private_key(ur: &str) -> Result<HsIdKeypair> {
 
     let seed: &[u8; 32] = ed_key.data();
     let keypair = Keypair::from_bytes(seed);
-    let expanded = ExpandedKeypair::from(&keypair);
-    Ok(HsIdKeypair::from(expanded))
+    Ok(ExpandedKeypair::from(&keypair))
+}
+
+/// Parse a private key UR string into the Ed25519 key used to sign
+/// response bodies.  It is the same key that determines the `.onion`
+/// address, so clients can verify signatures from the address alone.
+pub fn parse_signing_key(ur: &str) -> Result<SigningPrivateKey> {
+    let signing_key = extract_signing_private_key(ur)?;
+    if !matches!(signing_key, SigningPrivateKey::Ed25519(_)) {
+        return Err(anyhow!("expected an Ed25519 private key"));
+    }
+    Ok(signing_key)
+}
+
+/// Parse a `ur:crypto-prvkeys` key bundle, whose encapsulation key lets
+/// the service decrypt messages sealed to it.  The signing key must be
+/// Ed25519, as it also determines the `.onion` address.
+pub fn parse_private_keys(ur: &str) -> Result<PrivateKeys> {
+    let keys = PrivateKeys::from_ur_string(ur)
+        .map_err(|e| anyhow!("{e}"))
+        .context("expected a ur:crypto-prvkeys key bundle")?;
+    if !matches!(keys.signing_private_key(), SigningPrivateKey::Ed25519(_)) {
+        return Err(anyhow!("expected an Ed25519 private key"));
+    }
+    Ok(keys)
+}
+
+/// Parse the X25519 key a client presents to an onion service in
+/// restricted discovery mode, from a `ur:agreement-private-key` or the
+/// encapsulation key of a `ur:crypto-prvkeys` bundle.
+pub fn parse_client_auth_key(ur: &str) -> Result<[u8; 32]> {
+    if let Ok(keys) = PrivateKeys::from_ur_string(ur) {
+        return match keys.encapsulation_private_key() {
+            EncapsulationPrivateKey::X25519(key) => Ok(*key.data()),
+            #[allow(unreachable_patterns)]
+            _ => Err(anyhow!("expected an X25519 encapsulation key")),
+        };
+    }
+    let key = X25519PrivateKey::from_ur_string(ur)
+        .map_err(|e| anyhow!("{e}"))
+        .context("expected ur:agreement-private-key or ur:crypto-prvkeys")?;
+    Ok(*key.data())
+}
+
+/// Sign `body` and return the signature as a `ur:signature/…` string.
+pub fn sign_body(key: &SigningPrivateKey, body: &[u8]) -> Result<String> {
+    let signature = key.sign(&body).map_err(|e| anyhow!("{e}"))?;
+    Ok(signature.ur_string())
+}
+
+/// Verify a `ur:signature/…` string made by [`sign_body`] against the
+/// identity key of `onion_host`.
+pub fn verify_body(
+    onion_host: &str,
+    body: &[u8],
+    signature: &str,
+) -> Result<()> {
+    let public_key = onion_public_key(onion_host)?;
+    let signature = Signature::from_ur_string(signature)
+        .map_err(|e| anyhow!("{e}"))
+        .context("expected ur:signature")?;
+    if !public_key.verify(&signature, &body) {
+        return Err(anyhow!("signature does not match {onion_host}"));
+    }
+    Ok(())
+}
+
+/// Check that `onion_host` (`<address>.onion`, perhaps under a subdomain)
+/// is a well-formed v3 onion address: 56 base32 characters encoding a
+/// public key, a checksum over it, and version 3.  A malformed address
+/// would otherwise only fail once Tor gives up looking for its
+/// descriptor.
+pub fn check_onion_address(onion_host: &str) -> Result<()> {
+    use sha2::Digest as _;
+    use tor_llcrypto::d::Sha3_256;
+
+    let host = onion_host.to_ascii_lowercase();
+    let name = host.strip_suffix(".onion").unwrap_or(&host);
+    let address = name.rsplit('.').next().unwrap_or(name);
+    let invalid = |reason: String| {
+        anyhow!("invalid onion address {onion_host}: {reason}")
+    };
+    if address.len() == 16 {
+        return Err(invalid(
+            "it is a v2 address, which Tor no longer supports".to_string(),
+        ));
+    }
+    if address.len() != 56 {
+        return Err(invalid(format!(
+            "expected 56 characters before .onion, got {}",
+            address.len()
+        )));
+    }
+    // Decode base32 (RFC 4648, lowercase, no padding): 56 characters of
+    // 5 bits each make 35 bytes.
+    let mut bytes = Vec::with_capacity(35);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in address.chars() {
+        let value = match c {
+            'a'..='z' => c as u32 - 'a' as u32,
+            '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => {
+                return Err(invalid(format!(
+                    "{c:?} is not a base32 character (a-z, 2-7)"
+                )));
+            }
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    let (public_key, rest) = bytes.split_at(32);
+    let (checksum, version) = (&rest[..2], rest[2]);
+    if version != 3 {
+        return Err(invalid(format!(
+            "it has version {version}; only v3 addresses are supported"
+        )));
+    }
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(public_key);
+    hasher.update([version]);
+    if hasher.finalize()[..2] != *checksum {
+        return Err(invalid(
+            "its checksum does not match; check it for typos".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The identity key that `onion_host` encodes.
+pub fn onion_public_key(onion_host: &str) -> Result<SigningPublicKey> {
+    let hs_id: HsId = onion_host
+        .parse()
+        .map_err(|e| anyhow!("invalid onion address {onion_host}: {e}"))?;
+    let bytes: &[u8; 32] = hs_id.as_ref();
+    Ok(SigningPublicKey::from_ed25519(Ed25519PublicKey::from_data(
+        *bytes,
+    )))
 }
 
 /// Parse a public key UR string and return the corresponding `.onion`
@@ -82,16 +345,176 @@ pub fn parse_public_key_to_onion_host(ur: &str) -> Result<String> {
     Ok(hs_id.display_unredacted().to_string())
 }
 
+/// Encrypt a private key UR with a passphrase, as a `ur:envelope` whose
+/// content key is locked with Argon2id.
+pub fn encrypt_private_key(ur: &str, passphrase: &str) -> Result<String> {
+    if passphrase.is_empty() {
+        return Err(anyhow!("empty passphrase"));
+    }
+    let signing_key = parse_signing_key(ur)?;
+    let envelope =
+        Envelope::new(dcbor::CBORTaggedEncodable::tagged_cbor(&signing_key))
+            .lock(KeyDerivationMethod::Argon2id, passphrase)
+            .map_err(|e| anyhow!("{e}"))?;
+    Ok(envelope.ur_string())
+}
+
+/// Whether `key` is a private key encrypted with a passphrase.
+pub fn is_encrypted(key: &str) -> bool {
+    Envelope::from_ur_string(key)
+        .is_ok_and(|envelope| envelope.is_locked_with_password())
+}
+
+/// The private key UR that `encrypt_private_key` encrypted.
+pub fn decrypt_private_key(ur: &str, passphrase: &str) -> Result<String> {
+    let envelope = Envelope::from_ur_string(ur).map_err(|e| anyhow!("{e}"))?;
+    let envelope = envelope
+        .unlock(passphrase)
+        .map_err(|_| anyhow!("wrong passphrase for the encrypted key"))?;
+    let signing_key: SigningPrivateKey = envelope
+        .extract_subject()
+        .map_err(|e| anyhow!("{e}"))
+        .context("the encrypted envelope does not hold a private key")?;
+    Ok(signing_key.ur_string())
+}
+
+/// `key` itself, or if it is encrypted, the private key UR it holds,
+/// asking for its passphrase on the terminal.
+pub fn unlock(key: &str) -> Result<String> {
+    if !is_encrypted(key) {
+        return Ok(key.to_string());
+    }
+    let passphrase = crate::ui::read_passphrase("Passphrase for the key: ")?;
+    decrypt_private_key(key, &passphrase)
+}
+
+/// Prefix of a key imported from C Tor: the hex of its expanded secret
+/// key.
+pub const TOR_PREFIX: &str = "tor:";
+
+/// Salt for stretching a `--from-seed` passphrase into a seed.  Changing
+/// it would change the key every passphrase regenerates.
+const PASSPHRASE_SALT: &str = "garner onion service key";
+
 /// Generate a random Ed25519 keypair and return the private and public key
 /// UR strings.
 pub fn generate_keypair() -> Result<(String, String)> {
-    let ed_priv = Ed25519PrivateKey::new();
+    keypair_urs(Ed25519PrivateKey::new())
+}
+
+/// Derive an Ed25519 keypair from a seed, so that the same `.onion`
+/// address can be regenerated anywhere the seed is known, and return the
+/// private and public key UR strings.  The seed is a `ur:seed`, at least
+/// 16 bytes of hex, or `passphrase:` followed by a passphrase stretched
+/// into one with Argon2id.
+pub fn generate_keypair_from_seed(seed: &str) -> Result<(String, String)> {
+    let seed = parse_seed(seed)?;
+    keypair_urs(Ed25519PrivateKey::derive_from_key_material(seed))
+}
+
+fn parse_seed(seed: &str) -> Result<Vec<u8>> {
+    if let Some(passphrase) = seed.strip_prefix("passphrase:") {
+        if passphrase.is_empty() {
+            return Err(anyhow!("empty passphrase"));
+        }
+        return Ok(bc_crypto::argon2id(passphrase, PASSPHRASE_SALT, 32));
+    }
+    if seed.get(..3).is_some_and(|s| s.eq_ignore_ascii_case("ur:")) {
+        let seed = Seed::from_ur_string(seed)
+            .map_err(|e| anyhow!("{e}"))
+            .context("expected a ur:seed")?;
+        return Ok(seed.as_bytes().to_vec());
+    }
+    let bytes = hex::decode(seed).map_err(|_| {
+        anyhow!("expected a ur:seed, hex, or passphrase:<PASSPHRASE> seed")
+    })?;
+    if bytes.len() < Seed::MIN_SEED_LENGTH {
+        return Err(anyhow!(
+            "seed is {} bytes; it must be at least {}",
+            bytes.len(),
+            Seed::MIN_SEED_LENGTH
+        ));
+    }
+    Ok(bytes)
+}
+
+fn keypair_urs(ed_priv: Ed25519PrivateKey) -> Result<(String, String)> {
     let ed_pub = ed_priv.public_key();
     let signing_priv = SigningPrivateKey::new_ed25519(ed_priv);
     let signing_pub = SigningPublicKey::from_ed25519(ed_pub);
     Ok((signing_priv.ur_string(), signing_pub.ur_string()))
 }
 
+/// Read a key UR from the systemd credential `name`, as passed to the
+/// service with `LoadCredential=` or `LoadCredentialEncrypted=`.  systemd
+/// decrypts the credential into `$CREDENTIALS_DIRECTORY`, which only the
+/// service can read, so the key never appears in the environment or on
+/// the command line.
+pub fn read_credential(name: &str) -> Result<String> {
+    let dir = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+        anyhow!(
+            "--key-credential needs $CREDENTIALS_DIRECTORY; run garner as a \
+             systemd service with LoadCredential= or LoadCredentialEncrypted="
+        )
+    })?;
+    read_credential_in(std::path::Path::new(&dir), name)
+}
+
+fn read_credential_in(dir: &std::path::Path, name: &str) -> Result<String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(anyhow!("invalid credential name {name:?}"));
+    }
+    let path = dir.join(name);
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading credential {}", path.display()))?;
+    Ok(text.trim().to_string())
+}
+
+/// The key given as `--key`, or read from `--key-file`.  A key or key
+/// file of `-` is read from stdin, so that the key appears in neither the
+/// process list nor the environment, and `keychain:<NAME>` is read from
+/// the OS keychain.
+pub fn read_key_arg(
+    key: Option<String>,
+    key_file: Option<&std::path::Path>,
+) -> Result<Option<String>> {
+    let text = match (key, key_file) {
+        (_, Some(path)) if path.as_os_str() != "-" => {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?
+        }
+        (_, Some(_)) => read_key_stdin()?,
+        (Some(key), None) if key == "-" => read_key_stdin()?,
+        (Some(key), None) => key,
+        (None, None) => return Ok(None),
+    };
+    let key = text.trim();
+    if key.is_empty() {
+        return Err(anyhow!("no key was given"));
+    }
+    match key.strip_prefix(keychain::PREFIX) {
+        Some(name) => keychain::load(name).map(Some),
+        None => Ok(Some(key.to_string())),
+    }
+}
+
+/// Whether `--key` or `--key-file` asks for the key to be read from
+/// stdin.
+pub fn reads_stdin(
+    key: Option<&str>,
+    key_file: Option<&std::path::Path>,
+) -> bool {
+    match key_file {
+        Some(path) => path.as_os_str() == "-",
+        None => key == Some("-"),
+    }
+}
+
+fn read_key_stdin() -> Result<String> {
+    std::io::read_to_string(std::io::stdin())
+        .context("reading the key from stdin")
+}
+
 /// Derive the `.onion` hostname from an [`HsIdKeypair`].
 #[cfg(test)]
 fn onion_host_from_keypair(keypair: &HsIdKeypair) -> String {
@@ -103,7 +526,6 @@ fn onion_host_from_keypair(keypair: &HsIdKeypair) -> String {
 mod tests {
     use std::sync::Once;
 
-    use bc_components::{EncapsulationPrivateKey, X25519PrivateKey};
     use bc_ur::UREncodable;
 
     use super::*;
@@ -158,6 +580,34 @@ mod tests {
             .ur_string()
     }
 
+    #[test]
+    fn test_check_onion_address() {
+        init();
+        let onion =
+            parse_public_key_to_onion_host(&make_ur_signing_public_key())
+                .unwrap();
+        check_onion_address(&onion).unwrap();
+        check_onion_address(&onion.to_ascii_uppercase()).unwrap();
+        check_onion_address(&format!("www.{onion}")).unwrap();
+
+        let error = |host: &str| {
+            format!("{:#}", check_onion_address(host).unwrap_err())
+        };
+        assert!(error("expyuzz4wqqyqhjn.onion").contains("v2"));
+        assert!(error(&onion[1..]).contains("got 55"));
+        assert!(error(&format!("1{}", &onion[1..])).contains("not a base32"));
+        // Flip one character of the key.
+        let typo = format!(
+            "{}{}",
+            if onion.starts_with('a') { 'b' } else { 'a' },
+            &onion[1..]
+        );
+        assert!(error(&typo).contains("checksum"));
+        // The last character holds the low bits of the version byte.
+        let version = format!("{}b.onion", &onion[..55]);
+        assert!(error(&version).contains("version"));
+    }
+
     // --- Tests for ur:signing-private-key / ur:signing-public-key ---
 
     #[test]
@@ -208,6 +658,14 @@ mod tests {
 
     // --- Tests for ur:crypto-prvkeys / ur:crypto-pubkeys ---
 
+    #[test]
+    fn test_parse_private_keys() {
+        init();
+        parse_private_keys(&make_ur_crypto_prvkeys())
+            .expect("should parse crypto-prvkeys");
+        assert!(parse_private_keys(&make_ur_signing_private_key()).is_err());
+    }
+
     #[test]
     fn test_parse_crypto_prvkeys() {
         init();
@@ -325,6 +783,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_generate_keypair_from_seed() {
+        init();
+        let hex_seed = hex::encode(&KNOWN_SEED[..16]);
+        let (priv_ur, pub_ur) = generate_keypair_from_seed(&hex_seed).unwrap();
+        assert_eq!(
+            generate_keypair_from_seed(&hex_seed).unwrap(),
+            (priv_ur.clone(), pub_ur.clone())
+        );
+        parse_private_key(&priv_ur).unwrap();
+        let ur_seed = Seed::new_opt(&KNOWN_SEED[..16], None, None, None)
+            .unwrap()
+            .ur_string();
+        assert_eq!(
+            generate_keypair_from_seed(&ur_seed).unwrap().1,
+            pub_ur,
+            "a ur:seed holding the same bytes gives the same key"
+        );
+
+        let (_, a) = generate_keypair_from_seed("passphrase:correct horse")
+            .expect("passphrase seed");
+        let (_, b) = generate_keypair_from_seed("passphrase:correct horse")
+            .expect("passphrase seed");
+        assert_eq!(a, b);
+        assert_ne!(a, pub_ur);
+
+        assert!(generate_keypair_from_seed("0102").is_err());
+        assert!(generate_keypair_from_seed("not a seed").is_err());
+        assert!(generate_keypair_from_seed("passphrase:").is_err());
+    }
+
+    #[test]
+    fn test_mnemonic_keys() {
+        init();
+        let (priv_ur, pub_ur) = generate_keypair().unwrap();
+        let phrase = mnemonic_for_private_key(&priv_ur).unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+        let key = format!("mnemonic:{phrase}");
+        assert_eq!(
+            parse_signing_key(&key).unwrap(),
+            parse_signing_key(&priv_ur).unwrap()
+        );
+        assert_eq!(
+            parse_public_key_to_onion_host(&key).unwrap(),
+            parse_public_key_to_onion_host(&pub_ur).unwrap()
+        );
+        let short = mnemonic::to_phrase(&[1; 16]).unwrap();
+        assert!(parse_private_key(&format!("mnemonic:{short}")).is_err());
+    }
+
+    #[test]
+    fn test_public_key_ur_from_private_key() {
+        init();
+        let (priv_ur, pub_ur) = generate_keypair().unwrap();
+        assert_eq!(public_key_ur_from_private_key(&priv_ur).unwrap(), pub_ur);
+        assert_eq!(
+            public_key_ur_from_private_key(&make_ur_crypto_prvkeys()).unwrap(),
+            make_ur_signing_public_key()
+        );
+        assert!(public_key_ur_from_private_key(&pub_ur).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_keys() {
+        init();
+        bc_envelope::register_tags();
+        let (priv_ur, _) = generate_keypair().unwrap();
+        let encrypted = encrypt_private_key(&priv_ur, "hunter2").unwrap();
+        assert!(encrypted.starts_with("ur:envelope/"), "{encrypted}");
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(&priv_ur));
+        assert_eq!(unlock(&priv_ur).unwrap(), priv_ur);
+        assert_eq!(
+            decrypt_private_key(&encrypted, "hunter2").unwrap(),
+            priv_ur
+        );
+        assert!(decrypt_private_key(&encrypted, "hunter3").is_err());
+        assert!(encrypt_private_key(&priv_ur, "").is_err());
+    }
+
+    #[test]
+    fn test_tor_keys() {
+        init();
+        let (priv_ur, pub_ur) = generate_keypair().unwrap();
+        let expanded = parse_private_key_expanded(&priv_ur).unwrap();
+        let tor_key = tor_key_from_expanded(expanded).unwrap();
+        assert!(tor_key.starts_with("tor:"));
+        assert_eq!(parse_private_key_expanded(&tor_key).unwrap(), expanded);
+        assert_eq!(public_key_ur_from_private_key(&tor_key).unwrap(), pub_ur);
+        assert!(parse_signing_key(&tor_key).is_err());
+        assert!(parse_private_key("tor:00").is_err());
+    }
+
+    #[test]
+    fn test_sskr() {
+        init();
+        let (priv_ur, _) = generate_keypair().unwrap();
+        let groups = ["2-of-3".to_string(), "1-of-1".to_string()];
+        let shares = sskr_split(&priv_ur, 2, &groups).unwrap();
+        assert_eq!(shares.len(), 2);
+        assert_eq!(shares[0].len(), 3);
+        assert!(shares[0][0].starts_with("ur:sskr/"), "{}", shares[0][0]);
+
+        let quorum = [
+            shares[0][2].clone(),
+            shares[1][0].clone(),
+            shares[0][0].clone(),
+        ];
+        assert_eq!(sskr_recover(&quorum).unwrap(), priv_ur);
+        // One group alone is not enough.
+        assert!(sskr_recover(&shares[0]).is_err());
+        assert!(sskr_recover(&["ur:nope".to_string()]).is_err());
+        assert!(sskr_split(&priv_ur, 1, &["2-3".to_string()]).is_err());
+        assert!(sskr_split(&priv_ur, 3, &groups).is_err());
+    }
+
     // --- Error cases ---
 
     #[test]
@@ -350,4 +924,86 @@ mod tests {
             "should reject a public key UR as private key"
         );
     }
+
+    #[test]
+    fn test_read_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("garner-key"), "ur:example\n").unwrap();
+        let key = read_credential_in(dir.path(), "garner-key").unwrap();
+        assert_eq!(key, "ur:example");
+        assert!(read_credential_in(dir.path(), "missing").is_err());
+    }
+
+    #[test]
+    fn test_read_credential_invalid_name() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["", ".", "..", "../garner-key", "a/b"] {
+            assert!(read_credential_in(dir.path(), name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_read_key_arg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.ur");
+        std::fs::write(&path, "ur:example\n").unwrap();
+        let key = Some("ur:other".to_string());
+        assert_eq!(
+            read_key_arg(key.clone(), Some(&path)).unwrap().as_deref(),
+            Some("ur:example")
+        );
+        assert_eq!(read_key_arg(key.clone(), None).unwrap(), key);
+        assert_eq!(read_key_arg(None, None).unwrap(), None);
+        std::fs::write(&path, "\n").unwrap();
+        assert!(read_key_arg(None, Some(&path)).is_err());
+        assert!(read_key_arg(None, Some(&dir.path().join("missing"))).is_err());
+        assert!(reads_stdin(Some("-"), None));
+        assert!(reads_stdin(None, Some(std::path::Path::new("-"))));
+        assert!(!reads_stdin(Some("-"), Some(&path)));
+    }
+
+    #[test]
+    fn test_sign_and_verify_body() {
+        init();
+        let (priv_ur, pub_ur) = generate_keypair().unwrap();
+        let onion_host = parse_public_key_to_onion_host(&pub_ur).unwrap();
+        let key = parse_signing_key(&priv_ur).unwrap();
+        let signature = sign_body(&key, b"hello").unwrap();
+        assert!(signature.starts_with("ur:signature/"));
+        assert!(verify_body(&onion_host, b"hello", &signature).is_ok());
+        assert!(verify_body(&onion_host, b"hellO", &signature).is_err());
+    }
+
+    #[test]
+    fn test_verify_body_wrong_key() {
+        init();
+        let (priv_ur, _) = generate_keypair().unwrap();
+        let (_, other_pub_ur) = generate_keypair().unwrap();
+        let other_host = parse_public_key_to_onion_host(&other_pub_ur).unwrap();
+        let key = parse_signing_key(&priv_ur).unwrap();
+        let signature = sign_body(&key, b"hello").unwrap();
+        assert!(verify_body(&other_host, b"hello", &signature).is_err());
+    }
+
+    #[test]
+    fn test_parse_client_auth_key() {
+        init();
+        let key = X25519PrivateKey::new();
+        assert_eq!(
+            parse_client_auth_key(&key.ur_string()).unwrap(),
+            *key.data()
+        );
+        let bundle = PrivateKeys::with_keys(
+            SigningPrivateKey::new_ed25519(Ed25519PrivateKey::from_data(
+                KNOWN_SEED,
+            )),
+            EncapsulationPrivateKey::X25519(key),
+        );
+        assert_eq!(
+            parse_client_auth_key(&bundle.ur_string()).unwrap(),
+            *key.data()
+        );
+        assert!(parse_client_auth_key(&make_ur_signing_private_key()).is_err());
+        assert!(parse_client_auth_key("not a ur").is_err());
+    }
 }
@@ -0,0 +1,289 @@
+use anyhow::{Result, anyhow};
+
+/// Prefix that names a key in the OS keychain where a key UR is expected.
+pub const PREFIX: &str = "keychain:";
+
+/// Service the keys are filed under: the keychain item's service on
+/// macOS, the Secret Service's `service` attribute, and the prefix of the
+/// Credential Manager's target name on Windows.
+const SERVICE: &str = "garner";
+
+/// Store `key` in the OS keychain as `name`.  An existing key is not
+/// replaced, since that would lose the address it belongs to.
+pub fn store(name: &str, key: &str) -> Result<()> {
+    check_name(name)?;
+    if platform::lookup(name)?.is_some() {
+        return Err(anyhow!(
+            "the keychain already has a key named {name:?}; remove it first"
+        ));
+    }
+    platform::store(name, key)
+}
+
+/// The key stored in the OS keychain as `name`.
+pub fn load(name: &str) -> Result<String> {
+    check_name(name)?;
+    platform::lookup(name)?
+        .map(|key| key.trim().to_string())
+        .ok_or_else(|| anyhow!("the keychain has no key named {name:?}"))
+}
+
+/// Remove the key stored in the OS keychain as `name`.
+pub fn remove(name: &str) -> Result<()> {
+    check_name(name)?;
+    if platform::lookup(name)?.is_none() {
+        return Err(anyhow!("the keychain has no key named {name:?}"));
+    }
+    platform::remove(name)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_control) {
+        return Err(anyhow!("invalid keychain name {name:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "native-keychain", target_os = "macos"))]
+mod platform {
+    use anyhow::{Context as _, Result};
+    use security_framework::passwords;
+
+    use super::SERVICE;
+
+    /// `errSecItemNotFound`.
+    const ITEM_NOT_FOUND: i32 = -25300;
+
+    pub fn store(name: &str, key: &str) -> Result<()> {
+        passwords::set_generic_password(SERVICE, name, key.as_bytes())
+            .context("storing the key in the keychain")
+    }
+
+    pub fn lookup(name: &str) -> Result<Option<String>> {
+        match passwords::get_generic_password(SERVICE, name) {
+            Ok(key) => Ok(Some(String::from_utf8(key)?)),
+            Err(e) if e.code() == ITEM_NOT_FOUND => Ok(None),
+            Err(e) => Err(e).context("reading the key from the keychain"),
+        }
+    }
+
+    pub fn remove(name: &str) -> Result<()> {
+        passwords::delete_generic_password(SERVICE, name)
+            .context("removing the key from the keychain")
+    }
+}
+
+#[cfg(all(feature = "native-keychain", windows))]
+mod platform {
+    use anyhow::{Context as _, Result};
+    use windows_sys::Win32::{
+        Foundation::ERROR_NOT_FOUND,
+        Security::Credentials::{
+            CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC, CREDENTIALW,
+            CredDeleteW, CredFree, CredReadW, CredWriteW,
+        },
+    };
+
+    use super::SERVICE;
+
+    /// The Credential Manager's name for the key, NUL-terminated.
+    fn target(name: &str) -> Vec<u16> {
+        format!("{SERVICE}:{name}")
+            .encode_utf16()
+            .chain([0])
+            .collect()
+    }
+
+    pub fn store(name: &str, key: &str) -> Result<()> {
+        let mut target = target(name);
+        let mut blob = key.as_bytes().to_vec();
+        let credential = CREDENTIALW {
+            Type: CRED_TYPE_GENERIC,
+            TargetName: target.as_mut_ptr(),
+            CredentialBlobSize: u32::try_from(blob.len())?,
+            CredentialBlob: blob.as_mut_ptr(),
+            Persist: CRED_PERSIST_LOCAL_MACHINE,
+            ..Default::default()
+        };
+        // SAFETY: the credential's pointers are to buffers that outlive
+        // the call, which copies them.
+        if unsafe { CredWriteW(&credential, 0) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("storing the key in the Credential Manager");
+        }
+        Ok(())
+    }
+
+    pub fn lookup(name: &str) -> Result<Option<String>> {
+        let target = target(name);
+        let mut credential: *mut CREDENTIALW = std::ptr::null_mut();
+        // SAFETY: `target` is NUL-terminated, and on success `credential`
+        // points to a credential that is read once and then freed.
+        let key = unsafe {
+            if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential)
+                == 0
+            {
+                let error = std::io::Error::last_os_error();
+                if error.raw_os_error() == Some(ERROR_NOT_FOUND as i32) {
+                    return Ok(None);
+                }
+                return Err(error)
+                    .context("reading the key from the Credential Manager");
+            }
+            let blob = std::slice::from_raw_parts(
+                (*credential).CredentialBlob,
+                (*credential).CredentialBlobSize as usize,
+            )
+            .to_vec();
+            CredFree(credential.cast());
+            blob
+        };
+        Ok(Some(String::from_utf8(key)?))
+    }
+
+    pub fn remove(name: &str) -> Result<()> {
+        let target = target(name);
+        // SAFETY: `target` is NUL-terminated.
+        if unsafe { CredDeleteW(target.as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("removing the key from the Credential Manager");
+        }
+        Ok(())
+    }
+}
+
+/// The Secret Service (GNOME Keyring, KWallet), through libsecret's
+/// `secret-tool`.
+#[cfg(not(any(target_os = "macos", windows)))]
+mod platform {
+    use std::{
+        io::Write as _,
+        process::{Command, Output, Stdio},
+    };
+
+    use anyhow::{Context as _, Result, anyhow};
+
+    use super::SERVICE;
+
+    fn secret_tool(action: &str, name: &str) -> Command {
+        let mut command = Command::new("secret-tool");
+        command.arg(action);
+        if action == "store" {
+            command.arg(format!("--label=garner key {name}"));
+        }
+        command.args(["service", SERVICE, "account", name]);
+        command
+    }
+
+    fn run(mut command: Command, input: Option<&str>) -> Result<Output> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => anyhow!(
+                    "the keychain needs libsecret's secret-tool, which was \
+                     not found"
+                ),
+                _ => anyhow!(e).context("running secret-tool"),
+            })?;
+        let mut stdin = child.stdin.take().context("secret-tool's stdin")?;
+        if let Some(input) = input {
+            stdin.write_all(input.as_bytes())?;
+        }
+        drop(stdin);
+        Ok(child.wait_with_output()?)
+    }
+
+    fn failure(output: &Output) -> anyhow::Error {
+        anyhow!(
+            "secret-tool failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+
+    pub fn store(name: &str, key: &str) -> Result<()> {
+        let output = run(secret_tool("store", name), Some(key))?;
+        if !output.status.success() {
+            return Err(failure(&output));
+        }
+        Ok(())
+    }
+
+    pub fn lookup(name: &str) -> Result<Option<String>> {
+        let output = run(secret_tool("lookup", name), None)?;
+        match output.status.success() {
+            true => Ok(Some(String::from_utf8(output.stdout)?)),
+            // A key that is not there fails without a message.
+            false if output.stderr.is_empty() => Ok(None),
+            false => Err(failure(&output)),
+        }
+    }
+
+    pub fn remove(name: &str) -> Result<()> {
+        let output = run(secret_tool("clear", name), None)?;
+        if !output.status.success() {
+            return Err(failure(&output));
+        }
+        Ok(())
+    }
+}
+
+/// macOS and Windows without the `native-keychain` feature, whose
+/// backends are built only on request.
+#[cfg(all(
+    not(feature = "native-keychain"),
+    any(target_os = "macos", windows)
+))]
+mod platform {
+    use anyhow::{Result, anyhow};
+
+    fn unsupported() -> anyhow::Error {
+        anyhow!(
+            "this garner was built without the OS keychain; rebuild it with \
+             `--features native-keychain`"
+        )
+    }
+
+    pub fn store(_name: &str, _key: &str) -> Result<()> { Err(unsupported()) }
+
+    pub fn lookup(_name: &str) -> Result<Option<String>> { Err(unsupported()) }
+
+    pub fn remove(_name: &str) -> Result<()> { Err(unsupported()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("onion").is_ok());
+        assert!(check_name("my site.onion").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("a\nb").is_err());
+        assert!(load("").is_err());
+    }
+
+    /// Store, read, and remove a key through `secret-tool`.  This needs a
+    /// Secret Service on the session bus, so it is skipped where there is
+    /// none or `secret-tool` is not installed.
+    #[test]
+    #[cfg(not(any(target_os = "macos", windows)))]
+    fn test_secret_tool() {
+        let name = format!("garner-test-{}", std::process::id());
+        if let Err(e) = platform::lookup(&name) {
+            eprintln!("skipping the secret-tool test: {e:#}");
+            return;
+        }
+        store(&name, "ur:crypto-prvkeys/test\n").unwrap();
+        let stored = load(&name);
+        let again = store(&name, "ur:crypto-prvkeys/other");
+        remove(&name).unwrap();
+        assert_eq!(stored.unwrap(), "ur:crypto-prvkeys/test");
+        assert!(again.unwrap_err().to_string().contains("remove it first"));
+        assert!(load(&name).is_err());
+        assert!(remove(&name).is_err());
+    }
+}
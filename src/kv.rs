@@ -0,0 +1,168 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::auth::BearerToken;
+
+/// Request path prefix for objects in the depot: `/kv/<KEY>`.
+pub const PATH_PREFIX: &str = "/kv/";
+
+/// A small-object store reached through the onion service, for one owner
+/// who holds its bearer token.
+///
+/// Each value is kept in its own file, named by the SHA-256 digest of its
+/// key, so keys never touch the file system.  Values larger than
+/// `max_size` are refused, as are new keys once `max_keys` are stored.
+pub struct Depot {
+    dir: PathBuf,
+    token: BearerToken,
+    max_size: usize,
+    max_keys: usize,
+    /// Number of keys stored, counted at open and kept up to date by
+    /// puts and deletes.  Held while writing, so concurrent puts cannot
+    /// exceed the key quota.
+    keys: Mutex<usize>,
+}
+
+/// The outcome of storing a value.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Put {
+    Stored,
+    /// The key is new and the depot already holds `max_keys`.
+    Full,
+}
+
+impl Depot {
+    pub fn open(
+        dir: PathBuf,
+        token: &str,
+        max_size: usize,
+        max_keys: usize,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!("creating key-value depot {}", dir.display())
+        })?;
+        let keys = count_values(&dir)?;
+        Ok(Self {
+            dir,
+            token: BearerToken::new(token),
+            max_size,
+            max_keys,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Largest value accepted, in bytes.
+    pub fn max_size(&self) -> usize { self.max_size }
+
+    /// Whether an `Authorization` header carries the depot token.
+    pub fn authorized(&self, authorization: Option<&str>) -> bool {
+        self.token.authorizes(authorization)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+        match std::fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(e).with_context(|| format!("reading {}", path.display()))
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<Put> {
+        let mut keys = self.keys.lock().expect("depot lock");
+        let path = self.path(key);
+        let new = !path.exists();
+        if new && *keys >= self.max_keys {
+            return Ok(Put::Full);
+        }
+        // Write via a temporary file and rename, so a reader never sees
+        // a partial value.
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        std::io::Write::write_all(&mut file, value)?;
+        file.persist(&path)
+            .with_context(|| format!("writing {}", path.display()))?;
+        if new {
+            *keys += 1;
+        }
+        Ok(Put::Stored)
+    }
+
+    /// Remove `key`, returning whether it was stored.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let mut keys = self.keys.lock().expect("depot lock");
+        let path = self.path(key);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                *keys = keys.saturating_sub(1);
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => {
+                Err(e).with_context(|| format!("removing {}", path.display()))
+            }
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(hex::encode(Sha256::digest(key)))
+    }
+}
+
+/// Number of values stored in the depot directory `dir`.
+fn count_values(dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        if entry?.file_name().to_str().is_some_and(is_value_name) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Whether a file in the depot holds a value, rather than being a
+/// temporary file left by an interrupted write.
+fn is_value_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depot() {
+        let dir = tempfile::tempdir().unwrap();
+        let depot =
+            Depot::open(dir.path().join("kv"), "secret", 16, 2).unwrap();
+        assert_eq!(depot.get("a").unwrap(), None);
+        assert_eq!(depot.put("a", b"1").unwrap(), Put::Stored);
+        assert_eq!(depot.put("b", b"2").unwrap(), Put::Stored);
+        assert_eq!(depot.put("c", b"3").unwrap(), Put::Full);
+        // Replacing a stored key does not count against the quota.
+        assert_eq!(depot.put("a", b"one").unwrap(), Put::Stored);
+        assert_eq!(depot.get("a").unwrap().unwrap(), b"one");
+
+        assert!(depot.delete("b").unwrap());
+        assert!(!depot.delete("b").unwrap());
+        assert_eq!(depot.put("c", b"3").unwrap(), Put::Stored);
+        assert_eq!(depot.put("d", b"4").unwrap(), Put::Full);
+
+        // The count is taken again when the depot is reopened.
+        let depot =
+            Depot::open(dir.path().join("kv"), "secret", 16, 3).unwrap();
+        assert_eq!(*depot.keys.lock().unwrap(), 2);
+        assert_eq!(depot.put("d", b"4").unwrap(), Put::Stored);
+        assert_eq!(depot.put("e", b"5").unwrap(), Put::Full);
+    }
+}
@@ -0,0 +1,43 @@
+pub mod api;
+pub mod archive;
+mod auth;
+pub mod bench;
+mod cache;
+mod cache_policy;
+pub mod capabilities;
+pub mod check;
+mod checksums;
+pub mod config;
+pub mod ctl;
+pub mod daemon;
+mod delta;
+mod diagnostics;
+pub mod dictionary;
+mod envelope;
+mod feed;
+/// A C ABI for embedding garner in programs written in other languages.
+pub mod ffi;
+pub mod get;
+mod gstp;
+mod hash_index;
+pub mod hs_dir;
+pub mod key;
+pub mod keychain;
+mod kv;
+mod manifest;
+pub mod mirror;
+mod mnemonic;
+pub mod proxy;
+pub mod qr;
+mod releases;
+mod rewrite;
+pub mod server;
+pub mod sync;
+mod system_tor;
+mod tls;
+pub mod tor;
+mod transform;
+pub mod ui;
+
+pub use server::{GarnerServer, Ready, RequestEvent};
+pub use tokio_util::sync::CancellationToken;
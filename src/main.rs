@@ -1,133 +1,617 @@
-mod get;
-mod key;
-mod server;
-mod ui;
-
-use std::path::PathBuf;
-
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context as _, Result};
+use clap::{Args, CommandFactory as _, Parser, Subcommand};
+use garner::{
+    api, bench, capabilities, check, config, ctl, daemon, dictionary, get,
+    hs_dir, key, keychain, mirror, proxy, qr, server, sync, tor, ui,
+};
 
 #[derive(Parser)]
-#[command(about = "A Tor onion service that serves static files over HTTP")]
+#[command(
+    about = "A Tor onion service that serves static files over HTTP",
+    args_override_self = true
+)]
 struct Cli {
+    /// Read options from a TOML configuration file
+    #[arg(long, global = true, value_name = "PATH", env = "GARNER_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Async runtime settings shared by every subcommand.
+#[derive(Args)]
+struct RuntimeArgs {
+    /// Run on a single thread (smallest footprint, for tiny devices)
+    #[arg(long, global = true, conflicts_with = "workers")]
+    current_thread: bool,
+    /// Number of runtime worker threads [default: one per CPU core]
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: Option<u16>,
+    /// Pin runtime threads to CPU cores, round-robin (Linux only)
+    #[arg(long, global = true)]
+    pin_cores: bool,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run the Tor onion service, serving static files
-    Server {
-        /// Ed25519 private key in UR format for a deterministic .onion address
-        #[arg(long, env = "GARNER_KEY")]
-        key: Option<String>,
-        /// Directory to serve files from [default: public]
-        #[arg(long, default_value = "public")]
-        docroot: String,
-    },
+    Server(Box<server::ServerArgs>),
     /// Fetch a document from a .onion URL over Tor
-    Get {
-        /// URL(s) or path(s) to fetch (paths when --key or --address is given)
-        #[arg(required = true)]
-        urls: Vec<String>,
-        /// Ed25519 public key in UR format to derive the .onion address
-        #[arg(long, env = "GARNER_KEY")]
-        key: Option<String>,
-        /// The .onion address to connect to (e.g. xxxx.onion)
-        #[arg(long, env = "GARNER_ADDRESS")]
-        address: Option<String>,
+    Get(Box<get::GetArgs>),
+    /// Mirror a site served with --deltas into a local directory,
+    /// downloading only deltas for files that changed
+    Sync(sync::SyncArgs),
+    /// Download an onion site by following its links, writing each file
+    /// under a directory at its path
+    Mirror(mirror::MirrorArgs),
+    /// Run a local proxy that reaches onion services through Tor, for
+    /// browsers and other programs
+    Proxy(proxy::ProxyArgs),
+    /// Measure how quickly an onion service can be reached and how fast
+    /// it answers
+    Bench(bench::BenchArgs),
+    /// Check that an onion service is published and answering, for uptime
+    /// monitoring
+    Check(check::CheckArgs),
+    /// Keep a Tor client bootstrapped in the background for `get` and
+    /// other commands to connect through, or stop or query one
+    Daemon(daemon::DaemonArgs),
+    /// Send a command to a running server's control socket
+    Ctl(ctl::CtlArgs),
+    /// Serve a local JSON-RPC API for launching services and fetching
+    /// URLs over one Tor client
+    Api(api::ApiArgs),
+    /// Import a directory into a SQLite content database, replacing its
+    /// previous content in one transaction
+    #[cfg(feature = "sqlite")]
+    PublishDb {
+        /// Directory to import
+        docroot: std::path::PathBuf,
+        /// Content database to create or update (serve it with --docroot)
+        database: std::path::PathBuf,
+    },
+    /// Download the Tor network directory into garner's shared cache and
+    /// exit, so that later commands start faster
+    Bootstrap {
+        #[command(flatten)]
+        tor: tor::TorArgs,
+    },
+    /// Reassemble a private key from the SSKR shares that `garner
+    /// generate sskr` printed
+    Recover {
+        /// ur:sskr shares to combine [default: read from stdin, one per
+        /// line]
+        shares: Vec<String>,
     },
     /// Generate keys and other artifacts
     Generate {
         #[command(subcommand)]
         command: GenerateCommands,
     },
+    /// Convert keys to and from other tools' formats
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// Print the garner version
+    Version {
+        /// Also list compiled-in features and the active runtime settings
+        #[arg(long)]
+        features: bool,
+    },
 }
 
 #[derive(Subcommand)]
 enum GenerateCommands {
     /// Generate an Ed25519 keypair for use with garner server/get
-    Keypair,
+    Keypair {
+        /// Derive the keypair from SEED rather than at random: a ur:seed,
+        /// hex, or passphrase:<PASSPHRASE>
+        #[arg(long, value_name = "SEED", env = "GARNER_SEED")]
+        from_seed: Option<String>,
+        /// Also print the private key's 24-word recovery phrase, which
+        /// --key mnemonic:"…" accepts in place of its UR
+        #[arg(long)]
+        mnemonic: bool,
+        /// Print the private key encrypted with a passphrase, asked for on
+        /// the terminal; --key accepts it and asks for the passphrase
+        #[arg(long, conflicts_with = "mnemonic")]
+        encrypt: bool,
+        /// Also show the public key UR as a QR code, on stderr
+        #[arg(long)]
+        qr: bool,
+        /// Write the public key UR as a QR code PNG image to FILE
+        #[arg(long, value_name = "FILE")]
+        qr_png: Option<std::path::PathBuf>,
+    },
+    /// Print the public key UR and .onion address of a private key
+    Pubkey {
+        /// Ed25519 private key in UR format, or "-" to read it from stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the private key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+    },
+    /// Print the .onion address of a public key
+    Address {
+        /// Ed25519 public key in UR format, or "-" to read it from stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the public key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+    },
+    /// Split a private key into SSKR shares, printed one UR per line with
+    /// a blank line between groups; `garner recover` reassembles them
+    Sskr {
+        /// Ed25519 private key in UR format to split, or "-" to read it from
+        /// stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the private key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+        /// A group of shares, as M-of-N: N shares, any M of which make up
+        /// the group (repeatable)
+        #[arg(long = "groups", value_name = "M-of-N", required = true)]
+        groups: Vec<String>,
+        /// Number of groups needed to restore the key
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        group_threshold: usize,
+    },
+    /// Train a Zstandard dictionary on a site's files, for `garner server
+    /// --zstd-dictionary`
+    Dictionary {
+        /// Directory of files to train on
+        #[arg(long, default_value = "public")]
+        docroot: std::path::PathBuf,
+        /// Where to write the dictionary
+        #[arg(long, short, value_name = "FILE")]
+        output: std::path::PathBuf,
+        /// Largest dictionary size, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = dictionary::DEFAULT_SIZE)]
+        size: usize,
+    },
 }
 
-/// Build a [`TorClientConfigBuilder`] with garner's standard settings:
-/// ephemeral (in-memory) keystore so switching keys never conflicts.
-/// Callers provide explicit state and cache paths obtained from
-/// [`tor_dirs`].
-fn tor_config(
-    state_dir: impl AsRef<std::path::Path>,
-    cache_dir: impl AsRef<std::path::Path>,
-) -> arti_client::config::TorClientConfigBuilder {
-    let mut builder =
-        arti_client::config::TorClientConfigBuilder::from_directories(
-            state_dir, cache_dir,
-        );
-    builder.storage().keystore().primary().kind(
-        tor_config::ExplicitOrAuto::Explicit(
-            tor_keymgr::config::ArtiKeystoreKind::Ephemeral,
-        ),
-    );
-    builder
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Read the key of a C Tor onion service from its HiddenServiceDir,
+    /// printing it as a tor: key on line 1 and its public key UR on line 2
+    ImportTor {
+        /// The service's HiddenServiceDir
+        dir: std::path::PathBuf,
+    },
+    /// Write a private key into a complete HiddenServiceDir, for a stock
+    /// Tor daemon to serve
+    Export {
+        /// Directory to create for the service (HiddenServiceDir in torrc)
+        #[arg(long, value_name = "PATH")]
+        hs_dir: std::path::PathBuf,
+        /// Ed25519 private key in UR format, or "-" to read it from stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the private key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+    },
+    /// Write a private key into DIR as C Tor's hs_ed25519_secret_key and
+    /// hs_ed25519_public_key files
+    ExportTor {
+        /// Directory to write the key files into
+        dir: std::path::PathBuf,
+        /// Ed25519 private key in UR format, or "-" to read it from stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the private key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+    },
+    /// Store a private key in the OS keychain as NAME, for --key
+    /// keychain:NAME to use
+    Store {
+        /// Name to store the key under
+        name: String,
+        /// Ed25519 private key in UR format, or "-" to read it from stdin
+        #[arg(long, env = "GARNER_KEY", required_unless_present = "key_file")]
+        key: Option<String>,
+        /// Read the private key from FILE, or from stdin for "-", instead of
+        /// --key or GARNER_KEY
+        #[arg(long, value_name = "FILE")]
+        key_file: Option<std::path::PathBuf>,
+    },
+    /// Remove the private key stored in the OS keychain as NAME
+    Remove {
+        /// Name the key is stored under
+        name: String,
+    },
+}
+
+impl RuntimeArgs {
+    /// Describe the runtime these settings produce, for the capability
+    /// report.
+    fn describe(&self) -> String {
+        let mut description = if self.current_thread {
+            "current-thread".to_string()
+        } else {
+            let workers = self.workers.map_or_else(
+                || {
+                    std::thread::available_parallelism()
+                        .map(|n| n.get())
+                        .unwrap_or(1)
+                },
+                usize::from,
+            );
+            format!("multi-thread, {workers} workers")
+        };
+        if self.pin_cores {
+            description.push_str(", pinned to cores");
+        }
+        description
+    }
+}
+
+/// Build the Tokio runtime described by `args`.
+fn build_runtime(args: &RuntimeArgs) -> Result<tokio::runtime::Runtime> {
+    let mut builder = if args.current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(workers) = args.workers {
+            builder.worker_threads(workers.into());
+        }
+        builder
+    };
+    builder.enable_all();
+    if args.pin_cores {
+        #[cfg(target_os = "linux")]
+        {
+            let cores = std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1);
+            let next = std::sync::atomic::AtomicUsize::new(0);
+            builder.on_thread_start(move || {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                pin_current_thread(i % cores);
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!("--pin-cores is only supported on Linux"));
+    }
+    Ok(builder.build()?)
 }
 
-/// Create an ephemeral state directory and the shared cache directory
-/// under garner's data dir.  Returns `(state_dir, cache_dir)` where
-/// `state_dir` is a [`tempfile::TempDir`] that is automatically deleted
-/// when dropped.
-///
-/// Callers must keep `state_dir` alive for the lifetime of the
-/// `TorClient`, and must declare it *before* the `TorClient` so that
-/// Rust's reverse drop order releases the Tor locks before the
-/// directory is removed.
-fn tor_dirs() -> Result<(tempfile::TempDir, PathBuf)> {
-    let data_dir = ui::data_dir();
-    std::fs::create_dir_all(&data_dir)?;
-    let cache_dir = data_dir.join("cache");
-    let state_dir = tempfile::Builder::new()
-        .prefix("state-")
-        .tempdir_in(&data_dir)?;
-    // Arti requires state dirs to be owner-only (0o700).  tempfile
-    // inherits the default umask (typically 0o755 on macOS).
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        std::fs::set_permissions(
-            state_dir.path(),
-            std::fs::Permissions::from_mode(0o700),
-        )?;
+/// Restrict the calling thread to a single CPU core.  Failure is not
+/// fatal: the thread simply keeps its default affinity.
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) {
+    // SAFETY: `set` is a zero-initialized, properly sized cpu_set_t that
+    // outlives both calls, and pid 0 refers to the calling thread.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set);
     }
-    Ok((state_dir, cache_dir))
 }
 
-fn generate_keypair() -> Result<()> {
-    let (priv_ur, pub_ur) = key::generate_keypair()?;
-    println!("{priv_ur}");
+/// The key given as `--key` or `--key-file`, one of which clap requires.
+fn key_arg(
+    key: Option<String>,
+    key_file: Option<std::path::PathBuf>,
+) -> Result<String> {
+    key::read_key_arg(key, key_file.as_deref())?
+        .context("--key or --key-file is required")
+}
+
+fn generate_keypair(
+    from_seed: Option<&str>,
+    mnemonic: bool,
+    encrypt: bool,
+    qr: bool,
+    qr_png: Option<&std::path::Path>,
+) -> Result<()> {
+    let (priv_ur, pub_ur) = match from_seed {
+        Some(seed) => key::generate_keypair_from_seed(seed)?,
+        None => key::generate_keypair()?,
+    };
+    if encrypt {
+        let passphrase = ui::read_passphrase("Passphrase for the key: ")?;
+        if ui::read_passphrase("Repeat the passphrase: ")? != passphrase {
+            return Err(anyhow::anyhow!("the passphrases do not match"));
+        }
+        println!("{}", key::encrypt_private_key(&priv_ur, &passphrase)?);
+    } else {
+        println!("{priv_ur}");
+    }
     println!("{pub_ur}");
+    if mnemonic {
+        println!("{}", key::mnemonic_for_private_key(&priv_ur)?);
+    }
+    // URs go in QR codes in uppercase, which they encode more compactly.
+    let pub_ur = pub_ur.to_uppercase();
+    if let Some(path) = qr_png {
+        qr::write_png(path, &pub_ur)?;
+    }
+    if qr {
+        eprint!("{}", qr::QrCode::encode(&pub_ur)?.to_terminal());
+    }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    bc_components::register_tags();
-    let cli = Cli::parse();
-    let result = match cli.command {
-        Commands::Server { key, docroot } => {
-            server::run(key.as_deref(), &docroot).await
-        }
-        Commands::Get { urls, key, address } => {
-            get::run(&urls, key.as_deref(), address.as_deref()).await
+fn main() {
+    bc_envelope::register_tags();
+    if let Err(e) = try_main() {
+        if e.is::<get::Unchanged>() {
+            std::process::exit(get::EXIT_UNCHANGED);
         }
-        Commands::Generate { command } => match command {
-            GenerateCommands::Keypair => generate_keypair(),
-        },
-    };
-    if let Err(e) = result {
         if ui::is_interactive() {
             eprintln!("\x1b[1;31merror: {e:#}\x1b[0m");
         } else {
             eprintln!("error: {e:#}");
         }
-        std::process::exit(1);
+        let status = e.downcast_ref::<get::Failed>().map_or(1, |f| f.status);
+        std::process::exit(status);
+    }
+}
+
+fn try_main() -> Result<()> {
+    let args =
+        config::expand_args(std::env::args_os().collect(), &Cli::command())?;
+    let cli = Cli::parse_from(args);
+    let mut report = capabilities::Report::new();
+    if let Some(path) = &cli.config {
+        report.set_config_file(path);
+    }
+    report.section("runtime", cli.runtime.describe());
+    let runtime = build_runtime(&cli.runtime)?;
+    runtime.block_on(run(cli.command, report))
+}
+
+async fn run(command: Commands, report: capabilities::Report) -> Result<()> {
+    match command {
+        Commands::Server(args) => server::run(*args, report).await,
+        Commands::Get(args) => get::run(*args).await,
+        Commands::Sync(args) => sync::run(args).await,
+        Commands::Mirror(args) => mirror::run(args).await,
+        Commands::Proxy(args) => proxy::run(args).await,
+        Commands::Bench(args) => {
+            let runtime = report.value("runtime").unwrap_or("").to_string();
+            bench::run(args, runtime).await
+        }
+        Commands::Check(args) => check::run(args).await,
+        Commands::Daemon(args) => daemon::run(args).await,
+        Commands::Ctl(args) => ctl::run(args).await,
+        Commands::Api(args) => api::run(args).await,
+        #[cfg(feature = "sqlite")]
+        Commands::PublishDb { docroot, database } => {
+            let count = garner::archive::publish_db(&docroot, &database)?;
+            let files = if count == 1 { "file" } else { "files" };
+            eprintln!("Published {count} {files} to {}", database.display());
+            Ok(())
+        }
+        Commands::Bootstrap { tor } => {
+            let started = std::time::Instant::now();
+            eprintln!("Bootstrapping Tor...");
+            let cache_dir = tor::prewarm(&tor).await?;
+            eprintln!(
+                "Cached the Tor directory in {} ({:.1}s)",
+                cache_dir.display(),
+                started.elapsed().as_secs_f64()
+            );
+            Ok(())
+        }
+        Commands::Recover { mut shares } => {
+            if shares.is_empty() {
+                let stdin = std::io::read_to_string(std::io::stdin())
+                    .context("reading shares from stdin")?;
+                shares = stdin
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            println!("{}", key::sskr_recover(&shares)?);
+            Ok(())
+        }
+        Commands::Key { command } => match command {
+            KeyCommands::ImportTor { dir } => {
+                let tor_key = hs_dir::import(&dir)?;
+                let pub_ur = key::public_key_ur_from_private_key(&tor_key)?;
+                eprintln!(
+                    "Imported the key for {}",
+                    key::parse_public_key_to_onion_host(&pub_ur)?
+                );
+                println!("{tor_key}");
+                println!("{pub_ur}");
+                Ok(())
+            }
+            KeyCommands::Export {
+                hs_dir,
+                key,
+                key_file,
+            } => {
+                let key = key::unlock(&key_arg(key, key_file)?)?;
+                let host = hs_dir::export_service(&hs_dir, &key)?;
+                eprintln!("Wrote {} for {host}", hs_dir.display());
+                eprintln!(
+                    "Serve it with these torrc lines, pointing the port at \
+                     your web server:"
+                );
+                eprintln!("  HiddenServiceDir {}", hs_dir.display());
+                eprintln!("  HiddenServicePort 80 127.0.0.1:8080");
+                Ok(())
+            }
+            KeyCommands::Store {
+                name,
+                key,
+                key_file,
+            } => {
+                let key = key_arg(key, key_file)?;
+                // An encrypted key is stored as it is, and asks for its
+                // passphrase when used.
+                if !key::is_encrypted(&key) {
+                    key::parse_expanded_keypair(&key)?;
+                }
+                keychain::store(&name, &key)?;
+                eprintln!("Stored the key as {}{name}", keychain::PREFIX);
+                Ok(())
+            }
+            KeyCommands::Remove { name } => {
+                keychain::remove(&name)?;
+                eprintln!("Removed {}{name}", keychain::PREFIX);
+                Ok(())
+            }
+            KeyCommands::ExportTor { dir, key, key_file } => {
+                let key = key::unlock(&key_arg(key, key_file)?)?;
+                hs_dir::export(&dir, &key)?;
+                eprintln!("Wrote the key files to {}", dir.display());
+                Ok(())
+            }
+        },
+        Commands::Generate { command } => match command {
+            GenerateCommands::Keypair {
+                from_seed,
+                mnemonic,
+                encrypt,
+                qr,
+                qr_png,
+            } => generate_keypair(
+                from_seed.as_deref(),
+                mnemonic,
+                encrypt,
+                qr,
+                qr_png.as_deref(),
+            ),
+            GenerateCommands::Pubkey { key, key_file } => {
+                let key = key::unlock(&key_arg(key, key_file)?)?;
+                let pub_ur = key::public_key_ur_from_private_key(&key)?;
+                println!("{pub_ur}");
+                println!("{}", key::parse_public_key_to_onion_host(&pub_ur)?);
+                Ok(())
+            }
+            GenerateCommands::Address { key, key_file } => {
+                let key = key_arg(key, key_file)?;
+                println!("{}", key::parse_public_key_to_onion_host(&key)?);
+                Ok(())
+            }
+            GenerateCommands::Sskr {
+                key,
+                key_file,
+                groups,
+                group_threshold,
+            } => {
+                let key = key::unlock(&key_arg(key, key_file)?)?;
+                let shares = key::sskr_split(&key, group_threshold, &groups)?;
+                let groups: Vec<String> =
+                    shares.iter().map(|group| group.join("\n")).collect();
+                println!("{}", groups.join("\n\n"));
+                Ok(())
+            }
+            GenerateCommands::Dictionary {
+                docroot,
+                output,
+                size,
+            } => {
+                let data = dictionary::build(&docroot, size)?;
+                std::fs::write(&output, &data)
+                    .with_context(|| format!("writing {}", output.display()))?;
+                eprintln!(
+                    "Wrote a {}-byte dictionary to {}",
+                    data.len(),
+                    output.display()
+                );
+                Ok(())
+            }
+        },
+        Commands::Version { features } => {
+            if features {
+                for line in report.lines() {
+                    println!("{line}");
+                }
+            } else {
+                println!("garner {}", env!("CARGO_PKG_VERSION"));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime_args(args: &[&str]) -> clap::error::Result<RuntimeArgs> {
+        let argv = ["garner"].iter().chain(args).chain(&["version"]);
+        Cli::try_parse_from(argv).map(|cli| cli.runtime)
+    }
+
+    #[test]
+    fn test_runtime_args() {
+        let single = runtime_args(&["--current-thread"]).unwrap();
+        assert_eq!(single.describe(), "current-thread");
+        let runtime = build_runtime(&single).unwrap();
+        assert_eq!(
+            runtime.handle().runtime_flavor(),
+            tokio::runtime::RuntimeFlavor::CurrentThread
+        );
+
+        let two = runtime_args(&["--workers", "2"]).unwrap();
+        assert_eq!(two.describe(), "multi-thread, 2 workers");
+        assert_eq!(build_runtime(&two).unwrap().metrics().num_workers(), 2);
+
+        let pinned = runtime_args(&["--workers", "1", "--pin-cores"]).unwrap();
+        assert_eq!(
+            pinned.describe(),
+            "multi-thread, 1 workers, pinned to cores"
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(build_runtime(&pinned).unwrap().block_on(async { 1 }), 1);
+
+        assert!(runtime_args(&["--current-thread", "--workers", "2"]).is_err());
+        assert!(runtime_args(&["--workers", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_generate_address_args() {
+        bc_envelope::register_tags();
+        let (_, public) = key::generate_keypair().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pubkey.ur");
+        std::fs::write(&path, format!("{public}\n")).unwrap();
+        let address = |args: &[&str]| {
+            let argv = ["garner", "generate", "address"].iter().chain(args);
+            let cli = Cli::try_parse_from(argv)?;
+            let Commands::Generate {
+                command: GenerateCommands::Address { key, key_file },
+            } = cli.command
+            else {
+                panic!("not generate address");
+            };
+            key::parse_public_key_to_onion_host(&key_arg(key, key_file)?)
+        };
+
+        let onion = key::parse_public_key_to_onion_host(&public).unwrap();
+        assert_eq!(address(&["--key", &public]).unwrap(), onion);
+        let path = path.to_str().unwrap();
+        assert_eq!(address(&["--key-file", path]).unwrap(), onion);
+        assert!(address(&["--key", "ur:nonsense"]).is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_args() {
+        let cli = Cli::try_parse_from(["garner", "bootstrap"]).unwrap();
+        assert!(matches!(cli.command, Commands::Bootstrap { .. }));
+        assert!(
+            Cli::try_parse_from(["garner", "bootstrap", "x.onion"]).is_err()
+        );
     }
 }
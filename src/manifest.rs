@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+/// Path of the manifest.
+pub const PATH: &str = "/.manifest.json";
+
+/// A file listed in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the docroot, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// SHA-256 digest of the content, in hex.
+    pub digest: String,
+}
+
+/// A listing of every file under a docroot with its size, modification
+/// time, and digest, for mirroring tools and integrity checkers.
+///
+/// Files are hashed when first listed and again whenever their size or
+/// modification time changes, so serving the manifest costs a directory
+/// walk but rarely a read.
+pub struct Manifest {
+    docroot: PathBuf,
+    files: Mutex<HashMap<String, Entry>>,
+}
+
+impl Manifest {
+    pub fn open(docroot: &Path) -> Result<Self> {
+        let manifest = Self {
+            docroot: docroot.to_path_buf(),
+            files: Mutex::new(HashMap::new()),
+        };
+        manifest.entries()?;
+        Ok(manifest)
+    }
+
+    /// Every file under the docroot, sorted by path.  Symlinked
+    /// directories are not followed.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let mut files = self.files.lock().expect("manifest lock");
+        let mut current = HashMap::new();
+        let mut dirs = vec![(self.docroot.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("reading {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                let Some(name) = entry.file_name().to_str().map(str::to_owned)
+                else {
+                    continue;
+                };
+                let path = entry.path();
+                let relative = format!("{prefix}{name}");
+                if entry.file_type()?.is_dir() {
+                    dirs.push((path, format!("{relative}/")));
+                    continue;
+                }
+                let meta = std::fs::metadata(&path)?;
+                if !meta.is_file() {
+                    continue;
+                }
+                let (size, modified) = (meta.len(), meta.modified().ok());
+                let file = match files.remove(&relative) {
+                    Some(known)
+                        if (known.size, known.modified) == (size, modified) =>
+                    {
+                        known
+                    }
+                    _ => {
+                        let data = std::fs::read(&path).with_context(|| {
+                            format!("reading {}", path.display())
+                        })?;
+                        Entry {
+                            path: relative.clone(),
+                            size,
+                            modified,
+                            digest: hex::encode(Sha256::digest(&data)),
+                        }
+                    }
+                };
+                current.insert(relative, file);
+            }
+        }
+        *files = current;
+        let mut entries: Vec<Entry> = files.values().cloned().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// The manifest as served:
+    /// `{"files": [{"path", "size", "modified", "sha256"}, ...]}`, with
+    /// modification times in RFC 3339 format.
+    pub fn json(&self) -> Result<Vec<u8>> {
+        let files: Vec<Value> = self
+            .entries()?
+            .iter()
+            .map(|entry| {
+                json!({
+                    "path": entry.path,
+                    "size": entry.size,
+                    "modified": entry
+                        .modified
+                        .map(|time| DateTime::<Utc>::from(time).to_rfc3339()),
+                    "sha256": entry.digest,
+                })
+            })
+            .collect();
+        Ok(json!({ "files": files }).to_string().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/b.txt"), "bee").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let manifest = Manifest::open(dir.path()).unwrap();
+
+        let json: Value =
+            serde_json::from_slice(&manifest.json().unwrap()).unwrap();
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["path"], "a.txt");
+        assert_eq!(files[0]["size"], 5);
+        assert_eq!(files[0]["sha256"], hex::encode(Sha256::digest(b"hello")));
+        assert!(files[0]["modified"].is_string());
+        assert_eq!(files[1]["path"], "sub/b.txt");
+
+        std::fs::write(dir.path().join("a.txt"), "changed").unwrap();
+        let entries = manifest.entries().unwrap();
+        assert_eq!(entries[0].digest, hex::encode(Sha256::digest(b"changed")));
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use futures_util::{StreamExt as _, stream};
+
+use crate::{get, tor};
+
+/// Options for `garner mirror`.
+#[derive(Args)]
+pub struct MirrorArgs {
+    /// URL of the page to start from (e.g. http://xxxx.onion/)
+    pub url: String,
+    /// Directory to write the site into, keeping its paths
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: PathBuf,
+    /// Follow links at most N levels deep from the start page
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub depth: u32,
+    /// Fetch at most N pages at a time
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub jobs: u32,
+    /// Read pages at most RATE bytes per second in all, with an optional
+    /// K, M, or G suffix (e.g. 200k)
+    #[arg(long, value_name = "RATE")]
+    pub limit_rate: Option<String>,
+    /// Connect through a running Tor daemon's SOCKS port (e.g.
+    /// 127.0.0.1:9050) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_SOCKS")]
+    pub tor_socks: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+pub async fn run(args: MirrorArgs) -> Result<()> {
+    let start = args.url.strip_prefix("http://").unwrap_or(&args.url);
+    let (host, start_path) = match start.find('/') {
+        Some(i) => (&start[..i], &start[i..]),
+        None => (start, "/"),
+    };
+    if !host.split(':').next().unwrap_or("").ends_with(".onion") {
+        return Err(anyhow!("expected a .onion URL, got: {}", args.url));
+    }
+    let start_path = resolve(start_path, host, start_path)
+        .ok_or_else(|| anyhow!("cannot mirror from {}", args.url))?;
+    let rate_limit = args.limit_rate.as_deref().map(get::parse_rate);
+    let fetcher = get::Fetcher::connect(args.tor_socks.as_deref(), &args.tor)
+        .await?
+        .with_rate_limit(rate_limit.transpose()?);
+
+    // Breadth first, one level at a time, so --depth counts the shortest
+    // way to each page.
+    let mut seen = HashSet::from([start_path.clone()]);
+    let mut level = vec![start_path];
+    let (mut files, mut bytes, mut failed) = (0, 0, 0);
+    for depth in 0..=args.depth {
+        if level.is_empty() {
+            break;
+        }
+        let fetches = stream::iter(std::mem::take(&mut level))
+            .map(|path| async {
+                let url = format!("{host}{path}");
+                let result = fetcher.fetch(&url, &[]).await;
+                (path, url, result)
+            })
+            .buffer_unordered(args.jobs as usize)
+            .collect::<Vec<_>>()
+            .await;
+        for (path, url, result) in fetches {
+            let links = match result {
+                Ok(response) => {
+                    let file = get::mirror_path(&args.output_dir, &url)?;
+                    if let Some(parent) = file.parent() {
+                        std::fs::create_dir_all(parent).with_context(|| {
+                            format!("creating {}", parent.display())
+                        })?;
+                    }
+                    std::fs::write(&file, &response.body).with_context(
+                        || format!("writing {}", file.display()),
+                    )?;
+                    files += 1;
+                    bytes += response.body.len();
+                    match is_html(&path, response.header("content-type")) {
+                        true => links(&String::from_utf8_lossy(&response.body)),
+                        false => Vec::new(),
+                    }
+                }
+                // A redirect on the same host leads to another page to
+                // mirror, such as a directory's path with its slash.
+                Err(e) if e.is::<get::Redirect>() => {
+                    let redirect =
+                        e.downcast_ref::<get::Redirect>().expect("redirect");
+                    vec![redirect.location.clone()]
+                }
+                Err(e) => {
+                    eprintln!("{url}: {e:#}");
+                    failed += 1;
+                    Vec::new()
+                }
+            };
+            if depth == args.depth {
+                continue;
+            }
+            for link in links {
+                if let Some(next) = resolve(&path, host, &link)
+                    && seen.insert(next.clone())
+                {
+                    level.push(next);
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "Mirrored {files} file(s) ({bytes} bytes) into {}",
+        args.output_dir.display()
+    );
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} URLs failed", files + failed));
+    }
+    Ok(())
+}
+
+/// Whether a page is HTML, by its `Content-Type` or else its path.
+fn is_html(path: &str, content_type: Option<&str>) -> bool {
+    match content_type {
+        Some(value) => value
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .eq_ignore_ascii_case("text/html"),
+        None => path.ends_with('/') || path.ends_with(".html"),
+    }
+}
+
+/// The values of the `href` and `src` attributes in `html`.
+fn links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+    for attribute in ["href", "src"] {
+        let mut search = 0;
+        while let Some(found) = lower[search..].find(attribute) {
+            let start = search + found;
+            search = start + attribute.len();
+            // The name must stand alone, as in `<a href=`, not `data-href=`.
+            let standalone = lower[..start]
+                .chars()
+                .next_back()
+                .is_some_and(char::is_whitespace);
+            let rest = lower[search..].trim_start();
+            let Some(value) = rest.strip_prefix('=').filter(|_| standalone)
+            else {
+                continue;
+            };
+            let value_start = html.len() - value.trim_start().len();
+            let value = &html[value_start..];
+            let link = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    value[1..].split(quote).next().unwrap_or("")
+                }
+                _ => value
+                    .split(|c: char| c.is_whitespace() || c == '>')
+                    .next()
+                    .unwrap_or(""),
+            };
+            links.push(link.trim().replace("&amp;", "&"));
+        }
+    }
+    links
+}
+
+/// The path on `host` that `link`, found on the page at `page`, leads
+/// to, without its query or fragment.  Links to other hosts or schemes
+/// lead nowhere to mirror.
+fn resolve(page: &str, host: &str, link: &str) -> Option<String> {
+    let link = link.split(['#', '?']).next().unwrap_or("");
+    let absolute =
+        match link.strip_prefix("http://").or(link.strip_prefix("//")) {
+            Some(rest) => {
+                let (link_host, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                if !link_host.eq_ignore_ascii_case(host) {
+                    return None;
+                }
+                path.to_string()
+            }
+            // Another scheme, such as https: or mailto:.
+            None if link.split('/').next().unwrap_or("").contains(':') => {
+                return None;
+            }
+            None if link.is_empty() => return None,
+            None if link.starts_with('/') => link.to_string(),
+            None => {
+                let dir = &page[..page.rfind('/').map_or(0, |i| i + 1)];
+                format!("{dir}{link}")
+            }
+        };
+
+    // Remove `.` and `..` segments, as a browser would.
+    let mut segments: Vec<&str> = Vec::new();
+    let trailing = absolute.ends_with('/')
+        || absolute.ends_with("/.")
+        || absolute.ends_with("/..");
+    for segment in absolute.split('/').filter(|s| !s.is_empty()) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if trailing && !segments.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links() {
+        let html = r#"<a href="/a.html">A</a> <IMG SRC='b.png'>
+<link rel=stylesheet href=style.css><a data-href="/no">x</a>
+<a href = "c?x=1&amp;y=2">C</a>"#;
+        assert_eq!(links(html), ["/a.html", "style.css", "c?x=1&y=2", "b.png"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let host = "abc.onion";
+        let page = "/docs/guide/index.html";
+        for (link, path) in [
+            ("/a.html", Some("/a.html")),
+            ("b.html#top", Some("/docs/guide/b.html")),
+            ("../c/", Some("/docs/c/")),
+            ("./", Some("/docs/guide/")),
+            ("../../../../etc/passwd", Some("/etc/passwd")),
+            ("http://abc.onion/d?q=1", Some("/d")),
+            ("//ABC.onion", Some("/")),
+            ("http://other.onion/", None),
+            ("https://abc.onion/", None),
+            ("mailto:me@example.com", None),
+            ("#top", None),
+        ] {
+            assert_eq!(resolve(page, host, link).as_deref(), path, "{link}");
+        }
+    }
+
+    #[test]
+    fn test_is_html() {
+        assert!(is_html("/a", Some("text/html; charset=utf-8")));
+        assert!(!is_html("/a.html", Some("text/plain")));
+        assert!(is_html("/docs/", None));
+        assert!(!is_html("/a.png", None));
+    }
+}
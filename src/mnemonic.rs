@@ -0,0 +1,134 @@
+use std::sync::LazyLock;
+
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+
+/// The BIP-39 English wordlist, in order.
+static WORDS: LazyLock<Vec<&'static str>> =
+    LazyLock::new(|| include_str!("bip39_english.txt").lines().collect());
+
+/// Prefix that marks a recovery phrase where a key UR is expected.
+pub const PREFIX: &str = "mnemonic:";
+
+/// Encode `entropy` (16 to 32 bytes, in steps of 4) as a BIP-39 phrase:
+/// 11 bits per word, the last word ending in a checksum of the first bits
+/// of its SHA-256 digest.
+pub fn to_phrase(entropy: &[u8]) -> Result<String> {
+    if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+        return Err(anyhow!(
+            "a recovery phrase holds 16 to 32 bytes in steps of 4, not {}",
+            entropy.len()
+        ));
+    }
+    let mut bits = bits_of(entropy);
+    let checksum = Sha256::digest(entropy);
+    bits.extend(bits_of(&checksum).into_iter().take(entropy.len() / 4));
+    let words: Vec<&str> = bits
+        .chunks(11)
+        .map(|chunk| {
+            WORDS[chunk.iter().fold(0, |n, &bit| n << 1 | usize::from(bit))]
+        })
+        .collect();
+    Ok(words.join(" "))
+}
+
+/// Decode a BIP-39 phrase of 12 to 24 words back into the bytes it
+/// holds, checking its checksum.
+pub fn from_phrase(phrase: &str) -> Result<Vec<u8>> {
+    let words: Vec<String> =
+        phrase.split_whitespace().map(str::to_lowercase).collect();
+    if !(12..=24).contains(&words.len()) || !words.len().is_multiple_of(3) {
+        return Err(anyhow!(
+            "a recovery phrase has 12, 15, 18, 21, or 24 words, not {}",
+            words.len()
+        ));
+    }
+    let mut bits = Vec::with_capacity(words.len() * 11);
+    for (number, word) in words.iter().enumerate() {
+        let index = WORDS.binary_search(&word.as_str()).map_err(|_| {
+            anyhow!("word {} ({word:?}) is not a BIP-39 word", number + 1)
+        })?;
+        bits.extend((0..11).rev().map(|shift| (index >> shift) & 1 == 1));
+    }
+    let checksum_bits = words.len() / 3;
+    let (data, checksum) = bits.split_at(bits.len() - checksum_bits);
+    let entropy: Vec<u8> = data
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |n, &bit| n << 1 | u8::from(bit)))
+        .collect();
+    let digest = bits_of(&Sha256::digest(&entropy));
+    if digest[..checksum_bits] != *checksum {
+        return Err(anyhow!(
+            "the recovery phrase's checksum does not match; check it for \
+             typos"
+        ));
+    }
+    Ok(entropy)
+}
+
+fn bits_of(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| {
+            (0..8).rev().map(move |shift| (byte >> shift) & 1 == 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wordlist() {
+        assert_eq!(WORDS.len(), 2048);
+        assert!(WORDS.is_sorted());
+        assert_eq!(WORDS[0], "abandon");
+        assert_eq!(WORDS[2047], "zoo");
+    }
+
+    #[test]
+    fn test_vectors() {
+        // From the BIP-39 reference test vectors.
+        for (entropy, phrase) in [
+            (
+                "00000000000000000000000000000000",
+                "abandon abandon abandon abandon abandon abandon abandon \
+                 abandon abandon abandon abandon about",
+            ),
+            (
+                "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+                "legal winner thank year wave sausage worth useful legal \
+                 winner thank yellow",
+            ),
+            (
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo \
+                 zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            ),
+        ] {
+            let entropy = hex::decode(entropy).unwrap();
+            assert_eq!(to_phrase(&entropy).unwrap(), phrase);
+            assert_eq!(from_phrase(phrase).unwrap(), entropy);
+        }
+    }
+
+    #[test]
+    fn test_invalid_phrases() {
+        let phrase = to_phrase(&[7; 32]).unwrap();
+        assert_eq!(from_phrase(&phrase.to_uppercase()).unwrap(), [7; 32]);
+        let swapped = phrase.replacen(
+            phrase.split(' ').next().unwrap(),
+            if phrase.starts_with("able") {
+                "about"
+            } else {
+                "able"
+            },
+            1,
+        );
+        assert!(from_phrase(&swapped).is_err());
+        assert!(from_phrase("abandon abandon").is_err());
+        assert!(from_phrase(&phrase.replacen(' ', " garner ", 1)).is_err());
+        assert!(to_phrase(&[0; 17]).is_err());
+    }
+}
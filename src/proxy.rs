@@ -0,0 +1,532 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use clap::Args;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::TcpListener,
+};
+use tokio_util::compat::{
+    FuturesAsyncReadCompatExt as _, TokioAsyncReadCompatExt as _,
+};
+
+use crate::{get, server, tor, ui};
+
+/// Largest request head accepted from a proxy client.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Headers that describe the client's connection to the proxy, rather
+/// than the request, and so are not passed on.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Options for `garner proxy`.
+#[derive(Args)]
+pub struct ProxyArgs {
+    /// Accept HTTP proxy requests, including CONNECT, on this address
+    /// (e.g. 127.0.0.1:8118)
+    #[arg(long, value_name = "ADDR", required_unless_present = "socks")]
+    pub http: Option<String>,
+    /// Accept SOCKS5 connections on this address (e.g. 127.0.0.1:9150)
+    #[arg(long, value_name = "ADDR")]
+    pub socks: Option<String>,
+    /// Also relay to hosts other than onion services, through a Tor exit
+    #[arg(long)]
+    pub allow_clearnet: bool,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+/// Where a proxy request is headed.
+#[derive(Debug, PartialEq, Eq)]
+struct Target {
+    host: String,
+    port: u16,
+    /// The path to request, in origin form, or `None` for a CONNECT
+    /// tunnel.
+    path: Option<String>,
+}
+
+/// The protocol a proxy listener speaks.
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Http,
+    Socks,
+}
+
+pub async fn run(args: ProxyArgs) -> Result<()> {
+    // Listen first, so a port in use fails before bootstrapping.
+    let mut listeners = Vec::new();
+    for (addr, protocol) in
+        [(&args.http, Protocol::Http), (&args.socks, Protocol::Socks)]
+    {
+        if let Some(addr) = addr {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("listening on {addr}"))?;
+            listeners.push((listener, protocol));
+        }
+    }
+    ui::log("Bootstrapping Tor...");
+    let fetcher = Arc::new(get::Fetcher::connect(None, &args.tor).await?);
+    futures_util::future::try_join_all(listeners.into_iter().map(
+        |(listener, protocol)| {
+            serve(
+                listener,
+                protocol,
+                Arc::clone(&fetcher),
+                args.allow_clearnet,
+            )
+        },
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Accept proxy clients on `listener`, relaying each over Tor.
+async fn serve(
+    listener: TcpListener,
+    protocol: Protocol,
+    fetcher: Arc<get::Fetcher>,
+    clearnet: bool,
+) -> Result<()> {
+    let name = match protocol {
+        Protocol::Http => "HTTP",
+        Protocol::Socks => "SOCKS5",
+    };
+    ui::log(&format!(
+        "{name} proxy listening on {}",
+        listener.local_addr()?
+    ));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let fetcher = Arc::clone(&fetcher);
+        tokio::spawn(async move {
+            let open = async move |host: String, port, isolation: Option<_>| {
+                fetcher
+                    .open(&host, port, isolation.as_deref())
+                    .await
+                    .map(|stream| stream.compat())
+            };
+            let result = match protocol {
+                Protocol::Http => handle_http(stream, clearnet, open).await,
+                Protocol::Socks => handle_socks(stream, clearnet, open).await,
+            };
+            if let Err(e) = result {
+                ui::log(&format!("{name} proxy error: {e:#}"));
+            }
+        });
+    }
+}
+
+/// Relay one client connection: a CONNECT tunnel, or a single request
+/// given with an absolute `http://` URL.
+async fn handle_http<C, S, F>(
+    client: C,
+    clearnet: bool,
+    open: impl FnOnce(String, u16, Option<String>) -> F,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = Result<S>>,
+{
+    let mut client = client.compat();
+    let request =
+        match server::read_http_request(&mut client, MAX_HEAD_SIZE).await {
+            Ok(request) => request,
+            Err(e) => return server::refuse_head(&mut client, e).await,
+        };
+    let target = match target(&request) {
+        Ok(target) => target,
+        Err(e) => {
+            let body = format!("{e}\n");
+            return server::write_http_response(
+                &mut client,
+                400,
+                "text/plain",
+                &[],
+                body.as_bytes(),
+            )
+            .await;
+        }
+    };
+    if let Err(e) = check_host(&target.host, clearnet) {
+        let body = format!("{e}\n");
+        return server::write_http_response(
+            &mut client,
+            403,
+            "text/plain",
+            &[],
+            body.as_bytes(),
+        )
+        .await;
+    }
+    let mut service = match open(target.host.clone(), target.port, None).await {
+        Ok(service) => service,
+        Err(e) => {
+            let body = format!("{}:{}: {e:#}\n", target.host, target.port);
+            return server::write_http_response(
+                &mut client,
+                502,
+                "text/plain",
+                &[],
+                body.as_bytes(),
+            )
+            .await;
+        }
+    };
+
+    let mut client = client.into_inner();
+    match &target.path {
+        Some(path) => {
+            service.write_all(&forward_head(&request, path)).await?;
+        }
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+        }
+    }
+    // Whatever followed the head in the client's first read.
+    service.write_all(&request.body).await?;
+    // Either side hanging up partway is routine for a proxy, and the
+    // other side sees it as its stream closing.
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut service).await;
+    Ok(())
+}
+
+/// Relay one SOCKS5 client connection (RFC 1928), which asks for a
+/// stream to a host and port.  Clients that send a username and password
+/// get circuits of their own for each distinct pair, as with Tor's
+/// `IsolateSOCKSAuth`.
+async fn handle_socks<C, S, F>(
+    mut client: C,
+    clearnet: bool,
+    open: impl FnOnce(String, u16, Option<String>) -> F,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = Result<S>>,
+{
+    // Greeting: version 5 and the authentication methods offered.
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting).await?;
+    if greeting[0] != 5 {
+        bail!("not a SOCKS5 client (version {})", greeting[0]);
+    }
+    let mut methods = vec![0u8; greeting[1].into()];
+    client.read_exact(&mut methods).await?;
+    let method = if methods.contains(&2) {
+        2
+    } else if methods.contains(&0) {
+        0
+    } else {
+        client.write_all(&[5, 0xff]).await?;
+        bail!("SOCKS client offered no usable authentication method");
+    };
+    client.write_all(&[5, method]).await?;
+
+    // Username and password (RFC 1929), which only choose the circuit.
+    let isolation = if method == 2 {
+        let mut version_len = [0u8; 2];
+        client.read_exact(&mut version_len).await?;
+        let mut username = vec![0u8; version_len[1].into()];
+        client.read_exact(&mut username).await?;
+        let mut password = vec![0u8; client.read_u8().await?.into()];
+        client.read_exact(&mut password).await?;
+        client.write_all(&[1, 0]).await?;
+        Some(format!(
+            "socks-{}-{}",
+            hex::encode(username),
+            hex::encode(password)
+        ))
+    } else {
+        None
+    };
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0u8; client.read_u8().await?.into()];
+            client.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .context("SOCKS host name not valid UTF-8")?
+                .to_ascii_lowercase()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        other => {
+            socks_reply(&mut client, 8).await?;
+            bail!("SOCKS request has unknown address type {other}");
+        }
+    };
+    let port = client.read_u16().await?;
+    if request[1] != 1 {
+        socks_reply(&mut client, 7).await?;
+        bail!("SOCKS command {} is not supported", request[1]);
+    }
+    if check_host(&host, clearnet).is_err() {
+        return socks_reply(&mut client, 2).await;
+    }
+    let mut service = match open(host.clone(), port, isolation).await {
+        Ok(service) => service,
+        Err(e) => {
+            socks_reply(&mut client, 4).await?;
+            return Err(e.context(format!("opening {host}:{port}")));
+        }
+    };
+    socks_reply(&mut client, 0).await?;
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut service).await;
+    Ok(())
+}
+
+/// Send a SOCKS5 reply with status `code` and an unspecified bound
+/// address.
+async fn socks_reply(
+    client: &mut (impl AsyncWrite + Unpin),
+    code: u8,
+) -> Result<()> {
+    client.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
+
+/// The host, port, and path that a proxy request names: `host:port` for
+/// CONNECT, or else an absolute `http://` URL.
+fn target(request: &server::Request) -> Result<Target> {
+    if request.method.eq_ignore_ascii_case("CONNECT") {
+        let authority = request.path.as_str();
+        if !authority.contains(':') {
+            return Err(anyhow!("CONNECT needs host:port, got {authority}"));
+        }
+        let (host, port) = get::host_port(authority, 0)?;
+        return Ok(Target {
+            host: host.to_ascii_lowercase(),
+            port,
+            path: None,
+        });
+    }
+    let rest = request.path.strip_prefix("http://").ok_or_else(|| {
+        anyhow!("expected an http:// URL, got {}", request.path)
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = get::host_port(authority, server::HTTP_PORT)?;
+    if host.is_empty() {
+        return Err(anyhow!("no host in {}", request.path));
+    }
+    Ok(Target {
+        host: host.to_ascii_lowercase(),
+        port,
+        path: Some(path.to_string()),
+    })
+}
+
+/// Check that requests may be relayed to `host`.
+fn check_host(host: &str, clearnet: bool) -> Result<()> {
+    if host.ends_with(".onion") {
+        crate::key::check_onion_address(host)
+    } else if clearnet {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "expected a .onion address, got: {host} (--allow-clearnet \
+             relays to other hosts through a Tor exit)"
+        ))
+    }
+}
+
+/// The head of `request` as sent on to the service: for `path`, without
+/// the headers meant for the proxy, and asking the service to close the
+/// stream after its response.
+fn forward_head(request: &server::Request, path: &str) -> Vec<u8> {
+    let mut head = format!("{} {path} {}\r\n", request.method, request.version);
+    if request.header("host").is_none() {
+        let rest = request.path.strip_prefix("http://").unwrap_or("");
+        let authority = rest.split('/').next().unwrap_or("");
+        head.push_str(&format!("Host: {authority}\r\n"));
+    }
+    for (name, value) in &request.headers {
+        if !HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION: &str =
+        "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    async fn parse(head: &str) -> server::Request {
+        server::read_http_request(&mut head.as_bytes(), MAX_HEAD_SIZE)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_target() {
+        let request =
+            parse(&format!("GET http://{ONION}:8080/a?b HTTP/1.1\r\n\r\n"))
+                .await;
+        assert_eq!(
+            target(&request).unwrap(),
+            Target {
+                host: ONION.to_string(),
+                port: 8080,
+                path: Some("/a?b".to_string()),
+            }
+        );
+        let request = parse("CONNECT Example.com:443 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(
+            target(&request).unwrap(),
+            Target {
+                host: "example.com".to_string(),
+                port: 443,
+                path: None,
+            }
+        );
+        for head in [
+            "GET /index.html HTTP/1.1\r\n\r\n",
+            "GET https://example.com/ HTTP/1.1\r\n\r\n",
+            "CONNECT example.com HTTP/1.1\r\n\r\n",
+            "GET http:///a HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(target(&parse(head).await).is_err(), "{head}");
+        }
+
+        assert!(check_host(ONION, false).is_ok());
+        assert!(check_host("example.com", false).is_err());
+        assert!(check_host("example.com", true).is_ok());
+        assert!(check_host("abc.onion", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy() {
+        let (mut client, proxy_side) = tokio::io::duplex(16 * 1024);
+        let (mut service, service_side) = tokio::io::duplex(16 * 1024);
+        let open = async |host: String, port, isolation| {
+            assert_eq!((host.as_str(), port, isolation), (ONION, 80, None));
+            Ok(service_side)
+        };
+        let request = format!(
+            "GET http://{ONION}/notes.txt HTTP/1.1\r\nHost: {ONION}\r\n\
+             Proxy-Connection: keep-alive\r\n\r\n"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let proxy = handle_http(proxy_side, false, open);
+        let exchange = async move {
+            let mut head = vec![0; 1024];
+            let n = service.read(&mut head).await.unwrap();
+            service
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi")
+                .await
+                .unwrap();
+            drop(service);
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            (String::from_utf8(head[..n].to_vec()).unwrap(), response)
+        };
+        let (proxied, (head, response)) = tokio::join!(proxy, exchange);
+        proxied.unwrap();
+        assert_eq!(
+            head,
+            format!(
+                "GET /notes.txt HTTP/1.1\r\nHost: {ONION}\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        );
+        assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+
+        // A clearnet host is refused without opening a stream.
+        let (mut client, proxy_side) = tokio::io::duplex(1024);
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let open = async |_, _, _| -> Result<tokio::io::DuplexStream> {
+            panic!("opened a stream")
+        };
+        handle_http(proxy_side, false, open).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_socks_proxy() {
+        let (mut client, proxy_side) = tokio::io::duplex(16 * 1024);
+        let (mut service, service_side) = tokio::io::duplex(16 * 1024);
+        let open = async |host: String, port, isolation: Option<String>| {
+            assert_eq!((host.as_str(), port), (ONION, 80));
+            assert_eq!(isolation.as_deref(), Some("socks-61-6263"));
+            Ok(service_side)
+        };
+        let proxy = handle_socks(proxy_side, false, open);
+        let exchange = async move {
+            // Offer no authentication and username/password, and log in.
+            client.write_all(&[5, 2, 0, 2]).await.unwrap();
+            client
+                .write_all(&[1, 1, b'a', 2, b'b', b'c'])
+                .await
+                .unwrap();
+            let mut request = vec![5, 1, 0, 3, ONION.len() as u8];
+            request.extend_from_slice(ONION.as_bytes());
+            request.extend_from_slice(&80u16.to_be_bytes());
+            client.write_all(&request).await.unwrap();
+            let mut replies = [0u8; 14];
+            client.read_exact(&mut replies).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut ping = [0u8; 4];
+            service.read_exact(&mut ping).await.unwrap();
+            service.write_all(b"pong").await.unwrap();
+            drop(service);
+            let mut pong = Vec::new();
+            client.read_to_end(&mut pong).await.unwrap();
+            (replies, ping, pong)
+        };
+        let (proxied, (replies, ping, pong)) = tokio::join!(proxy, exchange);
+        proxied.unwrap();
+        assert_eq!(replies, [5, 2, 1, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&ping, b"ping");
+        assert_eq!(pong, b"pong");
+
+        // A clearnet host is not allowed, without opening a stream.
+        let (mut client, proxy_side) = tokio::io::duplex(1024);
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 1, 1, 1, 1, 0, 80];
+        client.write_all(&request).await.unwrap();
+        let open = async |_, _, _| -> Result<tokio::io::DuplexStream> {
+            panic!("opened a stream")
+        };
+        handle_socks(proxy_side, false, open).await.unwrap();
+        request.clear();
+        client.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, [5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,646 @@
+use std::path::Path;
+
+use anyhow::{Context as _, Result, anyhow};
+
+/// Light modules a reader needs around a code.
+const QUIET_ZONE: usize = 4;
+
+/// Pixels per module in a PNG.
+const PNG_SCALE: usize = 8;
+
+/// Error correction codewords per block for versions 1 to 40 at level M,
+/// which restores up to 15% of a damaged code.
+const ECC_PER_BLOCK: [usize; 40] = [
+    10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26,
+    26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    28, 28,
+];
+
+/// Error correction blocks for versions 1 to 40 at level M.
+const BLOCKS: [usize; 40] = [
+    1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17,
+    18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+];
+
+/// Level M in the format bits.
+const LEVEL_M: u32 = 0;
+
+/// The characters alphanumeric mode encodes, in order.
+const ALPHANUMERIC: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// A finder pattern's dark-light-dark-dark-dark-light-dark run beside
+/// four light modules, which the mask penalty avoids.
+const FINDER_LIKE: [bool; 11] = [
+    true, false, true, true, true, false, true, false, false, false, false,
+];
+
+/// A QR code at error correction level M.
+#[derive(Clone)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    /// Modules of the finder, timing, and alignment patterns and the
+    /// format and version bits, which masks leave alone.
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `text` in the smallest code that holds it: as alphanumeric
+    /// data if it has only those characters, as uppercase URs do, and
+    /// otherwise as bytes.
+    pub fn encode(text: &str) -> Result<Self> {
+        let alphanumeric = text.chars().all(|c| ALPHANUMERIC.contains(c));
+        let version = (1..=40)
+            .find(|&version| {
+                data_bits(text, alphanumeric, version)
+                    <= data_codewords(version) * 8
+            })
+            .ok_or_else(|| {
+                anyhow!("{} bytes is too long for a QR code", text.len())
+            })?;
+        let data = encode_data(text, alphanumeric, version);
+        let mut code = Self::new(version);
+        code.draw_codewords(&add_ecc_and_interleave(&data, version));
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                let mut trial = code.clone();
+                trial.apply_mask(mask);
+                trial.draw_format_bits(mask);
+                trial.penalty()
+            })
+            .expect("eight masks");
+        code.apply_mask(mask);
+        code.draw_format_bits(mask);
+        Ok(code)
+    }
+
+    /// A code of `version` with its function patterns drawn.
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        let mut code = Self {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        for i in 0..size {
+            code.set_function(6, i, i % 2 == 0);
+            code.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            code.draw_finder(x, y);
+        }
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // The finder patterns are in these corners.
+                if [(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    continue;
+                }
+                code.draw_alignment(x, y);
+            }
+        }
+        // Reserve the format bits until the mask is chosen.
+        code.draw_format_bits(0);
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = bits >> i & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                code.set_function(a, b, dark);
+                code.set_function(b, a, dark);
+            }
+        }
+        code
+    }
+
+    /// Modules along each side.
+    #[cfg(test)]
+    fn size(&self) -> usize { self.size }
+
+    /// Whether the module at column `x` and row `y` is dark.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_finder(&mut self, x: usize, y: usize) {
+        for dy in -4..=4_isize {
+            for dx in -4..=4_isize {
+                let distance = dx.abs().max(dy.abs());
+                let (xx, yy) = (x as isize + dx, y as isize + dy);
+                if (0..self.size as isize).contains(&xx)
+                    && (0..self.size as isize).contains(&yy)
+                {
+                    self.set_function(
+                        xx as usize,
+                        yy as usize,
+                        distance != 2 && distance != 4,
+                    );
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, x: usize, y: usize) {
+        for dy in 0..5_usize {
+            for dx in 0..5_usize {
+                let distance = dx.abs_diff(2).max(dy.abs_diff(2));
+                self.set_function(x + dx - 2, y + dy - 2, distance != 1);
+            }
+        }
+    }
+
+    /// Draw both copies of the format bits for `mask`, and the dark
+    /// module beside them.
+    fn draw_format_bits(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| bits >> i & 1 == 1;
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Lay `codewords` into the modules that are left, in two-column
+    /// strips zigzagging up and down from the bottom right.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            // Skip the vertical timing pattern.
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] =
+                            codewords[i / 8] >> (7 - i % 8) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 3 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Invert the data modules that mask pattern `mask` selects.
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if invert && !self.function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to read, by the standard's rules: long runs,
+    /// 2×2 blocks, and finder-like patterns of one colour, and an
+    /// imbalance of dark and light.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let rows =
+            (0..size).map(|y| (0..size).map(|x| self.get(x, y)).collect());
+        let columns =
+            (0..size).map(|x| (0..size).map(|y| self.get(x, y)).collect());
+        for line in rows.chain(columns).collect::<Vec<Vec<bool>>>() {
+            let mut run = 1;
+            for i in 1..size {
+                if line[i] != line[i - 1] {
+                    run = 1;
+                    continue;
+                }
+                run += 1;
+                penalty += match run {
+                    5 => 3,
+                    6.. => 1,
+                    _ => 0,
+                };
+            }
+            penalty += 40
+                * line
+                    .windows(FINDER_LIKE.len())
+                    .filter(|window| {
+                        window.iter().eq(FINDER_LIKE.iter())
+                            || window.iter().eq(FINDER_LIKE.iter().rev())
+                    })
+                    .count();
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.get(x, y);
+                if dark == self.get(x + 1, y)
+                    && dark == self.get(x, y + 1)
+                    && dark == self.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        let total = size * size;
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        penalty
+            + (dark * 20)
+                .abs_diff(total * 10)
+                .div_ceil(total)
+                .saturating_sub(1)
+                * 10
+    }
+
+    /// Whether the module at `x`, `y` of the code with its quiet zone is
+    /// light.
+    fn light(&self, x: usize, y: usize) -> bool {
+        let inside = QUIET_ZONE..QUIET_ZONE + self.size;
+        !(inside.contains(&x)
+            && inside.contains(&y)
+            && self.get(x - QUIET_ZONE, y - QUIET_ZONE))
+    }
+
+    /// Render the code for a terminal, two rows of modules to a line of
+    /// half blocks, in white on black whatever the terminal's colours.
+    pub fn to_terminal(&self) -> String {
+        let width = self.size + 2 * QUIET_ZONE;
+        // One more light row below keeps the number of rows even.
+        let height = width + 1;
+        let mut text = String::new();
+        for y in (0..height).step_by(2) {
+            text.push_str("\x1b[97;40m");
+            for x in 0..width {
+                text.push(match (self.light(x, y), self.light(x, y + 1)) {
+                    (true, true) => '\u{2588}',
+                    (true, false) => '\u{2580}',
+                    (false, true) => '\u{2584}',
+                    (false, false) => ' ',
+                });
+            }
+            text.push_str("\x1b[0m\n");
+        }
+        text
+    }
+
+    /// Encode the code as a black and white PNG image.
+    pub fn to_png(&self) -> Vec<u8> {
+        use std::io::Write as _;
+
+        let width = (self.size + 2 * QUIET_ZONE) * PNG_SCALE;
+        let mut pixels = Vec::new();
+        for y in 0..width {
+            // Each row starts with its filter type, none.
+            pixels.push(0);
+            let mut row = vec![0u8; width.div_ceil(8)];
+            for x in 0..width {
+                if self.light(x / PNG_SCALE, y / PNG_SCALE) {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            pixels.extend(row);
+        }
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&pixels).expect("writing to a Vec");
+        let idat = encoder.finish().expect("writing to a Vec");
+
+        let width = u32::try_from(width).expect("at most 1,496 pixels");
+        let mut ihdr = Vec::new();
+        ihdr.extend(width.to_be_bytes());
+        ihdr.extend(width.to_be_bytes());
+        // One bit per pixel, greyscale, and the only compression,
+        // filtering, and no interlacing.
+        ihdr.extend([1, 0, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in
+            [(b"IHDR", ihdr), (b"IDAT", idat), (b"IEND", vec![])]
+        {
+            png.extend(u32::try_from(data.len()).unwrap().to_be_bytes());
+            let mut crc = flate2::Crc::new();
+            crc.update(kind);
+            crc.update(&data);
+            png.extend(kind);
+            png.extend(data);
+            png.extend(crc.sum().to_be_bytes());
+        }
+        png
+    }
+}
+
+/// Write `text` as a QR code PNG image to `path`.
+pub fn write_png(path: &Path, text: &str) -> Result<()> {
+    let code = QrCode::encode(text)?;
+    std::fs::write(path, code.to_png())
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Bits of the character count for `version`.
+fn count_bits(alphanumeric: bool, version: usize) -> usize {
+    let index = match version {
+        1..=9 => 0,
+        10..=26 => 1,
+        _ => 2,
+    };
+    if alphanumeric {
+        [9, 11, 13][index]
+    } else {
+        [8, 16, 16][index]
+    }
+}
+
+/// Bits needed to encode `text` in a code of `version`.
+fn data_bits(text: &str, alphanumeric: bool, version: usize) -> usize {
+    let payload = if alphanumeric {
+        text.len() / 2 * 11 + text.len() % 2 * 6
+    } else {
+        text.len() * 8
+    };
+    4 + count_bits(alphanumeric, version) + payload
+}
+
+/// Data codewords a code of `version` holds, after its error correction.
+fn data_codewords(version: usize) -> usize {
+    raw_modules(version) / 8 - ECC_PER_BLOCK[version - 1] * BLOCKS[version - 1]
+}
+
+/// Modules a code of `version` has for data and error correction.
+fn raw_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+/// Centres of the alignment patterns along each axis.
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let last = version * 4 + 10;
+    let mut positions: Vec<usize> =
+        (0..count - 1).map(|i| last - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// The 15 format bits for level M and `mask`, with their BCH code.
+fn format_bits(mask: u32) -> u32 {
+    let data = LEVEL_M << 3 | mask;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    (data << 10 | remainder) ^ 0x5412
+}
+
+/// The 18 version bits for `version`, with their BCH code.
+fn version_bits(version: usize) -> u32 {
+    let version = u32::try_from(version).expect("at most 40");
+    let mut remainder = version;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+    }
+    version << 12 | remainder
+}
+
+/// The data codewords of `text` in a code of `version`: mode, count, and
+/// characters, then a terminator and padding to fill the code.
+fn encode_data(text: &str, alphanumeric: bool, version: usize) -> Vec<u8> {
+    let mut bits = Vec::new();
+    let mut push = |value: usize, count: usize| {
+        bits.extend((0..count).rev().map(|i| value >> i & 1 == 1));
+    };
+    push(if alphanumeric { 0b0010 } else { 0b0100 }, 4);
+    push(text.len(), count_bits(alphanumeric, version));
+    if alphanumeric {
+        let values: Vec<usize> = text
+            .chars()
+            .map(|c| ALPHANUMERIC.find(c).expect("an alphanumeric character"))
+            .collect();
+        for pair in values.chunks(2) {
+            match pair {
+                [a, b] => push(a * 45 + b, 11),
+                [a] => push(*a, 6),
+                _ => unreachable!(),
+            }
+        }
+    } else {
+        for &byte in text.as_bytes() {
+            push(byte.into(), 8);
+        }
+    }
+    let capacity = data_codewords(version) * 8;
+    let terminator = (capacity - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+    let mut data: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |n, &bit| n << 1 | u8::from(bit)))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if data.len() == capacity / 8 {
+            break;
+        }
+        data.push(pad);
+    }
+    data
+}
+
+/// Split `data` into the blocks of a code of `version`, add each block's
+/// error correction, and interleave the blocks' codewords.
+fn add_ecc_and_interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let blocks = BLOCKS[version - 1];
+    let ecc_len = ECC_PER_BLOCK[version - 1];
+    let raw = raw_modules(version) / 8;
+    // The first blocks are a codeword shorter than the rest.
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks;
+    let divisor = reed_solomon_divisor(ecc_len);
+    let mut rest = data;
+    let mut split = Vec::new();
+    for i in 0..blocks {
+        let len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let (block, tail) = rest.split_at(len);
+        rest = tail;
+        split.push((block, reed_solomon_remainder(block, &divisor)));
+    }
+    let mut codewords = Vec::with_capacity(raw);
+    for i in 0..=short_len - ecc_len {
+        for (block, _) in &split {
+            codewords.extend(block.get(i));
+        }
+    }
+    for i in 0..ecc_len {
+        for (_, ecc) in &split {
+            codewords.push(ecc[i]);
+        }
+    }
+    codewords
+}
+
+/// Multiply in GF(2⁸) modulo x⁸ + x⁴ + x³ + x² + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut product: u16 = 0;
+    for i in (0..8).rev() {
+        product = (product << 1) ^ ((product >> 7) * 0x11D);
+        product ^= u16::from(y >> i & 1) * u16::from(x);
+    }
+    product as u8
+}
+
+/// The generator polynomial for `degree` error correction codewords,
+/// without its leading 1.
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    divisor
+}
+
+/// The error correction codewords for `data`.
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &d) in remainder.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_world() {
+        // The worked example of a version 1-M code.
+        let data = encode_data("HELLO WORLD", true, 1);
+        assert_eq!(
+            data,
+            [
+                32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17,
+                236, 17
+            ]
+        );
+        assert_eq!(
+            reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+        let code = QrCode::encode("HELLO WORLD").unwrap();
+        assert_eq!(code.size(), 21);
+    }
+
+    #[test]
+    fn test_tables() {
+        for (version, codewords) in [
+            (1, 16),
+            (2, 28),
+            (3, 44),
+            (4, 64),
+            (5, 86),
+            (10, 216),
+            (40, 2334),
+        ] {
+            assert_eq!(data_codewords(version), codewords, "{version}");
+        }
+        for version in 1..=40 {
+            let size = version * 4 + 17;
+            assert_eq!(
+                alignment_positions(version).last().copied(),
+                (version > 1).then_some(size - 7)
+            );
+        }
+        assert_eq!(alignment_positions(7), [6, 22, 38]);
+        assert_eq!(alignment_positions(32), [6, 34, 60, 86, 112, 138]);
+        assert_eq!(format_bits(0), 0b101010000010010);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+    }
+
+    #[test]
+    fn test_encode() {
+        let ur = "UR:SIGNING-PUBLIC-KEY/LFAOHDCXPMSOJTGENYJNWDQZWNGHVDFLQZFSCLWZ\
+                  GSIOZCFWPRUOYLHTWFYNRSNDWSNSNNYATEBWHNNL";
+        let code = QrCode::encode(ur).unwrap();
+        assert_eq!(code.size(), 37);
+        // The finder patterns' corners are dark, and their separators
+        // light.
+        let last = code.size() - 1;
+        for (x, y) in [(0, 0), (last, 0), (0, last), (6, 6), (last - 6, 6)] {
+            assert!(code.get(x, y), "{x},{y}");
+        }
+        assert!(!code.get(7, 7) && !code.get(last - 7, 7));
+        assert!(code.get(8, last - 7), "the dark module");
+
+        let url = format!("http://{}.onion/", "a".repeat(56));
+        assert_eq!(QrCode::encode(&url).unwrap().size(), 37);
+        assert!(QrCode::encode(&"a".repeat(3000)).is_err());
+        assert!(QrCode::encode(&"a".repeat(2331)).is_ok());
+    }
+
+    #[test]
+    fn test_png() {
+        let png = QrCode::encode("HELLO WORLD").unwrap().to_png();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+        assert_eq!(png[16..20], 232u32.to_be_bytes());
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
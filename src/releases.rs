@@ -0,0 +1,420 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use bc_components::SigningPrivateKey;
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::transform::escape_html;
+
+/// Request path prefix the release directory is served under.
+pub const PATH_PREFIX: &str = "/releases/";
+
+/// Suffix of the generated checksum file for a release file.
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Suffix of the generated detached signature for a release file.
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// A file in the release directory.
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub name: String,
+    /// Version parsed from the name, such as `1.2.0` in
+    /// `tool-1.2.0-linux.tar.gz`.
+    pub version: Option<String>,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// SHA-256 digest of the content, in hex.
+    pub digest: String,
+    /// `ur:signature` over the content, made when first asked for.
+    signature: Option<String>,
+}
+
+/// What a request under [`PATH_PREFIX`] gets.
+pub enum Response {
+    /// A release file, sent as it is.
+    File(PathBuf),
+    /// A generated checksum, signature, or index.
+    Generated {
+        content_type: &'static str,
+        body: Vec<u8>,
+    },
+}
+
+/// A directory of release files published with checksums, detached
+/// signatures, and an index, none of which have to be made by hand.
+///
+/// For every file `<NAME>` directly in the directory, `<NAME>.sha256`
+/// holds its digest in `sha256sum` format and `<NAME>.sig` a signature
+/// over it by the service's key, so anyone who knows the onion address
+/// can check a download.  Files of those names that exist are served
+/// instead.  Digests are computed when a file is first listed or
+/// changes, as found by its size and modification time.
+///
+/// Lookups read and hash files with blocking calls, so the server makes
+/// them on a blocking thread.
+pub struct Releases {
+    dir: PathBuf,
+    key: SigningPrivateKey,
+    /// Files by name, with the stamp they had when hashed.
+    files: Mutex<HashMap<String, Release>>,
+}
+
+impl Releases {
+    pub fn open(dir: &Path, key: SigningPrivateKey) -> Result<Self> {
+        let releases = Self {
+            dir: dir.to_path_buf(),
+            key,
+            files: Mutex::new(HashMap::new()),
+        };
+        releases.list()?;
+        Ok(releases)
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Every release file, newest version first.  Companion `.sha256`
+    /// and `.sig` files are left out.
+    pub fn list(&self) -> Result<Vec<Release>> {
+        let mut current = HashMap::new();
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("reading {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_owned)
+            else {
+                continue;
+            };
+            if let Some(release) = self.stat(&name)? {
+                current.insert(name, release);
+            }
+        }
+        let mut list: Vec<Release> = current.values().cloned().collect();
+        *self.files.lock().expect("releases lock") = current;
+        list.sort_by(|a, b| {
+            compare_versions(b.version.as_deref(), a.version.as_deref())
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(list)
+    }
+
+    /// The response for a request path under [`PATH_PREFIX`], if it names
+    /// the index, a release file, or one of its companions.
+    pub fn lookup(&self, request_path: &str) -> Result<Option<Response>> {
+        let Some(name) = request_path.strip_prefix(PATH_PREFIX) else {
+            return Ok(None);
+        };
+        match name {
+            "" | "index.html" => {
+                let body = index_html(&self.list()?).into_bytes();
+                return Ok(Some(generated("text/html", body)));
+            }
+            "index.json" => {
+                let body = index_json(&self.list()?).to_string().into_bytes();
+                return Ok(Some(generated("application/json", body)));
+            }
+            _ => {}
+        }
+        if !is_file_name(name) {
+            return Ok(None);
+        }
+        let path = self.dir.join(name);
+        if path.is_file() {
+            return Ok(Some(Response::File(path)));
+        }
+        if let Some(file) = name.strip_suffix(CHECKSUM_SUFFIX)
+            && let Some(release) = self.stat(file)?
+        {
+            let body = format!("{}  {}\n", release.digest, release.name);
+            return Ok(Some(generated("text/plain", body.into_bytes())));
+        }
+        if let Some(file) = name.strip_suffix(SIGNATURE_SUFFIX)
+            && let Some(signature) = self.signature(file)?
+        {
+            let body = format!("{signature}\n").into_bytes();
+            return Ok(Some(generated("text/plain", body)));
+        }
+        Ok(None)
+    }
+
+    /// The current listing for the release file `name`, hashing it only
+    /// if it is new or has changed since it was last hashed.
+    fn stat(&self, name: &str) -> Result<Option<Release>> {
+        if !is_release_name(name) {
+            return Ok(None);
+        }
+        let path = self.dir.join(name);
+        let meta = match std::fs::metadata(&path) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("reading {}", path.display()));
+            }
+        };
+        let (size, modified) = (meta.len(), meta.modified().ok());
+        if let Some(known) = self.files.lock().expect("releases lock").get(name)
+            && (known.size, known.modified) == (size, modified)
+        {
+            return Ok(Some(known.clone()));
+        }
+        // Hashed without the lock held, so other lookups are not kept
+        // waiting on a large file.
+        let release = Release {
+            version: version(name),
+            digest: hash_file(&path)?,
+            name: name.to_string(),
+            size,
+            modified,
+            signature: None,
+        };
+        self.files
+            .lock()
+            .expect("releases lock")
+            .insert(name.to_string(), release.clone());
+        Ok(Some(release))
+    }
+
+    /// The signature over the release file `name`, made on first use and
+    /// kept until the file changes.  Signing needs the whole file in
+    /// memory; its digest is checked against the same read.
+    fn signature(&self, name: &str) -> Result<Option<String>> {
+        let Some(release) = self.stat(name)? else {
+            return Ok(None);
+        };
+        if let Some(signature) = release.signature {
+            return Ok(Some(signature));
+        }
+        let path = self.dir.join(name);
+        let data = std::fs::read(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if hex::encode(Sha256::digest(&data)) != release.digest {
+            // Changed since it was listed; the next request signs it.
+            return Ok(None);
+        }
+        let signature = crate::key::sign_body(&self.key, &data)?;
+        if let Some(known) =
+            self.files.lock().expect("releases lock").get_mut(name)
+            && known.digest == release.digest
+        {
+            known.signature = Some(signature.clone());
+        }
+        Ok(Some(signature))
+    }
+}
+
+/// The SHA-256 digest of the file at `path`, in hex, read in chunks.
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("reading {}", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn generated(content_type: &'static str, body: Vec<u8>) -> Response {
+    Response::Generated { content_type, body }
+}
+
+/// Whether `name` is a plain file name, with no path separators and not
+/// hidden.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+/// Whether `name` is a release file rather than the index or a companion.
+fn is_release_name(name: &str) -> bool {
+    is_file_name(name)
+        && !name.ends_with(CHECKSUM_SUFFIX)
+        && !name.ends_with(SIGNATURE_SUFFIX)
+        && !matches!(name, "index.html" | "index.json")
+}
+
+/// The version in a release file name: the first `-` or `_` separated
+/// part that starts with a digit (after an optional `v`) and has a dot,
+/// up to the first character that is neither a digit nor a dot.
+pub fn version(name: &str) -> Option<String> {
+    name.split(['-', '_']).find_map(|part| {
+        let part = part.strip_prefix('v').unwrap_or(part);
+        let end = part
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(part.len());
+        let version = part[..end].trim_end_matches('.');
+        (version.starts_with(|c: char| c.is_ascii_digit())
+            && version.contains('.'))
+        .then(|| version.to_string())
+    })
+}
+
+/// Order versions by their numeric components; names without one sort
+/// before every version.
+fn compare_versions(a: Option<&str>, b: Option<&str>) -> Ordering {
+    let parts = |version: Option<&str>| {
+        version.map(|v| {
+            v.split('.')
+                .map(|part| part.parse::<u64>().unwrap_or(0))
+                .collect::<Vec<_>>()
+        })
+    };
+    parts(a).cmp(&parts(b))
+}
+
+fn modified(release: &Release) -> Option<String> {
+    release
+        .modified
+        .map(|time| DateTime::<Utc>::from(time).to_rfc3339())
+}
+
+/// The index as JSON:
+/// `{"files": [{"name", "version", "size", "sha256", "modified"}, ...]}`.
+pub fn index_json(releases: &[Release]) -> Value {
+    let files: Vec<Value> = releases
+        .iter()
+        .map(|release| {
+            json!({
+                "name": release.name,
+                "version": release.version,
+                "size": release.size,
+                "sha256": release.digest,
+                "modified": modified(release),
+            })
+        })
+        .collect();
+    json!({ "files": files })
+}
+
+/// The index as an HTML page, a table per version.
+pub fn index_html(releases: &[Release]) -> String {
+    let mut body = String::new();
+    let mut current = None;
+    for release in releases {
+        if current != Some(&release.version) {
+            if current.is_some() {
+                body.push_str("</table>\n");
+            }
+            let heading = release.version.as_deref().unwrap_or("Other files");
+            body.push_str(&format!(
+                "<h2>{}</h2>\n<table>\n<tr><th>File</th><th>Size</th>\
+                 <th>SHA-256</th><th>Signature</th></tr>\n",
+                escape_html(heading)
+            ));
+            current = Some(&release.version);
+        }
+        let name = escape_html(&release.name);
+        body.push_str(&format!(
+            "<tr><td><a href=\"{name}\">{name}</a></td><td>{}</td>\
+             <td><a href=\"{name}{CHECKSUM_SUFFIX}\"><code>{}</code></a></td>\
+             <td><a href=\"{name}{SIGNATURE_SUFFIX}\">sig</a></td></tr>\n",
+            release.size, release.digest
+        ));
+    }
+    if current.is_some() {
+        body.push_str("</table>\n");
+    }
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>Releases</title></head><body>\n<h1>Releases</h1>\n{body}\
+         <p><a href=\"index.json\">index.json</a></p>\n</body></html>\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        assert_eq!(
+            version("garner-0.2.0-x86_64-linux.tar.gz").as_deref(),
+            Some("0.2.0")
+        );
+        assert_eq!(version("tool_v1.10.tar.gz").as_deref(), Some("1.10"));
+        assert_eq!(version("notes.txt"), None);
+        assert_eq!(version("tool-2-linux"), None);
+        assert_eq!(
+            compare_versions(Some("1.10"), Some("1.9")),
+            Ordering::Greater
+        );
+        assert_eq!(compare_versions(None, Some("0.1")), Ordering::Less);
+    }
+
+    #[test]
+    fn test_releases() {
+        bc_components::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in [
+            ("tool-1.9.0.tar.gz", "old"),
+            ("tool-1.10.0.tar.gz", "new"),
+            ("NOTES", "notes"),
+            ("tool-1.9.0.tar.gz.sha256", "custom\n"),
+        ] {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        let (private, public) = crate::key::generate_keypair().unwrap();
+        let key = crate::key::parse_signing_key(&private).unwrap();
+        let releases = Releases::open(dir.path(), key).unwrap();
+
+        let names: Vec<_> = releases
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, ["tool-1.10.0.tar.gz", "tool-1.9.0.tar.gz", "NOTES"]);
+
+        let body = |path: &str| match releases.lookup(path).unwrap() {
+            Some(Response::Generated { body, .. }) => {
+                String::from_utf8(body).unwrap()
+            }
+            Some(Response::File(path)) => {
+                std::fs::read_to_string(path).unwrap()
+            }
+            None => panic!("{path} not found"),
+        };
+        let digest = hex::encode(Sha256::digest(b"new"));
+        assert_eq!(
+            body("/releases/tool-1.10.0.tar.gz.sha256"),
+            format!("{digest}  tool-1.10.0.tar.gz\n")
+        );
+        assert_eq!(body("/releases/tool-1.9.0.tar.gz.sha256"), "custom\n");
+        assert_eq!(body("/releases/tool-1.10.0.tar.gz"), "new");
+
+        let signature = body("/releases/tool-1.10.0.tar.gz.sig");
+        let onion =
+            crate::key::parse_public_key_to_onion_host(&public).unwrap();
+        crate::key::verify_body(&onion, b"new", signature.trim()).unwrap();
+        assert_eq!(body("/releases/tool-1.10.0.tar.gz.sig"), signature);
+
+        let index: Value =
+            serde_json::from_str(&body("/releases/index.json")).unwrap();
+        assert_eq!(index["files"][0]["version"], "1.10.0");
+        assert_eq!(index["files"][0]["sha256"], digest);
+        assert!(body("/releases/").contains("<h2>1.9.0</h2>"));
+
+        // A changed file is hashed and signed again.
+        std::fs::write(dir.path().join("tool-1.10.0.tar.gz"), "newer").unwrap();
+        let digest = hex::encode(Sha256::digest(b"newer"));
+        assert_eq!(
+            body("/releases/tool-1.10.0.tar.gz.sha256"),
+            format!("{digest}  tool-1.10.0.tar.gz\n")
+        );
+        let signature = body("/releases/tool-1.10.0.tar.gz.sig");
+        crate::key::verify_body(&onion, b"newer", signature.trim()).unwrap();
+
+        for path in ["/releases/missing.sig", "/releases/../x", "/x"] {
+            assert!(releases.lookup(path).unwrap().is_none(), "{path}");
+        }
+    }
+}
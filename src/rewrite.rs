@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow};
+
+/// Parse a clearnet origin such as `https://example.com` into the host
+/// (and optional port) whose URLs should be rewritten.
+pub fn parse_origin(origin: &str) -> Result<String> {
+    let host = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .ok_or_else(|| {
+            anyhow!("origin {origin:?} must start with http:// or https://")
+        })?;
+    let host = host.strip_suffix('/').unwrap_or(host);
+    if host.is_empty() || host.contains(['/', '?', '#', '"', '\'', ' ']) {
+        return Err(anyhow!(
+            "origin {origin:?} must be a scheme and host only"
+        ));
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+/// Rewrite absolute URLs that point at any of `hosts` (as returned by
+/// [`parse_origin`]) so they point at `onion_host` instead.  `http://` and
+/// `https://` URLs become `http://<onion_host>`, since onion services
+/// need no TLS; protocol-relative `//host` URLs keep their form.  A match
+/// must end at the end of the host, so `example.com` does not rewrite
+/// `example.com.evil`.
+pub fn rewrite_html(html: &str, hosts: &[String], onion_host: &str) -> String {
+    let onion_url = format!("http://{onion_host}");
+    let onion_relative = format!("//{onion_host}");
+    let mut html = html.to_string();
+    for host in hosts {
+        html = replace_bounded(&html, &format!("https://{host}"), &onion_url);
+        html = replace_bounded(&html, &format!("http://{host}"), &onion_url);
+        html = replace_bounded(&html, &format!("//{host}"), &onion_relative);
+    }
+    html
+}
+
+/// Replace each ASCII-case-insensitive occurrence of `pattern` that is
+/// followed by the end of the host part of a URL.
+fn replace_bounded(text: &str, pattern: &str, replacement: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(pattern) {
+        let start = search + found;
+        let end = start + pattern.len();
+        let at_boundary = text[end..].chars().next().is_none_or(|c| {
+            !(c.is_ascii_alphanumeric() || "-.:@_".contains(c))
+        });
+        if at_boundary {
+            out.push_str(&text[copied..start]);
+            out.push_str(replacement);
+            copied = end;
+        }
+        search = end;
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION: &str = "abc.onion";
+
+    #[test]
+    fn test_parse_origin() {
+        assert_eq!(
+            parse_origin("https://Example.com/").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            parse_origin("http://example.com:8080").unwrap(),
+            "example.com:8080"
+        );
+        assert!(parse_origin("example.com").is_err());
+        assert!(parse_origin("https://example.com/blog").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_html() {
+        let hosts = vec!["example.com".to_string()];
+        let html = r#"<a href="https://example.com/a">a</a>
+<img src="//EXAMPLE.com/b.png"><a href="http://example.com">c</a>"#;
+        assert_eq!(
+            rewrite_html(html, &hosts, ONION),
+            r#"<a href="http://abc.onion/a">a</a>
+<img src="//abc.onion/b.png"><a href="http://abc.onion">c</a>"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_html_requires_host_boundary() {
+        let hosts = vec!["example.com".to_string()];
+        let html = r#"<a href="https://example.com.evil/">x</a>
+<a href="https://example.community/">y</a>"#;
+        assert_eq!(rewrite_html(html, &hosts, ONION), html);
+    }
+}
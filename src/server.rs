@@ -1,176 +1,720 @@
 use std::{
     path::{Path, PathBuf},
-    sync::Arc,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context as _, Result, anyhow};
 use arti_client::{
     TorClient, config::onion_service::OnionServiceConfigBuilder,
 };
-use futures_util::StreamExt;
+use bc_components::SigningPrivateKey;
+use clap::{Args, ValueEnum};
+use futures_util::{
+    StreamExt,
+    io::{AsyncRead, AsyncWrite},
+};
 use indicatif::{ProgressBar, ProgressStyle};
 use mime_guess::MimeGuess;
 use safelog::DisplayRedacted as _;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::{
+    compat::TokioAsyncReadCompatExt as _, sync::CancellationToken,
+};
 use tor_cell::relaycell::msg::{Connected, End};
-use tor_hsservice::{StreamRequest, handle_rend_requests, status::State};
+use tor_hsservice::{
+    HsNickname, RendRequest, RunningOnionService, handle_rend_requests,
+    status::{OnionServiceStatusStream, State},
+};
 use tor_proto::client::stream::IncomingStreamRequest;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::{
+    archive::Archive,
+    cache_policy,
+    capabilities::Report,
+    ctl, delta,
+    diagnostics::Journal,
+    dictionary::{self, Dictionary},
+    envelope, feed, gstp,
+    hash_index::{self, HashIndex},
+    kv, manifest, qr, releases, rewrite, system_tor, tls, tor, transform, ui,
+};
 
-use crate::ui;
+/// Options for `garner server`.
+#[derive(Args)]
+pub struct ServerArgs {
+    /// Ed25519 private key in UR format for a deterministic .onion address,
+    /// or "-" to read it from stdin
+    #[arg(long, env = "GARNER_KEY")]
+    pub key: Option<String>,
+    /// Read the private key from FILE, or from stdin for "-", instead of
+    /// --key or GARNER_KEY
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+    /// Read the private key from this systemd credential (in
+    /// $CREDENTIALS_DIRECTORY) instead of --key
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["key", "key_file"]
+    )]
+    pub key_credential: Option<String>,
+    /// Directory to serve files from [default: public]
+    #[arg(long, default_value = "public")]
+    pub docroot: String,
+    /// Onion service nickname, also used to name its state directory
+    #[arg(long, default_value = "garner")]
+    pub nickname: String,
+    /// Use small buffers and few concurrent connections (for devices
+    /// with little RAM)
+    #[arg(long)]
+    pub low_memory: bool,
+    /// Maximum number of requests handled at once [default: 64, or 8
+    /// with --low-memory]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+    /// Keep onion service state (keystore, descriptor, introduction
+    /// points) under DIR/<nickname> so restarts are reachable quickly
+    #[arg(long, value_name = "DIR")]
+    pub persistent_state: Option<PathBuf>,
+    /// Publish through a running Tor daemon's control port (e.g.
+    /// 127.0.0.1:9051) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_CONTROL")]
+    pub tor_control: Option<String>,
+    /// Password for the Tor control port, if it uses
+    /// HashedControlPassword (cookie authentication is automatic)
+    #[arg(long, env = "GARNER_TOR_CONTROL_PASSWORD", requires = "tor_control")]
+    pub tor_control_password: Option<String>,
+    /// Also accept TLS on the onion service's port 443 with this PEM
+    /// certificate chain, for clients that insist on https:// URLs
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key for --tls-cert
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Onion service virtual port on which to serve plain HTTP
+    #[arg(
+        long,
+        value_name = "PORT",
+        default_value_t = HTTP_PORT,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub port: u16,
+    /// Send this Cache-Control value with files whose paths match a glob,
+    /// given as <GLOB>=<VALUE>, e.g. "/assets/**=public, max-age=31536000,
+    /// immutable" (repeatable; the first match wins)
+    #[arg(long = "cache-control", value_name = "GLOB=VALUE")]
+    pub cache_control: Vec<String>,
+    /// Reject requests whose Host header names neither this service's
+    /// .onion address nor an --allow-host, with 421 Misdirected Request
+    #[arg(long)]
+    pub check_host: bool,
+    /// Also accept requests for this host when checking Host headers
+    /// (repeatable; implies --check-host)
+    #[arg(long = "allow-host", value_name = "HOST")]
+    pub allow_hosts: Vec<String>,
+    /// Add an Onion-Location header pointing at this service to every
+    /// response, for clearnet front ends that pass it on
+    #[arg(long)]
+    pub onion_location: bool,
+    /// Rewrite absolute URLs to this clearnet origin (e.g.
+    /// https://example.com) in served HTML to the onion address
+    /// (repeatable)
+    #[arg(long = "rewrite-origin", value_name = "URL")]
+    pub rewrite_origins: Vec<String>,
+    /// Transform files under a path prefix as they are served, with the
+    /// steps markdown, minify, rewrite-origins, and sign, given as
+    /// <PREFIX>=<STEP>[,<STEP>...] (repeatable)
+    #[arg(long = "transform", value_name = "PREFIX=STEPS")]
+    pub transforms: Vec<String>,
+    /// The robots.txt served when the docroot has none: deny-all asks
+    /// crawlers (including those reaching the site through clearnet
+    /// gateways) to stay away, allow-all welcomes them, and file serves
+    /// only the docroot's own
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = RobotsPolicy::DenyAll
+    )]
+    pub robots: RobotsPolicy,
+    /// Write the access log to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+    /// Show the service's URL as a QR code when it starts
+    #[arg(long)]
+    pub qr: bool,
+    /// Write the service's URL as a QR code PNG image to FILE when it
+    /// starts
+    #[arg(long, value_name = "FILE")]
+    pub qr_png: Option<PathBuf>,
+    /// Accept `garner ctl` commands on this Unix socket
+    #[arg(long, value_name = "PATH", env = "GARNER_CONTROL_SOCKET")]
+    pub control_socket: Option<PathBuf>,
+    /// Page served with 503 Service Unavailable while `garner ctl
+    /// maintenance on` is in effect (read when maintenance starts)
+    #[arg(long, value_name = "FILE")]
+    pub maintenance_page: Option<PathBuf>,
+    /// Retry-After sent with maintenance responses
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub maintenance_retry_after: u64,
+    /// Sign every response body with the service's key, in an
+    /// X-Garner-Signature header (requires --key or --key-credential)
+    #[arg(long)]
+    pub sign_responses: bool,
+    /// Serve files as signed Gordian Envelopes to clients that send
+    /// `Accept: application/envelope` or request /.envelope/<PATH>
+    /// (requires --key or --key-credential)
+    #[arg(long)]
+    pub envelopes: bool,
+    /// Accept GSTP requests by POST to /.gstp, keeping values for their
+    /// store and retrieve functions under DIR (requires a
+    /// ur:crypto-prvkeys --key or --key-credential)
+    #[arg(long, value_name = "DIR")]
+    pub gstp_store: Option<PathBuf>,
+    /// Also serve each file at /.by-hash/<SHA256> by the digest of its
+    /// content, indexed at startup
+    #[arg(long)]
+    pub by_hash: bool,
+    /// Publish a JSON manifest of every file's path, size, modification
+    /// time, and SHA-256 digest at /.manifest.json
+    #[arg(long)]
+    pub manifest: bool,
+    /// Publish a manifest of file digests at /.manifest, keep each version
+    /// seen under the data dir, and serve deltas between versions at
+    /// /.delta/<FROM>/<TO> for `garner sync`
+    #[arg(long)]
+    pub deltas: bool,
+    /// Keep small objects put to /kv/<KEY> by clients that send this
+    /// bearer token, under the data dir
+    #[arg(long, value_name = "TOKEN", env = "GARNER_KV_TOKEN")]
+    pub kv_token: Option<String>,
+    /// Largest value the key-value depot accepts, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+    pub kv_max_size: usize,
+    /// Most keys the key-value depot holds
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    pub kv_max_keys: usize,
+    /// Compress responses with this Zstandard dictionary (from `garner
+    /// generate dictionary`) for clients that have it, and publish it at
+    /// /.zstd-dictionary
+    #[arg(long, value_name = "FILE")]
+    pub zstd_dictionary: Option<PathBuf>,
+    /// Let clients hold a request for up to this many seconds until the
+    /// file changes, with ?wait=<SECONDS> or Prefer: wait=<SECONDS> and
+    /// If-None-Match
+    #[arg(long, value_name = "SECONDS")]
+    pub max_wait: Option<u64>,
+    /// Publish the release files in DIR at /releases/, with generated
+    /// .sha256 checksums, .sig signatures, and an index (requires --key or
+    /// --key-credential)
+    #[arg(long, value_name = "DIR")]
+    pub releases: Option<PathBuf>,
+    /// Publish this append-only file at /.feed, readable from any byte
+    /// offset
+    #[arg(long, value_name = "FILE")]
+    pub feed: Option<PathBuf>,
+    /// Let clients that send this bearer token append records to the
+    /// feed by POST
+    #[arg(long, value_name = "TOKEN", env = "GARNER_FEED_TOKEN")]
+    pub feed_token: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
 
-pub async fn run(key: Option<&str>, docroot: &str) -> Result<()> {
-    let docroot = PathBuf::from(docroot);
-    if !docroot.is_dir() {
-        return Err(anyhow!("docroot does not exist: {}", docroot.display()));
+/// What `/robots.txt` says when the docroot has no such file.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RobotsPolicy {
+    /// Ask every crawler to stay away
+    DenyAll,
+    /// Let every crawler in
+    AllowAll,
+    /// Serve only the docroot's robots.txt
+    File,
+}
+
+/// Request path of the robots exclusion file.
+const ROBOTS_PATH: &str = "/robots.txt";
+
+impl RobotsPolicy {
+    /// The robots.txt this policy synthesizes, if any.
+    fn robots_txt(self) -> Option<&'static str> {
+        match self {
+            RobotsPolicy::DenyAll => Some("User-agent: *\nDisallow: /\n"),
+            RobotsPolicy::AllowAll => Some("User-agent: *\nDisallow:\n"),
+            RobotsPolicy::File => None,
+        }
     }
+}
 
-    let interactive = ui::is_interactive();
-    let start = Instant::now();
+/// Buffer sizes and concurrency bounds for serving requests.
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Maximum size of a request head read from the client.
+    request_buffer: usize,
+    /// Chunk size used when streaming a file to the client.
+    file_chunk: usize,
+    /// Maximum number of requests handled concurrently.
+    max_connections: usize,
+    /// Whether transformed bodies are kept in memory for reuse.
+    cache_transforms: bool,
+}
 
-    // Set up bootup spinner (interactive) or log (non-interactive)
-    let bar = if interactive {
-        let bar = ProgressBar::new_spinner();
-        bar.set_prefix(" 0s");
-        bar.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.yellow} {prefix} Connecting to the Tor network...")
-                .expect("valid template"),
-        );
-        bar.enable_steady_tick(Duration::from_millis(100));
-        Some(bar)
-    } else {
-        ui::log("Connecting to the Tor network...");
-        None
-    };
+impl Limits {
+    fn new(low_memory: bool, max_connections: Option<u32>) -> Self {
+        let (request_buffer, file_chunk, default_connections) = if low_memory {
+            (2 * 1024, 4 * 1024, 8)
+        } else {
+            (8 * 1024, 64 * 1024, 64)
+        };
+        Self {
+            request_buffer,
+            file_chunk,
+            max_connections: max_connections
+                .map_or(default_connections, |n| n as usize),
+            cache_transforms: !low_memory,
+        }
+    }
+}
 
-    let updater = bar.as_ref().map(ui::spawn_elapsed_updater);
+/// Largest request body read and discarded when the handler has no use
+/// for it, such as a GET with a body.  Larger ones get `413`.
+const MAX_UNREAD_BODY: usize = 64 * 1024;
 
-    // 1) Bootstrap Arti (Tor client)
-    // Ephemeral state dir avoids lock contention with concurrent
-    // invocations.  Declared before `tor` so it drops (and is deleted)
-    // after the TorClient releases its locks.
-    let (state_dir, cache_dir) = crate::tor_dirs()?;
-    let config = crate::tor_config(state_dir.path(), &cache_dir).build()?;
-    let tor =
-        TorClient::create_bootstrapped(config)
-            .await
-            .inspect_err(|_| {
-                if let Some(ref h) = updater {
-                    h.abort();
-                }
-                if let Some(ref bar) = bar {
-                    bar.finish_and_clear();
-                }
-            })?;
+/// Onion service virtual port on which connections are plain HTTP,
+/// unless `--port` names another.
+pub const HTTP_PORT: u16 = 80;
 
-    // 2) Configure + launch onion service
-    let svc_cfg = OnionServiceConfigBuilder::default()
-        .nickname("garner".to_string().try_into()?)
-        .build()?;
+/// Everything a connection handler needs, shared by all connections.
+struct Site {
+    docroot: PathBuf,
+    /// The docroot archive, when the docroot is one.
+    archive: Option<Arc<Archive>>,
+    limits: Limits,
+    /// Spinner shown while serving (interactive only).
+    serve_bar: Option<ProgressBar>,
+    interactive: bool,
+    /// The service's `.onion` host, once known.
+    onion_host: String,
+    /// Virtual port on which connections are plain HTTP.
+    port: u16,
+    onion_location: bool,
+    /// Hosts accepted besides the onion host, when the Host header is
+    /// checked.
+    allowed_hosts: Option<Vec<String>>,
+    /// TLS for connections to port 443, if enabled.
+    tls: Option<tls::Acceptor>,
+    /// Clearnet hosts whose URLs are rewritten in served HTML.
+    rewrite_hosts: Vec<String>,
+    /// Cache-Control values for files by path.
+    cache_policy: cache_policy::Policy,
+    /// Transformation pipelines for files under mounted prefixes.
+    pipelines: transform::Pipelines,
+    /// Key that signs every response body, if enabled.
+    signing_key: Option<SigningPrivateKey>,
+    /// Key that signs envelopes, if envelope responses are enabled.
+    envelope_key: Option<SigningPrivateKey>,
+    /// Sealed transaction endpoint, if enabled.
+    gstp: Option<gstp::Endpoint>,
+    /// Docroot files by content digest, if enabled.
+    hash_index: Option<HashIndex>,
+    /// Listing of the docroot's files, if enabled.
+    manifest: Option<manifest::Manifest>,
+    /// File versions, for the manifest and deltas.
+    versions: Option<delta::Versions>,
+    /// Longest a request may wait for a file to change.
+    max_wait: Duration,
+    /// Key-value depot, if enabled.
+    kv: Option<kv::Depot>,
+    /// Append-only feed, if enabled.
+    feed: Option<feed::Feed>,
+    /// Release directory, if enabled.
+    releases: Option<Arc<releases::Releases>>,
+    robots: RobotsPolicy,
+    /// Dictionary for compressed responses, if enabled.
+    dictionary: Option<Dictionary>,
+    access_log: Option<AccessLog>,
+    /// Page for maintenance responses, if not the built-in one.
+    maintenance_page: Option<PathBuf>,
+    maintenance_retry_after: u64,
+    /// The response to every request while in maintenance mode.
+    maintenance: Mutex<Option<Arc<Maintenance>>>,
+    /// Recent log lines and status changes for diagnostics bundles.
+    journal: Journal,
+    /// Capability report lines for diagnostics bundles.
+    report: Vec<String>,
+    config_file: Option<PathBuf>,
+    /// Called after each request, for embedders.
+    on_request: Option<Hook<RequestEvent>>,
+    /// Cancelled to stop accepting connections.
+    shutdown: CancellationToken,
+}
 
-    // Launch with a user-supplied key (deterministic address) or
-    // ephemerally.  The two methods return different opaque Stream
-    // types, so we box-erase them into a common type.
-    type RendStream = std::pin::Pin<
-        Box<dyn futures_util::Stream<Item = tor_hsservice::RendRequest> + Send>,
-    >;
-
-    let launch_result: Option<(
-        std::sync::Arc<tor_hsservice::RunningOnionService>,
-        RendStream,
-    )> = if let Some(key_ur) = key {
-        let hsid_keypair = crate::key::parse_private_key(key_ur)?;
-        tor.launch_onion_service_with_hsid(svc_cfg, hsid_keypair)?
-            .map(|(svc, s)| (svc, Box::pin(s) as RendStream))
-    } else {
-        tor.launch_onion_service(svc_cfg)?
-            .map(|(svc, s)| (svc, Box::pin(s) as RendStream))
-    };
+impl Site {
+    /// A site serving `docroot` with every optional feature off.
+    fn new(
+        docroot: PathBuf,
+        archive: Option<Arc<Archive>>,
+        limits: Limits,
+    ) -> Self {
+        Self {
+            docroot,
+            archive,
+            limits,
+            serve_bar: None,
+            interactive: false,
+            onion_host: String::new(),
+            port: HTTP_PORT,
+            onion_location: false,
+            allowed_hosts: None,
+            tls: None,
+            rewrite_hosts: Vec::new(),
+            cache_policy: cache_policy::Policy::default(),
+            pipelines: transform::Pipelines::default(),
+            signing_key: None,
+            envelope_key: None,
+            gstp: None,
+            hash_index: None,
+            manifest: None,
+            versions: None,
+            max_wait: Duration::ZERO,
+            kv: None,
+            feed: None,
+            releases: None,
+            robots: RobotsPolicy::File,
+            dictionary: None,
+            access_log: None,
+            maintenance_page: None,
+            maintenance_retry_after: 300,
+            maintenance: Mutex::new(None),
+            journal: Journal::default(),
+            report: Vec::new(),
+            config_file: None,
+            on_request: None,
+            shutdown: CancellationToken::new(),
+        }
+    }
 
-    let Some((svc, rend_requests)) = launch_result else {
-        if let Some(ref h) = updater {
-            h.abort();
+    /// The service's host as URLs name it, with the port when it is not
+    /// the default.
+    fn authority(&self) -> String {
+        match self.port {
+            HTTP_PORT => self.onion_host.clone(),
+            port => format!("{}:{port}", self.onion_host),
         }
-        if let Some(ref bar) = bar {
-            bar.finish_and_clear();
+    }
+
+    /// Whether `request` is for this service, when Host headers are
+    /// checked: its host is the onion host or a subdomain of it (which Tor
+    /// routes to the same service), or an allowed host.  A request
+    /// without a Host header cannot be checked and is refused.
+    fn host_allowed(&self, request: &Request) -> bool {
+        let Some(allowed) = &self.allowed_hosts else {
+            return true;
+        };
+        let Some(host) = request.header("host").map(host_name) else {
+            return false;
+        };
+        let onion = self.onion_host.as_str();
+        host == onion
+            || host
+                .strip_suffix(onion)
+                .is_some_and(|sub| sub.ends_with('.'))
+            || allowed.contains(&host)
+    }
+
+    /// Print a line while serving: above the spinner when interactive,
+    /// otherwise as a raw line on stderr.
+    fn println(&self, line: &str) {
+        if let Some(bar) = &self.serve_bar {
+            bar.println(format!("  {line}"));
+        } else if !self.interactive {
+            eprintln!("{line}");
         }
-        return Err(anyhow!(
-            "Onion service is disabled in config \
-             (launch_onion_service returned None)"
-        ));
-    };
+    }
 
-    let onion = svc.onion_address().ok_or_else(|| {
-        if let Some(ref h) = updater {
-            h.abort();
+    /// Write an access log line to the access log file, or else print it.
+    fn log_access(&self, line: &str) {
+        self.journal.log(line);
+        match &self.access_log {
+            Some(log) => {
+                if let Err(e) = log.write(line) {
+                    self.report_error(anyhow!(e).context("writing access log"));
+                }
+            }
+            None => self.println(line),
         }
-        if let Some(ref bar) = bar {
-            bar.finish_and_clear();
+    }
+
+    /// Log a handled request in Common Log Format, followed by the number
+    /// of request bytes received (like Apache's `%I`) and the
+    /// milliseconds from receiving the request to sending the first
+    /// response byte and the last.  Host is always "-" since Tor hides the
+    /// client address.
+    fn log_request<S>(
+        &self,
+        request: &Request,
+        status: u16,
+        body_len: u64,
+        stream: &Timed<S>,
+    ) {
+        let (first_byte, total) = stream.timing();
+        self.log_access(&format!(
+            "- - - [{}] \"{} {} {}\" {status} {body_len} {} {} {}",
+            ui::clf_timestamp(),
+            request.method,
+            request.path,
+            request.version,
+            request.len,
+            first_byte.as_millis(),
+            total.as_millis()
+        ));
+        if let Some(hook) = &self.on_request {
+            hook(&RequestEvent {
+                method: request.method.clone(),
+                path: request.path.clone(),
+                status,
+                body_len,
+            });
         }
-        anyhow!("Couldn't determine onion address (missing key?)")
-    })?;
-    let onion_host = onion.display_unredacted().to_string();
-    let pub_ur = crate::key::public_key_ur_from_hsid(&onion)?;
+    }
 
-    // Print the public key UR and .onion address as early as possible
-    // so the user can share them before the descriptor is published.
-    if let Some(ref bar) = bar {
-        bar.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.yellow} {prefix} Starting server...")
-                .expect("valid template"),
-        );
-        bar.println(format!("  {pub_ur}"));
-        bar.println(format!("  {onion_host}"));
-    } else {
-        ui::log(&pub_ur);
-        ui::log(&onion_host);
-        ui::log("Starting server...");
+    /// The `X-Garner-Signature` header for `body`, when signing is
+    /// enabled.
+    fn signature_headers(
+        &self,
+        body: &[u8],
+    ) -> Result<Vec<(&'static str, String)>> {
+        let Some(key) = &self.signing_key else {
+            return Ok(Vec::new());
+        };
+        Ok(vec![(
+            "X-Garner-Signature",
+            crate::key::sign_body(key, body)?,
+        )])
     }
 
-    // Wait for the descriptor to be published to the Tor network's
-    // HSDir nodes before declaring the service ready.
-    let mut status_stream = svc.status_events();
-    while let Some(status) = status_stream.next().await {
-        let state = status.state();
-        match state {
-            State::Running | State::DegradedReachable => break,
-            State::Broken => {
-                if let Some(ref h) = updater {
-                    h.abort();
-                }
-                if let Some(ref bar) = bar {
-                    bar.finish_and_clear();
-                }
-                let problem = status
-                    .current_problem()
-                    .map(|p| format!("{p:?}"))
-                    .unwrap_or_else(|| "unknown".into());
-                return Err(anyhow!("Onion service failed: {problem}"));
-            }
-            _ => {}
+    fn report_error(&self, e: anyhow::Error) {
+        self.journal.log(&format!("stream error: {e:#}"));
+        if let Some(bar) = &self.serve_bar {
+            bar.println(format!("  stream error: {e:#}"));
+        } else {
+            ui::log(&format!("stream error: {e:#}"));
         }
     }
+}
 
-    // Bootup complete
-    let elapsed = start.elapsed().as_secs();
-    if let Some(ref h) = updater {
-        h.abort();
+impl ctl::Control for Site {
+    fn rotate_logs(&self) -> Result<()> {
+        let log = self.access_log.as_ref().ok_or_else(|| {
+            anyhow!("no log file to rotate (see --access-log)")
+        })?;
+        log.rotate()?;
+        self.journal.status("access log rotated");
+        Ok(())
     }
-    if let Some(ref bar) = bar {
-        bar.finish_and_clear();
-        eprintln!("\u{2713} Server started in {elapsed}s");
-    } else {
-        ui::log(&format!("Server started in {elapsed}s"));
+
+    fn diagnostics(&self) -> Result<Vec<u8>> {
+        crate::diagnostics::bundle(
+            &self.report,
+            self.config_file.as_deref(),
+            &self.journal,
+        )
     }
 
-    // 3) Accept rendezvous requests => stream of StreamRequest
-    let mut stream_reqs = handle_rend_requests(rend_requests);
+    fn set_maintenance(&self, on: bool) -> Result<()> {
+        let page = match on {
+            true => Some(Arc::new(Maintenance::load(
+                self.maintenance_page.as_deref(),
+            )?)),
+            false => None,
+        };
+        *self.maintenance.lock().expect("maintenance lock") = page;
+        self.journal.status(if on {
+            "maintenance mode on"
+        } else {
+            "maintenance mode off"
+        });
+        Ok(())
+    }
+}
+
+/// The page served while the site is down for maintenance.
+struct Maintenance {
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Maintenance {
+    /// Read the page at `path`, or use a plain-text notice.
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self {
+                content_type: "text/plain".into(),
+                body: b"Down for maintenance; please try again later.\n"
+                    .to_vec(),
+            });
+        };
+        let body = std::fs::read(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        Ok(Self {
+            content_type: MimeGuess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            body,
+        })
+    }
+}
+
+/// An access log file that `garner ctl rotate-logs` can rotate.
+struct AccessLog {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+impl AccessLog {
+    fn open(path: PathBuf) -> Result<Self> {
+        let file = Self::open_file(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening access log {}", path.display()))
+    }
+
+    fn write(&self, line: &str) -> std::io::Result<()> {
+        use std::io::Write;
+
+        writeln!(self.file.lock().expect("access log lock"), "{line}")
+    }
+
+    /// Move the log to `<path>.1`, replacing any earlier one, and start a
+    /// new, empty log at `path`.
+    fn rotate(&self) -> Result<()> {
+        let mut file = self.file.lock().expect("access log lock");
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated).with_context(|| {
+            format!("rotating access log {}", self.path.display())
+        })?;
+        *file = Self::open_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Progress display for the bootup phase: a spinner with an elapsed-time
+/// counter when interactive, timestamped log lines otherwise.
+struct Startup {
+    bar: Option<ProgressBar>,
+    updater: Option<tokio::task::JoinHandle<()>>,
+    start: Instant,
+    interactive: bool,
+}
+
+impl Startup {
+    /// Print the capability report, then start the bootup progress
+    /// display.
+    fn new(interactive: bool, report: &Report) -> Self {
+        for line in report.lines() {
+            if interactive {
+                eprintln!("  {line}");
+            } else {
+                ui::log(&line);
+            }
+        }
+        let bar = if interactive {
+            let bar = ProgressBar::new_spinner();
+            bar.set_prefix(" 0s");
+            bar.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner:.yellow} {prefix} Connecting to the Tor network...")
+                    .expect("valid template"),
+            );
+            bar.enable_steady_tick(Duration::from_millis(100));
+            Some(bar)
+        } else {
+            ui::log("Connecting to the Tor network...");
+            None
+        };
+        let updater = bar.as_ref().map(ui::spawn_elapsed_updater);
+        Self {
+            bar,
+            updater,
+            start: Instant::now(),
+            interactive,
+        }
+    }
+
+    /// Print the public key UR and .onion address as early as possible so
+    /// the user can share them before the descriptor is published.
+    fn announce(&self, pub_ur: &str, onion_host: &str) {
+        if let Some(ref bar) = self.bar {
+            bar.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner:.yellow} {prefix} Starting server...")
+                    .expect("valid template"),
+            );
+            bar.println(format!("  {pub_ur}"));
+            bar.println(format!("  {onion_host}"));
+        } else {
+            ui::log(pub_ur);
+            ui::log(onion_host);
+            ui::log("Starting server...");
+        }
+    }
+
+    /// Show the service's URL as a QR code, as `--qr` and `--qr-png` ask.
+    fn show_qr(&self, args: &ServerArgs, onion_host: &str) -> Result<()> {
+        let url = match args.port {
+            HTTP_PORT => format!("http://{onion_host}/"),
+            port => format!("http://{onion_host}:{port}/"),
+        };
+        if let Some(path) = &args.qr_png {
+            qr::write_png(path, &url)?;
+        }
+        if args.qr {
+            let code = qr::QrCode::encode(&url)?.to_terminal();
+            match self.bar {
+                Some(ref bar) => bar.println(code.trim_end()),
+                None => eprint!("{code}"),
+            }
+        }
+        Ok(())
+    }
 
-    let docroot = Arc::new(docroot);
+    /// Stop the spinner and its elapsed-time counter.
+    fn clear(&self) {
+        if let Some(ref h) = self.updater {
+            h.abort();
+        }
+        if let Some(ref bar) = self.bar {
+            bar.finish_and_clear();
+        }
+    }
 
-    // Serving spinner (interactive only)
-    let serve_bar = if interactive {
+    /// Report that bootup is complete and return the serving spinner
+    /// (interactive only).
+    fn finish(self) -> Option<ProgressBar> {
+        let elapsed = self.start.elapsed().as_secs();
+        self.clear();
+        if self.bar.is_some() {
+            eprintln!("\u{2713} Server started in {elapsed}s");
+        } else {
+            ui::log(&format!("Server started in {elapsed}s"));
+        }
+        if !self.interactive {
+            return None;
+        }
         let bar = ProgressBar::new_spinner();
         bar.set_style(
             ProgressStyle::default_spinner()
@@ -179,162 +723,3367 @@ pub async fn run(key: Option<&str>, docroot: &str) -> Result<()> {
         );
         bar.enable_steady_tick(Duration::from_millis(200));
         Some(bar)
-    } else {
-        None
-    };
-
-    // Handle incoming streams forever
-    while let Some(req) = stream_reqs.next().await {
-        let docroot = Arc::clone(&docroot);
-        let serve_bar = serve_bar.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_stream_request(
-                req,
-                &docroot,
-                serve_bar.as_ref(),
-                interactive,
-            )
-            .await
-            {
-                if let Some(ref bar) = serve_bar {
-                    bar.println(format!("  stream error: {e:#}"));
-                } else {
-                    ui::log(&format!("stream error: {e:#}"));
-                }
-            }
-        });
     }
-
-    Ok(())
 }
 
-async fn handle_stream_request(
-    req: StreamRequest,
-    docroot: &Path,
-    serve_bar: Option<&ProgressBar>,
-    interactive: bool,
-) -> Result<()> {
-    if !matches!(req.request(), IncomingStreamRequest::Begin(_)) {
-        let _ = req.reject(End::new_misc()).await;
-        return Ok(());
+pub async fn run(mut args: ServerArgs, mut report: Report) -> Result<()> {
+    args.key =
+        crate::key::read_key_arg(args.key.take(), args.key_file.as_deref())?;
+    if let Some(name) = &args.key_credential {
+        args.key = Some(crate::key::read_credential(name)?);
+    }
+    if let Some(key) = &args.key {
+        args.key = Some(crate::key::unlock(key)?);
+    }
+    let docroot = PathBuf::from(&args.docroot);
+    let archive = open_docroot(&docroot)?;
+    // Validate up front: the nickname also names the state directory, so
+    // it must be a legal onion service nickname before touching disk.
+    let nickname = parse_nickname(&args.nickname)?;
+    let limits = Limits::new(args.low_memory, args.max_connections);
+    if args.tor_control.is_some()
+        && (args.persistent_state.is_some() || args.tor.uses_arti_options())
+    {
+        return Err(anyhow!(
+            "--tor-control cannot be combined with options for the \
+             embedded Arti client"
+        ));
     }
 
-    // Accept -> DataStream
-    let mut stream = req.accept(Connected::new_empty()).await?;
-
-    let (method, path) = read_http_request_line(&mut stream).await?;
-
-    let (status, body_len) = if method != "GET" {
-        write_http_response(
-            &mut stream,
-            405,
-            "text/plain",
-            b"Method Not Allowed",
-        )
-        .await?;
-        (405u16, 18usize)
-    } else if let Some(file_path) = resolve_file(&path, docroot) {
-        let body = tokio::fs::read(&file_path)
-            .await
-            .with_context(|| format!("reading {file_path:?}"))?;
-        let len = body.len();
-        let mime = MimeGuess::from_path(&file_path).first_or_octet_stream();
-        write_http_response(&mut stream, 200, mime.as_ref(), &body).await?;
-        (200, len)
-    } else {
-        write_http_response(&mut stream, 404, "text/plain", b"Not Found")
-            .await?;
-        (404, 9usize)
+    let service_key = |option: &str| -> Result<SigningPrivateKey> {
+        let key = args.key.as_deref().ok_or_else(|| {
+            anyhow!("{option} requires --key or --key-credential")
+        })?;
+        crate::key::parse_signing_key(key)
     };
-
-    // Log in Common Log Format:
-    //   <host> - - [<timestamp>] "<method> <path> HTTP/1.1" <status> <size>
-    // Host is always "-" since Tor hides the client address.
-    let log_line = format!(
-        "- - - [{}] \"{method} {path} HTTP/1.1\" {status} {body_len}",
-        ui::clf_timestamp()
+    let signing_key = args
+        .sign_responses
+        .then(|| service_key("--sign-responses"))
+        .transpose()?;
+    let envelope_key = args
+        .envelopes
+        .then(|| service_key("--envelopes"))
+        .transpose()?;
+    let gstp = match &args.gstp_store {
+        Some(store) => {
+            let key = args.key.as_deref().ok_or_else(|| {
+                anyhow!("--gstp-store requires --key or --key-credential")
+            })?;
+            let keys = crate::key::parse_private_keys(key)?;
+            Some(gstp::Endpoint::new(keys, store.clone())?)
+        }
+        None => None,
+    };
+    let rewrite_hosts = args
+        .rewrite_origins
+        .iter()
+        .map(|origin| rewrite::parse_origin(origin))
+        .collect::<Result<Vec<_>>>()?;
+    let cache_policy = cache_policy::Policy::new(
+        args.cache_control
+            .iter()
+            .map(|spec| cache_policy::parse_rule(spec))
+            .collect::<Result<_>>()?,
     );
-    if let Some(bar) = serve_bar {
-        bar.println(format!("  {log_line}"));
-    } else if !interactive {
-        eprintln!("{log_line}");
+    let mounts = args
+        .transforms
+        .iter()
+        .map(|spec| transform::parse_mount(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let transform_key = mounts
+        .iter()
+        .any(|mount| mount.steps().contains(&transform::Step::Sign))
+        .then(|| service_key("the sign transform step"))
+        .transpose()?;
+    let pipelines = transform::Pipelines::new(mounts, transform_key)?;
+    if pipelines.uses(transform::Step::RewriteOrigins)
+        && rewrite_hosts.is_empty()
+    {
+        return Err(anyhow!(
+            "the rewrite-origins transform step requires --rewrite-origin"
+        ));
     }
 
-    Ok(())
-}
-
-/// Map a request path to a file under `docroot`.  For `/`, try
-/// `index.html` first then fall back to `index.txt`.
-fn resolve_file(request_path: &str, docroot: &Path) -> Option<PathBuf> {
-    if request_path == "/" {
-        return ["index.html", "index.txt"]
-            .iter()
-            .map(|name| docroot.join(name))
-            .find(|p| p.is_file());
+    if args.by_hash && archive.as_ref().is_some_and(|a| a.is_database()) {
+        return Err(anyhow!(
+            "--by-hash cannot be used with a content database, whose files \
+             can change while it is served"
+        ));
+    }
+    let hash_index = args
+        .by_hash
+        .then(|| match &archive {
+            Some(archive) => HashIndex::build_archive(archive),
+            None => HashIndex::build(&docroot),
+        })
+        .transpose()?;
+    if args.manifest && archive.is_some() {
+        return Err(anyhow!(
+            "--manifest needs a docroot directory, not an archive or database"
+        ));
+    }
+    let manifest = args
+        .manifest
+        .then(|| manifest::Manifest::open(&docroot))
+        .transpose()?;
+    if args.deltas && archive.is_some() {
+        return Err(anyhow!(
+            "--deltas needs a docroot directory, not an archive or database"
+        ));
+    }
+    let versions = args
+        .deltas
+        .then(|| {
+            let store = ui::data_dir().join("versions").join(&args.nickname);
+            delta::Versions::open(&docroot, store)
+        })
+        .transpose()?;
+    let kv = args
+        .kv_token
+        .as_deref()
+        .map(|token| {
+            let dir = ui::data_dir().join("kv").join(&args.nickname);
+            kv::Depot::open(dir, token, args.kv_max_size, args.kv_max_keys)
+        })
+        .transpose()?;
+    let releases = args
+        .releases
+        .as_deref()
+        .map(|dir| releases::Releases::open(dir, service_key("--releases")?))
+        .transpose()?
+        .map(Arc::new);
+    let feed = args
+        .feed
+        .as_deref()
+        .map(|path| feed::Feed::open(path, args.feed_token.as_deref()))
+        .transpose()?;
+    let dictionary = args
+        .zstd_dictionary
+        .as_deref()
+        .map(Dictionary::open)
+        .transpose()?;
+    let access_log =
+        args.access_log.clone().map(AccessLog::open).transpose()?;
+    // Check the maintenance page now rather than when it is first needed.
+    Maintenance::load(args.maintenance_page.as_deref())?;
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::Acceptor::load(cert, key)?),
+        _ => None,
+    };
+    if tls.is_some() && args.port == tls::PORT {
+        return Err(anyhow!(
+            "--port {} is the TLS port; choose another for plain HTTP",
+            tls::PORT
+        ));
     }
 
-    let relative = request_path.strip_prefix('/')?;
-    let candidate = docroot.join(relative);
-
-    // Prevent path traversal (e.g. "/../secret")
-    if !candidate.starts_with(docroot) {
-        return None;
+    describe(&args, &limits, &mut report);
+    if let Some(archive) = &archive {
+        report.section(
+            "archive",
+            format!("{} files, read on demand", archive.names()?.len()),
+        );
+    }
+    if let Some(index) = &hash_index {
+        report.section("by-hash", format!("{} files indexed", index.len()));
+    }
+    if let Some(versions) = &versions {
+        report.section(
+            "deltas",
+            format!(
+                "{} and {} keeping versions in {}",
+                delta::MANIFEST_PATH,
+                delta::PATH_PREFIX,
+                versions.store().display()
+            ),
+        );
+    }
+    if let Some(releases) = &releases {
+        report.section(
+            "releases",
+            format!(
+                "{} from {} ({} files)",
+                releases::PATH_PREFIX,
+                releases.dir().display(),
+                releases.list()?.len()
+            ),
+        );
     }
+    if let Some(depot) = &kv {
+        report.section(
+            "kv",
+            format!(
+                "{} storing in {} (at most {} keys of {} bytes)",
+                kv::PATH_PREFIX,
+                depot.dir().display(),
+                args.kv_max_keys,
+                args.kv_max_size
+            ),
+        );
+    }
+    let startup = Startup::new(ui::is_interactive(), &report);
+    let site = Site {
+        interactive: startup.interactive,
+        port: args.port,
+        onion_location: args.onion_location,
+        allowed_hosts: (args.check_host || !args.allow_hosts.is_empty())
+            .then(|| args.allow_hosts.iter().map(|h| host_name(h)).collect()),
+        tls,
+        rewrite_hosts,
+        cache_policy,
+        pipelines,
+        signing_key,
+        envelope_key,
+        gstp,
+        hash_index,
+        manifest,
+        versions,
+        max_wait: Duration::from_secs(args.max_wait.unwrap_or(0)),
+        kv,
+        feed,
+        releases,
+        robots: args.robots,
+        dictionary,
+        access_log,
+        maintenance_page: args.maintenance_page.clone(),
+        maintenance_retry_after: args.maintenance_retry_after,
+        report: report.lines(),
+        config_file: report.config_file().map(Path::to_path_buf),
+        ..Site::new(docroot, archive, limits)
+    };
+    site.journal.status("connecting to the Tor network");
+    match args.tor_control.as_deref() {
+        Some(addr) => run_with_system_tor(&args, addr, startup, site).await,
+        None => run_with_arti(&args, nickname, startup, site).await,
+    }
+}
 
-    if candidate.is_file() {
-        Some(candidate)
+/// Open the docroot: a directory, or an archive read on demand.
+fn open_docroot(docroot: &Path) -> Result<Option<Arc<Archive>>> {
+    if docroot.is_file() && Archive::is_archive(docroot) {
+        Ok(Some(Arc::new(Archive::open(docroot)?)))
+    } else if docroot.is_dir() {
+        Ok(None)
     } else {
-        None
+        Err(anyhow!("docroot does not exist: {}", docroot.display()))
     }
 }
 
-async fn read_http_request_line(
-    stream: &mut tor_proto::client::stream::DataStream,
-) -> Result<(String, String)> {
-    use futures_util::io::AsyncReadExt;
+fn parse_nickname(nickname: &str) -> Result<HsNickname> {
+    nickname
+        .parse()
+        .map_err(|e| anyhow!("invalid nickname {nickname:?}: {e}"))
+}
 
-    let mut buf = vec![0u8; 8192];
-    let n = stream.read(&mut buf).await?;
-    let s =
-        std::str::from_utf8(&buf[..n]).context("request not valid UTF-8")?;
+/// An onion service launched by `garner api` on its shared Tor client.
+/// The service stops when this is dropped.
+pub struct Service {
+    pub onion_host: String,
+    pub public_key: String,
+    _svc: Arc<RunningOnionService>,
+    task: tokio::task::JoinHandle<()>,
+}
 
-    let first_line =
-        s.lines().next().ok_or_else(|| anyhow!("empty request"))?;
-    let mut parts = first_line.split_whitespace();
-    let method = parts.next().unwrap_or("").to_string();
-    let path = parts.next().unwrap_or("/").to_string();
-    Ok((method, path))
+impl Drop for Service {
+    fn drop(&mut self) { self.task.abort(); }
 }
 
-async fn write_http_response(
-    stream: &mut tor_proto::client::stream::DataStream,
-    status: u16,
-    content_type: &str,
-    body: &[u8],
-) -> Result<()> {
-    use futures_util::io::AsyncWriteExt;
+/// Launch an onion service serving `docroot` with default settings on
+/// an already-bootstrapped client, and return once it is reachable.  The
+/// service has a deterministic address when `key` is given.
+pub async fn launch(
+    tor: &TorClient<PreferredRuntime>,
+    docroot: &str,
+    key: Option<&str>,
+    nickname: &str,
+) -> Result<Service> {
+    let docroot = PathBuf::from(docroot);
+    let archive = open_docroot(&docroot)?;
+    let launched = launch_reachable(tor, key, nickname).await?;
+    let mut site = Site::new(docroot, archive, Limits::new(false, None));
+    site.onion_host = launched.onion_host.clone();
+    site.journal.status("serving");
+    let rend_requests = launched.rend_requests;
+    let task = tokio::spawn(async move {
+        let _ = serve_rend_requests(Arc::new(site), rend_requests).await;
+    });
+    Ok(Service {
+        onion_host: launched.onion_host,
+        public_key: launched.public_key,
+        _svc: launched.svc,
+        task,
+    })
+}
 
-    let reason = match status {
-        200 => "OK",
-        404 => "Not Found",
-        405 => "Method Not Allowed",
-        _ => "OK",
+/// An onion service that has been launched and is reachable.
+struct Launched {
+    svc: Arc<RunningOnionService>,
+    rend_requests: RendStream,
+    onion_host: String,
+    public_key: String,
+}
+
+/// Launch an onion service on an already-bootstrapped client, with a
+/// deterministic address when `key` is given, and wait until it is
+/// reachable.
+async fn launch_reachable(
+    tor: &TorClient<PreferredRuntime>,
+    key: Option<&str>,
+    nickname: &str,
+) -> Result<Launched> {
+    let svc_cfg = OnionServiceConfigBuilder::default()
+        .nickname(parse_nickname(nickname)?)
+        .build()?;
+    let launched = match key {
+        Some(key_ur) => {
+            let hsid_keypair = crate::key::parse_private_key(key_ur)?;
+            tor.launch_onion_service_with_hsid(svc_cfg, hsid_keypair)?
+                .map(|(svc, s)| (svc, Box::pin(s) as RendStream))
+        }
+        None => tor
+            .launch_onion_service(svc_cfg)?
+            .map(|(svc, s)| (svc, Box::pin(s) as RendStream)),
     };
+    let (svc, rend_requests) = launched
+        .ok_or_else(|| anyhow!("onion service is disabled in config"))?;
+    let onion = svc
+        .onion_address()
+        .ok_or_else(|| anyhow!("couldn't determine onion address"))?;
+    let onion_host = onion.display_unredacted().to_string();
+    let public_key = crate::key::public_key_ur_from_hsid(&onion)?;
+    let mut status_stream = svc.status_events();
+    wait_until_reachable(&mut status_stream).await?;
+    Ok(Launched {
+        svc,
+        rend_requests,
+        onion_host,
+        public_key,
+    })
+}
 
-    let header = format!(
-        "HTTP/1.1 {status} {reason}\r\n\
-         Content-Length: {}\r\n\
-         Content-Type: {content_type}\r\n\
-         Connection: close\r\n\
-         \r\n",
-        body.len()
-    );
+/// A hook a [`GarnerServer`] calls with an event.
+type Hook<T> = Arc<dyn Fn(&T) + Send + Sync>;
 
-    stream.write_all(header.as_bytes()).await?;
-    stream.write_all(body).await?;
-    stream.flush().await?;
-    // Explicitly close the write half so the remote side sees a clean
-    // stream shutdown rather than an abrupt drop.
-    stream.close().await?;
-    Ok(())
+/// The service a [`GarnerServer`] publishes, as its `on_ready` hook sees
+/// it.
+#[derive(Debug, Clone)]
+pub struct Ready {
+    pub onion_host: String,
+    pub public_key: String,
+}
+
+/// A request a [`GarnerServer`] answered, as its `on_request` hook sees
+/// it.
+#[derive(Debug, Clone)]
+pub struct RequestEvent {
+    pub method: String,
+    /// The request target, with any query.
+    pub path: String,
+    pub status: u16,
+    /// Response body bytes sent.
+    pub body_len: u64,
+}
+
+/// An onion service serving a docroot with default settings, for programs
+/// that embed garner and run it under their own supervision.
+///
+/// It serves until its cancellation token is cancelled, then stops
+/// accepting connections and finishes those in progress before it
+/// returns.  Hooks are called once the service is reachable, after each
+/// request, and once it has shut down, whether it stopped on request or
+/// failed.
+pub struct GarnerServer {
+    docroot: PathBuf,
+    key: Option<String>,
+    nickname: String,
+    cancel: CancellationToken,
+    on_ready: Option<Hook<Ready>>,
+    on_request: Option<Hook<RequestEvent>>,
+    on_shutdown: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl GarnerServer {
+    pub fn new(docroot: impl Into<PathBuf>) -> Self {
+        Self {
+            docroot: docroot.into(),
+            key: None,
+            nickname: "garner".to_string(),
+            cancel: CancellationToken::new(),
+            on_ready: None,
+            on_request: None,
+            on_shutdown: None,
+        }
+    }
+
+    /// Publish under the private key `key_ur`, for a deterministic
+    /// address, rather than a new one.
+    pub fn with_key(self, key_ur: &str) -> Self {
+        Self {
+            key: Some(key_ur.to_string()),
+            ..self
+        }
+    }
+
+    /// Name the service `nickname` rather than `garner`.
+    pub fn with_nickname(self, nickname: &str) -> Self {
+        Self {
+            nickname: nickname.to_string(),
+            ..self
+        }
+    }
+
+    /// Stop when `token` is cancelled, such as a child of the embedder's
+    /// own token, rather than on a token of the server's own.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        Self {
+            cancel: token,
+            ..self
+        }
+    }
+
+    /// The token that stops the server when cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Call `hook` once the service is reachable.
+    pub fn on_ready(
+        self,
+        hook: impl Fn(&Ready) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_ready: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// Call `hook` after each request is answered.
+    pub fn on_request(
+        self,
+        hook: impl Fn(&RequestEvent) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            on_request: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// Call `hook` once the server has shut down.
+    pub fn on_shutdown(self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            on_shutdown: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// Bootstrap a Tor client of the server's own, with ephemeral state,
+    /// and serve on it until cancelled.
+    pub async fn run(self) -> Result<()> {
+        let serve = async {
+            // Declared before the client, so it drops (and is deleted)
+            // after the client releases its locks.
+            let (state_dir, cache_dir) = tor::dirs(Some(&self.nickname))?;
+            let config =
+                tor::config(&state_dir, &cache_dir, &tor::TorArgs::default())?
+                    .build()?;
+            let tor = TorClient::create_bootstrapped(config).await?;
+            self.serve_until_cancelled(&tor).await
+        };
+        let result = tokio::select! {
+            biased;
+            () = self.cancel.cancelled() => Ok(()),
+            result = serve => result,
+        };
+        self.shut_down();
+        result
+    }
+
+    /// Serve on an already-bootstrapped client until cancelled.
+    pub async fn serve(self, tor: &TorClient<PreferredRuntime>) -> Result<()> {
+        let result = self.serve_until_cancelled(tor).await;
+        self.shut_down();
+        result
+    }
+
+    async fn serve_until_cancelled(
+        &self,
+        tor: &TorClient<PreferredRuntime>,
+    ) -> Result<()> {
+        let archive = open_docroot(&self.docroot)?;
+        let launched = tokio::select! {
+            biased;
+            () = self.cancel.cancelled() => return Ok(()),
+            launched = launch_reachable(tor, self.key.as_deref(), &self.nickname)
+                => launched?,
+        };
+        let mut site =
+            Site::new(self.docroot.clone(), archive, Limits::new(false, None));
+        site.onion_host = launched.onion_host.clone();
+        site.on_request = self.on_request.clone();
+        site.shutdown = self.cancel.clone();
+        site.journal.status("serving");
+        if let Some(hook) = &self.on_ready {
+            hook(&Ready {
+                onion_host: launched.onion_host,
+                public_key: launched.public_key,
+            });
+        }
+        // The service stops when `svc` is dropped, after serving.
+        let _svc = launched.svc;
+        serve_rend_requests(Arc::new(site), launched.rend_requests).await
+    }
+
+    fn shut_down(&self) {
+        if let Some(hook) = &self.on_shutdown {
+            hook();
+        }
+    }
+}
+
+/// Add the server's active configuration sections to `report`.
+fn describe(args: &ServerArgs, limits: &Limits, report: &mut Report) {
+    report.section("docroot", args.docroot.as_str());
+    match &args.tor_control {
+        Some(addr) => report.section("tor", format!("system daemon at {addr}")),
+        None => report.section("tor", "embedded Arti"),
+    }
+    if let Some(path) = &args.tor.arti_config {
+        report.section("arti config", path.display().to_string());
+    }
+    #[cfg(feature = "bridges")]
+    if !args.tor.bridges.is_empty() || !args.tor.transports.is_empty() {
+        report.section(
+            "bridges",
+            format!(
+                "{} bridges, {} transports",
+                args.tor.bridges.len(),
+                args.tor.transports.len()
+            ),
+        );
+    }
+    if args.tor_control.is_none() {
+        match &args.persistent_state {
+            Some(root) => report.section(
+                "state",
+                format!(
+                    "persistent in {}",
+                    root.join(&args.nickname).display()
+                ),
+            ),
+            None => report.section("state", "ephemeral"),
+        }
+    }
+    let response_options = [
+        (args.onion_location, "onion-location"),
+        (
+            args.check_host || !args.allow_hosts.is_empty(),
+            "host-checked",
+        ),
+        (!args.rewrite_origins.is_empty(), "rewrite-origin"),
+        (!args.transforms.is_empty(), "transform"),
+        (!args.cache_control.is_empty(), "cache-control"),
+        (args.sign_responses, "signed"),
+        (args.envelopes, "envelopes"),
+        (args.zstd_dictionary.is_some(), "zstd-dictionary"),
+        (args.manifest, "manifest"),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>();
+    if !response_options.is_empty() {
+        report.section("responses", response_options.join(", "));
+    }
+    if let Some(path) = &args.feed {
+        report.section(
+            "feed",
+            format!(
+                "{} from {}{}",
+                feed::PATH,
+                path.display(),
+                if args.feed_token.is_some() {
+                    ", appendable"
+                } else {
+                    ""
+                }
+            ),
+        );
+    }
+    if args.port != HTTP_PORT {
+        report.section("port", args.port.to_string());
+    }
+    if let Some(cert) = &args.tls_cert {
+        report.section(
+            "tls",
+            format!("port {} with {}", tls::PORT, cert.display()),
+        );
+    }
+    if let Some(store) = &args.gstp_store {
+        report.section(
+            "gstp",
+            format!("{} storing in {}", gstp::PATH, store.display()),
+        );
+    }
+    report.section(
+        "limits",
+        format!(
+            "{} connections{}",
+            limits.max_connections,
+            if args.low_memory { ", low-memory" } else { "" }
+        ),
+    );
+}
+
+/// Bootstrap the embedded Arti client, launch the onion service, and
+/// serve rendezvous streams until the service stops.
+async fn run_with_arti(
+    args: &ServerArgs,
+    nickname: tor_hsservice::HsNickname,
+    startup: Startup,
+    mut site: Site,
+) -> Result<()> {
+    // 1) Bootstrap Arti (Tor client)
+    // Ephemeral state dir avoids lock contention with concurrent
+    // invocations.  Declared before `tor` so it drops (and is deleted)
+    // after the TorClient releases its locks.  A persistent state dir
+    // keeps the keystore and descriptor state for fast restarts.
+    let (state_dir, cache_dir) = match &args.persistent_state {
+        Some(root) => tor::persistent_dirs(root, nickname.as_ref())?,
+        None => tor::dirs(Some(nickname.as_ref()))?,
+    };
+    let persistent = matches!(state_dir, tor::StateDir::Persistent(_));
+    let config = tor::config(&state_dir, &cache_dir, &args.tor)?.build()?;
+    let tor = TorClient::create_bootstrapped(config)
+        .await
+        .inspect_err(|_| startup.clear())?;
+
+    // 2) Configure + launch onion service
+    let svc_cfg = OnionServiceConfigBuilder::default()
+        .nickname(nickname)
+        .build()?;
+
+    // Launch with a user-supplied key (deterministic address) or
+    // ephemerally.
+    let launch_result: Option<(Arc<RunningOnionService>, RendStream)> =
+        if let Some(key_ur) = args.key.as_deref() {
+            let hsid_keypair = crate::key::parse_private_key(key_ur)?;
+            let expected = tor_hscrypto::pk::HsIdKey::from(&hsid_keypair).id();
+            match tor
+                .launch_onion_service_with_hsid(svc_cfg.clone(), hsid_keypair)
+            {
+                Ok(launched) => {
+                    launched.map(|(svc, s)| (svc, Box::pin(s) as RendStream))
+                }
+                // A persistent keystore already holds the identity from an
+                // earlier run.  Reuse it, but only if it is the same key.
+                Err(e) if persistent && is_key_already_exists(&e) => {
+                    let launched = tor.launch_onion_service(svc_cfg)?;
+                    if let Some((svc, _)) = &launched
+                        && svc.onion_address() != Some(expected)
+                    {
+                        startup.clear();
+                        return Err(anyhow!(
+                            "persistent state for nickname {:?} holds a \
+                             different identity than --key; use another \
+                             --nickname or remove the state directory",
+                            args.nickname
+                        ));
+                    }
+                    launched.map(|(svc, s)| (svc, Box::pin(s) as RendStream))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            tor.launch_onion_service(svc_cfg)?
+                .map(|(svc, s)| (svc, Box::pin(s) as RendStream))
+        };
+
+    let Some((svc, rend_requests)) = launch_result else {
+        startup.clear();
+        return Err(anyhow!(
+            "Onion service is disabled in config \
+             (launch_onion_service returned None)"
+        ));
+    };
+
+    let onion = svc.onion_address().ok_or_else(|| {
+        startup.clear();
+        anyhow!("Couldn't determine onion address (missing key?)")
+    })?;
+    let onion_host = onion.display_unredacted().to_string();
+    let pub_ur = crate::key::public_key_ur_from_hsid(&onion)?;
+    startup.announce(&pub_ur, &onion_host);
+    startup
+        .show_qr(args, &onion_host)
+        .inspect_err(|_| startup.clear())?;
+    site.journal.status(&format!("publishing {onion_host}"));
+    site.onion_host = onion_host;
+
+    let mut status_stream = svc.status_events();
+    wait_until_reachable(&mut status_stream)
+        .await
+        .inspect_err(|_| startup.clear())?;
+
+    // Bootup complete
+    site.serve_bar = startup.finish();
+    site.journal.status("serving");
+    let site = Arc::new(site);
+    start_control_socket(args, &site)?;
+
+    // Keep recording status changes for diagnostics.
+    let status_site = Arc::clone(&site);
+    tokio::spawn(async move {
+        while let Some(status) = status_stream.next().await {
+            status_site
+                .journal
+                .status(&format!("onion service {:?}", status.state()));
+        }
+    });
+
+    // 3) Accept rendezvous requests and serve their streams
+    serve_rend_requests(site, rend_requests).await
+}
+
+/// The rendezvous requests of a launched onion service.  Launching with
+/// and without a key return different opaque stream types, so they are
+/// box-erased into this one.
+type RendStream = Pin<Box<dyn futures_util::Stream<Item = RendRequest> + Send>>;
+
+/// Wait for the descriptor to be published to the Tor network's HSDir
+/// nodes before declaring the service ready.
+async fn wait_until_reachable(
+    status_stream: &mut OnionServiceStatusStream,
+) -> Result<()> {
+    while let Some(status) = status_stream.next().await {
+        match status.state() {
+            State::Running | State::DegradedReachable => break,
+            State::Broken => {
+                let problem = status
+                    .current_problem()
+                    .map(|p| format!("{p:?}"))
+                    .unwrap_or_else(|| "unknown".into());
+                return Err(anyhow!("Onion service failed: {problem}"));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Accept rendezvous requests and serve their streams until the service
+/// stops or the site is shut down, and then wait for the requests in
+/// progress to finish.
+async fn serve_rend_requests(
+    site: Arc<Site>,
+    rend_requests: RendStream,
+) -> Result<()> {
+    let mut stream_reqs = handle_rend_requests(rend_requests);
+
+    // Handle incoming streams forever.  Waiting for a permit before
+    // taking the next request applies backpressure once
+    // `max_connections` requests are in flight.
+    let permits = Arc::new(Semaphore::new(site.limits.max_connections));
+    loop {
+        let req = tokio::select! {
+            () = site.shutdown.cancelled() => break,
+            req = stream_reqs.next() => match req {
+                Some(req) => req,
+                None => break,
+            },
+        };
+        let permit = Arc::clone(&permits).acquire_owned().await?;
+        let port = match req.request() {
+            IncomingStreamRequest::Begin(begin) => Some(begin.port()),
+            _ => None,
+        };
+        let tls = port == Some(tls::PORT) && site.tls.is_some();
+        if tls {
+            let tls_site = Arc::clone(&site);
+            spawn_connection(&site, permit, async move {
+                let stream = req.accept(Connected::new_empty()).await?;
+                let acceptor = tls_site.tls.as_ref().expect("TLS enabled");
+                Ok(Some(acceptor.accept(stream).await?))
+            });
+            continue;
+        }
+        let http = port == Some(site.port);
+        spawn_connection(&site, permit, async move {
+            if !http {
+                let _ = req.reject(End::new_misc()).await;
+                return Ok(None);
+            }
+            Ok(Some(req.accept(Connected::new_empty()).await?))
+        });
+    }
+
+    let _all = permits
+        .acquire_many_owned(site.limits.max_connections as u32)
+        .await?;
+    Ok(())
+}
+
+/// Publish the onion service through a running Tor daemon and serve the
+/// connections it forwards to a local listener.  The service lives as
+/// long as the control connection stays open.
+async fn run_with_system_tor(
+    args: &ServerArgs,
+    control_addr: &str,
+    startup: Startup,
+    mut site: Site,
+) -> Result<()> {
+    let secret_key = args
+        .key
+        .as_deref()
+        .map(crate::key::parse_private_key_expanded)
+        .transpose()?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let mut ports = vec![(args.port, listener.local_addr()?)];
+    let tls_listener = match site.tls {
+        Some(_) => {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            ports.push((tls::PORT, listener.local_addr()?));
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let mut control = system_tor::ControlPort::connect(
+        control_addr,
+        args.tor_control_password.as_deref(),
+    )
+    .await
+    .inspect_err(|_| startup.clear())?;
+    let onion_host = control
+        .add_onion(secret_key.as_ref(), &ports)
+        .await
+        .inspect_err(|_| startup.clear())?;
+    let hs_id: tor_hscrypto::pk::HsId = onion_host
+        .parse()
+        .map_err(|e| anyhow!("Tor returned an invalid onion address: {e}"))?;
+    let pub_ur = crate::key::public_key_ur_from_hsid(&hs_id)?;
+    startup.announce(&pub_ur, &onion_host);
+    startup
+        .show_qr(args, &onion_host)
+        .inspect_err(|_| startup.clear())?;
+
+    control
+        .wait_for_upload(&onion_host)
+        .await
+        .inspect_err(|_| startup.clear())?;
+    site.journal.status(&format!("published {onion_host}"));
+    site.onion_host = onion_host;
+
+    site.serve_bar = startup.finish();
+    site.journal.status("serving");
+    let site = Arc::new(site);
+    start_control_socket(args, &site)?;
+
+    let permits = Arc::new(Semaphore::new(site.limits.max_connections));
+    loop {
+        let permit = Arc::clone(&permits).acquire_owned().await?;
+        let (stream, tls) = tokio::select! {
+            accepted = listener.accept() => (accepted?.0, false),
+            accepted = accept_tls(tls_listener.as_ref()) => (accepted?.0, true),
+            closed = control.closed() => {
+                return closed.context("onion service was removed");
+            }
+        };
+        if tls {
+            let tls_site = Arc::clone(&site);
+            spawn_connection(&site, permit, async move {
+                let acceptor = tls_site.tls.as_ref().expect("TLS enabled");
+                Ok(Some(acceptor.accept(stream.compat()).await?))
+            });
+            continue;
+        }
+        spawn_connection(
+            &site,
+            permit,
+            async move { Ok(Some(stream.compat())) },
+        );
+    }
+}
+
+/// Accept a connection on the TLS listener, or wait forever without one.
+async fn accept_tls(
+    listener: Option<&tokio::net::TcpListener>,
+) -> std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Start accepting `garner ctl` commands, if a control socket is
+/// configured.
+fn start_control_socket(args: &ServerArgs, site: &Arc<Site>) -> Result<()> {
+    match &args.control_socket {
+        Some(path) => {
+            ctl::listen(path, Arc::clone(site) as Arc<dyn ctl::Control>)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Handle one connection on its own task.  `accept` yields the stream,
+/// or `None` if the connection was declined; `permit` is held until the
+/// connection is finished.
+fn spawn_connection<S, F>(
+    site: &Arc<Site>,
+    permit: OwnedSemaphorePermit,
+    accept: F,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: Future<Output = Result<Option<S>>> + Send + 'static,
+{
+    let site = Arc::clone(site);
+    tokio::spawn(async move {
+        let _permit = permit;
+        let result = match accept.await {
+            Ok(Some(stream)) => handle_connection(stream, &site).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            site.report_error(e);
+        }
+    });
+}
+
+/// Answer a request whose head could not be read with its [`BadHead`]
+/// status, or drop the connection if it failed otherwise.
+pub async fn refuse_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    error: anyhow::Error,
+) -> Result<()> {
+    let Some(bad) = error.downcast_ref::<BadHead>() else {
+        return Err(error);
+    };
+    let body = format!("{bad}\n");
+    write_http_response(stream, bad.status, "text/plain", &[], body.as_bytes())
+        .await
+}
+
+/// Whether a launch failure was caused by the keystore already holding
+/// the service's identity key.
+fn is_key_already_exists(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |e| e.source()).any(|cause| {
+        matches!(
+            cause.downcast_ref::<tor_keymgr::Error>(),
+            Some(tor_keymgr::Error::KeyAlreadyExists)
+        )
+    })
+}
+
+async fn handle_connection<S>(mut stream: S, site: &Site) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let limits = site.limits;
+    let mut request =
+        match read_http_request(&mut stream, limits.request_buffer).await {
+            Ok(request) => request,
+            Err(e) => return refuse_head(&mut stream, e).await,
+        };
+    let head = request.method == "HEAD";
+    let mut stream = Timed::new(HeadOnly::new(stream, head));
+    if !request.supported_version() {
+        return reject(stream, site, &request, 505).await;
+    }
+    if let Some(status) = request.framing_error() {
+        return reject(stream, site, &request, status).await;
+    }
+    if request.version != "HTTP/1.0"
+        && request.header("expect").is_some()
+        && !request.expects_continue()
+    {
+        return reject(stream, site, &request, 417).await;
+    }
+    if !site.host_allowed(&request) {
+        return reject(stream, site, &request, 421).await;
+    }
+    let maintenance =
+        site.maintenance.lock().expect("maintenance lock").clone();
+    if let Some(page) = maintenance {
+        let headers =
+            [("Retry-After", site.maintenance_retry_after.to_string())];
+        write_http_response(
+            &mut stream,
+            503,
+            &page.content_type,
+            &headers,
+            &page.body,
+        )
+        .await?;
+        site.log_request(&request, 503, page.body.len() as u64, &stream);
+        return Ok(());
+    }
+    if let Some(endpoint) = &site.gstp
+        && request.method == "POST"
+        && request.path == gstp::PATH
+    {
+        return handle_gstp(stream, site, endpoint, request).await;
+    }
+    if let Some(depot) = &site.kv
+        && let Some(key) = request.path.strip_prefix(kv::PATH_PREFIX)
+        && !key.is_empty()
+    {
+        let key = key.to_string();
+        return handle_kv(stream, site, depot, &key, request).await;
+    }
+    if let Some(feed) = &site.feed
+        && request.path.split('?').next() == Some(feed::PATH)
+    {
+        return handle_feed(stream, site, feed, request).await;
+    }
+    // Nothing below reads a request body, but the client must finish
+    // sending any it has before the response closes the stream.
+    if !drain_http_body(&mut stream, &mut request, MAX_UNREAD_BODY).await? {
+        return reject(stream, site, &request, 413).await;
+    }
+    // HEAD is answered as GET is, with the body dropped on the way out.
+    let (status, body_len) = match request.method.as_str() {
+        "GET" | "HEAD" => serve_get(&mut stream, site, &request).await?,
+        _ => {
+            let body = b"Method Not Allowed";
+            send_signed(&mut stream, site, 405, "text/plain", vec![], body)
+                .await?
+        }
+    };
+    let body_len = if head { 0 } else { body_len };
+
+    site.log_request(&request, status, body_len, &stream);
+
+    Ok(())
+}
+
+/// Answer a GET or HEAD request from the endpoint its path names, and
+/// return the status and body length sent.
+async fn serve_get(
+    stream: &mut (impl AsyncWrite + Unpin),
+    site: &Site,
+    request: &Request,
+) -> Result<(u16, u64)> {
+    let path = request_path(request).0;
+    if let Some(dictionary) = &site.dictionary
+        && path == dictionary::PATH
+    {
+        let headers =
+            vec![(dictionary::DIGEST_HEADER, dictionary.digest().to_string())];
+        let body = dictionary.data();
+        return send_signed(
+            stream,
+            site,
+            200,
+            "application/octet-stream",
+            headers,
+            body,
+        )
+        .await;
+    }
+    if let Some(manifest) = &site.manifest
+        && path == manifest::PATH
+    {
+        let body = manifest.json()?;
+        return send_signed(
+            stream,
+            site,
+            200,
+            "application/json",
+            vec![],
+            &body,
+        )
+        .await;
+    }
+    if let Some(versions) = &site.versions
+        && path == delta::MANIFEST_PATH
+    {
+        let body = delta::manifest_json(&versions.manifest()?);
+        return send_signed(
+            stream,
+            site,
+            200,
+            "application/json",
+            vec![],
+            &body,
+        )
+        .await;
+    }
+    if let Some(versions) = &site.versions
+        && let Some(body) = versions.delta(path)?
+    {
+        // A delta between two versions never changes.
+        let headers =
+            vec![("Cache-Control", hash_index::CACHE_CONTROL.to_string())];
+        return send_signed(
+            stream,
+            site,
+            200,
+            delta::MEDIA_TYPE,
+            headers,
+            &body,
+        )
+        .await;
+    }
+    if path == ROBOTS_PATH
+        && let Some(body) = site.robots.robots_txt()
+        && locate_file(site, path).is_none()
+    {
+        return send_signed(
+            stream,
+            site,
+            200,
+            "text/plain",
+            vec![],
+            body.as_bytes(),
+        )
+        .await;
+    }
+
+    // A single byte range of a file sent as it is, as `garner get
+    // --continue` asks for, is honored.  Every ETag here is weak, which
+    // never matches an If-Range, so a request with one gets the whole file.
+    let range = request
+        .header("range")
+        .filter(|_| request.header("if-range").is_none());
+
+    if let Some(releases) = &site.releases {
+        // Listing and hashing release files block.
+        let releases = Arc::clone(releases);
+        let release_path = path.to_string();
+        let response =
+            tokio::task::spawn_blocking(move || releases.lookup(&release_path))
+                .await??;
+        if let Some(response) = response {
+            return serve_release(stream, site, response, range).await;
+        }
+    }
+
+    // With envelopes enabled, `/.envelope/<PATH>` names the file at
+    // `/<PATH>`; either that or an Accept header selects the envelope.
+    let envelope_path = site
+        .envelope_key
+        .as_ref()
+        .and_then(|_| path.strip_prefix(envelope::PATH_PREFIX))
+        .map(|rest| format!("/{rest}"));
+    let envelope_key = site.envelope_key.as_ref().filter(|_| {
+        envelope_path.is_some()
+            || request
+                .header("accept")
+                .is_some_and(envelope::accepts_envelope)
+    });
+    if let Some((source, by_hash)) =
+        locate_file(site, envelope_path.as_deref().unwrap_or(path))
+    {
+        return serve_file(
+            stream,
+            site,
+            request,
+            source,
+            by_hash,
+            envelope_key,
+            range,
+        )
+        .await;
+    }
+
+    send_signed(stream, site, 404, "text/plain", vec![], b"Not Found").await
+}
+
+/// A request's path and its query, if it has one.
+fn request_path(request: &Request) -> (&str, Option<&str>) {
+    match request.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.path.as_str(), None),
+    }
+}
+
+/// Send `body` with `headers` and the site's signature headers, and
+/// return the status and body length sent.
+async fn send_signed(
+    stream: &mut (impl AsyncWrite + Unpin),
+    site: &Site,
+    status: u16,
+    content_type: &str,
+    mut headers: Vec<(&str, String)>,
+    body: &[u8],
+) -> Result<(u16, u64)> {
+    headers.extend(site.signature_headers(body)?);
+    write_http_response(stream, status, content_type, &headers, body).await?;
+    Ok((status, body.len() as u64))
+}
+
+/// Answer a request for a release file or one of the listings generated
+/// for `--releases`.
+async fn serve_release(
+    stream: &mut (impl AsyncWrite + Unpin),
+    site: &Site,
+    response: releases::Response,
+    range: Option<&str>,
+) -> Result<(u16, u64)> {
+    match response {
+        releases::Response::File(file_path) if site.signing_key.is_none() => {
+            write_file_response(
+                stream,
+                &file_path,
+                site.limits.file_chunk,
+                &[],
+                range,
+            )
+            .await
+        }
+        releases::Response::File(file_path) => {
+            let body = tokio::fs::read(&file_path)
+                .await
+                .with_context(|| format!("reading {file_path:?}"))?;
+            let mime = MimeGuess::from_path(&file_path).first_or_octet_stream();
+            send_signed(stream, site, 200, mime.as_ref(), vec![], &body).await
+        }
+        releases::Response::Generated { content_type, body } => {
+            send_signed(stream, site, 200, content_type, vec![], &body).await
+        }
+    }
+}
+
+/// Answer a request for a file in the docroot or archive: `304` if the
+/// client has the current version, and otherwise the file as an
+/// envelope, as it is, compressed, or run through its pipeline.
+async fn serve_file(
+    stream: &mut (impl AsyncWrite + Unpin),
+    site: &Site,
+    request: &Request,
+    source: Source,
+    by_hash: bool,
+    envelope_key: Option<&SigningPrivateKey>,
+    range: Option<&str>,
+) -> Result<(u16, u64)> {
+    let (path, query) = request_path(request);
+    let limits = site.limits;
+    let mut headers = Vec::new();
+    if by_hash {
+        headers.push(("Cache-Control", hash_index::CACHE_CONTROL.into()));
+    } else if let Some(value) = site.cache_policy.value(path) {
+        headers.push(("Cache-Control", value.into()));
+    }
+    if site.onion_location {
+        headers.push((
+            "Onion-Location",
+            format!("http://{}{path}", site.authority()),
+        ));
+    }
+    let mime = source_mime_type(site, &source);
+    let steps = transform::applicable(
+        &site.pipelines.steps(
+            path,
+            !site.rewrite_hosts.is_empty(),
+            site.signing_key.is_some(),
+        ),
+        &mime,
+    );
+    let mut vary = Vec::new();
+    if site.envelope_key.is_some() {
+        vary.push("Accept");
+    }
+    if site.dictionary.is_some() {
+        vary.extend(["Accept-Encoding", dictionary::DIGEST_HEADER]);
+    }
+    if !vary.is_empty() {
+        headers.push(("Vary", vary.join(", ")));
+    }
+
+    // Compress with the site's dictionary for clients that hold it.
+    let compress_with = site.dictionary.as_ref().filter(|d| {
+        d.accepted_by(
+            request.header("accept-encoding"),
+            request.header(dictionary::DIGEST_HEADER),
+        )
+    });
+
+    // A client that already has the current version gets `304`, after
+    // waiting for it to change if it asked to and may.
+    let if_none_match = request.header("if-none-match");
+    let mut etag = source_etag(&source);
+    if let (Some(current), Some(if_none_match)) = (&etag, if_none_match)
+        && etag_matches(if_none_match, current)
+    {
+        let wait = requested_wait(query, request.header("prefer"))
+            .unwrap_or_default()
+            .min(site.max_wait);
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline
+            && source_etag(&source).as_ref() == Some(current)
+        {
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+        etag = source_etag(&source);
+    }
+    let not_modified = etag
+        .as_deref()
+        .zip(if_none_match)
+        .is_some_and(|(etag, if_none_match)| etag_matches(if_none_match, etag));
+    if let Some(etag) = &etag {
+        headers.push(("ETag", etag.clone()));
+    }
+
+    if not_modified {
+        write_http_response(stream, 304, mime.as_ref(), &headers, &[]).await?;
+        return Ok((304, 0));
+    }
+    if let Some(key) = envelope_key {
+        let file = read_source(site, &source).await?;
+        let body = envelope::file_envelope(&file, mime.as_ref(), key);
+        return send_signed(
+            stream,
+            site,
+            200,
+            envelope::MEDIA_TYPE,
+            headers,
+            &body,
+        )
+        .await;
+    }
+    if let (Source::File(file_path), true) = (&source, steps.is_empty()) {
+        match compress_with {
+            None => {
+                return write_file_response(
+                    stream,
+                    file_path,
+                    limits.file_chunk,
+                    &headers,
+                    range,
+                )
+                .await;
+            }
+            Some(dictionary) if request.version != "HTTP/1.0" => {
+                headers.push(("Content-Encoding", dictionary::CODING.into()));
+                let len = write_compressed_file_response(
+                    stream,
+                    file_path,
+                    limits.file_chunk,
+                    dictionary,
+                    &headers,
+                )
+                .await?;
+                return Ok((200, len));
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Transforming, and compressing for HTTP/1.0 clients, need the whole
+    // body in memory, and archive entries are read whole.  Transformed
+    // bodies are kept for reuse while the file is unchanged, except in
+    // low-memory mode.
+    let etag = etag
+        .as_deref()
+        .filter(|_| !steps.is_empty() && limits.cache_transforms);
+    let output = match site.pipelines.cached(path, etag) {
+        Some(output) => output,
+        None => {
+            let body = read_source(site, &source).await?;
+            let authority = site.authority();
+            let context = transform::Context {
+                rewrite_hosts: &site.rewrite_hosts,
+                onion_host: &authority,
+                signing_key: site.signing_key.as_ref(),
+            };
+            site.pipelines
+                .apply(path, etag, &steps, body, &mime, &context)?
+        }
+    };
+    // The signature covers the uncompressed body, which is what the client
+    // ends up with.
+    if let Some(signature) = output.signature {
+        headers.push(("X-Garner-Signature", signature));
+    }
+    let mut body = output.body;
+    if let Some(dictionary) = compress_with {
+        body = dictionary.compress(&body)?;
+        headers.push(("Content-Encoding", dictionary::CODING.into()));
+    }
+    write_http_response(stream, 200, output.mime.as_ref(), &headers, &body)
+        .await?;
+    Ok((200, body.len() as u64))
+}
+
+/// A stream that passes on the head of a response and, when `omit_body`
+/// is set, drops the body after it, so HEAD requests can be answered by
+/// the code that answers GET.
+struct HeadOnly<S> {
+    inner: S,
+    omit_body: bool,
+    /// How much of the blank line ending the head has been written.
+    matched: usize,
+}
+
+impl<S> HeadOnly<S> {
+    const END: &[u8] = b"\r\n\r\n";
+
+    fn new(inner: S, omit_body: bool) -> Self {
+        Self {
+            inner,
+            omit_body,
+            matched: 0,
+        }
+    }
+
+    /// How far the head's end has been matched after `bytes`, and where
+    /// in them it ends, if it does.
+    fn scan(mut matched: usize, bytes: &[u8]) -> (usize, Option<usize>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            matched = match byte {
+                _ if byte == Self::END[matched] => matched + 1,
+                b'\r' => 1,
+                _ => 0,
+            };
+            if matched == Self::END.len() {
+                return (matched, Some(i + 1));
+            }
+        }
+        (matched, None)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HeadOnly<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HeadOnly<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if !self.omit_body {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        if self.matched == Self::END.len() {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let (_, end) = Self::scan(self.matched, buf);
+        let head = &buf[..end.unwrap_or(buf.len())];
+        let result = Pin::new(&mut self.inner).poll_write(cx, head);
+        if let Poll::Ready(Ok(n)) = result {
+            self.matched = Self::scan(self.matched, &buf[..n]).0;
+            if self.matched == Self::END.len() {
+                return Poll::Ready(Ok(buf.len()));
+            }
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// A stream that notes when the response starts, for the access log.
+struct Timed<S> {
+    inner: S,
+    started: Instant,
+    first_byte: Option<Instant>,
+}
+
+impl<S> Timed<S> {
+    /// Start timing a response to a request just received.
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            started: Instant::now(),
+            first_byte: None,
+        }
+    }
+
+    /// Time to the first byte written, and time so far.
+    fn timing(&self) -> (Duration, Duration) {
+        let total = self.started.elapsed();
+        let first_byte = self.first_byte.map_or(total, |at| at - self.started);
+        (first_byte, total)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result
+            && n > 0
+        {
+            self.first_byte.get_or_insert_with(Instant::now);
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Answer a request the server will not handle with a bare `status`.
+async fn reject<S>(
+    mut stream: Timed<S>,
+    site: &Site,
+    request: &Request,
+    status: u16,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let body: &[u8] = match status {
+        411 => b"Length Required",
+        413 => b"Payload Too Large",
+        417 => b"Expectation Failed",
+        421 => b"Misdirected Request",
+        505 => b"HTTP Version Not Supported",
+        _ => b"Bad Request",
+    };
+    write_http_response(&mut stream, status, "text/plain", &[], body).await?;
+    site.log_request(request, status, body.len() as u64, &stream);
+    Ok(())
+}
+
+/// Answer a sealed GSTP request posted to [`gstp::PATH`].
+async fn handle_gstp<S>(
+    mut stream: Timed<S>,
+    site: &Site,
+    endpoint: &gstp::Endpoint,
+    mut request: Request,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let headers = Vec::new();
+    let fits =
+        read_http_body(&mut stream, &mut request, gstp::MAX_REQUEST_SIZE)
+            .await?;
+    let (status, body_len) = if !fits {
+        let body = b"Payload Too Large";
+        drain_http_body(&mut stream, &mut request, MAX_UNREAD_BODY).await?;
+        write_http_response(&mut stream, 413, "text/plain", &headers, body)
+            .await?;
+        (413u16, body.len() as u64)
+    } else if let Ok(sealed) = endpoint.handle(&request.body) {
+        write_http_response(
+            &mut stream,
+            200,
+            envelope::MEDIA_TYPE,
+            &headers,
+            &sealed,
+        )
+        .await?;
+        (200, sealed.len() as u64)
+    } else {
+        let body = b"Bad Request";
+        write_http_response(&mut stream, 400, "text/plain", &headers, body)
+            .await?;
+        (400, body.len() as u64)
+    };
+    site.log_request(&request, status, body_len, &stream);
+    Ok(())
+}
+
+/// Answer a key-value depot request for `/kv/<key>`: GET, PUT, or
+/// DELETE, each with the depot's bearer token.
+async fn handle_kv<S>(
+    mut stream: Timed<S>,
+    site: &Site,
+    depot: &kv::Depot,
+    key: &str,
+    mut request: Request,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut headers = Vec::new();
+    let authorized = depot.authorized(request.header("authorization"));
+    let (status, body): (u16, Vec<u8>) = if !authorized {
+        headers.push(("WWW-Authenticate", "Bearer".to_string()));
+        (401, b"Unauthorized".to_vec())
+    } else {
+        match request.method.as_str() {
+            "GET" => match depot.get(key)? {
+                Some(value) => (200, value),
+                None => (404, b"Not Found".to_vec()),
+            },
+            "PUT" => {
+                let fits =
+                    read_http_body(&mut stream, &mut request, depot.max_size())
+                        .await?;
+                if !fits {
+                    (413, b"Payload Too Large".to_vec())
+                } else if depot.put(key, &request.body)? == kv::Put::Full {
+                    (507, b"Insufficient Storage".to_vec())
+                } else {
+                    (200, Vec::new())
+                }
+            }
+            "DELETE" => match depot.delete(key)? {
+                true => (200, Vec::new()),
+                false => (404, b"Not Found".to_vec()),
+            },
+            _ => (405, b"Method Not Allowed".to_vec()),
+        }
+    };
+    let content_type = if status == 200 {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    };
+    drain_http_body(&mut stream, &mut request, MAX_UNREAD_BODY).await?;
+    write_http_response(&mut stream, status, content_type, &headers, &body)
+        .await?;
+    site.log_request(&request, status, body.len() as u64, &stream);
+    Ok(())
+}
+
+/// Answer a request for the feed: GET reads it from an offset, POST with
+/// the feed's bearer token appends a record.
+async fn handle_feed<S>(
+    mut stream: Timed<S>,
+    site: &Site,
+    feed: &feed::Feed,
+    mut request: Request,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut headers = Vec::new();
+    let (status, body): (u16, Vec<u8>) = match request.method.as_str() {
+        "GET" => {
+            let query = request.path.split_once('?').map(|(_, q)| q);
+            match feed::requested_offset(query, request.header("range")) {
+                None => (400, b"Bad Request".to_vec()),
+                Some((offset, ranged)) => {
+                    let (data, len) = feed.read_from(offset)?;
+                    let end = offset + data.len() as u64;
+                    if offset > len || (ranged && offset == len) {
+                        headers
+                            .push(("Content-Range", format!("bytes */{len}")));
+                        (416, b"Range Not Satisfiable".to_vec())
+                    } else {
+                        headers.push(("X-Feed-Offset", end.to_string()));
+                        if ranged {
+                            headers.push((
+                                "Content-Range",
+                                format!("bytes {offset}-{}/{len}", end - 1),
+                            ));
+                            (206, data)
+                        } else {
+                            (200, data)
+                        }
+                    }
+                }
+            }
+        }
+        "POST" if feed.authorized(request.header("authorization")) => {
+            let fits = read_http_body(
+                &mut stream,
+                &mut request,
+                feed::MAX_RECORD_SIZE,
+            )
+            .await?;
+            if fits {
+                let len = feed.append(&request.body)?;
+                headers.push(("X-Feed-Offset", len.to_string()));
+                (200, Vec::new())
+            } else {
+                (413, b"Payload Too Large".to_vec())
+            }
+        }
+        "POST" => {
+            headers.push(("WWW-Authenticate", "Bearer".to_string()));
+            (401, b"Unauthorized".to_vec())
+        }
+        _ => (405, b"Method Not Allowed".to_vec()),
+    };
+    drain_http_body(&mut stream, &mut request, MAX_UNREAD_BODY).await?;
+    write_http_response(&mut stream, status, "text/plain", &headers, &body)
+        .await?;
+    site.log_request(&request, status, body.len() as u64, &stream);
+    Ok(())
+}
+
+/// Where a response body comes from.
+enum Source {
+    /// A file under the docroot directory.
+    File(PathBuf),
+    /// An entry in the docroot archive.
+    Entry(String),
+}
+
+impl Source {
+    /// The file name or entry name, for guessing the MIME type.
+    fn name(&self) -> &Path {
+        match self {
+            Source::File(path) => path,
+            Source::Entry(name) => Path::new(name),
+        }
+    }
+}
+
+/// The MIME type for a response body: as recorded in a content database,
+/// or else guessed from the file name.
+fn source_mime_type(site: &Site, source: &Source) -> String {
+    if let (Source::Entry(name), Some(archive)) = (source, &site.archive)
+        && let Some(mime) = archive.mime_type(name)
+    {
+        return mime;
+    }
+    MimeGuess::from_path(source.name())
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// Read a whole response body.
+async fn read_source(site: &Site, source: &Source) -> Result<Vec<u8>> {
+    match source {
+        Source::File(path) => tokio::fs::read(path)
+            .await
+            .with_context(|| format!("reading {path:?}")),
+        Source::Entry(name) => {
+            let archive = Arc::clone(site.archive.as_ref().expect("archive"));
+            let name = name.clone();
+            tokio::task::spawn_blocking(move || archive.read(&name)).await?
+        }
+    }
+}
+
+/// Find the body for a request path, by content digest or in the
+/// docroot.  The flag is set for content-addressed files.
+fn locate_file(site: &Site, request_path: &str) -> Option<(Source, bool)> {
+    let source = |path: &Path| match &site.archive {
+        Some(_) => Source::Entry(path.to_string_lossy().into_owned()),
+        None => Source::File(path.to_path_buf()),
+    };
+    if let Some(index) = &site.hash_index
+        && let Some(path) = index.resolve(request_path)
+    {
+        return Some((source(path), true));
+    }
+    match &site.archive {
+        Some(archive) => archive
+            .resolve(request_path)
+            .map(|n| (Source::Entry(n), false)),
+        None => resolve_file(request_path, &site.docroot)
+            .map(|p| (Source::File(p), false)),
+    }
+}
+
+/// How often a waiting request checks whether its file has changed.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A weak entity tag for a file's current version, from its size and
+/// modification time.  Archive entries have none.
+fn source_etag(source: &Source) -> Option<String> {
+    let Source::File(path) = source else {
+        return None;
+    };
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(format!("W/\"{:x}-{:x}\"", mtime.as_nanos(), meta.len()))
+}
+
+/// The first and last byte a `Range` header asks for in a body of `len`
+/// bytes: `Some(None)` for the whole body, when the header is malformed
+/// or asks for several ranges, and `None` when the range lies past the
+/// end.
+fn byte_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let Some((first, last)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Some(None);
+    };
+    let (first, last) = match (first.trim(), last.trim()) {
+        // A suffix: the last `n` bytes.
+        ("", n) => match n.parse::<u64>() {
+            Ok(0) => return None,
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return Some(None),
+        },
+        (first, "") => match first.parse::<u64>() {
+            Ok(first) => (first, len.saturating_sub(1)),
+            Err(_) => return Some(None),
+        },
+        (first, last) => match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => {
+                (first, last.min(len.saturating_sub(1)))
+            }
+            _ => return Some(None),
+        },
+    };
+    (first < len).then_some(Some((first, last)))
+}
+
+/// Whether an `If-None-Match` value names `etag`, comparing weakly.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// How long a request asks to wait for a change: a `wait=<SECONDS>` query
+/// parameter (optionally with an `s` suffix), or a `Prefer: wait=<SECONDS>`
+/// header.
+fn requested_wait(
+    query: Option<&str>,
+    prefer: Option<&str>,
+) -> Option<Duration> {
+    let from_query = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| param.strip_prefix("wait="))
+        .map(|wait| wait.strip_suffix('s').unwrap_or(wait));
+    let from_prefer = || {
+        prefer?
+            .split(',')
+            .find_map(|preference| preference.trim().strip_prefix("wait="))
+    };
+    let seconds = from_query.or_else(from_prefer)?;
+    Some(Duration::from_secs(seconds.trim().parse().ok()?))
+}
+
+/// Map a request path to a file under `docroot`.  For `/`, try
+/// `index.html` first then fall back to `index.txt`.
+fn resolve_file(request_path: &str, docroot: &Path) -> Option<PathBuf> {
+    if request_path == "/" {
+        return ["index.html", "index.txt"]
+            .iter()
+            .map(|name| docroot.join(name))
+            .find(|p| p.is_file());
+    }
+
+    let relative = request_path.strip_prefix('/')?;
+    let candidate = docroot.join(relative);
+
+    // Prevent path traversal (e.g. "/../secret")
+    if !candidate.starts_with(docroot) {
+        return None;
+    }
+
+    if candidate.is_file() {
+        Some(candidate)
+    } else {
+        None
+    }
+}
+
+/// The parts of a request head the server uses.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The protocol version from the request line, such as `HTTP/1.0`.
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// The part of the body received so far.
+    pub body: Vec<u8>,
+    /// Number of bytes received from the client.
+    pub len: usize,
+}
+
+/// The host name in a `Host` header value, lowercased, without the port
+/// or a trailing dot.
+fn host_name(value: &str) -> String {
+    let value = value.trim();
+    let host = match value.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            if port.bytes().all(|b| b.is_ascii_digit()) {
+                host
+            } else {
+                value
+            }
+        }
+        _ => value,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Request {
+    /// The value of the first header called `name` (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the request is in a version of HTTP the server speaks:
+    /// HTTP/1.0 or HTTP/1.1, or a later HTTP/1.x, answered as HTTP/1.1.
+    /// Neither needs a `Host` header, since an onion service has only one
+    /// host.
+    pub fn supported_version(&self) -> bool {
+        self.version.strip_prefix("HTTP/1.").is_some_and(|minor| {
+            !minor.is_empty() && minor.bytes().all(|b| b.is_ascii_digit())
+        })
+    }
+
+    /// Whether the client waits for `100 Continue` before sending its
+    /// body.  HTTP/1.0 clients never do.
+    pub fn expects_continue(&self) -> bool {
+        self.version != "HTTP/1.0"
+            && self
+                .header("expect")
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// The body length given by `Content-Length`, or 0 without one.
+    /// `None` if the body cannot be delimited: it has a
+    /// `Transfer-Encoding`, or its `Content-Length` headers are malformed
+    /// or disagree.
+    pub fn body_length(&self) -> Option<usize> {
+        if self.header("transfer-encoding").is_some() {
+            return None;
+        }
+        let mut length = None;
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("content-length") {
+                continue;
+            }
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let value: usize = value.parse().ok()?;
+            if length.is_some_and(|length| length != value) {
+                return None;
+            }
+            length = Some(value);
+        }
+        Some(length.unwrap_or(0))
+    }
+
+    /// The status to reject the request with if its body cannot be
+    /// delimited.  Chunked bodies are not supported, so a request with a
+    /// `Transfer-Encoding` is asked for a `Content-Length` instead; in
+    /// HTTP/1.0, which has no transfer codings, it is malformed.
+    pub fn framing_error(&self) -> Option<u16> {
+        let chunked = self.header("transfer-encoding").is_some();
+        match self.body_length() {
+            Some(_) => None,
+            None if chunked && self.version != "HTTP/1.0" => Some(411),
+            None => Some(400),
+        }
+    }
+}
+
+/// A request head that could not be read, and the status to refuse it
+/// with.
+#[derive(Debug)]
+pub struct BadHead {
+    pub status: u16,
+}
+
+impl std::fmt::Display for BadHead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            431 => write!(f, "request head too large"),
+            _ => write!(f, "request head not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for BadHead {}
+
+/// Read the request head from the client, reading until its blank line:
+/// Tor delivers data in cells of at most 498 bytes, so a head can take
+/// many reads.  A head that does not fit in `buffer_size` bytes fails
+/// with [`BadHead`] 431, and one that is not UTF-8 with [`BadHead`] 400.
+pub async fn read_http_request(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer_size: usize,
+) -> Result<Request> {
+    use futures_util::io::AsyncReadExt;
+
+    let mut buf = vec![0u8; buffer_size];
+    let mut n = 0;
+    let head_len = loop {
+        if let Some(end) = head_end(&buf[..n]) {
+            break end;
+        }
+        if n == buf.len() {
+            return Err(BadHead { status: 431 }.into());
+        }
+        match stream.read(&mut buf[n..]).await? {
+            // A client that stops sending has sent its whole head.
+            0 => break n,
+            read => n += read,
+        }
+    };
+    let s = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| BadHead { status: 400 })?;
+
+    let mut lines = s.lines();
+    let first_line = lines.next().ok_or_else(|| anyhow!("empty request"))?;
+    let mut parts = first_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+    // A request line without a version is an HTTP/0.9 simple request.
+    let version = parts.next().unwrap_or("HTTP/0.9").to_string();
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| {
+            (name.trim().to_string(), value.trim().to_string())
+        })
+        .collect();
+    Ok(Request {
+        method,
+        path,
+        version,
+        headers,
+        body: buf[head_len..n].to_vec(),
+        len: n,
+    })
+}
+
+/// Where the request head at the start of `data` ends: after its blank
+/// line, or after the request line of an HTTP/0.9 simple request, which
+/// has no headers.
+fn head_end(data: &[u8]) -> Option<usize> {
+    let line_end = data.iter().position(|&b| b == b'\n')?;
+    let words = data[..line_end]
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+        .count();
+    if words < 3 {
+        return Some(line_end + 1);
+    }
+    let crlf = data
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4);
+    let lf = data.windows(2).position(|w| w == b"\n\n").map(|i| i + 2);
+    crlf.into_iter().chain(lf).min()
+}
+
+/// Read the rest of the request body given by its `Content-Length`,
+/// first sending `100 Continue` if the client is waiting for it.  Returns
+/// `false` without reading if the body is larger than `limit`, so a
+/// waiting client can be refused before it sends anything.
+pub async fn read_http_body(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    request: &mut Request,
+    limit: usize,
+) -> Result<bool> {
+    use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+
+    let length = request
+        .body_length()
+        .ok_or_else(|| anyhow!("request body cannot be delimited"))?;
+    if length > limit {
+        return Ok(false);
+    }
+    let received = request.body.len().min(length);
+    if received < length && request.expects_continue() {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        stream.flush().await?;
+    }
+    request.body.resize(length, 0);
+    stream.read_exact(&mut request.body[received..]).await?;
+    request.len += length - received;
+    Ok(true)
+}
+
+/// Read and discard the rest of a body the handler did not read, so the
+/// client has finished sending before the response closes the stream.
+/// Returns `false` without reading if more than `limit` bytes are left.
+/// A client waiting for `100 Continue` has sent nothing, and is answered
+/// at once.
+pub async fn drain_http_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut Request,
+    limit: usize,
+) -> Result<bool> {
+    use futures_util::io::AsyncReadExt;
+
+    if request.expects_continue() {
+        return Ok(true);
+    }
+    let length = request.body_length().unwrap_or(0);
+    let unread = length.saturating_sub(request.body.len());
+    if unread > limit {
+        return Ok(false);
+    }
+    let drained = futures_util::io::copy(
+        (&mut *stream).take(unread as u64),
+        &mut futures_util::io::sink(),
+    )
+    .await?;
+    request.body.truncate(length);
+    request.len += drained as usize;
+    Ok(true)
+}
+
+pub async fn write_http_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    use futures_util::io::AsyncWriteExt;
+
+    let len = Some(body.len() as u64);
+    write_http_head(stream, status, content_type, len, headers).await?;
+    stream.write_all(body).await?;
+    close_stream(stream).await
+}
+
+/// Send a response whose body is streamed from `file_path` in
+/// `chunk_size` pieces, so memory use does not grow with file size: a 200
+/// with the whole file, or a 206 with the part a single byte `range`
+/// names, or a 416 if the range lies past the end.  Returns the status
+/// and the number of body bytes sent.
+async fn write_file_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    file_path: &Path,
+    chunk_size: usize,
+    headers: &[(&str, String)],
+    range: Option<&str>,
+) -> Result<(u16, u64)> {
+    use futures_util::io::AsyncWriteExt;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut file = tokio::fs::File::open(file_path)
+        .await
+        .with_context(|| format!("opening {file_path:?}"))?;
+    let file_len = file.metadata().await?.len();
+    let mut headers = headers.to_vec();
+    headers.push(("Accept-Ranges", "bytes".into()));
+    let (status, first, len) =
+        match range.map_or(Some(None), |range| byte_range(range, file_len)) {
+            None => {
+                headers.push(("Content-Range", format!("bytes */{file_len}")));
+                let body = b"Range Not Satisfiable";
+                write_http_response(stream, 416, "text/plain", &headers, body)
+                    .await?;
+                return Ok((416, body.len() as u64));
+            }
+            Some(None) => (200, 0, file_len),
+            Some(Some((first, last))) => {
+                headers.push((
+                    "Content-Range",
+                    format!("bytes {first}-{last}/{file_len}"),
+                ));
+                (206, first, last - first + 1)
+            }
+        };
+    let mime = MimeGuess::from_path(file_path).first_or_octet_stream();
+    write_http_head(stream, status, mime.as_ref(), Some(len), &headers).await?;
+
+    if first > 0 {
+        file.seek(std::io::SeekFrom::Start(first))
+            .await
+            .with_context(|| format!("reading {file_path:?}"))?;
+    }
+    let mut buf = vec![0u8; chunk_size];
+    let mut sent = 0u64;
+    while sent < len {
+        let want = buf.len().min((len - sent) as usize);
+        let n = file
+            .read(&mut buf[..want])
+            .await
+            .with_context(|| format!("reading {file_path:?}"))?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n]).await?;
+        sent += n as u64;
+    }
+    close_stream(stream).await?;
+    Ok((status, sent))
+}
+
+/// Send a 200 response whose body is `file_path` compressed with
+/// `dictionary` as it is read, in `chunk_size` pieces.  The compressed
+/// length is not known up front, so the body is sent chunked; only
+/// HTTP/1.1 clients can receive it.  Returns the number of compressed
+/// bytes sent.
+async fn write_compressed_file_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    file_path: &Path,
+    chunk_size: usize,
+    dictionary: &Dictionary,
+    headers: &[(&str, String)],
+) -> Result<u64> {
+    use std::io::Write as _;
+
+    use futures_util::io::AsyncWriteExt;
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(file_path)
+        .await
+        .with_context(|| format!("opening {file_path:?}"))?;
+    let mime = MimeGuess::from_path(file_path).first_or_octet_stream();
+    write_http_head(stream, 200, mime.as_ref(), None, headers).await?;
+
+    let mut encoder = dictionary.encoder()?;
+    let mut buf = vec![0u8; chunk_size];
+    let mut sent = 0u64;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .with_context(|| format!("reading {file_path:?}"))?;
+        if n == 0 {
+            encoder.do_finish()?;
+        } else {
+            encoder.write_all(&buf[..n])?;
+        }
+        let compressed = std::mem::take(encoder.get_mut());
+        sent += write_chunk(stream, &compressed).await?;
+        if n == 0 {
+            break;
+        }
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+    close_stream(stream).await?;
+    Ok(sent)
+}
+
+/// Send `data` as one chunk of a chunked body, unless it is empty (which
+/// would end the body).  Returns its length.
+async fn write_chunk(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+) -> Result<u64> {
+    use futures_util::io::AsyncWriteExt;
+
+    if !data.is_empty() {
+        stream
+            .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+            .await?;
+        stream.write_all(data).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    Ok(data.len() as u64)
+}
+
+/// Send the response head.  Without a `content_length`, the body must
+/// follow chunked.
+async fn write_http_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    content_type: &str,
+    content_length: Option<u64>,
+    headers: &[(&str, String)],
+) -> Result<()> {
+    use futures_util::io::AsyncWriteExt;
+
+    let reason = match status {
+        200 => "OK",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        421 => "Misdirected Request",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "OK",
+    };
+
+    // A 304 response has no body, so no framing.
+    let framing = match content_length {
+        _ if status == 304 => String::new(),
+        Some(len) => format!("Content-Length: {len}\r\n"),
+        None => "Transfer-Encoding: chunked\r\n".to_string(),
+    };
+    let mut header = format!(
+        "HTTP/1.1 {status} {reason}\r\n\
+         {framing}\
+         Content-Type: {content_type}\r\n\
+         Connection: close\r\n"
+    );
+    for (name, value) in headers {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    header.push_str("\r\n");
+
+    stream.write_all(header.as_bytes()).await?;
+    Ok(())
+}
+
+async fn close_stream(stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    use futures_util::io::AsyncWriteExt;
+
+    stream.flush().await?;
+    // Explicitly close the write half so the remote side sees a clean
+    // stream shutdown rather than an abrupt drop.
+    stream.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio_util::compat::TokioAsyncReadCompatExt as _;
+
+    use super::*;
+
+    fn site(docroot: &Path) -> Site {
+        Site {
+            interactive: true,
+            onion_host: "abc.onion".to_string(),
+            ..Site::new(docroot.to_path_buf(), None, Limits::new(false, None))
+        }
+    }
+
+    /// Send `request` to `handle_connection` and return the raw response.
+    async fn exchange(site: &Site, request: &str) -> String {
+        // Envelope bodies are binary; the tests only inspect text parts.
+        String::from_utf8_lossy(&exchange_bytes(site, request).await)
+            .into_owned()
+    }
+
+    async fn exchange_bytes(site: &Site, request: &str) -> Vec<u8> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = client.compat();
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle_connection(server.compat(), site).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.txt"), "hello").unwrap();
+        let response =
+            exchange(&site(dir.path()), "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    /// A reader that hands out a few bytes at a time, as Tor cells do.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl futures_util::io::AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let n = self.0.len().min(buf.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            std::task::Poll::Ready(Ok(n))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_head_in_pieces() {
+        let head = "PUT /upload HTTP/1.1\r\nHost: caf\u{e9}.onion\r\n\
+                    Authorization: Bearer token\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_http_request(&mut Trickle(head.as_bytes()), 8192)
+            .await
+            .unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.header("host"), Some("caf\u{e9}.onion"));
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+        assert_eq!(request.body_length(), Some(4));
+        assert!(request.body.len() <= 4);
+
+        let request = read_http_request(&mut Trickle(b"GET /old\r\n"), 8192)
+            .await
+            .unwrap();
+        assert_eq!(
+            (request.path.as_str(), request.version.as_str()),
+            ("/old", "HTTP/0.9")
+        );
+
+        let long =
+            format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(100));
+        let status = async |head: &[u8]| {
+            let Err(e) = read_http_request(&mut Trickle(head), 64).await else {
+                panic!("read a bad head");
+            };
+            e.downcast_ref::<BadHead>().unwrap().status
+        };
+        assert_eq!(status(long.as_bytes()).await, 431);
+        assert_eq!(status(b"GET /\xff HTTP/1.1\r\n\r\n").await, 400);
+
+        let dir = tempfile::tempdir().unwrap();
+        let huge = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        );
+        let response = exchange(&site(dir.path()), &huge).await;
+        assert!(
+            response
+                .starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_found_and_method() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = site(dir.path());
+        let response = exchange(&site, "GET /missing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = exchange(&site, "POST / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_request_bodies() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let site = site(dir.path());
+        let response = exchange(
+            &site,
+            "GET /a.txt HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello"));
+        let response = exchange(
+            &site,
+            "POST /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             4\r\nbody\r\n0\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 411 Length Required\r\n"));
+        let response = exchange(
+            &site,
+            "POST /a.txt HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = exchange(
+            &site,
+            "POST /a.txt HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.txt"), "hello").unwrap();
+        let site = site(dir.path());
+        for request in ["GET / HTTP/1.0\r\n\r\n", "GET / HTTP/1.2\r\n\r\n"] {
+            let response = exchange(&site, request).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(response.contains("Connection: close\r\n"));
+            assert!(!response.contains("Transfer-Encoding"));
+            assert!(response.ends_with("\r\n\r\nhello"));
+        }
+        for request in ["GET / HTTP/2.0\r\n\r\n", "GET /\r\n\r\n"] {
+            let response = exchange(&site, request).await;
+            assert!(response.starts_with("HTTP/1.1 505 "), "{response}");
+        }
+        let response = exchange(
+            &site,
+            "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_body_length() {
+        let request = |headers: &[(&str, &str)]| Request {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: Vec::new(),
+            len: 0,
+        };
+        assert_eq!(request(&[]).body_length(), Some(0));
+        assert_eq!(
+            request(&[("content-length", "12")]).body_length(),
+            Some(12)
+        );
+        assert_eq!(
+            request(&[("Content-Length", "3"), ("Content-Length", "3")])
+                .body_length(),
+            Some(3)
+        );
+        assert_eq!(
+            request(&[("Content-Length", "3"), ("Content-Length", "4")])
+                .body_length(),
+            None
+        );
+        assert_eq!(request(&[("Content-Length", "+3")]).body_length(), None);
+        let chunked = request(&[("Transfer-Encoding", "chunked")]);
+        assert_eq!(chunked.body_length(), None);
+        assert_eq!(chunked.framing_error(), Some(411));
+    }
+
+    #[test]
+    fn test_is_key_already_exists() {
+        let exists =
+            arti_client::Error::from(tor_keymgr::Error::KeyAlreadyExists);
+        assert!(is_key_already_exists(&exists));
+        let missing =
+            tor_keymgr::Error::KeystoreNotFound("arti".parse().unwrap());
+        assert!(!is_key_already_exists(&arti_client::Error::from(missing)));
+    }
+
+    #[tokio::test]
+    async fn test_onion_location_and_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("index.html"),
+            r#"<a href="https://example.com/about">About</a>"#,
+        )
+        .unwrap();
+        let mut site = site(dir.path());
+        site.onion_location = true;
+        site.rewrite_hosts = vec!["example.com".to_string()];
+        let response =
+            exchange(&site, "GET /index.html HTTP/1.1\r\n\r\n").await;
+        assert!(
+            response
+                .contains("Onion-Location: http://abc.onion/index.html\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.ends_with(r#"<a href="http://abc.onion/about">About</a>"#)
+        );
+
+        // A fresh site, since the first one cached the rewritten page.
+        let mut site = Site {
+            port: 8080,
+            ..self::site(dir.path())
+        };
+        site.onion_location = true;
+        site.rewrite_hosts = vec!["example.com".to_string()];
+        let response =
+            exchange(&site, "GET /index.html HTTP/1.1\r\n\r\n").await;
+        assert!(
+            response.contains(
+                "Onion-Location: http://abc.onion:8080/index.html\r\n"
+            ),
+            "{response}"
+        );
+        assert!(
+            response.ends_with(
+                r#"<a href="http://abc.onion:8080/about">About</a>"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transform_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("notes")).unwrap();
+        for path in ["a.md", "notes/a.md"] {
+            std::fs::write(dir.path().join(path), "# Hi\n\n  *there*\n")
+                .unwrap();
+        }
+        let mut site = site(dir.path());
+        site.pipelines = transform::Pipelines::new(
+            vec![transform::parse_mount("/notes=markdown,minify").unwrap()],
+            None,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            let response =
+                exchange(&site, "GET /notes/a.md HTTP/1.1\r\n\r\n").await;
+            assert!(response.contains("Content-Type: text/html\r\n"));
+            assert!(
+                response.contains("<h1>Hi</h1>\n<p><em>there</em></p>\n"),
+                "{response}"
+            );
+        }
+        let response = exchange(&site, "GET /a.md HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Type: text/markdown\r\n"));
+        assert!(response.ends_with("\r\n\r\n# Hi\n\n  *there*\n"));
+    }
+
+    #[tokio::test]
+    async fn test_low_memory_transforms() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.md"), "# Hi\n").unwrap();
+        let mut site = Site {
+            limits: Limits::new(true, None),
+            ..site(dir.path())
+        };
+        site.pipelines = transform::Pipelines::new(
+            vec![transform::parse_mount("/=markdown").unwrap()],
+            None,
+        )
+        .unwrap();
+        let etag =
+            || source_etag(&Source::File(dir.path().join("a.md"))).unwrap();
+        for _ in 0..2 {
+            let response = exchange(&site, "GET /a.md HTTP/1.1\r\n\r\n").await;
+            assert!(response.contains("<h1>Hi</h1>\n"), "{response}");
+            assert!(site.pipelines.cached("/a.md", Some(&etag())).is_none());
+        }
+
+        site.limits = Limits::new(false, None);
+        exchange(&site, "GET /a.md HTTP/1.1\r\n\r\n").await;
+        assert!(site.pipelines.cached("/a.md", Some(&etag())).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_releases() {
+        use sha2::{Digest, Sha256};
+
+        bc_components::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("tool-1.0.tar.gz"), "tool").unwrap();
+        let (private, _) = crate::key::generate_keypair().unwrap();
+        let key = crate::key::parse_signing_key(&private).unwrap();
+        let mut site = site(dir.path());
+        site.releases =
+            Some(Arc::new(releases::Releases::open(dir.path(), key).unwrap()));
+        let response = exchange(
+            &site,
+            "GET /releases/tool-1.0.tar.gz.sha256 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        let digest = hex::encode(Sha256::digest(b"tool"));
+        assert!(
+            response.ends_with(&format!("\r\n\r\n{digest}  tool-1.0.tar.gz\n")),
+            "{response}"
+        );
+        let response =
+            exchange(&site, "GET /releases/index.json HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains(&digest), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let site = site(dir.path());
+        let get = exchange(&site, "GET /a.txt HTTP/1.1\r\n\r\n").await;
+        let head = exchange(&site, "HEAD /a.txt HTTP/1.1\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Length: 5\r\n"), "{head}");
+        assert_eq!(get, format!("{head}hello"));
+
+        let head = exchange(&site, "HEAD /missing HTTP/1.1\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{head}");
+        assert!(head.ends_with("\r\n\r\n"), "{head}");
+        let put = exchange(&site, "PUT /a.txt HTTP/1.1\r\n\r\n").await;
+        assert!(put.starts_with("HTTP/1.1 405 "), "{put}");
+    }
+
+    #[test]
+    fn test_head_only_scan() {
+        let scan = HeadOnly::<()>::scan;
+        assert_eq!(scan(0, b"HTTP/1.1 200 OK\r\n\r\nbody"), (4, Some(19)));
+        assert_eq!(scan(0, b"a\r\n\r"), (3, None));
+        assert_eq!(scan(3, b"\nbody"), (4, Some(1)));
+        assert_eq!(scan(2, b"\r\r\n\r\n"), (4, Some(5)));
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=2-", 10), Some(Some((2, 9))));
+        assert_eq!(byte_range("bytes=2-4", 10), Some(Some((2, 4))));
+        assert_eq!(byte_range("bytes=8-20", 10), Some(Some((8, 9))));
+        assert_eq!(byte_range("bytes=-3", 10), Some(Some((7, 9))));
+        assert_eq!(byte_range("bytes=-30", 10), Some(Some((0, 9))));
+        assert_eq!(byte_range("bytes=10-", 10), None);
+        assert_eq!(byte_range("bytes=-0", 10), None);
+        assert_eq!(byte_range("bytes=0-", 0), None);
+        for range in ["bytes=4-2", "bytes=0-1,4-5", "items=0-1", "bytes=x-"] {
+            assert_eq!(byte_range(range, 10), Some(None), "{range}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "0123456789").unwrap();
+        let site = site(dir.path());
+        let request = |range: &str| {
+            format!("GET /a.txt HTTP/1.1\r\nRange: {range}\r\n\r\n")
+        };
+        let response = exchange(&site, &request("bytes=4-")).await;
+        assert!(
+            response.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Content-Range: bytes 4-9/10\r\n"));
+        assert!(response.contains("Content-Length: 6\r\n"));
+        assert!(response.ends_with("\r\n\r\n456789"));
+
+        let response = exchange(&site, &request("bytes=2-3")).await;
+        assert!(response.ends_with("\r\n\r\n23"), "{response}");
+
+        let response = exchange(&site, &request("bytes=10-")).await;
+        assert!(response.starts_with("HTTP/1.1 416 "), "{response}");
+        assert!(response.contains("Content-Range: bytes */10\r\n"));
+
+        let response = exchange(
+            &site,
+            "GET /a.txt HTTP/1.1\r\nRange: bytes=4-\r\nIf-Range: \"x\"\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Accept-Ranges: bytes\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[tokio::test]
+    async fn test_request_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut site = site(dir.path());
+        let seen = Arc::clone(&events);
+        site.on_request = Some(Arc::new(move |event: &RequestEvent| {
+            seen.lock().unwrap().push(event.clone());
+        }));
+        exchange(&site, "GET /a.txt HTTP/1.1\r\n\r\n").await;
+        exchange(&site, "GET /missing?x=1 HTTP/1.1\r\n\r\n").await;
+        let events = events.lock().unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.method.as_str(), e.path.as_str(), e.status, e.body_len))
+            .collect();
+        assert_eq!(
+            summary,
+            [("GET", "/a.txt", 200, 5), ("GET", "/missing?x=1", 404, 9)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_garner_server_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let shutdowns = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&shutdowns);
+        let server = GarnerServer::new(dir.path())
+            .on_ready(|_| panic!("cancelled before it was ready"))
+            .on_shutdown(move || *counted.lock().unwrap() += 1);
+        // Cancelled before it starts, it returns without reaching Tor.
+        server.cancellation_token().cancel();
+        server.run().await.unwrap();
+        assert_eq!(*shutdowns.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_host_check() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let mut site = site(dir.path());
+        site.onion_host = "abc.onion".to_string();
+        let request =
+            |host: &str| format!("GET /a.txt HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        let response = exchange(&site, &request("example.com")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        site.allowed_hosts = Some(vec!["mirror.example".to_string()]);
+        for host in [
+            "abc.onion",
+            "ABC.onion.:80",
+            "www.abc.onion",
+            "mirror.example",
+        ] {
+            let response = exchange(&site, &request(host)).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{host}");
+        }
+        for host in ["example.com", "xabc.onion", "127.0.0.1:80"] {
+            let response = exchange(&site, &request(host)).await;
+            assert!(
+                response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+                "{host}"
+            );
+        }
+        let response = exchange(&site, "GET /a.txt HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 421 "));
+
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+        assert_eq!(host_name("Example.COM."), "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        use crate::ctl::Control as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("down.html"), "<p>Back soon</p>")
+            .unwrap();
+        let mut site = site(dir.path());
+        site.maintenance_page = Some(dir.path().join("down.html"));
+        site.maintenance_retry_after = 60;
+        let request = "GET /a.txt HTTP/1.1\r\n\r\n";
+
+        site.set_maintenance(true).unwrap();
+        let response = exchange(&site, request).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Content-Type: text/html\r\n"));
+        assert!(response.contains("Retry-After: 60\r\n"));
+        assert!(response.ends_with("\r\n\r\n<p>Back soon</p>"));
+
+        site.set_maintenance(false).unwrap();
+        let response = exchange(&site, request).await;
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn test_robots_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut site = site(dir.path());
+        let robots = "GET /robots.txt HTTP/1.1\r\n\r\n";
+        let response = exchange(&site, robots).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        site.robots = RobotsPolicy::DenyAll;
+        let response = exchange(&site, robots).await;
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\nDisallow: /\n"));
+        site.robots = RobotsPolicy::AllowAll;
+        let response = exchange(&site, robots).await;
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\nDisallow:\n"));
+
+        std::fs::write(dir.path().join("robots.txt"), "# ours\n").unwrap();
+        site.robots = RobotsPolicy::DenyAll;
+        let response = exchange(&site, robots).await;
+        assert!(response.ends_with("\r\n\r\n# ours\n"));
+    }
+
+    #[tokio::test]
+    async fn test_signed_responses() {
+        bc_components::register_tags();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.txt"), "hello").unwrap();
+        let (priv_ur, pub_ur) = crate::key::generate_keypair().unwrap();
+        let mut site = site(dir.path());
+        site.onion_host =
+            crate::key::parse_public_key_to_onion_host(&pub_ur).unwrap();
+        site.signing_key =
+            Some(crate::key::parse_signing_key(&priv_ur).unwrap());
+        for (request, body) in [
+            ("GET / HTTP/1.1\r\n\r\n", "hello"),
+            ("GET /missing HTTP/1.1\r\n\r\n", "Not Found"),
+        ] {
+            let response = exchange(&site, request).await;
+            let signature = response
+                .lines()
+                .find_map(|l| l.strip_prefix("X-Garner-Signature: "))
+                .expect("signature header");
+            assert!(response.ends_with(body));
+            crate::key::verify_body(
+                &site.onion_host,
+                body.as_bytes(),
+                signature,
+            )
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_envelope_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let (priv_ur, _) = crate::key::generate_keypair().unwrap();
+        let mut site = site(dir.path());
+        let response =
+            exchange(&site, "GET /.envelope/a.txt HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        site.envelope_key =
+            Some(crate::key::parse_signing_key(&priv_ur).unwrap());
+        for request in [
+            "GET /.envelope/a.txt HTTP/1.1\r\n\r\n",
+            "GET /a.txt HTTP/1.1\r\nAccept: application/envelope\r\n\r\n",
+        ] {
+            let response = exchange(&site, request).await;
+            assert!(
+                response.contains("Content-Type: application/envelope\r\n"),
+                "{response}"
+            );
+            assert!(response.contains("Vary: Accept\r\n"));
+        }
+        let response = exchange(&site, "GET /a.txt HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_from_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("site.tar");
+        let mut builder =
+            tar::Builder::new(std::fs::File::create(&path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "index.txt", &b"hello"[..])
+            .unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let mut site = site(&path);
+        site.archive = Some(Arc::new(Archive::open(&path).unwrap()));
+        let response = exchange(&site, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        let response = exchange(&site, "GET /site.tar HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut site = site(dir.path());
+        site.kv = Some(
+            kv::Depot::open(dir.path().join("kv"), "secret", 4, 8).unwrap(),
+        );
+        let head = "PUT /kv/a HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+                    Expect: 100-continue\r\n";
+
+        // The body is sent only once the server asks for it.
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = client.compat();
+        client
+            .write_all(format!("{head}Content-Length: 3\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let talk = async {
+            let mut interim = [0u8; 25];
+            client.read_exact(&mut interim).await.unwrap();
+            assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+            client.write_all(b"one").await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let (served, response) =
+            tokio::join!(handle_connection(server.compat(), &site), talk);
+        served.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(
+            site.kv.as_ref().unwrap().get("a").unwrap().unwrap(),
+            b"one"
+        );
+
+        // A body over the limit is refused before it is sent.
+        let response =
+            exchange(&site, &format!("{head}Content-Length: 5\r\n\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+        let response =
+            exchange(&site, "GET / HTTP/1.1\r\nExpect: something-else\r\n\r\n")
+                .await;
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "one").unwrap();
+        let mut site = site(dir.path());
+        let response = exchange(&site, "GET /a.txt HTTP/1.1\r\n\r\n").await;
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap()
+            .to_string();
+        let conditional = format!(
+            "GET /a.txt?wait=5s HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n"
+        );
+
+        // Without --max-wait, an unchanged file is answered at once.
+        let response = exchange(&site, &conditional).await;
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(!response.contains("Content-Length"));
+
+        site.max_wait = Duration::from_secs(5);
+        let change = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            std::fs::write(&path, "two!").unwrap();
+        };
+        let (response, ()) =
+            tokio::join!(exchange(&site, &conditional), change);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ntwo!"));
+    }
+
+    #[tokio::test]
+    async fn test_timed_stream() {
+        let mut stream = Timed::new(futures_util::io::Cursor::new(Vec::new()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(b"x").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(b"y").await.unwrap();
+        let (first_byte, total) = stream.timing();
+        assert!(first_byte >= Duration::from_millis(20));
+        assert!(total >= first_byte + Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_requested_wait() {
+        assert_eq!(
+            requested_wait(Some("a=1&wait=30s"), None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            requested_wait(None, Some("respond-async, wait=10")),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(requested_wait(Some("wait=soon"), None), None);
+        assert_eq!(requested_wait(None, None), None);
+        assert!(etag_matches("\"x\", W/\"abc\"", "W/\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abd\"", "W/\"abc\""));
+    }
+
+    #[tokio::test]
+    async fn test_kv_depot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut site = site(dir.path());
+        site.kv = Some(
+            kv::Depot::open(dir.path().join("kv"), "secret", 4, 8).unwrap(),
+        );
+        let auth = "Authorization: Bearer secret\r\n";
+        let response = exchange(
+            &site,
+            "PUT /kv/a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = exchange(
+            &site,
+            &format!("PUT /kv/a HTTP/1.1\r\n{auth}Content-Length: 2\r\n\r\nhi"),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let response =
+            exchange(&site, &format!("GET /kv/a HTTP/1.1\r\n{auth}\r\n")).await;
+        assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+        let response = exchange(
+            &site,
+            &format!(
+                "PUT /kv/b HTTP/1.1\r\n{auth}Content-Length: 5\r\n\r\nhello"
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+        let response =
+            exchange(&site, &format!("DELETE /kv/a HTTP/1.1\r\n{auth}\r\n"))
+                .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response =
+            exchange(&site, &format!("GET /kv/a HTTP/1.1\r\n{auth}\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut site = site(dir.path());
+        site.feed = Some(
+            feed::Feed::open(&dir.path().join("events.log"), Some("secret"))
+                .unwrap(),
+        );
+        let post = "POST /.feed HTTP/1.1\r\nContent-Length: 3\r\n";
+        let response = exchange(&site, &format!("{post}\r\none")).await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        for record in ["one", "two"] {
+            let response = exchange(
+                &site,
+                &format!("{post}Authorization: Bearer secret\r\n\r\n{record}"),
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        }
+        let response =
+            exchange(&site, "GET /.feed?offset=4 HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("X-Feed-Offset: 8\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ntwo\n"));
+        let response =
+            exchange(&site, "GET /.feed HTTP/1.1\r\nRange: bytes=2-\r\n\r\n")
+                .await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 2-7/8\r\n"));
+        let response =
+            exchange(&site, "GET /.feed?offset=9 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 416 "), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_zstd_dictionary() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = "<html><body><p>hello, dictionary</p></body></html>";
+        std::fs::write(dir.path().join("index.html"), page).unwrap();
+        let dict_path = dir.path().join("site.dict");
+        std::fs::write(&dict_path, page.repeat(8)).unwrap();
+        let mut site = site(dir.path());
+        site.dictionary = Some(Dictionary::open(&dict_path).unwrap());
+        let digest = site.dictionary.as_ref().unwrap().digest().to_string();
+
+        let response =
+            exchange(&site, "GET /.zstd-dictionary HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.contains(&format!("X-Garner-Dictionary: {digest}\r\n"))
+        );
+
+        let response = exchange(&site, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(!response.contains("Content-Encoding"));
+        assert!(
+            response.contains("Vary: Accept-Encoding, X-Garner-Dictionary\r\n")
+        );
+        assert!(response.ends_with(page));
+
+        // HTTP/1.1 clients get the file compressed as it is sent, in
+        // chunks; HTTP/1.0 clients get it compressed whole.
+        let dictionary = site.dictionary.as_ref().unwrap();
+        for version in ["1.1", "1.0"] {
+            let response = exchange_bytes(
+                &site,
+                &format!(
+                    "GET / HTTP/{version}\r\nAccept-Encoding: garner-zstd\r\n\
+                     X-Garner-Dictionary: {digest}\r\n\r\n"
+                ),
+            )
+            .await;
+            let end = response.windows(4).position(|w| w == b"\r\n\r\n");
+            let (head, mut body) = response.split_at(end.unwrap() + 4);
+            let head = std::str::from_utf8(head).unwrap();
+            assert!(head.contains("Content-Encoding: garner-zstd\r\n"));
+            let chunked = head.contains("Transfer-Encoding: chunked\r\n");
+            assert_eq!(chunked, version == "1.1", "{head}");
+            let mut compressed = body.to_vec();
+            if chunked {
+                compressed.clear();
+                loop {
+                    let i = body.windows(2).position(|w| w == b"\r\n").unwrap();
+                    let size = std::str::from_utf8(&body[..i]).unwrap();
+                    let size = usize::from_str_radix(size, 16).unwrap();
+                    compressed.extend_from_slice(&body[i + 2..i + 2 + size]);
+                    body = &body[i + 4 + size..];
+                    if size == 0 {
+                        break;
+                    }
+                }
+                assert!(body.is_empty());
+            }
+            let decompressed =
+                dictionary::decompress(dictionary.data(), &compressed).unwrap();
+            assert_eq!(decompressed, page.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_manifest_and_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let docroot = dir.path().join("public");
+        std::fs::create_dir(&docroot).unwrap();
+        std::fs::write(docroot.join("a.txt"), "hello").unwrap();
+        let mut site = site(&docroot);
+        let versions =
+            delta::Versions::open(&docroot, dir.path().join("store")).unwrap();
+        let from = versions.manifest().unwrap()[0].digest.clone();
+        std::fs::write(docroot.join("a.txt"), "hello, world").unwrap();
+        let to = versions.manifest().unwrap()[0].digest.clone();
+        site.versions = Some(versions);
+
+        let response = exchange(&site, "GET /.manifest HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains(&format!("\"sha256\":\"{to}\"")));
+        let response = exchange(
+            &site,
+            &format!("GET /.delta/{from}/{to} HTTP/1.1\r\n\r\n"),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: application/zstd\r\n"));
+        let response = exchange(
+            &site,
+            &format!("GET /.delta/{}/{to} HTTP/1.1\r\n\r\n", "0".repeat(64)),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let mut site = site(dir.path());
+        site.hash_index = Some(HashIndex::build(dir.path()).unwrap());
+        let digest =
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let response = exchange(
+            &site,
+            &format!("GET /.by-hash/{digest} HTTP/1.1\r\n\r\n"),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.contains("immutable\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        let response =
+            exchange(&site, "GET /.by-hash/00 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_gstp_endpoint_rejects_bad_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = bc_components::PrivateKeys::with_keys(
+            SigningPrivateKey::new_ed25519(
+                bc_components::Ed25519PrivateKey::new(),
+            ),
+            bc_components::EncapsulationPrivateKey::X25519(
+                bc_components::X25519PrivateKey::new(),
+            ),
+        );
+        let mut site = site(dir.path());
+        site.gstp =
+            Some(gstp::Endpoint::new(keys, dir.path().join("store")).unwrap());
+        let response = exchange(
+            &site,
+            "POST /.gstp HTTP/1.1\r\nContent-Length: 8\r\n\r\nnot cbor",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = exchange(
+            &site,
+            "POST /.gstp HTTP/1.1\r\nContent-Length: 999999\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        // Other POSTs are still refused.
+        let response = exchange(&site, "POST / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let path = dir.path().join("access.log");
+        let mut site = site(dir.path());
+        site.access_log = Some(AccessLog::open(path.clone()).unwrap());
+        site.kv = Some(
+            kv::Depot::open(dir.path().join("kv"), "secret", 16, 8).unwrap(),
+        );
+        let get = "GET /a.txt HTTP/1.1\r\n\r\n";
+        let put = "PUT /kv/a HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+                   Content-Length: 3\r\n\r\none";
+        exchange(&site, get).await;
+        exchange(&site, put).await;
+        let log = std::fs::read_to_string(&path).unwrap();
+        let fields: Vec<Vec<&str>> = log
+            .lines()
+            .map(|line| line.rsplit(' ').take(4).collect())
+            .collect();
+        // From the end: timings, request bytes, response body bytes.
+        assert_eq!(fields[0][2..], [get.len().to_string().as_str(), "5"]);
+        assert_eq!(fields[1][2], put.len().to_string());
+    }
+
+    #[test]
+    fn test_access_log_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(path.clone()).unwrap();
+        log.write("first").unwrap();
+        log.rotate().unwrap();
+        log.write("second").unwrap();
+        let rotated = dir.path().join("access.log.1");
+        assert_eq!(std::fs::read_to_string(rotated).unwrap(), "first\n");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "second\n");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_framing() {
+        let mut sent = Vec::new();
+        let headers = [("X-A", "b".to_string())];
+        write_http_head(&mut sent, 200, "text/plain", None, &headers)
+            .await
+            .unwrap();
+        let head = String::from_utf8(std::mem::take(&mut sent)).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert!(head.contains("X-A: b\r\n"), "{head}");
+
+        assert_eq!(write_chunk(&mut sent, b"hello world!").await.unwrap(), 12);
+        // An empty chunk would end the body, so none is sent.
+        assert_eq!(write_chunk(&mut sent, b"").await.unwrap(), 0);
+        assert_eq!(sent, b"c\r\nhello world!\r\n");
+
+        sent.clear();
+        write_http_head(&mut sent, 200, "text/plain", Some(3), &[])
+            .await
+            .unwrap();
+        let head = String::from_utf8(sent).unwrap();
+        assert!(head.contains("Content-Length: 3\r\n"), "{head}");
+        assert!(!head.contains("Transfer-Encoding"), "{head}");
+    }
+
+    #[test]
+    fn test_parse_nickname() {
+        assert_eq!(parse_nickname("blog-2").unwrap().as_ref(), "blog-2");
+        for bad in ["", "a b", "../x", "x/y"] {
+            let error = parse_nickname(bad).unwrap_err().to_string();
+            assert!(error.starts_with("invalid nickname"), "{error}");
+        }
+    }
 }
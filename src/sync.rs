@@ -0,0 +1,182 @@
+use std::{
+    io::Write as _,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    delta::{self, File},
+    get, key, tor,
+};
+
+/// Options for `garner sync`.
+#[derive(Args)]
+pub struct SyncArgs {
+    /// Local directory to mirror the site into
+    pub dir: PathBuf,
+    /// Ed25519 public key in UR format to derive the .onion address, or
+    /// "-" to read it from stdin
+    #[arg(long, env = "GARNER_KEY")]
+    pub key: Option<String>,
+    /// Read the public key from FILE, or from stdin for "-", instead of
+    /// --key or GARNER_KEY
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+    /// The .onion address to connect to (e.g. xxxx.onion)
+    #[arg(long, env = "GARNER_ADDRESS")]
+    pub address: Option<String>,
+    /// Connect through a running Tor daemon's SOCKS port (e.g.
+    /// 127.0.0.1:9050) instead of the embedded Arti client
+    #[arg(long, value_name = "ADDR", env = "GARNER_TOR_SOCKS")]
+    pub tor_socks: Option<String>,
+    #[command(flatten)]
+    pub tor: tor::TorArgs,
+}
+
+/// How a file was brought up to date.
+#[derive(Default)]
+struct Tally {
+    unchanged: usize,
+    patched: usize,
+    downloaded: usize,
+    /// Bytes received for changed files.
+    received: u64,
+}
+
+pub async fn run(args: SyncArgs) -> Result<()> {
+    let key = key::read_key_arg(args.key.clone(), args.key_file.as_deref())?;
+    let host = get::onion_host(key.as_deref(), args.address.as_deref())?
+        .ok_or_else(|| anyhow!("sync needs --key or --address"))?;
+    let fetcher =
+        get::Fetcher::connect(args.tor_socks.as_deref(), &args.tor).await?;
+    let url = |path: &str| format!("{host}{path}");
+
+    let manifest = fetcher
+        .fetch(&url(delta::MANIFEST_PATH), &[])
+        .await
+        .context("fetching the manifest (is the server run with --deltas?)")?;
+    let files = delta::parse_manifest(&manifest.body)?;
+    let mut tally = Tally::default();
+    for file in &files {
+        let local = local_path(&args.dir, &file.path)?;
+        let old = match std::fs::read(&local) {
+            Ok(data) => Some(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("reading {}", local.display()));
+            }
+        };
+        let old_digest = old.as_deref().map(digest);
+        if old_digest.as_deref() == Some(file.digest.as_str()) {
+            tally.unchanged += 1;
+            continue;
+        }
+
+        // Try a delta from the version we hold, falling back to the whole
+        // file if the server no longer has that version.
+        let mut new = None;
+        if let (Some(old), Some(old_digest)) = (&old, &old_digest) {
+            let path =
+                format!("{}{old_digest}/{}", delta::PATH_PREFIX, file.digest);
+            if let Ok(response) = fetcher.fetch(&url(&path), &[]).await {
+                tally.received += response.body.len() as u64;
+                match delta::patch(old, &response.body, file.size) {
+                    Ok(data) if digest(&data) == file.digest => {
+                        tally.patched += 1;
+                        new = Some(data);
+                    }
+                    _ => eprintln!(
+                        "{}: delta did not apply, downloading it whole",
+                        file.path
+                    ),
+                }
+            }
+        }
+        let new = match new {
+            Some(data) => data,
+            None => {
+                let response = fetcher
+                    .fetch(&url(&format!("/{}", file.path)), &[])
+                    .await
+                    .with_context(|| format!("fetching {}", file.path))?;
+                tally.received += response.body.len() as u64;
+                tally.downloaded += 1;
+                check(file, &response.body)?;
+                response.body
+            }
+        };
+        write_file(&local, &new)?;
+    }
+    eprintln!(
+        "Synced {} file(s) into {}: {} unchanged, {} patched, {} downloaded \
+         ({} bytes received)",
+        files.len(),
+        args.dir.display(),
+        tally.unchanged,
+        tally.patched,
+        tally.downloaded,
+        tally.received
+    );
+    Ok(())
+}
+
+fn digest(data: &[u8]) -> String { hex::encode(Sha256::digest(data)) }
+
+/// Fail unless `data` is the version of `file` the manifest lists.
+fn check(file: &File, data: &[u8]) -> Result<()> {
+    if digest(data) != file.digest {
+        return Err(anyhow!(
+            "{}: content does not match the manifest digest",
+            file.path
+        ));
+    }
+    Ok(())
+}
+
+/// Where a manifest path goes under `dir`.  Paths that could escape it
+/// are refused, since the manifest comes from the server.
+fn local_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let plain = !path.is_empty()
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !plain {
+        return Err(anyhow!("manifest lists an unsafe path {path:?}"));
+    }
+    Ok(dir.join(relative))
+}
+
+/// Replace `path` with `data` via a temporary file and rename, so an
+/// interrupted sync never leaves a partial file.
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let parent = path.parent().expect("path under the sync dir");
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("creating {}", parent.display()))?;
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    file.write_all(data)?;
+    file.persist(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_path() {
+        let dir = Path::new("/mirror");
+        assert_eq!(
+            local_path(dir, "sub/a.txt").unwrap(),
+            Path::new("/mirror/sub/a.txt")
+        );
+        for path in ["", "/etc/passwd", "../a.txt", "sub/../../a.txt", "./a"] {
+            assert!(local_path(dir, path).is_err(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine as _;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+/// An authenticated connection to a Tor daemon's control port.
+///
+/// Onion services added through this connection are removed by Tor when
+/// it is dropped, so the connection must be kept open for as long as the
+/// service should stay reachable.
+pub struct ControlPort {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl ControlPort {
+    /// Connect to the control port at `addr` and authenticate with
+    /// whichever method the daemon offers: none, cookie file, or
+    /// `password` (for `HashedControlPassword`).
+    pub async fn connect(addr: &str, password: Option<&str>) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await.with_context(|| {
+            format!("connecting to Tor control port {addr}")
+        })?;
+        let (reader, writer) = stream.into_split();
+        let mut control = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        let info = control.command("PROTOCOLINFO 1").await?;
+        let auth = info
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or_else(|| anyhow!("PROTOCOLINFO reply has no AUTH line"))?;
+        let methods: Vec<&str> = auth
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .map(|m| m.split(',').collect())
+            .unwrap_or_default();
+
+        let auth_command = if let Some(password) = password {
+            format!("AUTHENTICATE {}", quote(password))
+        } else if methods.contains(&"NULL") {
+            "AUTHENTICATE".to_string()
+        } else if methods.contains(&"COOKIE") {
+            let path = cookie_file(auth).ok_or_else(|| {
+                anyhow!("PROTOCOLINFO reply has no COOKIEFILE")
+            })?;
+            let cookie = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("reading Tor auth cookie {path}"))?;
+            format!("AUTHENTICATE {}", hex::encode(cookie))
+        } else {
+            bail!(
+                "Tor control port requires one of {methods:?}; supply a \
+                 password with --tor-control-password"
+            );
+        };
+        control
+            .command(&auth_command)
+            .await
+            .context("authenticating to Tor control port")?;
+        Ok(control)
+    }
+
+    /// Publish an onion service that forwards each virtual port in `ports`
+    /// to its target.  `secret_key` is the 64-byte expanded Ed25519 secret
+    /// key for a deterministic address; without it Tor generates a new
+    /// identity that is discarded when the service is removed.  Returns
+    /// the `.onion` hostname.
+    pub async fn add_onion(
+        &mut self,
+        secret_key: Option<&[u8; 64]>,
+        ports: &[(u16, SocketAddr)],
+    ) -> Result<String> {
+        let key = match secret_key {
+            Some(bytes) => format!(
+                "ED25519-V3:{}",
+                base64::engine::general_purpose::STANDARD.encode(bytes)
+            ),
+            None => "NEW:ED25519-V3 Flags=DiscardPK".to_string(),
+        };
+        let ports: String = ports
+            .iter()
+            .map(|(port, target)| format!(" Port={port},{target}"))
+            .collect();
+        let reply = self
+            .command(&format!("ADD_ONION {key}{ports}"))
+            .await
+            .context("adding onion service")?;
+        let service_id = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| anyhow!("ADD_ONION reply has no ServiceID"))?;
+        Ok(format!("{service_id}.onion"))
+    }
+
+    /// Wait until Tor reports that the descriptor for `onion_host` has
+    /// been uploaded to at least one HSDir.
+    pub async fn wait_for_upload(&mut self, onion_host: &str) -> Result<()> {
+        let service_id = onion_host.trim_end_matches(".onion");
+        self.command("SETEVENTS HS_DESC").await?;
+        loop {
+            let line = self.read_line().await?;
+            let mut fields = line.split_whitespace().skip(1);
+            if fields.next() != Some("HS_DESC") {
+                continue;
+            }
+            let action = fields.next();
+            if fields.next() != Some(service_id) {
+                continue;
+            }
+            if action == Some("UPLOADED") {
+                break;
+            }
+        }
+        self.command("SETEVENTS").await?;
+        Ok(())
+    }
+
+    /// Wait until the control connection is closed by Tor.
+    pub async fn closed(&mut self) -> Result<()> {
+        loop {
+            self.read_line().await?;
+        }
+    }
+
+    /// Send `line` and return the reply lines with their status codes
+    /// stripped, failing on any non-250 reply.  Asynchronous event lines
+    /// (status 650) received before the reply are skipped.
+    async fn command(&mut self, line: &str) -> Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await?;
+        let mut lines = Vec::new();
+        loop {
+            let reply = self.read_line().await?;
+            if reply.len() < 4 {
+                bail!("malformed control port reply: {reply:?}");
+            }
+            let (code, rest) = reply.split_at(3);
+            if code == "650" {
+                continue;
+            }
+            if code != "250" {
+                bail!("Tor control port: {reply}");
+            }
+            let (separator, text) = rest.split_at(1);
+            lines.push(text.to_string());
+            if separator == " " {
+                return Ok(lines);
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            bail!("Tor control port closed the connection");
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Open a TCP stream to `host:port` through the SOCKS5 proxy at
+/// `proxy`.  The hostname is resolved by the proxy, so `.onion` names
+/// work when the proxy is a Tor SOCKS port.
+///
+/// With an `isolation` key, the stream authenticates with it as the SOCKS
+/// password.  Tor keeps streams with different credentials on separate
+/// circuits (its default `IsolateSOCKSAuth`).
+pub async fn socks_connect(
+    proxy: &str,
+    host: &str,
+    port: u16,
+    isolation: Option<&str>,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .with_context(|| format!("connecting to SOCKS proxy {proxy}"))?;
+
+    // Greeting: version 5, one method: "no authentication", or
+    // username/password when isolating.
+    let method = if isolation.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, method] {
+        bail!("SOCKS proxy {proxy} refused the authentication method");
+    }
+    if let Some(key) = isolation {
+        let key_len = u8::try_from(key.len())
+            .map_err(|_| anyhow!("isolation key too long for SOCKS"))?;
+        let mut auth = vec![1, 6];
+        auth.extend_from_slice(b"garner");
+        auth.push(key_len);
+        auth.extend_from_slice(key.as_bytes());
+        stream.write_all(&auth).await?;
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            bail!("SOCKS proxy {proxy} rejected the isolation credentials");
+        }
+    }
+
+    let host_len = u8::try_from(host.len())
+        .map_err(|_| anyhow!("hostname too long for SOCKS: {host}"))?;
+    let mut request = vec![5, 1, 0, 3, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(SocksFailure {
+            target: format!("{host}:{port}"),
+            code: reply[1],
+        }
+        .into());
+    }
+    // Skip the bound address, whose length depends on its type.
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => usize::from(stream.read_u8().await?),
+        other => bail!("SOCKS reply has unknown address type {other}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// A SOCKS proxy's refusal to connect, with its reply code.
+#[derive(Debug)]
+pub struct SocksFailure {
+    target: String,
+    pub code: u8,
+}
+
+/// The reply code for an onion service whose descriptor Tor could not
+/// find.
+pub const SOCKS_DESCRIPTOR_NOT_FOUND: u8 = 0xf0;
+
+impl std::fmt::Display for SocksFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SOCKS connect to {} failed: {}",
+            self.target,
+            socks_error(self.code)
+        )
+    }
+}
+
+impl std::error::Error for SocksFailure {}
+
+/// Describe a SOCKS5 reply code, including Tor's onion service
+/// extensions.
+fn socks_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        SOCKS_DESCRIPTOR_NOT_FOUND => "onion service descriptor not found",
+        0xf1 => "onion service descriptor is invalid",
+        0xf2 => "onion service introduction failed",
+        0xf3 => "onion service rendezvous failed",
+        0xf4 => "onion service requires client authorization",
+        0xf5 => "onion service client authorization is invalid",
+        0xf6 => "invalid onion address",
+        0xf7 => "onion service introduction timed out",
+        _ => "unknown error",
+    }
+}
+
+/// Extract the `COOKIEFILE="…"` path from a PROTOCOLINFO AUTH line.
+fn cookie_file(auth: &str) -> Option<String> {
+    let start = auth.find("COOKIEFILE=\"")? + "COOKIEFILE=\"".len();
+    let mut path = String::new();
+    let mut chars = auth[start..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => path.push(chars.next()?),
+            '"' => return Some(path),
+            c => path.push(c),
+        }
+    }
+    None
+}
+
+/// Quote a string for the control protocol.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Run a fake control port that answers each received line with the
+    /// next scripted reply, and return its address.
+    async fn fake_control_port(replies: &'static [&'static str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            for reply in replies {
+                lines.next_line().await.unwrap();
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_control_port_add_onion() {
+        let addr = fake_control_port(&[
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n\
+             250-VERSION Tor=\"0.4.8.10\"\r\n250 OK\r\n",
+            "250 OK\r\n",
+            "250-ServiceID=abcdef\r\n250 OK\r\n",
+        ])
+        .await;
+        let mut control = ControlPort::connect(&addr, None).await.unwrap();
+        let target = "127.0.0.1:8080".parse().unwrap();
+        let host = control.add_onion(None, &[(80, target)]).await.unwrap();
+        assert_eq!(host, "abcdef.onion");
+    }
+
+    #[tokio::test]
+    async fn test_control_port_rejected_auth() {
+        let addr = fake_control_port(&[
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n\
+             250 OK\r\n",
+            "515 Authentication failed\r\n",
+        ])
+        .await;
+        assert!(ControlPort::connect(&addr, Some("wrong")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = vec![0u8; 5 + "example.onion".len() + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[5..18], b"example.onion");
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+        let mut stream = socks_connect(&proxy, "example.onion", 80, None)
+            .await
+            .unwrap();
+        let mut body = String::new();
+        stream.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect_isolated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 12];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x06garner\x03abc");
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut request = vec![0u8; 5 + "example.onion".len() + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        socks_connect(&proxy, "example.onion", 80, Some("abc"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks_connect_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&[5, 0xf0, 0, 1]).await.unwrap();
+        });
+        let err = socks_connect(&proxy, "example.onion", 80, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SocksFailure>()
+                .map(|failure| failure.code),
+            Some(SOCKS_DESCRIPTOR_NOT_FOUND)
+        );
+        let err = err.to_string();
+        assert!(err.contains("descriptor not found"), "{err}");
+    }
+
+    #[test]
+    fn test_cookie_file() {
+        let auth = r#"METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/run/tor/control.authcookie""#;
+        assert_eq!(
+            cookie_file(auth).as_deref(),
+            Some("/run/tor/control.authcookie")
+        );
+    }
+
+    #[test]
+    fn test_cookie_file_escaped() {
+        let auth = r#"METHODS=COOKIE COOKIEFILE="C:\\tor\\cookie""#;
+        assert_eq!(cookie_file(auth).as_deref(), Some(r"C:\tor\cookie"));
+    }
+
+    #[test]
+    fn test_cookie_file_missing() {
+        assert_eq!(cookie_file("METHODS=NULL"), None);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(r#"pa"ss\word"#), r#""pa\"ss\\word""#);
+    }
+}
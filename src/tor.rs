@@ -0,0 +1,360 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+#[cfg(feature = "bridges")]
+use arti_client::config::{BridgeConfigBuilder, pt::TransportConfigBuilder};
+use arti_client::config::{CfgPath, TorClientConfigBuilder};
+use clap::Args;
+
+use crate::ui;
+
+/// Tor network options shared by every subcommand that bootstraps Arti.
+#[derive(Args, Default)]
+pub struct TorArgs {
+    /// Arti TOML configuration file for advanced Tor settings; garner's
+    /// own settings take precedence over it
+    #[arg(long, value_name = "PATH")]
+    pub arti_config: Option<PathBuf>,
+    /// Bridge to connect through instead of public guards (repeatable),
+    /// e.g. "obfs4 192.0.2.1:443 <FINGERPRINT> cert=… iat-mode=0"
+    #[cfg(feature = "bridges")]
+    #[arg(long = "bridge", value_name = "LINE")]
+    pub bridges: Vec<String>,
+    /// Pluggable transport client for bridges, as PROTOCOL[,PROTOCOL…]=PATH
+    /// (repeatable), e.g. "obfs4=/usr/bin/lyrebird"
+    #[cfg(feature = "bridges")]
+    #[arg(long = "transport", value_name = "PROTOCOLS=PATH")]
+    pub transports: Vec<String>,
+}
+
+impl TorArgs {
+    /// Whether any option that only applies to the embedded Arti client
+    /// is set.
+    pub fn uses_arti_options(&self) -> bool {
+        if self.arti_config.is_some() {
+            return true;
+        }
+        #[cfg(feature = "bridges")]
+        if !self.bridges.is_empty() || !self.transports.is_empty() {
+            return true;
+        }
+        false
+    }
+}
+
+/// A Tor state directory: either ephemeral (deleted when dropped) or
+/// persistent across runs.
+pub enum StateDir {
+    Ephemeral(tempfile::TempDir),
+    Persistent(PathBuf),
+}
+
+impl StateDir {
+    pub fn path(&self) -> &Path {
+        match self {
+            StateDir::Ephemeral(dir) => dir.path(),
+            StateDir::Persistent(path) => path,
+        }
+    }
+}
+
+/// Build a [`TorClientConfigBuilder`] with garner's standard settings.
+/// With an ephemeral state directory the keystore is ephemeral
+/// (in-memory) too, so switching keys never conflicts; a persistent
+/// state directory keeps its keystore on disk alongside the rest of the
+/// service state.  Callers provide the directories obtained from
+/// [`dirs`] or [`persistent_dirs`], and the network options from the
+/// command line.  Settings from `--arti-config` form the base; the
+/// storage directories and command-line options are applied over them.
+pub fn config(
+    state_dir: &StateDir,
+    cache_dir: impl AsRef<Path>,
+    args: &TorArgs,
+) -> Result<TorClientConfigBuilder> {
+    let mut builder = match &args.arti_config {
+        Some(path) => load_arti_config(path)?,
+        None => TorClientConfigBuilder::default(),
+    };
+    builder
+        .storage()
+        .cache_dir(CfgPath::new_literal(cache_dir.as_ref()))
+        .state_dir(CfgPath::new_literal(state_dir.path()));
+    if let StateDir::Ephemeral(_) = state_dir {
+        builder.storage().keystore().primary().kind(
+            tor_config::ExplicitOrAuto::Explicit(
+                tor_keymgr::config::ArtiKeystoreKind::Ephemeral,
+            ),
+        );
+    }
+    #[cfg(feature = "bridges")]
+    {
+        for line in &args.bridges {
+            let bridge: BridgeConfigBuilder = line
+                .parse()
+                .with_context(|| format!("invalid bridge line {line:?}"))?;
+            builder.bridges().bridges().push(bridge);
+        }
+        for spec in &args.transports {
+            builder.bridges().transports().push(parse_transport(spec)?);
+        }
+    }
+    #[cfg(not(feature = "bridges"))]
+    let _ = args;
+    Ok(builder)
+}
+
+/// Top-level sections of an `arti.toml` that configure the arti program
+/// rather than the Tor client, and so have no meaning for garner.
+const ARTI_APPLICATION_SECTIONS: &[&str] = &[
+    "application",
+    "logging",
+    "metrics",
+    "onion_services",
+    "proxy",
+    "rpc",
+    "system",
+];
+
+/// Read an Arti TOML configuration file into a client config builder.
+/// Sections that only apply to the arti program are skipped, so a
+/// standard `arti.toml` can be used as is; any other unrecognized key is
+/// an error, to catch typos.
+fn load_arti_config(path: &Path) -> Result<TorClientConfigBuilder> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    parse_arti_config(&text)
+        .with_context(|| format!("invalid Arti config {}", path.display()))
+}
+
+fn parse_arti_config(text: &str) -> Result<TorClientConfigBuilder> {
+    let mut unknown = Vec::new();
+    let builder =
+        serde_ignored::deserialize(toml::Deserializer::new(text), |key| {
+            let key = key.to_string();
+            let section = key.split('.').next().unwrap_or_default();
+            if !ARTI_APPLICATION_SECTIONS.contains(&section) {
+                unknown.push(key);
+            }
+        })?;
+    if !unknown.is_empty() {
+        return Err(anyhow!("unrecognized keys: {}", unknown.join(", ")));
+    }
+    Ok(builder)
+}
+
+/// Parse a `PROTOCOL[,PROTOCOL…]=PATH` pluggable transport spec.
+#[cfg(feature = "bridges")]
+fn parse_transport(spec: &str) -> Result<TransportConfigBuilder> {
+    let (protocols, path) = spec.split_once('=').ok_or_else(|| {
+        anyhow!("invalid transport {spec:?}: expected PROTOCOLS=PATH")
+    })?;
+    let protocols = protocols
+        .split(',')
+        .map(|p| {
+            p.trim()
+                .parse()
+                .map_err(|e| anyhow!("invalid transport protocol {p:?}: {e}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut transport = TransportConfigBuilder::default();
+    transport
+        .protocols(protocols)
+        .path(CfgPath::new(path.to_string()))
+        .run_on_startup(true);
+    Ok(transport)
+}
+
+/// Create an ephemeral state directory and the shared cache directory
+/// under garner's data dir.  Returns `(state_dir, cache_dir)` where
+/// `state_dir` wraps a [`tempfile::TempDir`] that is automatically
+/// deleted when dropped.  When `instance` is given (the server's
+/// nickname), it is included in the state directory name so that
+/// concurrent services are easy to tell apart.
+///
+/// Callers must keep `state_dir` alive for the lifetime of the
+/// `TorClient`, and must declare it *before* the `TorClient` so that
+/// Rust's reverse drop order releases the Tor locks before the
+/// directory is removed.
+pub fn dirs(instance: Option<&str>) -> Result<(StateDir, PathBuf)> {
+    let data_dir = ui::data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+    let cache_dir = data_dir.join("cache");
+    let prefix = match instance {
+        Some(name) => format!("state-{name}-"),
+        None => "state-".to_string(),
+    };
+    let state_dir = tempfile::Builder::new()
+        .prefix(&prefix)
+        .tempdir_in(&data_dir)?;
+    restrict_to_owner(state_dir.path())?;
+    Ok((StateDir::Ephemeral(state_dir), cache_dir))
+}
+
+/// Like [`dirs`], but use `<root>/<instance>` as a state directory that
+/// survives restarts, creating it if needed.  Only one process may use a
+/// given persistent state directory at a time.
+pub fn persistent_dirs(
+    root: &Path,
+    instance: &str,
+) -> Result<(StateDir, PathBuf)> {
+    let data_dir = ui::data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+    let cache_dir = data_dir.join("cache");
+    let state_dir = root.join(instance);
+    std::fs::create_dir_all(&state_dir).with_context(|| {
+        format!("creating state directory {}", state_dir.display())
+    })?;
+    restrict_to_owner(&state_dir)?;
+    Ok((StateDir::Persistent(state_dir), cache_dir))
+}
+
+/// Bootstrap a client to fill the shared cache directory with the
+/// current consensus and relay descriptors, then shut it down, so that
+/// later clients start from a warm cache.  Returns the cache directory.
+pub async fn prewarm(args: &TorArgs) -> Result<PathBuf> {
+    let (state_dir, cache_dir) = dirs(None)?;
+    let config = config(&state_dir, &cache_dir, args)?.build()?;
+    let client = arti_client::TorClient::create_bootstrapped(config).await?;
+    // The client releases its locks before its state directory goes.
+    drop(client);
+    drop(state_dir);
+    Ok(cache_dir)
+}
+
+/// Arti requires state dirs to be owner-only (0o700).  tempfile and
+/// `create_dir_all` inherit the default umask (typically 0o755 on macOS).
+fn restrict_to_owner(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_arti_config() {
+        let builder = parse_arti_config(
+            "[application]\nwatch_configuration = true\n\
+             [proxy]\nsocks_listen = 9150\n\
+             [path_rules]\nipv4_subnet_family_prefix = 20\n",
+        )
+        .unwrap();
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_parse_arti_config_unknown_key() {
+        let err = parse_arti_config("[path_rulez]\nipv4 = 20\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("path_rulez"), "{err}");
+    }
+
+    #[test]
+    fn test_config_storage_overrides_arti_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("arti.toml");
+        std::fs::write(&path, "[storage]\nstate_dir = \"/elsewhere\"\n")
+            .unwrap();
+        let state_dir = StateDir::Persistent(dir.path().join("state"));
+        // Other fields exist only with some features.
+        #[allow(clippy::needless_update)]
+        let args = TorArgs {
+            arti_config: Some(path),
+            ..Default::default()
+        };
+        let config = config(&state_dir, dir.path().join("cache"), &args)
+            .unwrap()
+            .build()
+            .unwrap();
+        let expected = format!("{:?}", dir.path().join("state"));
+        assert!(format!("{config:?}").contains(&expected));
+    }
+
+    #[cfg(feature = "bridges")]
+    #[test]
+    fn test_parse_transport() {
+        assert!(parse_transport("obfs4=/usr/bin/lyrebird").is_ok());
+        assert!(parse_transport("obfs4,meek=/usr/bin/lyrebird").is_ok());
+    }
+
+    #[cfg(feature = "bridges")]
+    #[test]
+    fn test_parse_transport_missing_path() {
+        assert!(parse_transport("obfs4").is_err());
+    }
+
+    #[cfg(feature = "bridges")]
+    #[test]
+    fn test_parse_transport_invalid_protocol() {
+        assert!(parse_transport("not a protocol=/bin/pt").is_err());
+    }
+
+    #[cfg(feature = "bridges")]
+    #[test]
+    fn test_config_rejects_bad_bridge_line() {
+        let state_dir = StateDir::Persistent(PathBuf::from("state"));
+        let args = TorArgs {
+            bridges: vec!["not a bridge".to_string()],
+            ..Default::default()
+        };
+        assert!(config(&state_dir, "cache", &args).is_err());
+    }
+
+    #[test]
+    fn test_state_dir_names() {
+        let (state_dir, cache_dir) = dirs(Some("blog")).unwrap();
+        let path = state_dir.path().to_path_buf();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("state-blog-"), "{name}");
+        assert_eq!(path.parent(), cache_dir.parent());
+        drop(state_dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_prewarmed_cache_is_shared() {
+        // `prewarm` fills the cache of `dirs(None)`, which every server and
+        // client reads, whatever its own state directory.
+        let (_, prewarmed) = dirs(None).unwrap();
+        assert_eq!(prewarmed, ui::data_dir().join("cache"));
+        assert_eq!(dirs(Some("blog")).unwrap().1, prewarmed);
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(persistent_dirs(root.path(), "blog").unwrap().1, prewarmed);
+    }
+
+    #[test]
+    fn test_persistent_state() {
+        let root = tempfile::tempdir().unwrap();
+        let (state_dir, cache_dir) =
+            persistent_dirs(root.path(), "blog").unwrap();
+        assert_eq!(state_dir.path(), root.path().join("blog"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let mode = std::fs::metadata(state_dir.path()).unwrap().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        let keystore = |state_dir: &StateDir| {
+            let config = config(state_dir, &cache_dir, &TorArgs::default())
+                .unwrap()
+                .build()
+                .unwrap();
+            format!("{config:?}").contains("Ephemeral")
+        };
+        // The keystore lives on disk with the rest of the service state.
+        assert!(!keystore(&state_dir));
+        drop(state_dir);
+        assert!(root.path().join("blog").is_dir());
+
+        let ephemeral = StateDir::Ephemeral(tempfile::tempdir().unwrap());
+        assert!(keystore(&ephemeral));
+    }
+}
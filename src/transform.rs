@@ -0,0 +1,648 @@
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use anyhow::{Result, anyhow};
+use bc_components::SigningPrivateKey;
+
+use crate::rewrite;
+
+/// Most bytes of transformed bodies kept in memory; the cache is emptied
+/// when it fills up.
+const MAX_CACHED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Largest body that is cached, so that one big file does not push out
+/// all the others.
+const MAX_CACHED_BODY: usize = MAX_CACHED_BYTES / 8;
+
+/// One stage of a transformation pipeline.  Each applies only to the
+/// media types it understands and passes other bodies through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Render Markdown as an HTML page.
+    Markdown,
+    /// Strip indentation and blank lines from HTML and CSS.
+    Minify,
+    /// Point clearnet URLs in HTML at the onion address (see
+    /// `--rewrite-origin`).
+    RewriteOrigins,
+    /// Sign the body with the service's key, in an X-Garner-Signature
+    /// header.
+    Sign,
+}
+
+impl Step {
+    /// Whether the step changes or signs a body of type `mime`.
+    fn applies_to(self, mime: &str) -> bool {
+        let html = mime == mime_guess::mime::TEXT_HTML.as_ref();
+        match self {
+            Self::Markdown => {
+                matches!(mime, "text/markdown" | "text/x-markdown")
+            }
+            Self::Minify => html || mime == mime_guess::mime::TEXT_CSS.as_ref(),
+            Self::RewriteOrigins => html,
+            Self::Sign => true,
+        }
+    }
+}
+
+impl FromStr for Step {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "markdown" => Ok(Self::Markdown),
+            "minify" => Ok(Self::Minify),
+            "rewrite-origins" => Ok(Self::RewriteOrigins),
+            "sign" => Ok(Self::Sign),
+            _ => Err(anyhow!(
+                "unknown transform step {s:?} (expected markdown, minify, \
+                 rewrite-origins, or sign)"
+            )),
+        }
+    }
+}
+
+/// The steps applied to files under a path prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Request path prefix, starting with `/` and without a trailing one.
+    prefix: String,
+    steps: Vec<Step>,
+}
+
+impl Mount {
+    pub fn steps(&self) -> &[Step] { &self.steps }
+}
+
+/// Parse a `--transform` value: `<PREFIX>=<STEP>[,<STEP>...]`, such as
+/// `/notes=markdown,minify,sign`.
+pub fn parse_mount(spec: &str) -> Result<Mount> {
+    let (prefix, steps) = spec.split_once('=').ok_or_else(|| {
+        anyhow!("transform {spec:?} must be <PREFIX>=<STEP>[,<STEP>...]")
+    })?;
+    if !prefix.starts_with('/') {
+        return Err(anyhow!("transform prefix {prefix:?} must start with /"));
+    }
+    let steps = steps
+        .split(',')
+        .map(|step| step.trim().parse())
+        .collect::<Result<Vec<Step>>>()?;
+    if steps
+        .iter()
+        .position(|step| *step == Step::Sign)
+        .is_some_and(|i| i + 1 != steps.len())
+    {
+        return Err(anyhow!(
+            "transform {spec:?}: sign must be the last step, so the \
+             signature covers the body as sent"
+        ));
+    }
+    Ok(Mount {
+        prefix: prefix.trim_end_matches('/').to_string(),
+        steps,
+    })
+}
+
+/// What a pipeline makes of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub body: Vec<u8>,
+    pub mime: String,
+    /// Value for the X-Garner-Signature header, if a step signed the body.
+    pub signature: Option<String>,
+}
+
+/// Site-wide settings the steps use.
+pub struct Context<'a> {
+    /// Clearnet hosts whose URLs are rewritten, from `--rewrite-origin`.
+    pub rewrite_hosts: &'a [String],
+    pub onion_host: &'a str,
+    /// Key that signs every response, from `--sign-responses`.
+    pub signing_key: Option<&'a SigningPrivateKey>,
+}
+
+/// The mounts of a site, with the transformed bodies of recently served
+/// files.
+#[derive(Default)]
+pub struct Pipelines {
+    /// Sorted by decreasing prefix length, so the first match is the
+    /// longest.
+    mounts: Vec<Mount>,
+    /// Key for mounts with a `sign` step.
+    key: Option<SigningPrivateKey>,
+    /// Output by request path, with the ETag of the source it came from.
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    outputs: HashMap<String, (String, Output)>,
+    /// Total size of the bodies in `outputs`.
+    bytes: usize,
+}
+
+impl Pipelines {
+    pub fn new(
+        mut mounts: Vec<Mount>,
+        key: Option<SigningPrivateKey>,
+    ) -> Result<Self> {
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+        let pipelines = Self {
+            mounts,
+            key,
+            ..Self::default()
+        };
+        if pipelines.uses(Step::Sign) && pipelines.key.is_none() {
+            return Err(anyhow!(
+                "the sign transform step requires --key or --key-credential"
+            ));
+        }
+        Ok(pipelines)
+    }
+
+    /// Whether any mount has `step`.
+    pub fn uses(&self, step: Step) -> bool {
+        self.mounts.iter().any(|mount| mount.steps.contains(&step))
+    }
+
+    /// The steps for a request path: those of the longest mount prefix
+    /// it falls under.  Site-wide URL rewriting and signing are steps too,
+    /// added to every pipeline that does not list them, so a path under
+    /// no mount gets just those.
+    pub fn steps(
+        &self,
+        path: &str,
+        rewrite_origins: bool,
+        sign_responses: bool,
+    ) -> Vec<Step> {
+        let mut steps = self
+            .mounts
+            .iter()
+            .find(|mount| {
+                path.strip_prefix(&mount.prefix).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/')
+                })
+            })
+            .map_or_else(Vec::new, |mount| mount.steps.clone());
+        if rewrite_origins && !steps.contains(&Step::RewriteOrigins) {
+            let signed = steps.last() == Some(&Step::Sign);
+            steps.insert(
+                steps.len() - usize::from(signed),
+                Step::RewriteOrigins,
+            );
+        }
+        if sign_responses && !steps.contains(&Step::Sign) {
+            steps.push(Step::Sign);
+        }
+        steps
+    }
+
+    /// The output for `path` if it was made from the source version named
+    /// by `etag`.
+    pub fn cached(&self, path: &str, etag: Option<&str>) -> Option<Output> {
+        let cache = self.cache.lock().expect("cache lock");
+        let (cached, output) = cache.outputs.get(path)?;
+        (Some(cached.as_str()) == etag).then(|| output.clone())
+    }
+
+    /// Run `steps` over `body`, keeping the output for `path` if `etag`
+    /// names the source version it came from.
+    pub fn apply(
+        &self,
+        path: &str,
+        etag: Option<&str>,
+        steps: &[Step],
+        body: Vec<u8>,
+        mime: &str,
+        context: &Context,
+    ) -> Result<Output> {
+        let mut output = Output {
+            body,
+            mime: mime.to_string(),
+            signature: None,
+        };
+        for step in steps {
+            self.run(*step, &mut output, context)?;
+        }
+        if let Some(etag) = etag
+            && output.body.len() <= MAX_CACHED_BODY
+        {
+            let mut cache = self.cache.lock().expect("cache lock");
+            if cache.bytes + output.body.len() > MAX_CACHED_BYTES {
+                *cache = Cache::default();
+            }
+            cache.bytes += output.body.len();
+            let entry = (etag.to_string(), output.clone());
+            if let Some((_, old)) =
+                cache.outputs.insert(path.to_string(), entry)
+            {
+                cache.bytes -= old.body.len();
+            }
+        }
+        Ok(output)
+    }
+
+    fn run(
+        &self,
+        step: Step,
+        output: &mut Output,
+        context: &Context,
+    ) -> Result<()> {
+        if !step.applies_to(&output.mime) {
+            return Ok(());
+        }
+        let text = std::str::from_utf8(&output.body).ok();
+        match (step, text) {
+            (Step::Markdown, Some(text)) => {
+                output.body = markdown_to_html(text).into_bytes();
+                output.mime = mime_guess::mime::TEXT_HTML.to_string();
+            }
+            (Step::Minify, Some(text)) => {
+                output.body = minify(text).into_bytes();
+            }
+            (Step::RewriteOrigins, Some(text)) => {
+                output.body = rewrite::rewrite_html(
+                    text,
+                    context.rewrite_hosts,
+                    context.onion_host,
+                )
+                .into_bytes();
+            }
+            (Step::Sign, _) => {
+                let key = context
+                    .signing_key
+                    .or(self.key.as_ref())
+                    .ok_or_else(|| anyhow!("no key for the sign step"))?;
+                output.signature =
+                    Some(crate::key::sign_body(key, &output.body)?);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Those of `steps` that apply to a file of type `mime`, in order.  A
+/// file none apply to can be sent as it is.
+pub fn applicable(steps: &[Step], mime: &str) -> Vec<Step> {
+    let mut mime = mime;
+    let mut applicable = Vec::new();
+    for &step in steps {
+        if step.applies_to(mime) {
+            applicable.push(step);
+            if step == Step::Markdown {
+                mime = mime_guess::mime::TEXT_HTML.as_ref();
+            }
+        }
+    }
+    applicable
+}
+
+/// Strip indentation, trailing spaces, and blank lines, except inside
+/// `<pre>` and `<textarea>` elements, where whitespace is content.
+pub fn minify(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut preformatted = false;
+    for line in text.lines() {
+        let lower = line.to_ascii_lowercase();
+        let opens = lower.contains("<pre") || lower.contains("<textarea");
+        let closes = lower.contains("</pre>") || lower.contains("</textarea>");
+        let line = if preformatted { line } else { line.trim() };
+        if !line.is_empty() || preformatted {
+            out.push_str(line);
+            out.push('\n');
+        }
+        preformatted = (preformatted || opens) && !closes;
+    }
+    out
+}
+
+/// Render Markdown as a complete HTML page, titled by its first heading.
+///
+/// This covers what notes and documentation mostly use: headings,
+/// paragraphs, bulleted lists, fenced code blocks, and inline code,
+/// links, strong and emphasized text.  Raw HTML is escaped.
+pub fn markdown_to_html(text: &str) -> String {
+    let mut body = String::new();
+    let mut title = None;
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut in_list = false;
+    let mut lines = text.lines();
+
+    let flush = |body: &mut String, paragraph: &mut Vec<&str>| {
+        if !paragraph.is_empty() {
+            body.push_str("<p>");
+            inline(&paragraph.join("\n"), body);
+            body.push_str("</p>\n");
+            paragraph.clear();
+        }
+    };
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        let item = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "));
+        if in_list && item.is_none() {
+            body.push_str("</ul>\n");
+            in_list = false;
+        }
+        if let Some(info) = trimmed.strip_prefix("```") {
+            flush(&mut body, &mut paragraph);
+            let language = info.trim();
+            if language.is_empty() {
+                body.push_str("<pre><code>");
+            } else {
+                body.push_str("<pre><code class=\"language-");
+                escape(language, &mut body);
+                body.push_str("\">");
+            }
+            for code in lines.by_ref() {
+                if code.trim_start().starts_with("```") {
+                    break;
+                }
+                escape(code, &mut body);
+                body.push('\n');
+            }
+            body.push_str("</code></pre>\n");
+        } else if let Some((level, heading)) = heading(trimmed) {
+            flush(&mut body, &mut paragraph);
+            if title.is_none() {
+                let mut text = String::new();
+                escape(heading, &mut text);
+                title = Some(text);
+            }
+            body.push_str(&format!("<h{level}>"));
+            inline(heading, &mut body);
+            body.push_str(&format!("</h{level}>\n"));
+        } else if let Some(item) = item {
+            flush(&mut body, &mut paragraph);
+            if !in_list {
+                body.push_str("<ul>\n");
+                in_list = true;
+            }
+            body.push_str("<li>");
+            inline(item, &mut body);
+            body.push_str("</li>\n");
+        } else if trimmed.is_empty() {
+            flush(&mut body, &mut paragraph);
+        } else {
+            paragraph.push(trimmed);
+        }
+    }
+    flush(&mut body, &mut paragraph);
+    if in_list {
+        body.push_str("</ul>\n");
+    }
+
+    let title = title
+        .map(|title| format!("<title>{title}</title>"))
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">{title}</head>\
+         <body>\n{body}</body></html>\n"
+    )
+}
+
+/// The level and text of an ATX heading such as `## Usage`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let text = line[level..].strip_prefix(' ')?;
+    (1..=6).contains(&level).then(|| (level, text.trim()))
+}
+
+/// Render inline Markdown: code spans, links, strong and emphasized text.
+fn inline(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '`'
+            && let Some(end) = rest[1..].find('`')
+        {
+            out.push_str("<code>");
+            escape(&rest[1..1 + end], out);
+            out.push_str("</code>");
+            rest = &rest[end + 2..];
+        } else if let Some(inner) = rest.strip_prefix("**")
+            && let Some(end) = inner.find("**").filter(|&end| end > 0)
+        {
+            out.push_str("<strong>");
+            inline(&inner[..end], out);
+            out.push_str("</strong>");
+            rest = &inner[end + 2..];
+        } else if let Some(inner) = rest.strip_prefix('*')
+            && let Some(end) = inner.find('*').filter(|&end| end > 0)
+        {
+            out.push_str("<em>");
+            inline(&inner[..end], out);
+            out.push_str("</em>");
+            rest = &inner[end + 1..];
+        } else if c == '['
+            && let Some(close) = rest.find("](")
+            && let Some(end) = rest[close + 2..].find(')')
+        {
+            let url = &rest[close + 2..close + 2 + end];
+            out.push_str("<a href=\"");
+            escape(link_target(url), out);
+            out.push_str("\">");
+            inline(&rest[1..close], out);
+            out.push_str("</a>");
+            rest = &rest[close + 3 + end..];
+        } else {
+            escape(&rest[..c.len_utf8()], out);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+}
+
+/// `url` if it is an `http:`, `https:` or relative link, and `#` for any
+/// other scheme, such as `javascript:`, that could run in the page.
+fn link_target(url: &str) -> &str {
+    let end = url.find(['/', '?', '#']).unwrap_or(url.len());
+    match url[..end].split_once(':') {
+        None => url,
+        Some((scheme, _))
+            if scheme.eq_ignore_ascii_case("http")
+                || scheme.eq_ignore_ascii_case("https") =>
+        {
+            url
+        }
+        Some(_) => "#",
+    }
+}
+
+/// `text` with the characters that are special in HTML escaped.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    escape(text, &mut out);
+    out
+}
+
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTEXT: Context = Context {
+        rewrite_hosts: &[],
+        onion_host: "abc.onion",
+        signing_key: None,
+    };
+
+    #[test]
+    fn test_parse_mount() {
+        let mount = parse_mount("/notes/=markdown, minify,sign").unwrap();
+        assert_eq!(mount.prefix, "/notes");
+        assert_eq!(mount.steps(), [Step::Markdown, Step::Minify, Step::Sign]);
+        assert!(parse_mount("/notes").is_err());
+        assert!(parse_mount("notes=minify").is_err());
+        assert!(parse_mount("/notes=compress").is_err());
+        assert!(parse_mount("/notes=sign,minify").is_err());
+    }
+
+    #[test]
+    fn test_pipeline_selection() {
+        bc_components::register_tags();
+        let mounts = vec![
+            parse_mount("/docs=minify,sign").unwrap(),
+            parse_mount("/docs/api=markdown").unwrap(),
+        ];
+        assert!(Pipelines::new(mounts.clone(), None).is_err());
+        let (private, _) = crate::key::generate_keypair().unwrap();
+        let key = crate::key::parse_signing_key(&private).unwrap();
+        let pipelines = Pipelines::new(mounts, Some(key)).unwrap();
+        assert_eq!(
+            pipelines.steps("/docs/a.html", false, false),
+            [Step::Minify, Step::Sign]
+        );
+        assert_eq!(
+            pipelines.steps("/docs/api/x.md", false, false),
+            [Step::Markdown]
+        );
+        assert!(pipelines.steps("/docsx/a.html", false, false).is_empty());
+        assert_eq!(
+            pipelines.steps("/docs/a.html", true, true),
+            [Step::Minify, Step::RewriteOrigins, Step::Sign]
+        );
+        assert_eq!(
+            pipelines.steps("/index.html", true, true),
+            [Step::RewriteOrigins, Step::Sign]
+        );
+
+        let steps = [Step::Markdown, Step::Minify, Step::RewriteOrigins];
+        assert_eq!(applicable(&steps, "text/markdown"), steps);
+        assert_eq!(applicable(&steps, "text/css"), [Step::Minify]);
+        assert!(applicable(&steps, "image/png").is_empty());
+    }
+
+    #[test]
+    fn test_markdown_and_minify() {
+        let pipelines = Pipelines::default();
+        let steps = [Step::Markdown, Step::Minify];
+        let markdown = "# Notes & *more*\n\nSee [the `docs`](/d?a=1&b=2) \
+                        for **details**.\n\n- one\n- two\n\n```rust\nfn \
+                        main() {}\n    <x>\n```\n";
+        let apply = |path, etag, body: &str, mime| {
+            pipelines
+                .apply(path, etag, &steps, body.into(), mime, &CONTEXT)
+                .unwrap()
+        };
+        let output = apply("/a.md", Some("v1"), markdown, "text/markdown");
+        assert_eq!(output.mime, "text/html");
+        assert_eq!(
+            String::from_utf8(output.body.clone()).unwrap(),
+            "<!DOCTYPE html>\n\
+             <html><head><meta charset=\"utf-8\"><title>Notes &amp; \
+             *more*</title></head><body>\n\
+             <h1>Notes &amp; <em>more</em></h1>\n\
+             <p>See <a href=\"/d?a=1&amp;b=2\">the <code>docs</code></a> \
+             for <strong>details</strong>.</p>\n\
+             <ul>\n<li>one</li>\n<li>two</li>\n</ul>\n\
+             <pre><code class=\"language-rust\">fn main() {}\n    \
+             &lt;x&gt;\n</code></pre>\n\
+             </body></html>\n"
+        );
+        assert_eq!(pipelines.cached("/a.md", Some("v1")), Some(output));
+        assert_eq!(pipelines.cached("/a.md", Some("v2")), None);
+        assert_eq!(pipelines.cached("/a.md", None), None);
+
+        let plain = apply("/a.txt", None, "  a\n\n", "text/plain");
+        assert_eq!(plain.body, b"  a\n\n");
+        assert_eq!(pipelines.cached("/a.txt", None), None);
+    }
+
+    #[test]
+    fn test_markdown_links() {
+        for (url, href) in [
+            ("https://example.com/a", "https://example.com/a"),
+            ("HTTP://example.com", "HTTP://example.com"),
+            ("notes/a.md", "notes/a.md"),
+            ("/d?q=a:b", "/d?q=a:b"),
+            ("#usage", "#usage"),
+            ("javascript:alert(1", "#"),
+            ("JavaScript:alert", "#"),
+            (" javascript:x", "#"),
+            ("data:text/html,x", "#"),
+            ("vbscript:x", "#"),
+        ] {
+            assert_eq!(link_target(url), href, "{url}");
+        }
+        let html = markdown_to_html("[x](javascript:alert(1))");
+        assert!(html.contains("<a href=\"#\">x</a>"), "{html}");
+        assert!(!html.contains("javascript"), "{html}");
+    }
+
+    #[test]
+    fn test_cache_limits() {
+        let pipelines = Pipelines::default();
+        let apply = |pipelines: &Pipelines, path: &str, size| {
+            pipelines
+                .apply(
+                    path,
+                    Some("v1"),
+                    &[Step::Minify],
+                    vec![b'x'; size],
+                    "text/css",
+                    &CONTEXT,
+                )
+                .unwrap();
+        };
+        let bytes =
+            |pipelines: &Pipelines| pipelines.cache.lock().unwrap().bytes;
+        apply(&pipelines, "/a.css", 1000);
+        apply(&pipelines, "/a.css", 1000);
+        assert_eq!(bytes(&pipelines), 1001);
+        apply(&pipelines, "/big.css", MAX_CACHED_BODY + 1);
+        assert!(pipelines.cached("/big.css", Some("v1")).is_none());
+        assert_eq!(bytes(&pipelines), 1001);
+
+        // Filling the cache empties it.
+        for i in 0..8 {
+            // Minifying adds a final newline.
+            apply(&pipelines, &format!("/{i}.css"), MAX_CACHED_BODY - 1);
+        }
+        assert!(bytes(&pipelines) <= MAX_CACHED_BYTES);
+        assert!(pipelines.cached("/a.css", Some("v1")).is_none());
+        assert!(pipelines.cached("/7.css", Some("v1")).is_some());
+    }
+
+    #[test]
+    fn test_minify() {
+        assert_eq!(
+            minify("<ul>\n    <li>a</li>\n\n  <li>b</li>  \n</ul>"),
+            "<ul>\n<li>a</li>\n<li>b</li>\n</ul>\n"
+        );
+        assert_eq!(
+            minify("  <div>\n  <pre>\n  x\n\n  y</pre>\n  </div>"),
+            "<div>\n<pre>\n  x\n\n  y</pre>\n</div>\n"
+        );
+    }
+}
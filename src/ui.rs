@@ -10,6 +10,18 @@ use indicatif::ProgressBar;
 /// Check if stderr is connected to an interactive terminal.
 pub fn is_interactive() -> bool { std::io::stderr().is_terminal() }
 
+/// Ask for a passphrase on the terminal without echoing it.
+pub fn read_passphrase(prompt: &str) -> anyhow::Result<String> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        return Err(anyhow::anyhow!(
+            "a terminal is needed to ask for the passphrase"
+        ));
+    }
+    term.write_str(prompt)?;
+    Ok(term.read_secure_line()?)
+}
+
 /// Format a timestamp in Common Log Format: `DD/Mon/YYYY:HH:MM:SS +0000`.
 pub fn clf_timestamp() -> String {
     Utc::now().format("%d/%b/%Y:%H:%M:%S +0000").to_string()